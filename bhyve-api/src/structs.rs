@@ -236,6 +236,12 @@ pub struct vm_lapic_irq {
     pub vector: c_int,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_nmi {
+    pub cpuid: c_int,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
 pub struct vm_ioapic_irq {
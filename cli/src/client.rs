@@ -0,0 +1,306 @@
+//! Subcommands which drive instances of a running propolis-server through its
+//! API, rather than creating a VM directly.
+
+use std::io::{Error, ErrorKind, Read, Result};
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+use std::thread;
+
+use propolis_client::serial::Detached;
+use propolis_client::{api, Client};
+use slog::{o, Discard, Logger};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Names of the subcommands.
+const COMMANDS: [&str; 5] = ["new", "state", "get", "monitor", "serial"];
+
+/// Ctrl-], which followed by '.' detaches from the serial console.
+const ESCAPE: u8 = 0x1d;
+
+pub const USAGE: &str = "\
+usage: propolis <CONFIG.toml>
+       propolis new -s <ADDR> [--cpus N] [--memory MiB] [--id UUID] <NAME>
+       propolis state -s <ADDR> <ID> run|stop|reboot
+       propolis get -s <ADDR> <ID>
+       propolis monitor -s <ADDR> <ID>
+       propolis serial -s <ADDR> [--port com1] [--offset N] <ID>";
+
+/// Returns true if `name` is one of the client subcommands.
+pub fn is_command(name: &str) -> bool {
+    COMMANDS.contains(&name)
+}
+
+fn arg_err(err: pico_args::Error) -> Error {
+    Error::new(ErrorKind::InvalidInput, err.to_string())
+}
+
+fn client_err(err: propolis_client::Error) -> Error {
+    Error::new(ErrorKind::Other, err.to_string())
+}
+
+/// Runs subcommand `cmd`, parsing its arguments from `args`.
+pub fn run(cmd: &str, mut args: pico_args::Arguments) -> Result<()> {
+    let server: SocketAddr = args
+        .opt_value_from_str(["-s", "--server"])
+        .map_err(arg_err)?
+        .ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "missing server address")
+        })?;
+    let log = Logger::root(Discard, o!());
+    let client = Client::new(server, log);
+    let rt = tokio::runtime::Runtime::new()?;
+
+    match cmd {
+        "new" => {
+            let vcpus = args.opt_value_from_str("--cpus").map_err(arg_err)?;
+            let memory =
+                args.opt_value_from_str("--memory").map_err(arg_err)?;
+            let id = args.opt_value_from_str("--id").map_err(arg_err)?;
+            let name: String = args.free_from_str().map_err(arg_err)?;
+            args.finish().map_err(arg_err)?;
+
+            let properties = api::InstanceProperties {
+                id: id.unwrap_or_else(Uuid::new_v4),
+                name,
+                description: "created by propolis-cli".to_string(),
+                image_id: Uuid::default(),
+                bootrom_id: Uuid::default(),
+                memory: memory.unwrap_or(1024),
+                vcpus: vcpus.unwrap_or(1),
+            };
+            rt.block_on(new_instance(&client, properties))
+        }
+        "state" => {
+            let id = args.free_from_str().map_err(arg_err)?;
+            let state = match args.free().map_err(arg_err)?.as_slice() {
+                [s] if s == "run" => api::InstanceStateRequested::Run,
+                [s] if s == "stop" => api::InstanceStateRequested::Stop,
+                [s] if s == "reboot" => api::InstanceStateRequested::Reboot,
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "expected state of run, stop or reboot",
+                    ))
+                }
+            };
+            rt.block_on(client.instance_state_put(id, state))
+                .map_err(client_err)
+        }
+        "get" => {
+            let id = args.free_from_str().map_err(arg_err)?;
+            args.finish().map_err(arg_err)?;
+            rt.block_on(get_instance(&client, id))
+        }
+        "monitor" => {
+            let id = args.free_from_str().map_err(arg_err)?;
+            args.finish().map_err(arg_err)?;
+            rt.block_on(monitor(&client, id))
+        }
+        "serial" => {
+            let port: Option<String> =
+                args.opt_value_from_str("--port").map_err(arg_err)?;
+            let offset =
+                args.opt_value_from_str("--offset").map_err(arg_err)?;
+            let id = args.free_from_str().map_err(arg_err)?;
+            args.finish().map_err(arg_err)?;
+
+            let port = port.unwrap_or_else(|| "com1".to_string());
+            rt.block_on(serial(&client, id, &port, offset.unwrap_or(0)))
+        }
+        _ => unreachable!("unknown subcommand {}", cmd),
+    }
+}
+
+async fn new_instance(
+    client: &Client,
+    properties: api::InstanceProperties,
+) -> Result<()> {
+    let id = properties.id;
+    let request =
+        api::InstanceEnsureRequest { properties, nics: vec![], disks: vec![] };
+    client.instance_ensure(&request).await.map_err(client_err)?;
+    println!("{}", id);
+    Ok(())
+}
+
+async fn get_instance(client: &Client, id: Uuid) -> Result<()> {
+    let instance = client.instance_get(id).await.map_err(client_err)?.instance;
+    let json = serde_json::to_string_pretty(&instance)?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// Prints each state the instance enters until it is destroyed.
+async fn monitor(client: &Client, id: Uuid) -> Result<()> {
+    let mut gen = 0;
+    loop {
+        let response =
+            client.instance_state_monitor(id, gen).await.map_err(client_err)?;
+        println!("{:?}", response.state);
+        if response.state == api::InstanceState::Destroyed {
+            return Ok(());
+        }
+        gen = response.gen + 1;
+    }
+}
+
+/// Relays the serial console between the terminal and the instance, until
+/// the user types the escape sequence or the console is detached.
+async fn serial(
+    client: &Client,
+    id: Uuid,
+    port: &str,
+    offset: u64,
+) -> Result<()> {
+    let console =
+        client.instance_serial(id, port, offset).await.map_err(client_err)?;
+    let (mut reader, mut writer) = console.split();
+
+    let _raw = RawTerm::enable()?;
+    eprint!("Attached to {}, type Ctrl-] . to detach\r\n", port);
+
+    let mut stdin = read_stdin()?;
+    let mut stdout = tokio::io::stdout();
+    let mut output = [0u8; 1024];
+    let mut escaped = false;
+    loop {
+        tokio::select! {
+            nread = reader.read(&mut output) => {
+                match nread {
+                    Ok(0) => {
+                        eprint!("\r\nConnection closed\r\n");
+                        return Ok(());
+                    }
+                    Ok(n) => {
+                        stdout.write_all(&output[..n]).await?;
+                        stdout.flush().await?;
+                    }
+                    Err(e) => match Detached::from_io(&e) {
+                        Some(detached) => {
+                            eprint!("\r\n{}\r\n", detached);
+                            return Ok(());
+                        }
+                        None => return Err(e),
+                    },
+                }
+            }
+            input = stdin.recv() => {
+                let input = match input {
+                    Some(input) => input?,
+                    None => return Ok(()),
+                };
+                let (data, detach) = unescape(&input, &mut escaped);
+                writer.write_all(&data).await?;
+                if detach {
+                    eprint!("\r\nDetached\r\n");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Reads the terminal on a thread of its own, passing its input through the
+/// returned channel, which is closed at the end of input.
+///
+/// A read of stdin cannot be cancelled, so one left pending on the runtime
+/// would keep it from shutting down until the user next typed something.
+/// The thread is instead abandoned, blocked in its read, when the process
+/// exits.
+fn read_stdin() -> Result<mpsc::Receiver<Result<Vec<u8>>>> {
+    let (tx, rx) = mpsc::channel(1);
+    thread::Builder::new().name("stdin".to_string()).spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buf = [0u8; 1024];
+        loop {
+            let input = match stdin.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => Ok(buf[..n].to_vec()),
+                Err(e) => Err(e),
+            };
+            let failed = input.is_err();
+            if tx.blocking_send(input).is_err() || failed {
+                return;
+            }
+        }
+    })?;
+    Ok(rx)
+}
+
+/// Strips the escape sequence from terminal input, returning the input to
+/// send and whether the user asked to detach.
+///
+/// `escaped` records whether the preceding input ended with the escape
+/// character.  Typing the escape character twice sends it once.
+fn unescape(data: &[u8], escaped: &mut bool) -> (Vec<u8>, bool) {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        if *escaped {
+            *escaped = false;
+            match b {
+                b'.' => return (out, true),
+                ESCAPE => out.push(ESCAPE),
+                _ => out.extend_from_slice(&[ESCAPE, b]),
+            }
+        } else if b == ESCAPE {
+            *escaped = true;
+        } else {
+            out.push(b);
+        }
+    }
+    (out, false)
+}
+
+/// Puts the terminal on stdin into raw mode, restoring its settings when
+/// dropped.
+struct RawTerm {
+    orig: libc::termios,
+}
+
+impl RawTerm {
+    fn enable() -> Result<Self> {
+        let mut orig = MaybeUninit::<libc::termios>::uninit();
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, orig.as_mut_ptr()) }
+            != 0
+        {
+            return Err(Error::last_os_error());
+        }
+        let orig = unsafe { orig.assume_init() };
+
+        let mut raw = orig;
+        raw.c_iflag &= !(libc::IGNBRK
+            | libc::BRKINT
+            | libc::PARMRK
+            | libc::ISTRIP
+            | libc::INLCR
+            | libc::IGNCR
+            | libc::ICRNL
+            | libc::IXON);
+        raw.c_oflag &= !libc::OPOST;
+        raw.c_lflag &= !(libc::ECHO
+            | libc::ECHONL
+            | libc::ICANON
+            | libc::ISIG
+            | libc::IEXTEN);
+        raw.c_cflag &= !(libc::CSIZE | libc::PARENB);
+        raw.c_cflag |= libc::CS8;
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) }
+            != 0
+        {
+            return Err(Error::last_os_error());
+        }
+        Ok(RawTerm { orig })
+    }
+}
+
+impl Drop for RawTerm {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.orig);
+        }
+    }
+}
@@ -6,6 +6,7 @@ use serde_derive::Deserialize;
 
 use crate::hw::pci;
 use propolis::block::{BlockDev, BlockReq};
+use propolis::hw::chipset::ChipsetKind;
 
 #[derive(Deserialize, Debug)]
 struct Top {
@@ -24,6 +25,8 @@ struct Main {
     cpus: u8,
     bootrom: String,
     memory: usize,
+    #[serde(default)]
+    chipset: Option<String>,
 }
 
 pub struct Config {
@@ -42,6 +45,12 @@ impl Config {
     pub fn get_bootrom(&self) -> &String {
         &self.inner.main.bootrom
     }
+    pub fn get_chipset(&self) -> std::io::Result<ChipsetKind> {
+        match &self.inner.main.chipset {
+            Some(name) => name.parse(),
+            None => Ok(ChipsetKind::default()),
+        }
+    }
     pub fn devs(&self) -> IterDevs {
         IterDevs { inner: self.inner.devices.iter() }
     }
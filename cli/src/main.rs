@@ -8,13 +8,15 @@ extern crate serde_derive;
 extern crate toml;
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::{Error, ErrorKind, Result};
 use std::path::Path;
 use std::sync::Arc;
 
 use propolis::chardev::{BlockingSource, Sink, Source};
-use propolis::hw::chipset::Chipset;
+use propolis::hw::chipset::{i440fx::I440Fx, q35::Q35};
+use propolis::hw::chipset::{Chipset, ChipsetKind};
 use propolis::hw::ibmpc;
 use propolis::hw::ps2ctrl::PS2Ctrl;
 use propolis::hw::uart::LpcUart;
@@ -24,6 +26,7 @@ use propolis::*;
 
 use propolis::usdt::register_probes;
 
+mod client;
 mod config;
 
 const PAGE_OFFSET: u64 = 0xfff;
@@ -31,12 +34,21 @@ const PAGE_OFFSET: u64 = 0xfff;
 const MAX_ROM_SIZE: usize = 0x20_0000;
 
 fn parse_args() -> config::Config {
-    let args = pico_args::Arguments::from_env();
-    if let Some(cpath) = args.free().ok().map(|mut f| f.pop()).flatten() {
-        config::parse(&cpath)
-    } else {
-        eprintln!("usage: propolis <CONFIG.toml>");
-        std::process::exit(libc::EXIT_FAILURE);
+    let mut args = pico_args::Arguments::from_env();
+    match args.subcommand().ok().flatten() {
+        // Drive a propolis-server, rather than running a VM ourselves
+        Some(cmd) if client::is_command(&cmd) => {
+            if let Err(e) = client::run(&cmd, args) {
+                eprintln!("{}: {}", cmd, e);
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+            std::process::exit(libc::EXIT_SUCCESS);
+        }
+        Some(cpath) => config::parse(&cpath),
+        None => {
+            eprintln!("{}", client::USAGE);
+            std::process::exit(libc::EXIT_FAILURE);
+        }
     }
 }
 
@@ -133,11 +145,22 @@ fn main() {
         machine.initialize_rtc(lowmem, highmem).unwrap();
 
         let hdl = machine.get_hdl();
-        let chipset = hw::chipset::i440fx::I440Fx::create(Arc::clone(&hdl));
-        chipset.attach(mctx);
-        let chipset_id = inv
-            .register(&chipset, "chipset".to_string(), None)
-            .map_err(|e| -> std::io::Error { e.into() })?;
+        let (chipset, chipset_id) = match config.get_chipset()? {
+            ChipsetKind::I440Fx => {
+                let chipset = I440Fx::create(Arc::clone(&hdl));
+                chipset.attach(mctx);
+                let id = inv.register(&chipset, "chipset".to_string(), None);
+                (chipset as Arc<dyn Chipset>, id)
+            }
+            ChipsetKind::Q35 => {
+                let chipset = Q35::create(Arc::clone(&hdl));
+                chipset.attach(mctx);
+                let id = inv.register(&chipset, "chipset".to_string(), None);
+                (chipset as Arc<dyn Chipset>, id)
+            }
+        };
+        let chipset_id =
+            chipset_id.map_err(|e| -> std::io::Error { e.into() })?;
 
         // UARTs
         let com1 = LpcUart::new(chipset.irq_pin(ibmpc::IRQ_COM1).unwrap());
@@ -185,6 +208,46 @@ fn main() {
         inv.register(&debug_device, "debug".to_string(), None)
             .map_err(|e| -> std::io::Error { e.into() })?;
 
+        // Bridges are attached ahead of other devices, so those which sit
+        // behind them have a bus to attach to.
+        let mut bridges = config
+            .devs()
+            .filter(|(_name, dev)| dev.driver == "pci-bridge")
+            .map(|(name, dev)| {
+                let bdf = config::parse_bdf(
+                    dev.options.get("pci-path").unwrap().as_str().unwrap(),
+                )
+                .unwrap();
+                let bus = dev
+                    .options
+                    .get("downstream-bus")
+                    .unwrap()
+                    .as_integer()
+                    .unwrap();
+                (name, bdf, bus as u8)
+            })
+            .collect::<Vec<_>>();
+        while !bridges.is_empty() {
+            // A bridge nested behind another is attached only once the bridge
+            // providing its bus has been.
+            let next = bridges
+                .iter()
+                .position(|(_name, bdf, _bus)| {
+                    !bridges.iter().any(|(_, _, bus)| *bus == bdf.bus())
+                })
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "PCI bridges are nested in a cycle",
+                    )
+                })?;
+            let (name, bdf, bus) = bridges.remove(next);
+            let bridge = hw::pci::Bridge::new(0x1b36, 0x0001, bus);
+            inv.register(&bridge, format!("pci-bridge-{}", name), None)
+                .map_err(|e| -> std::io::Error { e.into() })?;
+            chipset.pci_attach(bdf, bridge)?;
+        }
+
         let mut devices = HashMap::new();
 
         for (name, dev) in config.devs() {
@@ -210,7 +273,7 @@ fn main() {
                     );
                     inv.register(&vioblk, format!("vioblk-{}", name), None)
                         .map_err(|e| -> std::io::Error { e.into() })?;
-                    chipset.pci_attach(bdf.unwrap(), vioblk);
+                    chipset.pci_attach(bdf.unwrap(), vioblk)?;
 
                     block_dev
                         .start_dispatch(format!("bdev-{} thread", name), disp);
@@ -225,12 +288,33 @@ fn main() {
                     .unwrap();
                     inv.register(&viona, format!("viona-{}", name), None)
                         .map_err(|e| -> std::io::Error { e.into() })?;
-                    chipset.pci_attach(bdf.unwrap(), viona);
+                    chipset.pci_attach(bdf.unwrap(), viona)?;
                 }
                 "pci-nvme" => {
-                    let nvme = hw::nvme::PciNvme::create(0x1de, 0x1000);
+                    // Optional Controller Memory Buffer size in bytes
+                    let cmb_size = match dev.options.get("cmb_size") {
+                        Some(v) => Some(
+                            v.as_integer()
+                                .and_then(|v| usize::try_from(v).ok())
+                                .ok_or_else(|| {
+                                    Error::new(
+                                        ErrorKind::InvalidData,
+                                        format!(
+                                            "invalid cmb_size for {}",
+                                            name
+                                        ),
+                                    )
+                                })?,
+                        ),
+                        None => None,
+                    };
+                    let nvme =
+                        hw::nvme::PciNvme::create(0x1de, 0x1000, cmb_size)?;
                     devices.insert(&**name, nvme.clone());
-                    chipset.pci_attach(bdf.unwrap(), nvme);
+                    chipset.pci_attach(bdf.unwrap(), nvme)?;
+                }
+                "pci-bridge" => {
+                    // Attached ahead of all other devices
                 }
                 "nvme-ns" => {
                     let nvme_ctrl = dev
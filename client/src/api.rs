@@ -17,16 +17,57 @@ pub struct InstancePathParams {
 pub struct InstanceEnsureRequest {
     pub properties: InstanceProperties,
     pub nics: Vec<NetworkInterfaceRequest>,
+    #[serde(default)]
+    pub disks: Vec<DiskRequest>,
 }
 
-#[derive(Clone, Deserialize, Serialize, JsonSchema)]
-pub struct InstanceEnsureResponse {}
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceEnsureResponse {
+    /// Properties of an existing instance which were changed immediately.
+    #[serde(default)]
+    pub applied: Vec<InstanceProperty>,
+    /// Properties of an existing instance which will change when it next
+    /// reboots.
+    #[serde(default)]
+    pub deferred: Vec<InstanceProperty>,
+}
+
+/// A property of an instance which may be changed after its creation.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub enum InstanceProperty {
+    Name,
+    Description,
+    Vcpus,
+    Memory,
+}
 
 #[derive(Clone, Deserialize, Serialize, JsonSchema)]
 pub struct InstanceGetResponse {
     pub instance: Instance,
 }
 
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceListResponse {
+    pub instances: Vec<Instance>,
+}
+
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceSerialPathParams {
+    pub instance_id: Uuid,
+    /// Name of the serial port, from "com1" through "com4".
+    pub port: String,
+}
+
+/// Query parameters for attaching to the serial console of an instance.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceSerialParams {
+    /// Offset in the console's output from which to replay it. Output which
+    /// is no longer retained is skipped. An offset beyond the output produced
+    /// so far is refused.
+    #[serde(default)]
+    pub offset: u64,
+}
+
 #[derive(Clone, Deserialize, Serialize, JsonSchema)]
 pub struct InstanceStateMonitorRequest {
     pub gen: u64,
@@ -49,6 +90,53 @@ pub enum InstanceStateRequested {
     Run,
     Stop,
     Reboot,
+    /// Presses the power button, asking the guest to shut down.  The instance
+    /// is stopped forcibly if the guest does not do so in a timely manner.
+    GracefulStop,
+    /// Presses the power button, rebooting the instance once the guest has
+    /// shut down (or, failing that, after a timeout).
+    GracefulReboot,
+}
+
+/// Keyboard and mouse input to be injected into an Instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceInputRequest {
+    pub events: Vec<InputEvent>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InputEvent {
+    /// Presses and then releases a key, named as in "Enter" or "F2".  Keys
+    /// may be combined with '+' (e.g. "Ctrl+Alt+Delete"), in which case they
+    /// are pressed in order and released in reverse.
+    Key { name: String },
+    /// Types ASCII text, as on a US keyboard layout.
+    Text { text: String },
+    /// Raw key presses and releases, encoded in the given scan code set.
+    Scancodes { set: ScanCodeSet, codes: Vec<u8> },
+    /// Moves the mouse relative to its current position (with positive `dy`
+    /// moving up), while holding the given buttons.
+    Mouse {
+        dx: i32,
+        dy: i32,
+        #[serde(default)]
+        buttons: MouseButtons,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub enum ScanCodeSet {
+    Set1,
+    Set2,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(default)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
 }
 
 /// Current state of an Instance.
@@ -94,7 +182,7 @@ pub struct Instance {
 }
 
 /// Describes how to connect to one or more storage agent services.
-#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct StorageAgentDescription {
     /// Addresses of storage agents.
     pub agents: Vec<std::net::SocketAddrV6>,
@@ -110,14 +198,14 @@ pub struct StorageAgentDescription {
 
 /// Refer to RFD 135 for more information on Virtual Storage Interfaces.
 /// This describes the type of disk which should be exposed to the guest VM.
-#[derive(Clone, Copy, Deserialize, Serialize, JsonSchema)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
 pub enum DiskType {
     NVMe,
     VirtioBlock,
 }
 
 /// Describes a virtual disk.
-#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Disk {
     /// Unique identifier for this disk.
     pub id: Uuid,
@@ -165,6 +253,7 @@ pub struct DiskAttachmentInfo {
 #[derive(Clone, Deserialize, Serialize, JsonSchema)]
 pub enum DiskAttachmentState {
     Attached(Uuid),
+    Detaching(Uuid),
     Detached,
     Destroyed,
     Faulted,
@@ -182,12 +271,52 @@ pub struct DiskAttachment {
 #[derive(Copy, Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Slot(pub u8);
 
+/// A disk to be attached to an instance as it is created.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskRequest {
+    pub disk: Disk,
+    pub slot: Slot,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct NetworkInterfaceRequest {
     pub name: String,
     pub slot: Slot,
 }
 
+/// Request to hotplug a disk into a running instance.
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceDiskAttachRequest {
+    /// Unique identifier for this disk.
+    pub id: Uuid,
+    /// Name of the block device, as configured on the server, backing the
+    /// disk.
+    pub name: String,
+    pub slot: Slot,
+    /// Storage interface.
+    pub interface: DiskType,
+}
+
+/// Path parameters naming the disk or network interface in a slot of an
+/// instance, such as to remove it from the running instance.
+///
+/// The guest is asked to release the device, which is removed once it has
+/// done so.
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceSlotPathParams {
+    pub instance_id: Uuid,
+    pub slot: u8,
+}
+
+/// Request to inject a non-maskable interrupt into an instance, such as to
+/// trigger a crash dump from a hung guest.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceNmiRequest {
+    /// IDs of the vCPUs to interrupt. If unset, all vCPUs are interrupted.
+    #[serde(default)]
+    pub vcpus: Option<Vec<u8>>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct NetworkInterface {
     pub name: String,
@@ -10,6 +10,7 @@ use thiserror::Error;
 use uuid::Uuid;
 
 pub mod api;
+pub mod serial;
 
 /// Errors which may be returend from the Propolis Client.
 #[derive(Debug, Error)]
@@ -19,6 +20,12 @@ pub enum Error {
 
     #[error("Bad Status: {0}")]
     Status(u16),
+
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 /// Client-side connection to propolis.
@@ -126,6 +133,22 @@ impl Client {
         self.get(path, None).await
     }
 
+    /// Lists all instances hosted by the server.
+    pub async fn instance_list(
+        &self,
+    ) -> Result<api::InstanceListResponse, Error> {
+        let path = format!("http://{}/instances", self.address);
+        self.get(path, None).await
+    }
+
+    /// Stops and destroys an instance.
+    pub async fn instance_delete(&self, id: Uuid) -> Result<(), Error> {
+        let path = format!("http://{}/instances/{}", self.address, id);
+        info!(self.log, "DELETE request to {}", path);
+        send_and_check_ok(self.client.delete(path)).await?;
+        Ok(())
+    }
+
     /// Long-poll for state changes.
     pub async fn instance_state_monitor(
         &self,
@@ -141,6 +164,51 @@ impl Client {
         self.get(path, Some(body)).await
     }
 
+    /// Captures the instance's framebuffer, returning it as a PNG image.
+    pub async fn instance_screenshot(
+        &self,
+        id: Uuid,
+    ) -> Result<Vec<u8>, Error> {
+        let path =
+            format!("http://{}/instances/{}/screenshot", self.address, id);
+        info!(self.log, "GET request to {}", path);
+        let response = send_and_check_ok(self.client.get(path)).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Injects keyboard and mouse input into an instance.
+    pub async fn instance_input(
+        &self,
+        id: Uuid,
+        events: Vec<api::InputEvent>,
+    ) -> Result<(), Error> {
+        let path = format!("http://{}/instances/{}/input", self.address, id);
+        let body = Body::from(
+            serde_json::to_string(&api::InstanceInputRequest { events })
+                .unwrap(),
+        );
+        info!(self.log, "POST request to {}", path);
+        send_and_check_ok(self.client.post(path).body(body)).await?;
+        Ok(())
+    }
+
+    /// Injects a non-maskable interrupt into the vCPUs of an instance.
+    ///
+    /// If `vcpus` is `None`, all vCPUs are interrupted.
+    pub async fn instance_nmi(
+        &self,
+        id: Uuid,
+        vcpus: Option<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let path = format!("http://{}/instances/{}/nmi", self.address, id);
+        let body = Body::from(
+            serde_json::to_string(&api::InstanceNmiRequest { vcpus }).unwrap(),
+        );
+        info!(self.log, "POST request to {}", path);
+        send_and_check_ok(self.client.post(path).body(body)).await?;
+        Ok(())
+    }
+
     /// Puts an instance into a new state.
     pub async fn instance_state_put(
         &self,
@@ -151,4 +219,29 @@ impl Client {
         let body = Body::from(serde_json::to_string(&state).unwrap());
         self.put_no_response(path, Some(body)).await
     }
+
+    /// Attaches to serial port `port` ("com1" through "com4") of an instance,
+    /// replaying its output from `offset`.
+    pub async fn instance_serial(
+        &self,
+        id: Uuid,
+        port: &str,
+        offset: u64,
+    ) -> Result<serial::SerialConsole, Error> {
+        info!(self.log, "Attaching to serial port {} of instance {}", port, id);
+        serial::SerialConsole::connect(self.address, id, port, offset).await
+    }
+
+    /// Detaches all clients from serial port `port` of an instance.
+    pub async fn instance_serial_detach(
+        &self,
+        id: Uuid,
+        port: &str,
+    ) -> Result<(), Error> {
+        let path = format!(
+            "http://{}/instances/{}/serial-port/{}/detach",
+            self.address, id, port
+        );
+        self.put_no_response(path, None).await
+    }
 }
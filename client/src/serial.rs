@@ -0,0 +1,204 @@
+//! Client-side connection to the serial console of an instance.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::{SplitSink, SplitStream};
+use futures::{ready, Sink, Stream, StreamExt};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+use crate::Error;
+
+type WsStream = WebSocketStream<TcpStream>;
+
+/// The error with which reads from a console fail once the server detaches
+/// it, such as in favor of another client.
+///
+/// Reads yield it wrapped in an [`io::Error`] of kind
+/// [`io::ErrorKind::ConnectionAborted`]; see [`Detached::from_io`].
+#[derive(Debug, Error)]
+#[error("serial console was detached: {reason}")]
+pub struct Detached {
+    pub reason: String,
+}
+
+impl Detached {
+    /// Returns the detach which caused `err`, if it was one.
+    pub fn from_io(err: &io::Error) -> Option<&Detached> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+/// A connection to the serial console of an instance.
+pub struct SerialConsole {
+    reader: SerialReader,
+    writer: SerialWriter,
+}
+
+impl SerialConsole {
+    /// Connects to serial port `port` of instance `id` on the server at
+    /// `address`, replaying its output from `offset`.
+    pub(crate) async fn connect(
+        address: SocketAddr,
+        id: Uuid,
+        port: &str,
+        offset: u64,
+    ) -> Result<Self, Error> {
+        let url = format!(
+            "ws://{}/instances/{}/serial-port/{}?offset={}",
+            address, id, port, offset
+        );
+        let stream = TcpStream::connect(address).await?;
+        let (ws, _) = tokio_tungstenite::client_async(url, stream).await?;
+        let (sink, stream) = ws.split();
+        Ok(SerialConsole {
+            reader: SerialReader { stream, pending: Vec::new(), consumed: 0 },
+            writer: SerialWriter { sink },
+        })
+    }
+
+    /// Splits the connection into its output and input halves.
+    pub fn split(self) -> (SerialReader, SerialWriter) {
+        (self.reader, self.writer)
+    }
+}
+
+impl AsyncRead for SerialConsole {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SerialConsole {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+/// The output of a serial console.
+///
+/// Reaches end-of-file when the connection is closed, or fails with
+/// [`Detached`] if the server detaches it.
+pub struct SerialReader {
+    stream: SplitStream<WsStream>,
+    /// Data received but not yet read
+    pending: Vec<u8>,
+    consumed: usize,
+}
+
+impl AsyncRead for SerialReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.consumed == self.pending.len() {
+            let msg = match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(msg)) => msg,
+                Some(Err(tungstenite::Error::ConnectionClosed)) | None => {
+                    return Poll::Ready(Ok(()));
+                }
+                Some(Err(e)) => return Poll::Ready(Err(ws_to_io(e))),
+            };
+            match msg {
+                Message::Binary(data) => {
+                    self.pending = data;
+                    self.consumed = 0;
+                }
+                Message::Text(text) => {
+                    self.pending = text.into_bytes();
+                    self.consumed = 0;
+                }
+                Message::Close(Some(frame))
+                    if frame.code == CloseCode::Policy =>
+                {
+                    let reason = frame.reason.into_owned();
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        Detached { reason },
+                    )));
+                }
+                Message::Close(_) => return Poll::Ready(Ok(())),
+                _ => {}
+            }
+        }
+
+        let n = buf.remaining().min(self.pending.len() - self.consumed);
+        let start = self.consumed;
+        buf.put_slice(&self.pending[start..start + n]);
+        self.consumed += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The input of a serial console.
+///
+/// Input is discarded by the server unless this client was the first of
+/// those attached to the console.
+pub struct SerialWriter {
+    sink: SplitSink<WsStream, Message>,
+}
+
+impl AsyncWrite for SerialWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut sink = Pin::new(&mut self.sink);
+        ready!(sink.as_mut().poll_ready(cx)).map_err(ws_to_io)?;
+        sink.start_send(Message::binary(buf)).map_err(ws_to_io)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.sink).poll_flush(cx).map_err(ws_to_io)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.sink).poll_close(cx).map_err(ws_to_io)
+    }
+}
+
+fn ws_to_io(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
@@ -0,0 +1,482 @@
+//! Block device encrypting the contents of another.
+//!
+//! Each block is encrypted with XTS-AES (IEEE 1619), using its block number
+//! as the tweak, so the store holds only ciphertext.  As the ciphertext of a
+//! block is the same size as its plaintext, no metadata is needed, and the
+//! layout of the underlying store is unchanged.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+
+use aes::cipher::{BlockDecrypt, BlockEncrypt, NewBlockCipher};
+use aes::{Aes128, Aes256, Block};
+
+use super::{
+    BlockDev, BlockInquiry, BlockOp, BlockReq, BlockResult, BlockStore,
+};
+use crate::dispatch::{DispCtx, Dispatcher, SyncCtx};
+
+const AES_BLOCK_SIZE: usize = 16;
+
+enum XtsKeys {
+    Aes128 { data: Aes128, tweak: Aes128 },
+    Aes256 { data: Aes256, tweak: Aes256 },
+}
+
+/// XTS-AES cipher, operating on a sector at a time.
+pub struct XtsCipher {
+    keys: XtsKeys,
+}
+impl XtsCipher {
+    /// Creates a cipher from `key`, the concatenation of the data and tweak
+    /// keys: 32 bytes for XTS-AES-128, or 64 for XTS-AES-256.
+    pub fn new(key: &[u8]) -> Result<Self> {
+        let (data, tweak) = key.split_at(key.len() / 2);
+        if data == tweak {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "XTS data and tweak keys must differ",
+            ));
+        }
+        let keys = match key.len() {
+            32 => XtsKeys::Aes128 {
+                data: Aes128::new_from_slice(data).unwrap(),
+                tweak: Aes128::new_from_slice(tweak).unwrap(),
+            },
+            64 => XtsKeys::Aes256 {
+                data: Aes256::new_from_slice(data).unwrap(),
+                tweak: Aes256::new_from_slice(tweak).unwrap(),
+            },
+            len => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("XTS key of {} bytes is not 32 or 64", len),
+                ))
+            }
+        };
+        Ok(Self { keys })
+    }
+
+    /// Encrypts `buf`, the contents of sector `sector`, in place.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `buf` is not a multiple of the AES block size.
+    pub fn encrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+        match &self.keys {
+            XtsKeys::Aes128 { data, tweak } => xts(
+                sector,
+                buf,
+                |t| tweak.encrypt_block(t),
+                |b| data.encrypt_block(b),
+            ),
+            XtsKeys::Aes256 { data, tweak } => xts(
+                sector,
+                buf,
+                |t| tweak.encrypt_block(t),
+                |b| data.encrypt_block(b),
+            ),
+        }
+    }
+
+    /// Decrypts `buf`, the contents of sector `sector`, in place.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `buf` is not a multiple of the AES block size.
+    pub fn decrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+        match &self.keys {
+            XtsKeys::Aes128 { data, tweak } => xts(
+                sector,
+                buf,
+                |t| tweak.encrypt_block(t),
+                |b| data.decrypt_block(b),
+            ),
+            XtsKeys::Aes256 { data, tweak } => xts(
+                sector,
+                buf,
+                |t| tweak.encrypt_block(t),
+                |b| data.decrypt_block(b),
+            ),
+        }
+    }
+}
+
+/// Applies `crypt`, the encryption or decryption function of the data key, to
+/// the AES blocks of a sector, with `encrypt_tweak` being the encryption
+/// function of the tweak key.
+fn xts(
+    sector: u64,
+    buf: &mut [u8],
+    encrypt_tweak: impl Fn(&mut Block),
+    crypt: impl Fn(&mut Block),
+) {
+    assert_eq!(buf.len() % AES_BLOCK_SIZE, 0);
+
+    let mut tweak = Block::default();
+    tweak[..8].copy_from_slice(&sector.to_le_bytes());
+    encrypt_tweak(&mut tweak);
+
+    for chunk in buf.chunks_exact_mut(AES_BLOCK_SIZE) {
+        let block = Block::from_mut_slice(chunk);
+        xor_block(block, &tweak);
+        crypt(block);
+        xor_block(block, &tweak);
+        mul_alpha(&mut tweak);
+    }
+}
+
+fn xor_block(block: &mut Block, tweak: &Block) {
+    for (b, t) in block.iter_mut().zip(tweak.iter()) {
+        *b ^= t;
+    }
+}
+
+/// Multiplies the tweak by the primitive element of GF(2^128), advancing it to
+/// that of the next AES block within the sector.
+fn mul_alpha(tweak: &mut Block) {
+    let mut carry = 0;
+    for byte in tweak.iter_mut() {
+        let next = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
+
+struct CryptQueue<R: BlockReq> {
+    reqs: Mutex<VecDeque<R>>,
+    cond: Condvar,
+}
+
+/// [`BlockDev`] implementation encrypting the contents of a [`BlockStore`].
+pub struct CryptBdev<R: BlockReq> {
+    inner: Arc<dyn BlockStore>,
+    info: BlockInquiry,
+    cipher: XtsCipher,
+    queue: CryptQueue<R>,
+}
+
+impl<R: BlockReq> CryptBdev<R> {
+    /// Creates a block device which encrypts its contents with `key` (see
+    /// [`XtsCipher::new`]) before passing them to `inner`, a store with the
+    /// geometry described by `info`.
+    pub fn create(
+        inner: Arc<dyn BlockStore>,
+        info: BlockInquiry,
+        key: &[u8],
+    ) -> Result<Arc<Self>> {
+        if info.block_size as usize % AES_BLOCK_SIZE != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot encrypt {} byte blocks", info.block_size),
+            ));
+        }
+        Ok(Arc::new(Self {
+            inner,
+            info,
+            cipher: XtsCipher::new(key)?,
+            queue: CryptQueue {
+                reqs: Mutex::new(VecDeque::new()),
+                cond: Condvar::new(),
+            },
+        }))
+    }
+
+    /// Returns the first block of a transfer of `len` bytes at `offset`, which
+    /// must be aligned to the block size.
+    fn first_block(&self, offset: u64, len: usize) -> Result<u64> {
+        let block_size = self.info.block_size as u64;
+        if offset % block_size != 0 || len as u64 % block_size != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "unaligned access to encrypted device",
+            ));
+        }
+        Ok(offset / block_size)
+    }
+
+    /// Takes requests from the queue, one at a time, until the worker is asked
+    /// to yield, completing each once it has passed through the cipher.
+    fn process_loop(&self, sctx: &mut SyncCtx) {
+        loop {
+            if sctx.check_yield() {
+                break;
+            }
+
+            let mut reqs = self.queue.reqs.lock().unwrap();
+            if let Some(mut req) = reqs.pop_front() {
+                drop(reqs);
+                let ctx = sctx.dispctx();
+                let result = match self.process_request(&mut req, &ctx) {
+                    Ok(status) => status,
+                    Err(_) => BlockResult::Failure,
+                };
+                req.complete(result, &ctx);
+            } else {
+                let _reqs = self.queue.cond.wait(reqs).unwrap();
+            }
+        }
+    }
+
+    /// Bounce the request buffers through host memory, where their contents
+    /// are encrypted or decrypted.
+    fn process_request(
+        &self,
+        req: &mut R,
+        ctx: &DispCtx,
+    ) -> Result<BlockResult> {
+        let mem = ctx.mctx.memctx();
+        let offset = req.offset() as u64;
+        let mut bufs = vec![];
+        while let Some(buf) = req.next_buf() {
+            bufs.push(buf);
+        }
+        let total_size: usize = bufs.iter().map(|buf| buf.1).sum();
+
+        let guest_err = || Error::new(ErrorKind::Other, "bad guest region");
+        let mut data = vec![0u8; total_size];
+        match req.oper() {
+            BlockOp::Flush => {
+                return self.flush().map(|_| BlockResult::Success)
+            }
+            BlockOp::Read => {
+                self.read_at(offset, &mut data)?;
+                let mut pos = 0;
+                for buf in bufs.iter() {
+                    let src = &data[pos..pos + buf.1];
+                    if mem.write_from(buf.0, src, buf.1) != Some(buf.1) {
+                        return Err(guest_err());
+                    }
+                    pos += buf.1;
+                }
+                return Ok(BlockResult::Success);
+            }
+            _ if !self.info.writable && req.oper() != BlockOp::Compare => {
+                return Ok(BlockResult::Failure);
+            }
+            _ => {}
+        }
+
+        let mut pos = 0;
+        for buf in bufs.iter() {
+            let dst = &mut data[pos..pos + buf.1];
+            if mem.read_into(buf.0, dst, buf.1) != Some(buf.1) {
+                return Err(guest_err());
+            }
+            pos += buf.1;
+        }
+        let cmp_len = match req.oper() {
+            BlockOp::Write => {
+                self.write_at(offset, &data)?;
+                return Ok(BlockResult::Success);
+            }
+            BlockOp::CompareAndWrite => total_size / 2,
+            _ => total_size,
+        };
+
+        // The comparison is against the decrypted contents, as the guest sees
+        // them. The inner store is only reached through this device's single
+        // worker, leaving nothing to write the blocks after they are read.
+        let mut contents = vec![0u8; cmp_len];
+        self.read_at(offset, &mut contents)?;
+        if contents[..] != data[..cmp_len] {
+            return Ok(BlockResult::Miscompare);
+        }
+        if req.oper() == BlockOp::CompareAndWrite {
+            self.write_at(offset, &data[cmp_len..])?;
+        }
+        Ok(BlockResult::Success)
+    }
+}
+
+impl<R: BlockReq> BlockStore for CryptBdev<R> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let first = self.first_block(offset, buf.len())?;
+        self.inner.read_at(offset, buf)?;
+        let block_size = self.info.block_size as usize;
+        for (n, sector) in buf.chunks_exact_mut(block_size).enumerate() {
+            self.cipher.decrypt_sector(first + n as u64, sector);
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        let first = self.first_block(offset, buf.len())?;
+        let mut data = buf.to_vec();
+        let block_size = self.info.block_size as usize;
+        for (n, sector) in data.chunks_exact_mut(block_size).enumerate() {
+            self.cipher.encrypt_sector(first + n as u64, sector);
+        }
+        self.inner.write_at(offset, &data)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: BlockReq> BlockDev<R> for CryptBdev<R> {
+    fn enqueue(&self, req: R) {
+        self.queue.reqs.lock().unwrap().push_back(req);
+        self.queue.cond.notify_all();
+    }
+
+    fn inquire(&self) -> BlockInquiry {
+        BlockInquiry {
+            total_size: self.info.total_size,
+            block_size: self.info.block_size,
+            writable: self.info.writable,
+        }
+    }
+
+    /// Spawns the worker `name`, which alone accesses the inner store on
+    /// behalf of the guest.
+    fn start_dispatch(self: Arc<Self>, name: String, disp: &Dispatcher) {
+        let ww = Arc::downgrade(&self);
+
+        let bdev = Arc::clone(&self);
+        disp.spawn_sync(
+            name,
+            Box::new(move |sctx| {
+                bdev.process_loop(sctx);
+            }),
+            Some(Box::new(move |_ctx| {
+                if let Some(this) = Weak::upgrade(&ww) {
+                    this.queue.cond.notify_all()
+                }
+            })),
+        )
+        .unwrap();
+    }
+
+    fn as_store(self: Arc<Self>) -> Option<Arc<dyn BlockStore>> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Vector 4 of IEEE 1619-2007
+    const KEY: &str =
+        "2718281828459045235360287471352631415926535897932384626433832795";
+
+    fn key() -> Vec<u8> {
+        (0..KEY.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&KEY[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn pattern() -> Vec<u8> {
+        (0..512).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn xts_vector() {
+        let cipher = XtsCipher::new(&key()).unwrap();
+        let mut buf = pattern();
+        cipher.encrypt_sector(0, &mut buf);
+        assert_eq!(
+            &buf[..16],
+            &[
+                0x27, 0xa7, 0x47, 0x9b, 0xef, 0xa1, 0xd4, 0x76, 0x48, 0x9f,
+                0x30, 0x8c, 0xd4, 0xcf, 0xa6, 0xe2
+            ]
+        );
+        assert_eq!(
+            &buf[496..],
+            &[
+                0x0a, 0x28, 0x2d, 0xf9, 0x20, 0x14, 0x7b, 0xea, 0xbe, 0x42,
+                0x1e, 0xe5, 0x31, 0x9d, 0x05, 0x68
+            ]
+        );
+
+        cipher.decrypt_sector(0, &mut buf);
+        assert_eq!(buf, pattern());
+    }
+
+    #[test]
+    fn sector_tweak() {
+        let cipher = XtsCipher::new(&key()).unwrap();
+        let mut first = pattern();
+        let mut second = pattern();
+        cipher.encrypt_sector(0, &mut first);
+        cipher.encrypt_sector(1, &mut second);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn bad_keys() {
+        assert!(XtsCipher::new(&[0u8; 16]).is_err());
+        assert!(XtsCipher::new(&[0u8; 32]).is_err());
+        let mut key = key();
+        key.push(0);
+        assert!(XtsCipher::new(&key).is_err());
+    }
+
+    struct NullReq;
+    impl BlockReq for NullReq {
+        fn oper(&self) -> BlockOp {
+            BlockOp::Flush
+        }
+        fn offset(&self) -> usize {
+            0
+        }
+        fn next_buf(&mut self) -> Option<crate::common::GuestRegion> {
+            None
+        }
+        fn complete(self, _res: BlockResult, _ctx: &DispCtx) {}
+    }
+
+    struct MemStore(Mutex<Vec<u8>>);
+    impl BlockStore for MemStore {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+            let data = self.0.lock().unwrap();
+            let start = offset as usize;
+            buf.copy_from_slice(&data[start..start + buf.len()]);
+            Ok(())
+        }
+        fn write_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+            let mut data = self.0.lock().unwrap();
+            let start = offset as usize;
+            data[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn store_roundtrip() {
+        let inner = Arc::new(MemStore(Mutex::new(vec![0u8; 2048])));
+        let info =
+            BlockInquiry { total_size: 4, block_size: 512, writable: true };
+        let bdev = CryptBdev::<NullReq>::create(
+            Arc::clone(&inner) as Arc<dyn BlockStore>,
+            info,
+            &key(),
+        )
+        .unwrap();
+
+        let data: Vec<u8> = pattern().into_iter().chain(pattern()).collect();
+        bdev.write_at(512, &data).unwrap();
+
+        // Only ciphertext reaches the store
+        let stored = inner.0.lock().unwrap().clone();
+        assert_ne!(&stored[512..1536], &data[..]);
+
+        let mut buf = vec![0u8; 1024];
+        bdev.read_at(512, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        assert!(bdev.read_at(100, &mut buf).is_err());
+        assert!(bdev.write_at(512, &data[..100]).is_err());
+    }
+}
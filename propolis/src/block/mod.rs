@@ -4,6 +4,7 @@ use std::collections::VecDeque;
 use std::fs::{metadata, File, OpenOptions};
 use std::io::Result;
 use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileExt;
 use std::path::Path;
 use std::sync::Condvar;
 use std::sync::{Arc, Mutex, Weak};
@@ -12,12 +13,26 @@ use crate::common::*;
 use crate::dispatch::{DispCtx, Dispatcher, SyncCtx};
 use crate::vmm::{MappingExt, MemCtx, SubMapping};
 
+pub mod crypt;
+pub mod net;
+
+pub use crypt::CryptBdev;
+pub use net::NetBdev;
+
 /// Type of operations which may be issued to a virtual block device.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum BlockOp {
     Flush,
     Read,
     Write,
+    /// Compare the contents of the device against the request buffers,
+    /// leaving both unmodified.
+    Compare,
+    /// Atomically compare and, if matching, write the device.
+    ///
+    /// The request buffers hold the data to compare against followed by
+    /// the data to write, each making up half of the total transfer.
+    CompareAndWrite,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -25,6 +40,8 @@ pub enum BlockResult {
     Success,
     Failure,
     Unsupported,
+    /// The device contents did not match for a compare operation.
+    Miscompare,
 }
 
 /// Trait indicating that a type may be used as a request to a block device.
@@ -63,6 +80,28 @@ pub trait BlockDev<R: BlockReq>: Send + Sync + 'static {
     /// Spawns a new thread named `name` on the dispatcher `disp` which
     /// begins processing incoming requests.
     fn start_dispatch(self: Arc<Self>, name: String, disp: &Dispatcher);
+
+    /// Returns the device as a [`BlockStore`], if its contents can be
+    /// accessed through host buffers.
+    fn as_store(self: Arc<Self>) -> Option<Arc<dyn BlockStore>> {
+        None
+    }
+}
+
+/// Storage holding the contents of a block device, accessed through host
+/// buffers rather than guest memory.
+///
+/// This allows devices such as [`CryptBdev`] to transform the contents of
+/// another device on their way to and from the guest.
+pub trait BlockStore: Send + Sync + 'static {
+    /// Fills `buf` with the contents of the store at byte `offset`.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Writes `buf` to the store at byte `offset`.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<()>;
+
+    /// Flushes prior writes to stable storage.
+    fn flush(&self) -> Result<()>;
 }
 
 /// Standard [`BlockDev`] implementation.
@@ -140,6 +179,13 @@ impl<R: BlockReq> FileBdev<R> {
                 self.process_rw_request(false, offset, &mem, bufs)
             }
             BlockOp::Flush => self.process_flush(),
+            BlockOp::Compare => {
+                self.process_compare_request(false, offset, &mem, bufs)
+            }
+            BlockOp::CompareAndWrite if self.is_ro => Ok(BlockResult::Failure),
+            BlockOp::CompareAndWrite => {
+                self.process_compare_request(true, offset, &mem, bufs)
+            }
         };
 
         match result {
@@ -183,6 +229,43 @@ impl<R: BlockReq> FileBdev<R> {
         Ok(BlockResult::Success)
     }
 
+    /// Compare the file contents against the request buffers, writing out the
+    /// second half of the buffers if `write` is set and the first half matched.
+    ///
+    /// Requests are processed one at a time, so no other I/O can slip in
+    /// between the comparison and the write.
+    fn process_compare_request(
+        &self,
+        write: bool,
+        offset: usize,
+        mem: &MemCtx,
+        bufs: Vec<GuestRegion>,
+    ) -> Result<BlockResult> {
+        let total_size: usize = bufs.iter().map(|buf| buf.1).sum();
+        let mut data = vec![0u8; total_size];
+        let mut pos = 0;
+        for buf in bufs.iter() {
+            let mapping = mem.readable_region(buf).ok_or_else(|| {
+                Error::new(ErrorKind::Other, "getting a region failed!")
+            })?;
+            let nbytes = mapping.read_bytes(&mut data[pos..pos + buf.1])?;
+            assert_eq!(nbytes, buf.1);
+            pos += buf.1;
+        }
+
+        let cmp_len = if write { total_size / 2 } else { total_size };
+        let mut contents = vec![0u8; cmp_len];
+        self.fp.read_exact_at(&mut contents, offset as u64)?;
+        if contents[..] != data[..cmp_len] {
+            return Ok(BlockResult::Miscompare);
+        }
+
+        if write {
+            self.fp.write_all_at(&data[cmp_len..], offset as u64)?;
+        }
+        Ok(BlockResult::Success)
+    }
+
     /// Send flush to the file
     fn process_flush(&self) -> Result<BlockResult> {
         self.fp.sync_data()?;
@@ -223,6 +306,27 @@ impl<R: BlockReq> BlockDev<R> for FileBdev<R> {
         )
         .unwrap();
     }
+
+    fn as_store(self: Arc<Self>) -> Option<Arc<dyn BlockStore>> {
+        Some(self)
+    }
+}
+
+impl<R: BlockReq> BlockStore for FileBdev<R> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.fp.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        if self.is_ro {
+            return Err(Error::new(ErrorKind::PermissionDenied, "read-only"));
+        }
+        self.fp.write_all_at(buf, offset)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.fp.sync_data()
+    }
 }
 
 /*
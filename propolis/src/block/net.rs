@@ -0,0 +1,1122 @@
+//! Block device backed by networked storage agents.
+//!
+//! Requests are carried to each agent over TCP as length-prefixed frames,
+//! with all integers little-endian:
+//!
+//! ```text
+//! request:  len: u32 | op: u8 | id: u64 | offset: u64 | size: u32 | data
+//! response: len: u32 | status: u8 | id: u64 | data
+//! ```
+//!
+//! The `len` prefix counts the bytes of the frame which follow it.  Only
+//! writes carry data in their request, and only reads in their response.
+//!
+//! Writes and flushes are issued to every agent, and are complete once the
+//! write redundancy threshold of agents have acknowledged them.  Reads are
+//! served by a single agent.  Should the connection to an agent be lost, it is
+//! re-established, and any requests the agent had yet to answer are replayed,
+//! other than reads which another agent can serve instead.
+//!
+//! Requests already completed are only held for replay up to a limit.  An
+//! agent which falls further behind than that is marked stale: it is no longer
+//! sent requests, nor relied upon for reads, as it must first be brought back
+//! in sync with the others.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LE};
+
+use super::{
+    BlockDev, BlockInquiry, BlockOp, BlockReq, BlockResult, BlockStore,
+};
+use crate::common::*;
+use crate::dispatch::{DispCtx, Dispatcher, SyncCtx};
+
+const OP_READ: u8 = 1;
+const OP_WRITE: u8 = 2;
+const OP_FLUSH: u8 = 3;
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERR: u8 = 1;
+
+const REQ_HDR_LEN: usize = 21;
+const RESP_HDR_LEN: usize = 9;
+
+/// Upper bound on the size of a frame, protecting against a corrupt length
+/// prefix forcing an absurd allocation.
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Delay between attempts to connect to an unreachable agent.
+const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+
+/// Default limit on the bytes of completed requests held for replay to an
+/// agent which has yet to answer them.
+const DEFAULT_MAX_BACKLOG: usize = 256 * 1024 * 1024;
+
+/// Operations which may be issued to a storage agent.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum AgentOp {
+    Read,
+    Write,
+    Flush,
+}
+
+#[derive(Clone)]
+struct AgentReq {
+    op: AgentOp,
+    offset: u64,
+    size: u32,
+    /// Data to be written, shared between the copies sent to each agent
+    data: Arc<Vec<u8>>,
+}
+
+fn read_frame(r: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = LE::read_u32(&len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds limit", len),
+        ));
+    }
+    let mut frame = vec![0u8; len];
+    r.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+fn write_request(w: &mut impl Write, id: u64, req: &AgentReq) -> Result<()> {
+    let (op, data): (u8, &[u8]) = match req.op {
+        AgentOp::Read => (OP_READ, &[]),
+        AgentOp::Write => (OP_WRITE, &req.data),
+        AgentOp::Flush => (OP_FLUSH, &[]),
+    };
+    let mut buf = vec![0u8; 4 + REQ_HDR_LEN];
+    LE::write_u32(&mut buf[0..4], (REQ_HDR_LEN + data.len()) as u32);
+    buf[4] = op;
+    LE::write_u64(&mut buf[5..13], id);
+    LE::write_u64(&mut buf[13..21], req.offset);
+    LE::write_u32(&mut buf[21..25], req.size);
+    buf.extend_from_slice(data);
+    w.write_all(&buf)
+}
+
+fn decode_request(frame: Vec<u8>) -> Result<(u64, AgentReq)> {
+    if frame.len() < REQ_HDR_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "short request"));
+    }
+    let op = match frame[0] {
+        OP_READ => AgentOp::Read,
+        OP_WRITE => AgentOp::Write,
+        OP_FLUSH => AgentOp::Flush,
+        op => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown op {}", op),
+            ))
+        }
+    };
+    let id = LE::read_u64(&frame[1..9]);
+    let offset = LE::read_u64(&frame[9..17]);
+    let size = LE::read_u32(&frame[17..21]);
+    let data = frame[REQ_HDR_LEN..].to_vec();
+    if op == AgentOp::Write && data.len() != size as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "write size does not match its data",
+        ));
+    }
+    Ok((id, AgentReq { op, offset, size, data: Arc::new(data) }))
+}
+
+fn write_response(
+    w: &mut impl Write,
+    id: u64,
+    res: &Result<Vec<u8>>,
+) -> Result<()> {
+    let (status, data): (u8, &[u8]) = match res {
+        Ok(data) => (STATUS_OK, data),
+        Err(_) => (STATUS_ERR, &[]),
+    };
+    let mut buf = vec![0u8; 4 + RESP_HDR_LEN];
+    LE::write_u32(&mut buf[0..4], (RESP_HDR_LEN + data.len()) as u32);
+    buf[4] = status;
+    LE::write_u64(&mut buf[5..13], id);
+    buf.extend_from_slice(data);
+    w.write_all(&buf)
+}
+
+fn decode_response(frame: Vec<u8>) -> Result<(u64, Result<Vec<u8>>)> {
+    if frame.len() < RESP_HDR_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "short response"));
+    }
+    let id = LE::read_u64(&frame[1..9]);
+    let res = match frame[0] {
+        STATUS_OK => Ok(frame[RESP_HDR_LEN..].to_vec()),
+        _ => Err(Error::new(ErrorKind::Other, "agent failed request")),
+    };
+    Ok((id, res))
+}
+
+type Completion = Box<dyn FnOnce(Result<Vec<u8>>) + Send>;
+
+/// Bytes which holding `req` for replay counts against the backlog limit.
+fn backlog_cost(req: &AgentReq) -> usize {
+    REQ_HDR_LEN + req.data.len()
+}
+
+struct Inflight {
+    req: AgentReq,
+    /// Agents which have yet to answer the request
+    outstanding: BTreeSet<usize>,
+    /// Agents which have not yet been asked to serve a read
+    untried: Vec<usize>,
+    acks: usize,
+    needed: usize,
+    /// Data returned for a read
+    data: Option<Vec<u8>>,
+    done: Option<Completion>,
+}
+
+/// An established connection to an agent.
+struct AgentConn {
+    stream: TcpStream,
+    /// Encoded requests, written to the agent by a thread of their own so
+    /// that an agent slow to read them holds up no others
+    queue: mpsc::Sender<Vec<u8>>,
+}
+impl AgentConn {
+    /// Takes over the connection `stream`, starting the thread which writes
+    /// the requests queued for `addr` to it.
+    fn start(stream: TcpStream, addr: SocketAddr) -> Result<Self> {
+        let mut writer = stream.try_clone()?;
+        let (queue, requests) = mpsc::channel::<Vec<u8>>();
+        thread::Builder::new()
+            .name(format!("storage agent {} writer", addr))
+            .spawn(move || {
+            for buf in requests {
+                if writer.write_all(&buf).is_err() {
+                    // The reader notices, and the connection is dropped
+                    let _ = writer.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+        })?;
+        Ok(Self { stream, queue })
+    }
+
+    fn close(self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+struct ClientState {
+    next_id: u64,
+    /// Agent to which the next read is issued
+    next_reader: usize,
+    inflight: BTreeMap<u64, Inflight>,
+    /// Connections to the agents, where established
+    conns: Vec<Option<AgentConn>>,
+    /// Bytes of completed requests held for replay to each agent
+    backlog: Vec<usize>,
+    max_backlog: usize,
+    /// Agents which fell too far behind to be replayed what they missed
+    stale: Vec<bool>,
+}
+impl ClientState {
+    /// Queues request `id` to be sent to `agent`, if connected, dropping the
+    /// connection should its writer have failed.  Unsent requests are
+    /// replayed on reconnection.
+    fn send(&mut self, agent: usize, id: u64) {
+        let conn = match self.conns[agent].as_ref() {
+            Some(conn) => conn,
+            None => return,
+        };
+        let mut buf = Vec::new();
+        write_request(&mut buf, id, &self.inflight[&id].req).unwrap();
+        if conn.queue.send(buf).is_err() {
+            self.conns[agent].take().unwrap().close();
+        }
+    }
+
+    /// Issues read `id` to one of the agents yet to be asked to serve it,
+    /// returning false if none remain.
+    fn retry_read(&mut self, id: u64) -> bool {
+        let entry = self.inflight.get_mut(&id).unwrap();
+        if entry.req.op != AgentOp::Read || entry.untried.is_empty() {
+            return false;
+        }
+        let untried = std::mem::take(&mut entry.untried);
+        let next = self.pick_reader(&untried);
+        let entry = self.inflight.get_mut(&id).unwrap();
+        entry.untried = untried.into_iter().filter(|a| *a != next).collect();
+        entry.outstanding.insert(next);
+        self.send(next, id);
+        true
+    }
+
+    /// Re-issues the reads awaiting `agent`, which has disconnected, to other
+    /// agents.  Reads which no other agent can serve wait for it to reconnect.
+    fn redirect_reads(&mut self, agent: usize) {
+        let ids: Vec<u64> = self
+            .inflight
+            .iter()
+            .filter(|(_id, entry)| {
+                entry.req.op == AgentOp::Read
+                    && !entry.untried.is_empty()
+                    && entry.outstanding.contains(&agent)
+            })
+            .map(|(id, _entry)| *id)
+            .collect();
+        for id in ids {
+            self.inflight.get_mut(&id).unwrap().outstanding.remove(&agent);
+            self.retry_read(id);
+        }
+    }
+
+    /// Picks the agent to serve a read, favoring those which are connected.
+    fn pick_reader(&mut self, candidates: &[usize]) -> usize {
+        let count = self.conns.len();
+        let start = self.next_reader;
+        self.next_reader = (self.next_reader + 1) % count;
+        (0..count)
+            .map(|n| (start + n) % count)
+            .filter(|agent| candidates.contains(agent))
+            .find(|agent| self.conns[*agent].is_some())
+            .unwrap_or(candidates[0])
+    }
+
+    /// Records the answer of `agent` to request `id`, returning the completion
+    /// of each request (and its result) which is now resolved.
+    fn answer(
+        &mut self,
+        agent: usize,
+        id: u64,
+        res: Result<Vec<u8>>,
+    ) -> Vec<(Completion, Result<Vec<u8>>)> {
+        let mut resolved = Vec::new();
+        let entry = match self.inflight.get_mut(&id) {
+            Some(entry) => entry,
+            None => return resolved,
+        };
+        if !entry.outstanding.remove(&agent) {
+            return resolved;
+        }
+        if entry.done.is_none() {
+            // Only held for replay to this agent, and the others lagging
+            self.backlog[agent] -= backlog_cost(&entry.req);
+        } else {
+            match res {
+                Ok(data) => {
+                    entry.acks += 1;
+                    if entry.req.op == AgentOp::Read {
+                        entry.data = Some(data);
+                    }
+                    if entry.acks >= entry.needed {
+                        let data = entry.data.take().unwrap_or_default();
+                        let done = self.resolve(id);
+                        resolved.push((done, Ok(data)));
+                    }
+                }
+                Err(e) => {
+                    // Try a read again elsewhere
+                    if !self.retry_read(id) {
+                        let entry = &self.inflight[&id];
+                        if entry.acks + entry.outstanding.len() < entry.needed {
+                            let done = self.resolve(id);
+                            resolved.push((done, Err(e)));
+                        }
+                    }
+                }
+            }
+        }
+        self.release(id);
+        resolved.extend(self.evict_lagging());
+        resolved
+    }
+
+    /// Takes the completion of request `id`, which is resolved, holding the
+    /// request only for replay to the agents yet to answer it.
+    fn resolve(&mut self, id: u64) -> Completion {
+        let entry = self.inflight.get_mut(&id).unwrap();
+        let cost = backlog_cost(&entry.req);
+        for agent in entry.outstanding.iter() {
+            self.backlog[*agent] += cost;
+        }
+        entry.done.take().unwrap()
+    }
+
+    /// Drops request `id` once every agent it was issued to has answered.
+    fn release(&mut self, id: u64) {
+        if self.inflight[&id].outstanding.is_empty() {
+            self.inflight.remove(&id);
+        }
+    }
+
+    /// Marks stale any agent whose backlog has outgrown the limit, returning
+    /// the completions of requests which fail without it.
+    fn evict_lagging(&mut self) -> Vec<(Completion, Result<Vec<u8>>)> {
+        let mut failed = Vec::new();
+        while let Some(agent) = (0..self.backlog.len())
+            .find(|agent| self.backlog[*agent] > self.max_backlog)
+        {
+            failed.extend(self.mark_stale(agent));
+        }
+        failed
+    }
+
+    /// Stops issuing requests to `agent`, dropping those it has yet to answer,
+    /// and returns the completions of the requests which then fail.
+    fn mark_stale(
+        &mut self,
+        agent: usize,
+    ) -> Vec<(Completion, Result<Vec<u8>>)> {
+        self.stale[agent] = true;
+        if let Some(conn) = self.conns[agent].take() {
+            conn.close();
+        }
+        let mut failed = Vec::new();
+        let ids: Vec<u64> = self.inflight.keys().copied().collect();
+        for id in ids {
+            let entry = self.inflight.get_mut(&id).unwrap();
+            entry.untried.retain(|a| *a != agent);
+            if entry.outstanding.remove(&agent)
+                && entry.done.is_some()
+                && !self.retry_read(id)
+            {
+                let entry = &self.inflight[&id];
+                if entry.acks + entry.outstanding.len() < entry.needed {
+                    let done = self.resolve(id);
+                    let e = Error::new(ErrorKind::Other, "storage agent stale");
+                    failed.push((done, Err(e)));
+                }
+            }
+            self.release(id);
+        }
+        self.backlog[agent] = 0;
+        failed
+    }
+}
+
+struct ClientShared {
+    agents: Vec<SocketAddr>,
+    threshold: usize,
+    state: Mutex<ClientState>,
+    shutdown: AtomicBool,
+}
+impl ClientShared {
+    /// Maintains the connection to `agent`, answering requests with the
+    /// responses it sends until the client is shut down.
+    fn agent_loop(&self, agent: usize) {
+        while !self.shutdown.load(Ordering::Acquire) {
+            if self.state.lock().unwrap().stale[agent] {
+                break;
+            }
+            let mut conn = match TcpStream::connect(self.agents[agent]) {
+                Ok(conn) => conn,
+                Err(_) => {
+                    thread::sleep(RECONNECT_DELAY);
+                    continue;
+                }
+            };
+            let _ = conn.set_nodelay(true);
+            let writer = match conn.try_clone() {
+                Ok(writer) => writer,
+                Err(_) => continue,
+            };
+            let writer = match AgentConn::start(writer, self.agents[agent]) {
+                Ok(writer) => writer,
+                Err(_) => continue,
+            };
+
+            let mut state = self.state.lock().unwrap();
+            if self.shutdown.load(Ordering::Acquire) || state.stale[agent] {
+                writer.close();
+                break;
+            }
+            state.conns[agent] = Some(writer);
+            // Replay anything the agent has yet to answer, in order
+            let replay: Vec<u64> = state
+                .inflight
+                .iter()
+                .filter(|(_id, entry)| entry.outstanding.contains(&agent))
+                .map(|(id, _entry)| *id)
+                .collect();
+            for id in replay {
+                state.send(agent, id);
+            }
+            drop(state);
+
+            while let Ok((id, res)) =
+                read_frame(&mut conn).and_then(decode_response)
+            {
+                let resolved =
+                    self.state.lock().unwrap().answer(agent, id, res);
+                for (done, res) in resolved {
+                    done(res);
+                }
+            }
+
+            let mut state = self.state.lock().unwrap();
+            if let Some(conn) = state.conns[agent].take() {
+                conn.close();
+            }
+            state.redirect_reads(agent);
+        }
+    }
+}
+
+/// Client issuing requests to a set of storage agents.
+pub struct AgentClient {
+    shared: Arc<ClientShared>,
+}
+impl AgentClient {
+    /// Creates a client for the storage agents at `agents`, connecting to
+    /// them in the background.
+    ///
+    /// Writes are considered complete once `threshold` agents have
+    /// acknowledged them.
+    pub fn new(agents: Vec<SocketAddr>, threshold: usize) -> Result<Self> {
+        if agents.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "no storage agents specified",
+            ));
+        }
+        if threshold == 0 || threshold > agents.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "write redundancy threshold {} is not within 1-{}",
+                    threshold,
+                    agents.len()
+                ),
+            ));
+        }
+        let count = agents.len();
+        let shared = Arc::new(ClientShared {
+            agents,
+            threshold,
+            state: Mutex::new(ClientState {
+                next_id: 0,
+                next_reader: 0,
+                inflight: BTreeMap::new(),
+                conns: (0..count).map(|_| None).collect(),
+                backlog: vec![0; count],
+                max_backlog: DEFAULT_MAX_BACKLOG,
+                stale: vec![false; count],
+            }),
+            shutdown: AtomicBool::new(false),
+        });
+        for agent in 0..count {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name(format!("storage agent {}", shared.agents[agent]))
+                .spawn(move || shared.agent_loop(agent))?;
+        }
+        Ok(Self { shared })
+    }
+
+    /// Sets the limit on the bytes of completed requests held for replay to
+    /// an agent, beyond which it is marked stale.
+    pub fn set_max_backlog(&self, max_backlog: usize) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.max_backlog = max_backlog;
+        let failed = state.evict_lagging();
+        drop(state);
+        for (done, res) in failed {
+            done(res);
+        }
+    }
+
+    fn submit(&self, req: AgentReq, done: Completion) {
+        let count = self.shared.agents.len();
+        let mut state = self.shared.state.lock().unwrap();
+        let live: Vec<usize> =
+            (0..count).filter(|agent| !state.stale[*agent]).collect();
+        let needed = match req.op {
+            AgentOp::Read => 1,
+            AgentOp::Write | AgentOp::Flush => self.shared.threshold,
+        };
+        if live.len() < needed {
+            drop(state);
+            done(Err(Error::new(
+                ErrorKind::Other,
+                "too few storage agents remain in sync",
+            )));
+            return;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+
+        let (targets, untried) = match req.op {
+            AgentOp::Read => {
+                let agent = state.pick_reader(&live);
+                let untried =
+                    live.into_iter().filter(|a| *a != agent).collect();
+                (vec![agent], untried)
+            }
+            AgentOp::Write | AgentOp::Flush => (live, Vec::new()),
+        };
+        state.inflight.insert(
+            id,
+            Inflight {
+                req,
+                outstanding: targets.iter().copied().collect(),
+                untried,
+                acks: 0,
+                needed,
+                data: None,
+                done: Some(done),
+            },
+        );
+        for agent in targets {
+            state.send(agent, id);
+        }
+    }
+
+    /// Reads `size` bytes at `offset`, passing them to `done`.
+    pub fn read(
+        &self,
+        offset: u64,
+        size: u32,
+        done: impl FnOnce(Result<Vec<u8>>) + Send + 'static,
+    ) {
+        let req = AgentReq {
+            op: AgentOp::Read,
+            offset,
+            size,
+            data: Arc::new(Vec::new()),
+        };
+        self.submit(req, Box::new(done));
+    }
+
+    /// Writes `data` at `offset`, calling `done` once enough agents have
+    /// acknowledged it.
+    pub fn write(
+        &self,
+        offset: u64,
+        data: Vec<u8>,
+        done: impl FnOnce(Result<Vec<u8>>) + Send + 'static,
+    ) {
+        let req = AgentReq {
+            op: AgentOp::Write,
+            offset,
+            size: data.len() as u32,
+            data: Arc::new(data),
+        };
+        self.submit(req, Box::new(done));
+    }
+
+    /// Flushes prior writes to stable storage, calling `done` once enough
+    /// agents have done so.
+    pub fn flush(&self, done: impl FnOnce(Result<Vec<u8>>) + Send + 'static) {
+        let req = AgentReq {
+            op: AgentOp::Flush,
+            offset: 0,
+            size: 0,
+            data: Arc::new(Vec::new()),
+        };
+        self.submit(req, Box::new(done));
+    }
+}
+impl Drop for AgentClient {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        self.shared.shutdown.store(true, Ordering::Release);
+        for conn in state.conns.iter_mut() {
+            if let Some(conn) = conn.take() {
+                conn.close();
+            }
+        }
+    }
+}
+
+struct NetQueue<R: BlockReq> {
+    /// Requests yet to be issued to the agents
+    pending: VecDeque<R>,
+    /// Requests answered by the agents, to be completed
+    answered: VecDeque<(R, Vec<GuestRegion>, Result<Vec<u8>>)>,
+}
+
+struct NetQueueShared<R: BlockReq> {
+    queue: Mutex<NetQueue<R>>,
+    cond: Condvar,
+}
+
+/// [`BlockDev`] implementation backed by networked storage agents.
+pub struct NetBdev<R: BlockReq> {
+    client: AgentClient,
+    block_size: u32,
+    block_count: u64,
+    shared: Arc<NetQueueShared<R>>,
+}
+
+impl<R: BlockReq> NetBdev<R> {
+    /// Creates a block device of `block_count` blocks of `block_size` bytes,
+    /// stored by the agents at `agents`.
+    ///
+    /// Writes are acknowledged to the guest once `threshold` agents have
+    /// committed them.
+    pub fn create(
+        agents: Vec<SocketAddr>,
+        threshold: usize,
+        block_size: u32,
+        block_count: u64,
+    ) -> Result<Arc<Self>> {
+        let client = AgentClient::new(agents, threshold)?;
+        Ok(Arc::new(Self {
+            client,
+            block_size,
+            block_count,
+            shared: Arc::new(NetQueueShared {
+                queue: Mutex::new(NetQueue {
+                    pending: VecDeque::new(),
+                    answered: VecDeque::new(),
+                }),
+                cond: Condvar::new(),
+            }),
+        }))
+    }
+
+    /// Issue enqueued requests to the agents, and complete those which they
+    /// have answered.
+    fn process_loop(&self, sctx: &mut SyncCtx) {
+        loop {
+            if sctx.check_yield() {
+                break;
+            }
+
+            let mut queue = self.shared.queue.lock().unwrap();
+            if let Some(req) = queue.pending.pop_front() {
+                drop(queue);
+                self.issue_request(req, &sctx.dispctx());
+            } else if let Some((req, bufs, res)) = queue.answered.pop_front() {
+                drop(queue);
+                let ctx = sctx.dispctx();
+                let result = Self::finish_request(&req, bufs, res, &ctx);
+                req.complete(result, &ctx);
+            } else {
+                let _queue = self.shared.cond.wait(queue).unwrap();
+            }
+        }
+    }
+
+    fn issue_request(&self, mut req: R, ctx: &DispCtx) {
+        let mut bufs = vec![];
+        while let Some(buf) = req.next_buf() {
+            bufs.push(buf);
+        }
+        let offset = req.offset() as u64;
+        let size: usize = bufs.iter().map(|buf| buf.1).sum();
+        let capacity = self.block_count * self.block_size as u64;
+        match offset.checked_add(size as u64) {
+            Some(end) if end <= capacity => {}
+            _ => {
+                req.complete(BlockResult::Failure, ctx);
+                return;
+            }
+        }
+
+        let data = match req.oper() {
+            BlockOp::Write => {
+                let mem = ctx.mctx.memctx();
+                let mut data = vec![0u8; size];
+                let mut pos = 0;
+                for buf in bufs.iter() {
+                    let dst = &mut data[pos..pos + buf.1];
+                    if mem.read_into(buf.0, dst, buf.1) != Some(buf.1) {
+                        req.complete(BlockResult::Failure, ctx);
+                        return;
+                    }
+                    pos += buf.1;
+                }
+                Some(data)
+            }
+            BlockOp::Read | BlockOp::Flush => None,
+            // Comparisons cannot be made atomic across the agents
+            BlockOp::Compare | BlockOp::CompareAndWrite => {
+                req.complete(BlockResult::Unsupported, ctx);
+                return;
+            }
+        };
+
+        let op = req.oper();
+        let shared = Arc::clone(&self.shared);
+        let done = move |res| {
+            let mut queue = shared.queue.lock().unwrap();
+            queue.answered.push_back((req, bufs, res));
+            shared.cond.notify_all();
+        };
+        match op {
+            BlockOp::Read => self.client.read(offset, size as u32, done),
+            BlockOp::Write => self.client.write(offset, data.unwrap(), done),
+            _ => self.client.flush(done),
+        }
+    }
+
+    fn finish_request(
+        req: &R,
+        bufs: Vec<GuestRegion>,
+        res: Result<Vec<u8>>,
+        ctx: &DispCtx,
+    ) -> BlockResult {
+        let data = match res {
+            Ok(data) => data,
+            Err(_) => return BlockResult::Failure,
+        };
+        if req.oper() == BlockOp::Read {
+            let mem = ctx.mctx.memctx();
+            let mut pos = 0;
+            for buf in bufs.iter() {
+                let src = match data.get(pos..pos + buf.1) {
+                    Some(src) => src,
+                    None => return BlockResult::Failure,
+                };
+                if mem.write_from(buf.0, src, buf.1) != Some(buf.1) {
+                    return BlockResult::Failure;
+                }
+                pos += buf.1;
+            }
+        }
+        BlockResult::Success
+    }
+}
+
+impl<R: BlockReq> BlockDev<R> for NetBdev<R> {
+    fn enqueue(&self, req: R) {
+        self.shared.queue.lock().unwrap().pending.push_back(req);
+        self.shared.cond.notify_all();
+    }
+
+    fn inquire(&self) -> BlockInquiry {
+        BlockInquiry {
+            total_size: self.block_count,
+            block_size: self.block_size,
+            writable: true,
+        }
+    }
+
+    /// Spawns the worker `name`, which hands requests to the agent client and
+    /// completes them once answered; the agent connections have their own
+    /// threads, started with the device.
+    fn start_dispatch(self: Arc<Self>, name: String, disp: &Dispatcher) {
+        let shared = Arc::clone(&self.shared);
+        let bdev = Arc::clone(&self);
+        disp.spawn_sync(
+            name,
+            Box::new(move |sctx| {
+                bdev.process_loop(sctx);
+            }),
+            Some(Box::new(move |_ctx| shared.cond.notify_all())),
+        )
+        .unwrap();
+    }
+
+    fn as_store(self: Arc<Self>) -> Option<Arc<dyn BlockStore>> {
+        Some(self)
+    }
+}
+
+impl<R: BlockReq> BlockStore for NetBdev<R> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let (done, wait) = waiter();
+        self.client.read(offset, buf.len() as u32, done);
+        let data = wait_for(wait)?;
+        if data.len() != buf.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "short read"));
+        }
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        let (done, wait) = waiter();
+        self.client.write(offset, buf.to_vec(), done);
+        wait_for(wait).map(|_| ())
+    }
+
+    fn flush(&self) -> Result<()> {
+        let (done, wait) = waiter();
+        self.client.flush(done);
+        wait_for(wait).map(|_| ())
+    }
+}
+
+type Waiter = mpsc::Receiver<Result<Vec<u8>>>;
+
+/// Returns a completion for a request to the agents, along with the channel
+/// on which its result is delivered.
+fn waiter() -> (impl FnOnce(Result<Vec<u8>>) + Send + 'static, Waiter) {
+    let (send, recv) = mpsc::channel();
+    (
+        move |res| {
+            let _ = send.send(res);
+        },
+        recv,
+    )
+}
+
+fn wait_for(wait: Waiter) -> Result<Vec<u8>> {
+    wait.recv().map_err(|_| {
+        Error::new(ErrorKind::BrokenPipe, "storage agent client shut down")
+    })?
+}
+
+struct AgentShared {
+    data: Mutex<Vec<u8>>,
+    conns: Mutex<Vec<TcpStream>>,
+    stalled: AtomicBool,
+}
+
+/// A storage agent, holding its data in memory, which runs within the
+/// process.  Intended as a stand-in for real agents when testing.
+pub struct LocalAgent {
+    addr: SocketAddr,
+    shared: Arc<AgentShared>,
+}
+impl LocalAgent {
+    /// Starts an agent storing `size` bytes of zeroes, listening on an
+    /// ephemeral port of the loopback interface.
+    pub fn start(size: usize) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(AgentShared {
+            data: Mutex::new(vec![0u8; size]),
+            conns: Mutex::new(Vec::new()),
+            stalled: AtomicBool::new(false),
+        });
+        let accept_shared = Arc::clone(&shared);
+        thread::Builder::new().name(format!("local agent {}", addr)).spawn(
+            move || {
+                for conn in listener.incoming().flatten() {
+                    let shared = Arc::clone(&accept_shared);
+                    if let Ok(clone) = conn.try_clone() {
+                        shared.conns.lock().unwrap().push(clone);
+                    }
+                    let _ = thread::Builder::new()
+                        .name(format!("local agent {} conn", addr))
+                        .spawn(move || shared.serve(conn));
+                }
+            },
+        )?;
+        Ok(Self { addr, shared })
+    }
+
+    /// Address on which the agent is listening.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns a copy of the data held by the agent.
+    pub fn contents(&self) -> Vec<u8> {
+        self.shared.data.lock().unwrap().clone()
+    }
+
+    /// Sets whether the agent discards requests, rather than answering them.
+    pub fn set_stalled(&self, stalled: bool) {
+        self.shared.stalled.store(stalled, Ordering::Release);
+    }
+
+    /// Drops all of the connections to the agent.
+    pub fn disconnect(&self) {
+        for conn in self.shared.conns.lock().unwrap().drain(..) {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl AgentShared {
+    fn serve(&self, mut conn: TcpStream) {
+        while let Ok((id, req)) = read_frame(&mut conn).and_then(decode_request)
+        {
+            if self.stalled.load(Ordering::Acquire) {
+                continue;
+            }
+            let res = self.process(&req);
+            if write_response(&mut conn, id, &res).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn process(&self, req: &AgentReq) -> Result<Vec<u8>> {
+        let mut data = self.data.lock().unwrap();
+        let start = req.offset as usize;
+        let range = start..start + req.size as usize;
+        if req.op != AgentOp::Flush && range.end > data.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "out of range"));
+        }
+        match req.op {
+            AgentOp::Read => Ok(data[range].to_vec()),
+            AgentOp::Write => {
+                data[range].copy_from_slice(&req.data);
+                Ok(Vec::new())
+            }
+            AgentOp::Flush => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn frames() {
+        let req = AgentReq {
+            op: AgentOp::Write,
+            offset: 0x1000,
+            size: 3,
+            data: Arc::new(vec![1, 2, 3]),
+        };
+        let mut buf = Vec::new();
+        write_request(&mut buf, 7, &req).unwrap();
+        let (id, decoded) =
+            decode_request(read_frame(&mut &buf[..]).unwrap()).unwrap();
+        assert_eq!(id, 7);
+        assert_eq!(decoded.op, AgentOp::Write);
+        assert_eq!(decoded.offset, 0x1000);
+        assert_eq!(decoded.size, 3);
+        assert_eq!(*decoded.data, vec![1, 2, 3]);
+
+        let mut buf = Vec::new();
+        write_response(&mut buf, 9, &Ok(vec![4, 5])).unwrap();
+        let (id, res) =
+            decode_response(read_frame(&mut &buf[..]).unwrap()).unwrap();
+        assert_eq!(id, 9);
+        assert_eq!(res.unwrap(), vec![4, 5]);
+    }
+
+    #[test]
+    fn read_write() {
+        let agent = LocalAgent::start(4096).unwrap();
+        let client = AgentClient::new(vec![agent.addr()], 1).unwrap();
+
+        let (done, wait) = waiter();
+        client.write(512, vec![0xaa; 512], done);
+        wait.recv_timeout(TIMEOUT).unwrap().unwrap();
+
+        let (done, wait) = waiter();
+        client.read(256, 512, done);
+        let data = wait.recv_timeout(TIMEOUT).unwrap().unwrap();
+        assert_eq!(&data[..256], &[0u8; 256][..]);
+        assert_eq!(&data[256..], &[0xaau8; 256][..]);
+
+        // Out-of-range accesses are refused
+        let (done, wait) = waiter();
+        client.read(4096, 512, done);
+        assert!(wait.recv_timeout(TIMEOUT).unwrap().is_err());
+    }
+
+    #[test]
+    fn redundancy_threshold() {
+        let agents: Vec<LocalAgent> =
+            (0..3).map(|_| LocalAgent::start(4096).unwrap()).collect();
+        let addrs = agents.iter().map(|agent| agent.addr()).collect();
+        let client = AgentClient::new(addrs, 2).unwrap();
+
+        // With one agent unresponsive, the other two satisfy the threshold
+        agents[2].set_stalled(true);
+        let (done, wait) = waiter();
+        client.write(0, vec![0x55; 512], done);
+        wait.recv_timeout(TIMEOUT).unwrap().unwrap();
+        assert_eq!(&agents[0].contents()[..512], &[0x55u8; 512][..]);
+        assert_eq!(&agents[1].contents()[..512], &[0x55u8; 512][..]);
+
+        // With two unresponsive, it cannot be
+        agents[1].set_stalled(true);
+        let (done, wait) = waiter();
+        client.write(512, vec![0x66; 512], done);
+        assert!(wait.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn replay_on_reconnect() {
+        let agent = LocalAgent::start(4096).unwrap();
+        let client = AgentClient::new(vec![agent.addr()], 1).unwrap();
+
+        agent.set_stalled(true);
+        let (done, wait) = waiter();
+        client.write(1024, vec![0x77; 512], done);
+        assert!(wait.recv_timeout(Duration::from_millis(500)).is_err());
+
+        // The write discarded by the agent is sent again on reconnection
+        agent.set_stalled(false);
+        agent.disconnect();
+        wait.recv_timeout(TIMEOUT).unwrap().unwrap();
+        assert_eq!(&agent.contents()[1024..1536], &[0x77u8; 512][..]);
+    }
+
+    #[test]
+    fn redirect_on_disconnect() {
+        let agents: Vec<LocalAgent> =
+            (0..2).map(|_| LocalAgent::start(4096).unwrap()).collect();
+        let addrs = agents.iter().map(|agent| agent.addr()).collect();
+        let client = AgentClient::new(addrs, 2).unwrap();
+
+        // Once both agents have acknowledged a write, both are connected
+        let (done, wait) = waiter();
+        client.write(0, vec![0x88; 512], done);
+        wait.recv_timeout(TIMEOUT).unwrap().unwrap();
+
+        // The first read goes to the first agent, which sits on it
+        agents[0].set_stalled(true);
+        let (done, wait) = waiter();
+        client.read(0, 512, done);
+        assert!(wait.recv_timeout(Duration::from_millis(500)).is_err());
+
+        // Losing it sends the read to the other agent
+        agents[0].disconnect();
+        let data = wait.recv_timeout(TIMEOUT).unwrap().unwrap();
+        assert_eq!(data, vec![0x88; 512]);
+    }
+
+    #[test]
+    fn absent_agent() {
+        let agent = LocalAgent::start(4096).unwrap();
+        // Nothing listens at the address of the second agent
+        let absent =
+            TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let client = AgentClient::new(vec![agent.addr(), absent], 1).unwrap();
+        client.set_max_backlog(4096);
+
+        // The writes held for the absent agent are dropped once they outgrow
+        // the backlog, and it is no longer sent any
+        for n in 0..32 {
+            let (done, wait) = waiter();
+            client.write(0, vec![n; 512], done);
+            wait.recv_timeout(TIMEOUT).unwrap().unwrap();
+        }
+        {
+            let state = client.shared.state.lock().unwrap();
+            assert!(state.stale[1]);
+            assert!(state.inflight.is_empty());
+            assert_eq!(state.backlog, vec![0, 0]);
+        }
+
+        // Nor is it asked to serve reads
+        for _ in 0..2 {
+            let (done, wait) = waiter();
+            client.read(0, 512, done);
+            let data = wait.recv_timeout(TIMEOUT).unwrap().unwrap();
+            assert_eq!(data, vec![31; 512]);
+        }
+    }
+
+    #[test]
+    fn bad_threshold() {
+        let agent = LocalAgent::start(512).unwrap();
+        assert!(AgentClient::new(vec![agent.addr()], 0).is_err());
+        assert!(AgentClient::new(vec![agent.addr()], 2).is_err());
+        assert!(AgentClient::new(vec![], 1).is_err());
+    }
+}
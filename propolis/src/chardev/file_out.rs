@@ -1,6 +1,9 @@
+use std::ffi::OsString;
 use std::fs::File as FsFile;
-use std::io::Result;
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result};
 use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
@@ -8,11 +11,114 @@ use crate::chardev::pollers;
 use crate::chardev::BlockingSource;
 use crate::dispatch::{AsyncCtx, Dispatcher};
 
-use tokio::fs::File;
+use tokio::fs::{self, File};
 use tokio::io::AsyncWriteExt;
 
+/// Limits on the growth of a [`RotatingFile`].
+struct Rotation {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+}
+
+/// An output file which, once it would grow beyond a size limit, is moved
+/// aside in favor of a new one.
+///
+/// Previous files are kept as `<path>.1` (the most recent), `<path>.2` and so
+/// on, up to a limit beyond which the oldest is removed.
+pub struct RotatingFile {
+    fp: File,
+    size: u64,
+    rotation: Option<Rotation>,
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending, rotating it before it exceeds `max_size`
+    /// bytes and keeping up to `keep` previous files.
+    pub fn create(
+        path: impl Into<PathBuf>,
+        max_size: u64,
+        keep: usize,
+    ) -> Result<Self> {
+        let path = path.into();
+        let fp = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = fp.metadata()?.len();
+        Ok(Self {
+            fp: File::from_std(fp),
+            size,
+            rotation: Some(Rotation { path, max_size, keep }),
+        })
+    }
+
+    /// Wraps `fp`, which grows without limit.
+    pub fn unbounded(fp: FsFile) -> Self {
+        Self { fp: File::from_std(fp), size: 0, rotation: None }
+    }
+
+    /// Writes all of `data` to the file, rotating it first if it would
+    /// otherwise exceed its size limit.
+    ///
+    /// Data larger than the limit is written in its entirety to a new file.
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        if let Some(rotation) = &self.rotation {
+            let len = data.len() as u64;
+            if self.size > 0 && self.size + len > rotation.max_size {
+                self.rotate().await?;
+            }
+        }
+        self.fp.write_all(data).await?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Waits for data written so far to reach the file.
+    pub async fn flush(&mut self) -> Result<()> {
+        self.fp.flush().await
+    }
+
+    async fn rotate(&mut self) -> Result<()> {
+        let rotation = self.rotation.as_ref().unwrap();
+        self.fp.flush().await?;
+
+        let path = &rotation.path;
+        if rotation.keep == 0 {
+            fs::remove_file(path).await?;
+        } else {
+            for n in (1..rotation.keep).rev() {
+                ignore_missing(
+                    fs::rename(numbered(path, n), numbered(path, n + 1)).await,
+                )?;
+            }
+            fs::rename(path, numbered(path, 1)).await?;
+        }
+
+        self.fp = File::create(path).await?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Returns the path of the `n`th most recent file rotated out of `path`.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn ignore_missing(res: Result<()>) -> Result<()> {
+    match res {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Called with the error which stopped a [`BlockingFileOutput`] from being
+/// written.
+pub type ErrorFn = dyn FnOnce(Error) + Send + 'static;
+
 struct Inner {
-    fp: Option<FsFile>,
+    fp: Option<RotatingFile>,
+    on_error: Option<Box<ErrorFn>>,
 }
 
 pub struct BlockingFileOutput {
@@ -24,6 +130,17 @@ const BUF_SIZE: usize = 256;
 
 impl BlockingFileOutput {
     pub fn new(fp: FsFile) -> Result<Arc<Self>> {
+        Self::rotating(RotatingFile::unbounded(fp), None)
+    }
+
+    /// Creates an output which is written to `fp`, subject to its rotation.
+    ///
+    /// Should a write fail, the error is passed to `on_error`, and further
+    /// output is discarded rather than left to stall its source.
+    pub fn rotating(
+        fp: RotatingFile,
+        on_error: Option<Box<ErrorFn>>,
+    ) -> Result<Arc<Self>> {
         let params = pollers::BlockingParams {
             poll_interval: Duration::from_millis(10),
             poll_miss_thresh: 5,
@@ -31,28 +148,98 @@ impl BlockingFileOutput {
         };
         let poller = pollers::BlockingSourceBuffer::new(params);
 
-        Ok(Arc::new(Self { poller, inner: Mutex::new(Inner { fp: Some(fp) }) }))
+        Ok(Arc::new(Self {
+            poller,
+            inner: Mutex::new(Inner { fp: Some(fp), on_error }),
+        }))
     }
 
     pub fn attach(&self, source: Arc<dyn BlockingSource>, disp: &Dispatcher) {
         let mut inner = self.inner.lock().unwrap();
         let fp = inner.fp.take().unwrap();
+        let on_error = inner.on_error.take();
 
         self.poller.attach(source.as_ref());
 
         let poller = Arc::clone(&self.poller);
         disp.spawn_async(|mut _actx: AsyncCtx| async move {
-            let afp = File::from_std(fp);
-            let _ = Self::run(poller, afp).await;
+            let _ = Self::run(poller, fp, on_error).await;
         });
     }
 
-    async fn run(poller: Arc<pollers::BlockingSourceBuffer>, mut fp: File) {
+    async fn run(
+        poller: Arc<pollers::BlockingSourceBuffer>,
+        fp: RotatingFile,
+        mut on_error: Option<Box<ErrorFn>>,
+    ) {
         let mut buf = [0u8; BUF_SIZE];
+        let mut fp = Some(fp);
         loop {
-            if let Some(n) = poller.read(&mut buf).await {
-                fp.write_all(&buf[..n]).await.unwrap();
+            let n = match poller.read(&mut buf).await {
+                Some(n) => n,
+                None => continue,
+            };
+            if let Some(out) = fp.as_mut() {
+                if let Err(e) = out.write_all(&buf[..n]).await {
+                    fp = None;
+                    if let Some(on_error) = on_error.take() {
+                        on_error(e);
+                    }
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn rotate_at_limit() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.log");
+        let read = |n: usize| {
+            let path = if n == 0 { path.clone() } else { numbered(&path, n) };
+            std::fs::read(path).ok()
+        };
+
+        let mut fp = RotatingFile::create(&path, 8, 2).unwrap();
+        fp.write_all(b"abcd").await.unwrap();
+        fp.write_all(b"efgh").await.unwrap();
+        fp.flush().await.unwrap();
+        assert_eq!(read(0).unwrap(), b"abcdefgh");
+        assert_eq!(read(1), None);
+
+        fp.write_all(b"ijk").await.unwrap();
+        fp.flush().await.unwrap();
+        assert_eq!(read(0).unwrap(), b"ijk");
+        assert_eq!(read(1).unwrap(), b"abcdefgh");
+
+        fp.write_all(b"lmnopqrstu").await.unwrap();
+        fp.write_all(b"v").await.unwrap();
+        fp.flush().await.unwrap();
+        assert_eq!(read(0).unwrap(), b"v");
+        assert_eq!(read(1).unwrap(), b"lmnopqrstu");
+        assert_eq!(read(2).unwrap(), b"ijk");
+        assert_eq!(read(3), None);
+    }
+
+    #[tokio::test]
+    async fn append_existing() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.log");
+        std::fs::write(&path, b"abcdef").unwrap();
+
+        let mut fp = RotatingFile::create(&path, 8, 0).unwrap();
+        fp.write_all(b"gh").await.unwrap();
+        fp.flush().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdefgh");
+
+        fp.write_all(b"ij").await.unwrap();
+        fp.flush().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"ij");
+        assert!(!numbered(&path, 1).exists());
+    }
+}
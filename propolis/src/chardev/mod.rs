@@ -7,7 +7,7 @@ mod file_out;
 pub mod pollers;
 mod sock;
 
-pub use file_out::BlockingFileOutput;
+pub use file_out::{BlockingFileOutput, ErrorFn, RotatingFile};
 pub use sock::UDSock;
 
 pub type SinkNotifier =
@@ -84,6 +84,14 @@ impl Dispatcher {
         self.sync_disp.spawn(name, func, wake, SharedCtx::create(self))
     }
 
+    /// Stops the worker thread named `name`, spawned by
+    /// [`Dispatcher::spawn_sync`], and waits for it to exit.
+    ///
+    /// Returns an error if there is no such worker.
+    pub fn stop_sync(&self, name: &str) -> Result<()> {
+        self.sync_disp.stop(name, SharedCtx::create(self))
+    }
+
     pub(crate) fn with_ctx(&self, func: impl FnOnce(&DispCtx)) {
         let mut sctx = SyncCtx::standalone(SharedCtx::create(self));
         let ctx = sctx.dispctx();
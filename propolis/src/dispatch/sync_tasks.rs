@@ -1,5 +1,5 @@
 use std::collections::BTreeMap;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread::{Builder, JoinHandle};
@@ -129,6 +129,32 @@ impl SyncDispatch {
         Ok(())
     }
 
+    /// Stops the worker thread named `name`, waiting for it to exit.
+    pub(super) fn stop(&self, name: &str, shared: SharedCtx) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let worker = inner
+            .workers
+            .remove(&Ident::Custom(name.to_string()))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("no worker named {}", name),
+                )
+            })?;
+        drop(inner);
+
+        // As with shutdown, the worker must not be joined with `inner` held.
+        worker.ctrl.req_exit();
+        if let Some(wake_fn) = worker.wake.as_ref() {
+            let mut sctx = SyncCtx { shared, ctrl: None };
+            wake_fn(&sctx.dispctx());
+        }
+        if let Some(join) = worker.join {
+            let _ = join.join();
+        }
+        Ok(())
+    }
+
     pub(super) fn release(&self) {
         let mut inner = self.inner.lock().unwrap();
         match inner.state {
@@ -0,0 +1,536 @@
+//! Minimal encoder for ACPI Machine Language (AML), sufficient to describe
+//! the platform devices in a DSDT.
+//!
+//! Each function returns the encoded bytes of a single term, allowing terms
+//! to be nested by passing them as the arguments or bodies of others.
+//!
+//! See ACPI 6.4 Section 20.2 AML Grammar Definition
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const METHOD_OP: u8 = 0x14;
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX_CHAR: u8 = b'^';
+const LOCAL0_OP: u8 = 0x60;
+const ARG0_OP: u8 = 0x68;
+const STORE_OP: u8 = 0x70;
+const ADD_OP: u8 = 0x72;
+const DECREMENT_OP: u8 = 0x76;
+const SHIFT_LEFT_OP: u8 = 0x79;
+const AND_OP: u8 = 0x7b;
+const OR_OP: u8 = 0x7d;
+const FIND_SET_RIGHT_BIT_OP: u8 = 0x82;
+const NOTIFY_OP: u8 = 0x86;
+const CREATE_WORD_FIELD_OP: u8 = 0x8b;
+const LAND_OP: u8 = 0x90;
+const LNOT_OP: u8 = 0x92;
+const LEQUAL_OP: u8 = 0x93;
+const IF_OP: u8 = 0xa0;
+const ELSE_OP: u8 = 0xa1;
+const RETURN_OP: u8 = 0xa4;
+const ONES_OP: u8 = 0xff;
+
+// Extended (0x5b-prefixed) opcodes
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+
+const END_TAG: u8 = 0x79;
+
+/// Address space of an operation region.
+#[derive(Copy, Clone, Debug)]
+pub enum RegionSpace {
+    SystemMemory = 0,
+    SystemIo = 1,
+    PciConfig = 2,
+}
+
+/// Access type of the fields of an operation region.
+#[derive(Copy, Clone, Debug)]
+pub enum FieldAccess {
+    Any = 0,
+    Byte = 1,
+    Word = 2,
+    DWord = 3,
+}
+
+/// Encodes the length of a package, including the bytes of the encoding.
+///
+/// See ACPI 6.4 Section 20.2.4 Package Length Encoding
+fn pkg_length(content_len: usize) -> Vec<u8> {
+    // The length must include the bytes used to encode itself
+    for nbytes in 1..=4 {
+        let total = content_len + nbytes;
+        if nbytes == 1 && total < 0x40 {
+            return vec![total as u8];
+        }
+        if nbytes > 1 && total < 1 << (4 + 8 * (nbytes - 1)) {
+            let mut out = vec![((nbytes - 1) << 6) as u8 | (total & 0xf) as u8];
+            out.extend((1..nbytes).map(|i| (total >> (4 + 8 * (i - 1))) as u8));
+            return out;
+        }
+    }
+    panic!("package length {} too large", content_len);
+}
+
+/// Encodes the bit length of a field, which unlike a package length, does
+/// not count the bytes of its own encoding.
+fn field_length(bits: usize) -> Vec<u8> {
+    if bits < 0x40 {
+        return vec![bits as u8];
+    }
+    let nbytes = if bits < 1 << 12 {
+        2
+    } else if bits < 1 << 20 {
+        3
+    } else {
+        4
+    };
+    let mut out = vec![((nbytes - 1) << 6) as u8 | (bits & 0xf) as u8];
+    out.extend((1..nbytes).map(|i| (bits >> (4 + 8 * (i - 1))) as u8));
+    out
+}
+
+fn with_pkg_length(op: &[u8], content: Vec<u8>) -> Vec<u8> {
+    let mut out = op.to_vec();
+    out.extend(pkg_length(content.len()));
+    out.extend(content);
+    out
+}
+
+fn name_seg(seg: &str) -> [u8; 4] {
+    assert!(!seg.is_empty() && seg.len() <= 4, "bad name segment {}", seg);
+    let mut out = [b'_'; 4];
+    out[..seg.len()].copy_from_slice(seg.as_bytes());
+    out
+}
+
+/// Encodes a name, such as `_SB`, `\_SB.PCI0` or `^LNKA`.
+pub fn name_string(path: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&c, tail)) = rest.split_first() {
+        if c != ROOT_CHAR && c != PARENT_PREFIX_CHAR {
+            break;
+        }
+        out.push(c);
+        rest = tail;
+    }
+    let rest = std::str::from_utf8(rest).unwrap();
+    let segs: Vec<&str> =
+        if rest.is_empty() { Vec::new() } else { rest.split('.').collect() };
+    match segs.len() {
+        // NullName
+        0 => out.push(0),
+        1 => {}
+        2 => out.push(DUAL_NAME_PREFIX),
+        n => {
+            out.push(MULTI_NAME_PREFIX);
+            out.push(n as u8);
+        }
+    }
+    for seg in segs {
+        out.extend_from_slice(&name_seg(seg));
+    }
+    out
+}
+
+/// Encodes an integer constant in the smallest available form.
+pub fn integer(val: u64) -> Vec<u8> {
+    match val {
+        0 => vec![ZERO_OP],
+        1 => vec![ONE_OP],
+        u64::MAX => vec![ONES_OP],
+        v if v <= u8::MAX as u64 => vec![BYTE_PREFIX, v as u8],
+        v if v <= u16::MAX as u64 => {
+            let mut out = vec![WORD_PREFIX];
+            out.extend_from_slice(&(v as u16).to_le_bytes());
+            out
+        }
+        v if v <= u32::MAX as u64 => {
+            let mut out = vec![DWORD_PREFIX];
+            out.extend_from_slice(&(v as u32).to_le_bytes());
+            out
+        }
+        v => {
+            let mut out = vec![QWORD_PREFIX];
+            out.extend_from_slice(&v.to_le_bytes());
+            out
+        }
+    }
+}
+
+/// Encodes a null-terminated ASCII string.
+pub fn string(s: &str) -> Vec<u8> {
+    let mut out = vec![STRING_PREFIX];
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+    out
+}
+
+/// Encodes a compressed EISA ID, such as `PNP0A03`, as an integer.
+pub fn eisa_id(id: &str) -> Vec<u8> {
+    let id = id.as_bytes();
+    assert_eq!(id.len(), 7, "EISA IDs are 7 characters");
+    let c = |i: usize| ((id[i] - b'@') & 0x1f) as u32;
+    let hex = |i: usize| (id[i] as char).to_digit(16).unwrap();
+    let val = (c(0) << 26)
+        | (c(1) << 21)
+        | (c(2) << 16)
+        | (hex(3) << 12)
+        | (hex(4) << 8)
+        | (hex(5) << 4)
+        | hex(6);
+    // The ID is stored big-endian within the little-endian DWord
+    let mut out = vec![DWORD_PREFIX];
+    out.extend_from_slice(&val.to_be_bytes());
+    out
+}
+
+pub fn name(path: &str, value: Vec<u8>) -> Vec<u8> {
+    let mut out = vec![NAME_OP];
+    out.extend(name_string(path));
+    out.extend(value);
+    out
+}
+
+pub fn scope(path: &str, body: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut content = name_string(path);
+    content.extend(body.concat());
+    with_pkg_length(&[SCOPE_OP], content)
+}
+
+pub fn device(path: &str, body: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut content = name_string(path);
+    content.extend(body.concat());
+    with_pkg_length(&[EXT_OP_PREFIX, DEVICE_OP], content)
+}
+
+pub fn method(
+    path: &str,
+    argc: u8,
+    serialized: bool,
+    body: Vec<Vec<u8>>,
+) -> Vec<u8> {
+    assert!(argc <= 7);
+    let mut content = name_string(path);
+    content.push(argc | if serialized { 1 << 3 } else { 0 });
+    content.extend(body.concat());
+    with_pkg_length(&[METHOD_OP], content)
+}
+
+pub fn package(elems: Vec<Vec<u8>>) -> Vec<u8> {
+    assert!(elems.len() <= u8::MAX as usize);
+    let mut content = vec![elems.len() as u8];
+    content.extend(elems.concat());
+    with_pkg_length(&[PACKAGE_OP], content)
+}
+
+pub fn buffer(data: &[u8]) -> Vec<u8> {
+    let mut content = integer(data.len() as u64);
+    content.extend_from_slice(data);
+    with_pkg_length(&[BUFFER_OP], content)
+}
+
+pub fn op_region(
+    path: &str,
+    space: RegionSpace,
+    offset: u64,
+    len: u64,
+) -> Vec<u8> {
+    let mut out = vec![EXT_OP_PREFIX, OP_REGION_OP];
+    out.extend(name_string(path));
+    out.push(space as u8);
+    out.extend(integer(offset));
+    out.extend(integer(len));
+    out
+}
+
+/// Declares named fields, each of the given bit width, within a region.
+///
+/// Fields with an empty name are reserved.  Updates preserve the contents of
+/// bits not covered by the field being written.
+pub fn field(
+    region: &str,
+    access: FieldAccess,
+    fields: &[(&str, usize)],
+) -> Vec<u8> {
+    let mut content = name_string(region);
+    // NoLock, Preserve
+    content.push(access as u8);
+    for (name, bits) in fields {
+        if name.is_empty() {
+            content.push(0);
+        } else {
+            content.extend_from_slice(&name_seg(name));
+        }
+        content.extend(field_length(*bits));
+    }
+    with_pkg_length(&[EXT_OP_PREFIX, FIELD_OP], content)
+}
+
+pub fn if_(predicate: Vec<u8>, body: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut content = predicate;
+    content.extend(body.concat());
+    with_pkg_length(&[IF_OP], content)
+}
+
+pub fn else_(body: Vec<Vec<u8>>) -> Vec<u8> {
+    with_pkg_length(&[ELSE_OP], body.concat())
+}
+
+pub fn ret(val: Vec<u8>) -> Vec<u8> {
+    let mut out = vec![RETURN_OP];
+    out.extend(val);
+    out
+}
+
+pub fn local(n: u8) -> Vec<u8> {
+    assert!(n <= 7);
+    vec![LOCAL0_OP + n]
+}
+
+pub fn arg(n: u8) -> Vec<u8> {
+    assert!(n <= 6);
+    vec![ARG0_OP + n]
+}
+
+/// A target which discards the result of an operation.
+pub fn no_target() -> Vec<u8> {
+    vec![0]
+}
+
+fn op(opcode: u8, args: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut out = vec![opcode];
+    out.extend(args.concat());
+    out
+}
+
+pub fn store(src: Vec<u8>, dst: Vec<u8>) -> Vec<u8> {
+    op(STORE_OP, vec![src, dst])
+}
+
+pub fn add(a: Vec<u8>, b: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    op(ADD_OP, vec![a, b, target])
+}
+
+pub fn and(a: Vec<u8>, b: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    op(AND_OP, vec![a, b, target])
+}
+
+pub fn or(a: Vec<u8>, b: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    op(OR_OP, vec![a, b, target])
+}
+
+pub fn shift_left(a: Vec<u8>, count: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    op(SHIFT_LEFT_OP, vec![a, count, target])
+}
+
+pub fn decrement(target: Vec<u8>) -> Vec<u8> {
+    op(DECREMENT_OP, vec![target])
+}
+
+pub fn find_set_right_bit(a: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    op(FIND_SET_RIGHT_BIT_OP, vec![a, target])
+}
+
+pub fn land(a: Vec<u8>, b: Vec<u8>) -> Vec<u8> {
+    op(LAND_OP, vec![a, b])
+}
+
+pub fn lnot(a: Vec<u8>) -> Vec<u8> {
+    op(LNOT_OP, vec![a])
+}
+
+pub fn lequal(a: Vec<u8>, b: Vec<u8>) -> Vec<u8> {
+    op(LEQUAL_OP, vec![a, b])
+}
+
+pub fn notify(object: Vec<u8>, value: Vec<u8>) -> Vec<u8> {
+    op(NOTIFY_OP, vec![object, value])
+}
+
+pub fn create_word_field(
+    source: Vec<u8>,
+    byte_index: u64,
+    name: &str,
+) -> Vec<u8> {
+    op(
+        CREATE_WORD_FIELD_OP,
+        vec![source, integer(byte_index), name_string(name)],
+    )
+}
+
+/// Encodes a buffer containing resource descriptors, as produced by the
+/// ResourceTemplate macro.
+///
+/// See ACPI 6.4 Section 6.4 Resource Data Types for ACPI
+pub fn resource_template(descs: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut data = descs.concat();
+    // A checksum of zero indicates that the template is not checksummed
+    data.extend_from_slice(&[END_TAG, 0]);
+    buffer(&data)
+}
+
+/// Resource descriptors for use in [`resource_template`].
+pub mod res {
+    const SMALL_IRQ: u8 = 0x4 << 3;
+    const SMALL_IO: u8 = 0x8 << 3;
+    const LARGE_MEM32_FIXED: u8 = 0x86;
+    const LARGE_DWORD_ADDR: u8 = 0x87;
+    const LARGE_WORD_ADDR: u8 = 0x88;
+    const LARGE_QWORD_ADDR: u8 = 0x8a;
+
+    const ADDR_TYPE_MEM: u8 = 0;
+    const ADDR_TYPE_IO: u8 = 1;
+    const ADDR_TYPE_BUS: u8 = 2;
+
+    // Min and max addresses fixed, positive decode, producer
+    const ADDR_FLAGS: u8 = 0b1100;
+
+    /// Interrupt trigger and polarity of an IRQ descriptor.
+    #[derive(Copy, Clone, Debug)]
+    pub enum IrqMode {
+        EdgeHigh,
+        LevelLow,
+    }
+
+    /// An IRQ descriptor covering the IRQs set in `mask`.
+    pub fn irq(mask: u16, mode: IrqMode, shared: bool) -> Vec<u8> {
+        let mut info = match mode {
+            IrqMode::EdgeHigh => 0b0000_0001,
+            IrqMode::LevelLow => 0b0000_1000,
+        };
+        if shared {
+            info |= 0b0001_0000;
+        }
+        let mut out = vec![SMALL_IRQ | 3];
+        out.extend_from_slice(&mask.to_le_bytes());
+        out.push(info);
+        out
+    }
+
+    /// An IRQ descriptor for a single edge-triggered, active-high IRQ, as
+    /// produced by the IRQNoFlags macro.
+    pub fn irq_no_flags(irq: u8) -> Vec<u8> {
+        let mut out = vec![SMALL_IRQ | 2];
+        out.extend_from_slice(&(1u16 << irq).to_le_bytes());
+        out
+    }
+
+    /// A fixed range of I/O ports, decoding all 16 address bits.
+    pub fn io(base: u16, len: u8) -> Vec<u8> {
+        let mut out = vec![SMALL_IO | 7, 1];
+        out.extend_from_slice(&base.to_le_bytes());
+        out.extend_from_slice(&base.to_le_bytes());
+        out.push(1);
+        out.push(len);
+        out
+    }
+
+    pub fn memory32_fixed(base: u32, len: u32, writable: bool) -> Vec<u8> {
+        let mut out = vec![LARGE_MEM32_FIXED, 9, 0];
+        out.push(writable as u8);
+        out.extend_from_slice(&base.to_le_bytes());
+        out.extend_from_slice(&len.to_le_bytes());
+        out
+    }
+
+    fn word_addr(kind: u8, type_flags: u8, min: u16, max: u16) -> Vec<u8> {
+        let mut out =
+            vec![LARGE_WORD_ADDR, 13, 0, kind, ADDR_FLAGS, type_flags];
+        // granularity, min, max, translation offset, length
+        for v in [0, min, max, 0, max - min + 1].iter() {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out
+    }
+
+    /// A range of bus numbers decoded by a bridge.
+    pub fn word_bus_number(min: u8, max: u8) -> Vec<u8> {
+        word_addr(ADDR_TYPE_BUS, 0, min as u16, max as u16)
+    }
+
+    /// A range of I/O ports decoded by a bridge.
+    pub fn word_io(min: u16, max: u16) -> Vec<u8> {
+        // Decode the entire ISA range
+        word_addr(ADDR_TYPE_IO, 0b11, min, max)
+    }
+
+    /// A range of (non-prefetchable, cacheable) memory decoded by a bridge.
+    pub fn dword_memory(min: u32, max: u32) -> Vec<u8> {
+        let mut out =
+            vec![LARGE_DWORD_ADDR, 23, 0, ADDR_TYPE_MEM, ADDR_FLAGS, 0b011];
+        for v in [0, min, max, 0, max - min + 1].iter() {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out
+    }
+
+    /// A range of (non-prefetchable, cacheable) 64-bit memory decoded by a
+    /// bridge.
+    pub fn qword_memory(min: u64, max: u64) -> Vec<u8> {
+        let mut out =
+            vec![LARGE_QWORD_ADDR, 43, 0, ADDR_TYPE_MEM, ADDR_FLAGS, 0b011];
+        for v in [0, min, max, 0, max - min + 1].iter() {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(name_string("_SB"), b"_SB_");
+        assert_eq!(name_string("\\_SB"), b"\\_SB_");
+        assert_eq!(name_string("^LNKA"), b"^LNKA");
+        assert_eq!(name_string("\\_SB.PCI0"), b"\\\x2e_SB_PCI0");
+        assert_eq!(name_string("_SB.PCI0.ISA"), b"\x2f\x03_SB_PCI0ISA_");
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(integer(0), [ZERO_OP]);
+        assert_eq!(integer(1), [ONE_OP]);
+        assert_eq!(integer(0x12), [BYTE_PREFIX, 0x12]);
+        assert_eq!(integer(0x1234), [WORD_PREFIX, 0x34, 0x12]);
+        assert_eq!(integer(0x1_0000), [DWORD_PREFIX, 0, 0, 1, 0]);
+        assert_eq!(eisa_id("PNP0A03"), [DWORD_PREFIX, 0x41, 0xd0, 0x0a, 0x03]);
+    }
+
+    #[test]
+    fn pkg_lengths() {
+        assert_eq!(pkg_length(0x3e), [0x3f]);
+        // 0x3f + 2 bytes of encoding
+        assert_eq!(pkg_length(0x3f), [0x41, 0x04]);
+        assert_eq!(pkg_length(0xffd), [0x4f, 0xff]);
+        assert_eq!(pkg_length(0xffe), [0x81, 0x00, 0x01]);
+        assert_eq!(field_length(8), [8]);
+        assert_eq!(field_length(0x100), [0x40, 0x10]);
+    }
+
+    #[test]
+    fn method_encoding() {
+        let m = method("_STA", 0, false, vec![ret(integer(0xf))]);
+        assert_eq!(
+            m,
+            [METHOD_OP, 0x09, b'_', b'S', b'T', b'A', 0, RETURN_OP, 0x0a, 0xf]
+        );
+    }
+}
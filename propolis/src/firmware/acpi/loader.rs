@@ -0,0 +1,138 @@
+//! Builder for the `etc/table-loader` script which instructs firmware (such
+//! as OVMF or SeaBIOS) how to place fw_cfg-provided ACPI tables in guest
+//! memory and link them together.
+//!
+//! The format is that of the QEMU BIOS linker/loader: a sequence of fixed
+//! size commands, each naming the fw_cfg file(s) it operates upon.
+
+/// Maximum length of fw_cfg file names in loader commands, including the
+/// null terminator.
+const FILE_NAME_LEN: usize = 56;
+const COMMAND_LEN: usize = 128;
+
+const CMD_ALLOCATE: u32 = 1;
+const CMD_ADD_POINTER: u32 = 2;
+const CMD_ADD_CHECKSUM: u32 = 3;
+
+/// Region of guest memory in which the firmware should allocate a file.
+#[derive(Copy, Clone, Debug)]
+pub enum Zone {
+    /// Anywhere in memory (below 4GiB)
+    High = 1,
+    /// Within the 0xe0000-0xfffff "F segment", as required for the RSDP on
+    /// legacy BIOS systems.
+    FSeg = 2,
+}
+
+#[derive(Default)]
+pub struct TableLoader {
+    cmds: Vec<u8>,
+}
+impl TableLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a command, returning its (zeroed) body.
+    fn command(&mut self, cmd: u32) -> &mut [u8] {
+        let start = self.cmds.len();
+        self.cmds.resize(start + COMMAND_LEN, 0);
+        let entry = &mut self.cmds[start..];
+        entry[..4].copy_from_slice(&cmd.to_le_bytes());
+        &mut entry[4..]
+    }
+
+    /// Allocates memory for the contents of `file`, which are then loaded
+    /// into it.  Files must be allocated before other commands refer to them.
+    pub fn allocate(&mut self, file: &str, align: u32, zone: Zone) {
+        let body = self.command(CMD_ALLOCATE);
+        put_name(&mut body[..FILE_NAME_LEN], file);
+        body[FILE_NAME_LEN..FILE_NAME_LEN + 4]
+            .copy_from_slice(&align.to_le_bytes());
+        body[FILE_NAME_LEN + 4] = zone as u8;
+    }
+
+    /// Adds the address at which `src_file` was allocated to the `size`-byte
+    /// little-endian value at `offset` within `dest_file`.
+    ///
+    /// The value initially present in `dest_file` is thus the offset within
+    /// `src_file` being pointed to.
+    pub fn add_pointer(
+        &mut self,
+        dest_file: &str,
+        src_file: &str,
+        offset: u32,
+        size: u8,
+    ) {
+        assert!(matches!(size, 1 | 2 | 4 | 8));
+        let body = self.command(CMD_ADD_POINTER);
+        put_name(&mut body[..FILE_NAME_LEN], dest_file);
+        put_name(&mut body[FILE_NAME_LEN..2 * FILE_NAME_LEN], src_file);
+        let off = 2 * FILE_NAME_LEN;
+        body[off..off + 4].copy_from_slice(&offset.to_le_bytes());
+        body[off + 4] = size;
+    }
+
+    /// Sets the byte at `offset` within `file` such that the bytes in the
+    /// range `start..start + len` sum to zero.
+    ///
+    /// Checksums must be computed after any pointers within the range have
+    /// been added.
+    pub fn add_checksum(
+        &mut self,
+        file: &str,
+        offset: u32,
+        start: u32,
+        len: u32,
+    ) {
+        let body = self.command(CMD_ADD_CHECKSUM);
+        put_name(&mut body[..FILE_NAME_LEN], file);
+        let off = FILE_NAME_LEN;
+        body[off..off + 4].copy_from_slice(&offset.to_le_bytes());
+        body[off + 4..off + 8].copy_from_slice(&start.to_le_bytes());
+        body[off + 8..off + 12].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.cmds
+    }
+}
+
+fn put_name(buf: &mut [u8], name: &str) {
+    assert!(name.len() < FILE_NAME_LEN, "file name {} too long", name);
+    buf[..name.len()].copy_from_slice(name.as_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_layout() {
+        let mut loader = TableLoader::new();
+        loader.allocate("etc/acpi/rsdp", 16, Zone::FSeg);
+        loader.add_pointer("etc/acpi/rsdp", "etc/acpi/tables", 24, 8);
+        loader.add_checksum("etc/acpi/rsdp", 8, 0, 20);
+        let cmds = loader.finish();
+        assert_eq!(cmds.len(), 3 * COMMAND_LEN);
+
+        let alloc = &cmds[..COMMAND_LEN];
+        assert_eq!(alloc[0], CMD_ALLOCATE as u8);
+        assert_eq!(&alloc[4..17], b"etc/acpi/rsdp");
+        assert_eq!(alloc[17], 0);
+        assert_eq!(alloc[60], 16);
+        assert_eq!(alloc[64], Zone::FSeg as u8);
+
+        let ptr = &cmds[COMMAND_LEN..2 * COMMAND_LEN];
+        assert_eq!(ptr[0], CMD_ADD_POINTER as u8);
+        assert_eq!(&ptr[60..75], b"etc/acpi/tables");
+        assert_eq!(ptr[116], 24);
+        assert_eq!(ptr[120], 8);
+
+        let csum = &cmds[2 * COMMAND_LEN..];
+        assert_eq!(csum[0], CMD_ADD_CHECKSUM as u8);
+        assert_eq!(csum[60], 8);
+        assert_eq!(csum[64], 0);
+        assert_eq!(csum[68], 20);
+    }
+}
@@ -0,0 +1,655 @@
+//! Generation of ACPI tables describing the virtual platform.
+//!
+//! The tables are handed to the guest firmware via fw_cfg, in the same form
+//! as QEMU provides them: the RSDP in `etc/acpi/rsdp`, all other tables
+//! packed into `etc/acpi/tables`, and an `etc/table-loader` script which
+//! directs the firmware to place them in memory and link them together.
+
+use std::convert::TryInto;
+use std::ops::RangeInclusive;
+
+use crate::hw::chipset::{self, hotplug, i440fx, pm, q35, ChipsetKind};
+use crate::hw::ibmpc;
+use crate::hw::pci::INTxPinID;
+use crate::hw::qemu::fwcfg::{self, FixedItem, FwCfgBuilder};
+
+pub mod aml;
+pub mod loader;
+pub mod tables;
+
+use aml::res;
+use loader::{TableLoader, Zone};
+pub use tables::mcfg::Ecam;
+
+const RSDP_FILE: &str = "etc/acpi/rsdp";
+const TABLES_FILE: &str = "etc/acpi/tables";
+const LOADER_FILE: &str = "etc/table-loader";
+
+pub const IOAPIC_ADDR: u32 = 0xfec0_0000;
+pub const HPET_ADDR: u32 = 0xfed0_0000;
+const HPET_LEN: u32 = 0x400;
+/// Event Timer Block ID of the bhyve HPET: Intel vendor ID, 64-bit counter,
+/// 8 comparators, revision 1.
+const HPET_BLOCK_ID: u32 = 0x8086_2701;
+
+/// CMOS RAM index of the RTC century
+const RTC_CENTURY: u8 = 0x32;
+
+const PCI_LINKS: [&str; 4] = ["LNKA", "LNKB", "LNKC", "LNKD"];
+const PCI_DEVS: u8 = 32;
+
+/// Description of the platform to be conveyed through ACPI.
+pub struct Config {
+    pub chipset: ChipsetKind,
+    pub num_cpus: u8,
+    /// Range of 32-bit MMIO space available for PCI BARs
+    pub pci_mem32: RangeInclusive<u32>,
+    /// Range of 64-bit MMIO space available for PCI BARs
+    pub pci_mem64: Option<RangeInclusive<u64>>,
+    /// PCIe enhanced configuration access regions, if supported by the
+    /// chipset.
+    pub ecam: Vec<Ecam>,
+    /// Serial ports, by I/O port and IRQ
+    pub serial_ports: Vec<(u16, u8)>,
+}
+
+/// A set of ACPI tables, along with the loader script for placing them.
+pub struct AcpiTables {
+    rsdp: Vec<u8>,
+    tables: Vec<u8>,
+    loader: Vec<u8>,
+}
+impl AcpiTables {
+    /// Makes the tables available to the guest firmware.
+    pub fn attach(self, builder: &mut FwCfgBuilder) -> fwcfg::Result {
+        builder.add_named(RSDP_FILE, FixedItem::new_raw(self.rsdp))?;
+        builder.add_named(TABLES_FILE, FixedItem::new_raw(self.tables))?;
+        builder.add_named(LOADER_FILE, FixedItem::new_raw(self.loader))?;
+        Ok(())
+    }
+}
+
+/// Accumulates tables into the `etc/acpi/tables` file, recording the loader
+/// commands to link them.
+struct Builder {
+    tables: Vec<u8>,
+    loader: TableLoader,
+}
+impl Builder {
+    fn new() -> Self {
+        let mut loader = TableLoader::new();
+        // The RSDP must be found by scanning memory on legacy systems
+        loader.allocate(RSDP_FILE, 16, Zone::FSeg);
+        loader.allocate(TABLES_FILE, 64, Zone::High);
+        Self { tables: Vec::new(), loader }
+    }
+
+    /// Appends `table`, returning its offset within the tables file.
+    fn append(&mut self, table: &[u8], align: usize) -> u32 {
+        let rem = self.tables.len() % align;
+        if rem != 0 {
+            self.tables.resize(self.tables.len() + align - rem, 0);
+        }
+        let off = self.tables.len();
+        self.tables.extend_from_slice(table);
+        off as u32
+    }
+
+    /// Points the `size`-byte field at `field_off` within the table at
+    /// `table_off` to the table at `target`.
+    fn link(
+        &mut self,
+        table_off: u32,
+        field_off: usize,
+        size: u8,
+        target: u32,
+    ) {
+        let start = table_off as usize + field_off;
+        let field = &mut self.tables[start..start + size as usize];
+        field.copy_from_slice(&(target as u64).to_le_bytes()[..size as usize]);
+        self.loader.add_pointer(TABLES_FILE, TABLES_FILE, start as u32, size);
+    }
+
+    /// Has the loader checksum the table at `table_off`, once any pointers
+    /// within it have been filled in.
+    fn checksum(&mut self, table_off: u32) {
+        let start = table_off as usize;
+        let len = u32::from_le_bytes(
+            self.tables[start + 4..start + 8].try_into().unwrap(),
+        );
+        self.loader.add_checksum(
+            TABLES_FILE,
+            table_off + tables::CHECKSUM_OFFSET as u32,
+            table_off,
+            len,
+        );
+    }
+
+    /// Appends a standard table, to be checksummed once loaded.
+    fn add_table(&mut self, table: &[u8]) -> u32 {
+        let off = self.append(table, 8);
+        self.checksum(off);
+        off
+    }
+}
+
+/// Generates the ACPI tables for the machine.
+pub fn build(cfg: &Config) -> AcpiTables {
+    let mut b = Builder::new();
+
+    let facs = b.append(&tables::facs::build(), tables::facs::ALIGN);
+    let dsdt = b.add_table(&tables::dsdt::build(&build_dsdt(cfg)));
+
+    let pm_base = pm::PMBASE_DEFAULT;
+    let fadt_cfg = tables::fadt::Config {
+        sci_irq: chipset::SCI_IRQ as u16,
+        pm1a_evt_blk: pm_base + pm::PM1_EVT_OFFSET,
+        pm1a_cnt_blk: pm_base + pm::PM1_CNT_OFFSET,
+        pm_tmr_blk: pm_base + pm::PM_TMR_OFFSET,
+        gpe0_blk: pm_base + pm::GPE0_OFFSET,
+        gpe0_blk_len: pm::GPE0_LEN,
+        flags: tables::fadt::Flags::WBINVD
+            | tables::fadt::Flags::PROC_C1
+            | tables::fadt::Flags::SLP_BUTTON
+            | tables::fadt::Flags::FIX_RTC,
+        boot_arch: tables::fadt::BootArch::LEGACY_DEVICES
+            | tables::fadt::BootArch::I8042,
+        century: RTC_CENTURY,
+    };
+    let fadt = b.append(&tables::fadt::build(&fadt_cfg), 8);
+    b.link(fadt, tables::fadt::FIRMWARE_CTRL_OFFSET, 4, facs);
+    b.link(fadt, tables::fadt::DSDT_OFFSET, 4, dsdt);
+    b.link(fadt, tables::fadt::X_DSDT_OFFSET, 8, dsdt);
+    b.checksum(fadt);
+
+    let madt_cfg = tables::madt::Config {
+        num_cpus: cfg.num_cpus,
+        ioapic: tables::madt::IoApic { id: 0, addr: IOAPIC_ADDR, gsi_base: 0 },
+        overrides: vec![
+            // The PIT is wired to IOAPIC pin 2
+            (0, 2, tables::madt::IntMode::Conforming),
+            (
+                chipset::SCI_IRQ,
+                chipset::SCI_IRQ as u32,
+                tables::madt::IntMode::LevelLow,
+            ),
+        ],
+    };
+    let mut xsdt_entries = vec![fadt];
+    xsdt_entries.push(b.add_table(&tables::madt::build(&madt_cfg)));
+    xsdt_entries.push(
+        b.add_table(&tables::hpet::build(HPET_BLOCK_ID, HPET_ADDR as u64)),
+    );
+    if !cfg.ecam.is_empty() {
+        xsdt_entries.push(b.add_table(&tables::mcfg::build(&cfg.ecam)));
+    }
+
+    let xsdt = b.append(&tables::xsdt::build(xsdt_entries.len()), 8);
+    for (i, target) in xsdt_entries.iter().enumerate() {
+        b.link(xsdt, tables::xsdt::entry_offset(i), 8, *target);
+    }
+    b.checksum(xsdt);
+
+    // The RSDP lives in its own file, pointing into the tables
+    let mut rsdp = tables::rsdp::build();
+    let off = tables::rsdp::XSDT_OFFSET;
+    rsdp[off..off + 8].copy_from_slice(&(xsdt as u64).to_le_bytes());
+    b.loader.add_pointer(RSDP_FILE, TABLES_FILE, off as u32, 8);
+    b.loader.add_checksum(
+        RSDP_FILE,
+        tables::rsdp::CHECKSUM_OFFSET as u32,
+        0,
+        tables::rsdp::CHECKSUM_LEN as u32,
+    );
+    b.loader.add_checksum(
+        RSDP_FILE,
+        tables::rsdp::EXT_CHECKSUM_OFFSET as u32,
+        0,
+        tables::rsdp::LEN as u32,
+    );
+
+    AcpiTables { rsdp, tables: b.tables, loader: b.loader.finish() }
+}
+
+/// Builds the AML definition block describing the platform devices.
+fn build_dsdt(cfg: &Config) -> Vec<u8> {
+    let mut sb = vec![aml::device("PCI0", build_pci0(cfg))];
+    for (idx, name) in PCI_LINKS.iter().enumerate() {
+        sb.push(build_pci_link(name, idx));
+    }
+    for cpu in 0..cfg.num_cpus {
+        sb.push(aml::device(
+            &format!("C{:03X}", cpu),
+            vec![
+                aml::name("_HID", aml::string("ACPI0007")),
+                aml::name("_UID", aml::integer(cpu as u64)),
+            ],
+        ));
+    }
+    // The hotplug registers sit within the I/O window of the host bridge, so
+    // are reserved to keep BARs out of them.  The ECAM regions must likewise
+    // be reserved as motherboard resources for the OS to trust the MCFG table.
+    let mut crs = vec![res::io(hotplug::PCIHP_BASE, hotplug::PCIHP_LEN as u8)];
+    crs.extend(cfg.ecam.iter().map(|ecam| {
+        let buses = (ecam.end_bus - ecam.start_bus) as u32 + 1;
+        res::memory32_fixed(ecam.base as u32, buses << 20, false)
+    }));
+    sb.push(aml::device(
+        "MRES",
+        vec![
+            aml::name("_HID", aml::eisa_id("PNP0C02")),
+            aml::name("_UID", aml::integer(0)),
+            aml::name("_CRS", aml::resource_template(crs)),
+        ],
+    ));
+    sb.push(aml::device(
+        "HPET",
+        vec![
+            aml::name("_HID", aml::eisa_id("PNP0103")),
+            aml::name("_UID", aml::integer(0)),
+            aml::name(
+                "_CRS",
+                aml::resource_template(vec![res::memory32_fixed(
+                    HPET_ADDR, HPET_LEN, false,
+                )]),
+            ),
+        ],
+    ));
+
+    vec![
+        aml::scope("\\_SB", sb),
+        // Hotplug events are signalled through GPE0 bit 1
+        aml::scope(
+            "\\_GPE",
+            vec![aml::method(
+                "_E01",
+                0,
+                false,
+                vec![aml::name_string("\\_SB.PCI0.PCNT")],
+            )],
+        ),
+        // Soft-off is entered with SLP_TYP 0
+        aml::name(
+            "\\_S5",
+            aml::package(vec![
+                aml::integer(0),
+                aml::integer(0),
+                aml::integer(0),
+                aml::integer(0),
+            ]),
+        ),
+    ]
+    .concat()
+}
+
+fn build_pci0(cfg: &Config) -> Vec<Vec<u8>> {
+    let mut crs = vec![
+        res::word_bus_number(0, 0xff),
+        res::io(crate::hw::pci::PORT_PCI_CONFIG_ADDR, 8),
+        res::word_io(0, 0xcf7),
+        res::word_io(0xd00, 0xffff),
+        // VGA memory
+        res::dword_memory(0xa_0000, 0xb_ffff),
+        res::dword_memory(*cfg.pci_mem32.start(), *cfg.pci_mem32.end()),
+    ];
+    if let Some(mem64) = &cfg.pci_mem64 {
+        crs.push(res::qword_memory(*mem64.start(), *mem64.end()));
+    }
+
+    // Interrupt routing for each INTx pin of each device, via the PCI link
+    // through which the chipset routes it.
+    let mut prt = Vec::new();
+    for dev in 0..PCI_DEVS {
+        let pins = [
+            INTxPinID::IntA,
+            INTxPinID::IntB,
+            INTxPinID::IntC,
+            INTxPinID::IntD,
+        ];
+        for (pin_idx, pin) in pins.iter().enumerate() {
+            let lnk = chipset::lnk_route(dev, *pin);
+            prt.push(aml::package(vec![
+                // any function of the device
+                aml::integer(((dev as u64) << 16) | 0xffff),
+                aml::integer(pin_idx as u64),
+                aml::name_string(&format!("^{}", PCI_LINKS[lnk as usize])),
+                aml::integer(0),
+            ]));
+        }
+    }
+
+    let mut body = match cfg.chipset {
+        ChipsetKind::I440Fx => vec![aml::name("_HID", aml::eisa_id("PNP0A03"))],
+        ChipsetKind::Q35 => vec![
+            aml::name("_HID", aml::eisa_id("PNP0A08")),
+            aml::name("_CID", aml::eisa_id("PNP0A03")),
+        ],
+    };
+    body.extend(vec![
+        aml::name("_ADR", aml::integer(0)),
+        aml::name("_UID", aml::integer(0)),
+        aml::name("_CRS", aml::resource_template(crs)),
+        aml::name("_PRT", aml::package(prt)),
+        aml::device("ISA", build_isa(cfg)),
+    ]);
+    body.extend(build_pci_hotplug(hotplug_slots(cfg.chipset)));
+    body
+}
+
+fn hotplug_slots(chipset: ChipsetKind) -> u32 {
+    match chipset {
+        ChipsetKind::I440Fx => i440fx::HOTPLUG_SLOTS,
+        ChipsetKind::Q35 => q35::HOTPLUG_SLOTS,
+    }
+}
+
+/// The hotplug controller registers, and a device for each hotplug slot of
+/// the root bus through which the OS is notified of insertion and removal
+/// (via `PCNT`), and ejects devices (via `_EJ0`).
+fn build_pci_hotplug(slots: u32) -> Vec<Vec<u8>> {
+    let slots: Vec<u8> =
+        (0..PCI_DEVS).filter(|dev| slots & (1 << dev) != 0).collect();
+    let slot_path = |dev: u8| format!("\\_SB.PCI0.S{:02X}", dev);
+
+    let mut notify = vec![
+        aml::store(aml::name_string("PCIU"), aml::local(0)),
+        aml::store(aml::name_string("PCID"), aml::local(1)),
+    ];
+    for &dev in slots.iter() {
+        let bit = || aml::integer(1 << dev);
+        // Device check for insertions, eject request for removals
+        notify.push(aml::if_(
+            aml::and(aml::local(0), bit(), aml::no_target()),
+            vec![aml::notify(
+                aml::name_string(&slot_path(dev)),
+                aml::integer(1),
+            )],
+        ));
+        notify.push(aml::if_(
+            aml::and(aml::local(1), bit(), aml::no_target()),
+            vec![aml::notify(
+                aml::name_string(&slot_path(dev)),
+                aml::integer(3),
+            )],
+        ));
+    }
+
+    let mut body = vec![
+        aml::op_region(
+            "PCST",
+            aml::RegionSpace::SystemIo,
+            hotplug::PCIHP_BASE as u64,
+            hotplug::PCIHP_LEN as u64,
+        ),
+        aml::field(
+            "PCST",
+            aml::FieldAccess::DWord,
+            &[("PCIU", 32), ("PCID", 32), ("PCEJ", 32), ("PCRM", 32)],
+        ),
+        aml::method("PCNT", 0, true, notify),
+    ];
+    for dev in slots {
+        body.push(aml::device(
+            &format!("S{:02X}", dev),
+            vec![
+                aml::name("_ADR", aml::integer((dev as u64) << 16)),
+                aml::name("_SUN", aml::integer(dev as u64)),
+                aml::method(
+                    "_EJ0",
+                    1,
+                    false,
+                    vec![aml::store(
+                        aml::integer(1 << dev),
+                        aml::name_string("\\_SB.PCI0.PCEJ"),
+                    )],
+                ),
+            ],
+        ));
+    }
+    body
+}
+
+/// Devices behind the LPC bridge, along with its PIRQ registers.
+fn build_isa(cfg: &Config) -> Vec<Vec<u8>> {
+    let (lpc_dev, lpc_func) = match cfg.chipset {
+        ChipsetKind::I440Fx => (i440fx::LPC_DEV, i440fx::LPC_FUNC),
+        ChipsetKind::Q35 => (q35::LPC_DEV, q35::LPC_FUNC),
+    };
+    let lpc_adr = ((lpc_dev as u64) << 16) | lpc_func as u64;
+    let mut body = vec![
+        aml::name("_ADR", aml::integer(lpc_adr)),
+        aml::op_region(
+            "PIRQ",
+            aml::RegionSpace::PciConfig,
+            chipset::PIR_OFFSET as u64,
+            chipset::PIR_LEN as u64,
+        ),
+        aml::field(
+            "PIRQ",
+            aml::FieldAccess::Byte,
+            &[("PRQ0", 8), ("PRQ1", 8), ("PRQ2", 8), ("PRQ3", 8)],
+        ),
+        isa_device(
+            "KBD",
+            "PNP0303",
+            None,
+            vec![
+                res::io(ibmpc::PORT_PS2_DATA, 1),
+                res::io(ibmpc::PORT_PS2_CMD_STATUS, 1),
+                res::irq_no_flags(ibmpc::IRQ_PS2_PRI),
+            ],
+        ),
+        isa_device(
+            "MOU",
+            "PNP0F13",
+            None,
+            vec![res::irq_no_flags(ibmpc::IRQ_PS2_AUX)],
+        ),
+        isa_device(
+            "RTC",
+            "PNP0B00",
+            None,
+            vec![res::io(0x70, 2), res::irq_no_flags(8)],
+        ),
+    ];
+    for (idx, (port, irq)) in cfg.serial_ports.iter().enumerate() {
+        body.push(isa_device(
+            &format!("COM{}", idx + 1),
+            "PNP0501",
+            Some(idx as u64 + 1),
+            vec![res::io(*port, 8), res::irq_no_flags(*irq)],
+        ));
+    }
+    body
+}
+
+fn isa_device(
+    name: &str,
+    hid: &str,
+    uid: Option<u64>,
+    crs: Vec<Vec<u8>>,
+) -> Vec<u8> {
+    let mut body = vec![aml::name("_HID", aml::eisa_id(hid))];
+    if let Some(uid) = uid {
+        body.push(aml::name("_UID", aml::integer(uid)));
+    }
+    body.push(aml::name("_CRS", aml::resource_template(crs)));
+    aml::device(name, body)
+}
+
+/// An interrupt link device, whose IRQ is controlled by the corresponding
+/// PIRQ route control register of the LPC bridge.
+fn build_pci_link(name: &str, idx: usize) -> Vec<u8> {
+    const PIR_DISABLE: u64 = 0x80;
+    const PIR_IRQ: u64 = 0x0f;
+    let prq = format!("\\_SB.PCI0.ISA.PRQ{}", idx);
+    let prq = || aml::name_string(&prq);
+    let irq_tmpl = |mask| {
+        aml::resource_template(vec![res::irq(
+            mask,
+            res::IrqMode::LevelLow,
+            true,
+        )])
+    };
+    let disabled =
+        || aml::and(prq(), aml::integer(PIR_DISABLE), aml::no_target());
+
+    aml::device(
+        name,
+        vec![
+            aml::name("_HID", aml::eisa_id("PNP0C0F")),
+            aml::name("_UID", aml::integer(idx as u64 + 1)),
+            aml::name("_PRS", irq_tmpl(chipset::PIR_VALID_IRQS)),
+            aml::method(
+                "_STA",
+                0,
+                false,
+                vec![
+                    // present and functioning, but perhaps not enabled
+                    aml::if_(disabled(), vec![aml::ret(aml::integer(0x09))]),
+                    aml::ret(aml::integer(0x0b)),
+                ],
+            ),
+            aml::method(
+                "_DIS",
+                0,
+                false,
+                vec![aml::or(prq(), aml::integer(PIR_DISABLE), prq())],
+            ),
+            aml::method(
+                "_CRS",
+                0,
+                true,
+                vec![
+                    aml::store(irq_tmpl(0), aml::local(0)),
+                    aml::create_word_field(aml::local(0), 1, "IRQW"),
+                    aml::if_(
+                        aml::lnot(disabled()),
+                        vec![aml::shift_left(
+                            aml::integer(1),
+                            aml::and(
+                                prq(),
+                                aml::integer(PIR_IRQ),
+                                aml::no_target(),
+                            ),
+                            aml::name_string("IRQW"),
+                        )],
+                    ),
+                    aml::ret(aml::local(0)),
+                ],
+            ),
+            aml::method(
+                "_SRS",
+                1,
+                true,
+                vec![
+                    aml::create_word_field(aml::arg(0), 1, "IRQW"),
+                    aml::find_set_right_bit(
+                        aml::name_string("IRQW"),
+                        aml::local(0),
+                    ),
+                    // FindSetRightBit is one-based
+                    aml::decrement(aml::local(0)),
+                    aml::store(aml::local(0), prq()),
+                ],
+            ),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_config() -> Config {
+        Config {
+            chipset: ChipsetKind::I440Fx,
+            num_cpus: 2,
+            pci_mem32: 0xc000_0000..=0xdfff_ffff,
+            pci_mem64: None,
+            ecam: Vec::new(),
+            serial_ports: vec![(ibmpc::PORT_COM1, ibmpc::IRQ_COM1)],
+        }
+    }
+
+    fn table_at(tables: &[u8], off: usize) -> &[u8] {
+        let len =
+            u32::from_le_bytes(tables[off + 4..off + 8].try_into().unwrap());
+        &tables[off..off + len as usize]
+    }
+
+    #[test]
+    fn xsdt_entries() {
+        let acpi = build(&test_config());
+        let xsdt_off = u64::from_le_bytes(
+            acpi.rsdp[tables::rsdp::XSDT_OFFSET..][..8].try_into().unwrap(),
+        ) as usize;
+        let xsdt = table_at(&acpi.tables, xsdt_off);
+        assert_eq!(&xsdt[..4], b"XSDT");
+
+        let sigs: Vec<&[u8]> = xsdt[tables::HEADER_LEN..]
+            .chunks(8)
+            .map(|e| {
+                let off = u64::from_le_bytes(e.try_into().unwrap()) as usize;
+                &acpi.tables[off..off + 4]
+            })
+            .collect();
+        assert_eq!(sigs, [&b"FACP"[..], b"APIC", b"HPET"]);
+
+        // Every pointer (RSDP, FADT x3, XSDT x3) and checksum (RSDP x2, plus
+        // one for each table aside from the FACS) has a loader command.
+        let ncmds = acpi.loader.len() / 128;
+        assert_eq!(ncmds, 2 + (1 + 3 + 3) + (2 + 5));
+    }
+
+    #[test]
+    fn mcfg_when_ecam() {
+        let mut cfg = test_config();
+        cfg.ecam.push(Ecam {
+            base: 0xe000_0000,
+            segment: 0,
+            start_bus: 0,
+            end_bus: 0xff,
+        });
+        let acpi = build(&cfg);
+        assert!(acpi.tables.windows(4).any(|w| w == b"MCFG"));
+    }
+
+    #[test]
+    fn q35_host_bridge() {
+        let contains = |tables: &[u8], needle: &[u8]| {
+            tables.windows(needle.len()).any(|w| w == needle)
+        };
+        // EISA ID encoding of PNP0A08
+        let pcie_hid = aml::eisa_id("PNP0A08");
+
+        let acpi = build(&test_config());
+        assert!(!contains(&acpi.tables, &pcie_hid));
+
+        let mut cfg = test_config();
+        cfg.chipset = ChipsetKind::Q35;
+        let acpi = build(&cfg);
+        assert!(contains(&acpi.tables, &pcie_hid));
+    }
+
+    #[test]
+    fn pci_hotplug_slots() {
+        let contains = |tables: &[u8], needle: &[u8]| {
+            tables.windows(needle.len()).any(|w| w == needle)
+        };
+
+        let acpi = build(&test_config());
+        assert!(contains(&acpi.tables, b"_E01"));
+        assert!(contains(&acpi.tables, b"S08_"));
+        // The PIIX3 occupies slot 1
+        assert!(!contains(&acpi.tables, b"S01_"));
+
+        let mut cfg = test_config();
+        cfg.chipset = ChipsetKind::Q35;
+        let acpi = build(&cfg);
+        assert!(contains(&acpi.tables, b"S08_"));
+        // Root ports and the LPC bridge are not removable
+        assert!(!contains(&acpi.tables, b"S18_"));
+        assert!(!contains(&acpi.tables, b"S1F_"));
+    }
+}
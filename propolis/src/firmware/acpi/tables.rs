@@ -0,0 +1,386 @@
+//! Encoders for the fixed-format ACPI tables.
+//!
+//! Tables are produced with their checksums (and any pointers to other
+//! tables) left for the firmware to fill in, as directed by the table
+//! loader.
+
+pub const HEADER_LEN: usize = 36;
+/// Offset of the checksum within the standard table header.
+pub const CHECKSUM_OFFSET: usize = 9;
+
+const OEM_ID: &[u8; 6] = b"OXIDE ";
+const OEM_TABLE_ID: &[u8; 8] = b"PROPOLIS";
+const OEM_REVISION: u32 = 1;
+const CREATOR_ID: &[u8; 4] = b"OXDE";
+const CREATOR_REVISION: u32 = 1;
+
+/// Begins a table with the standard description header.
+///
+/// See ACPI 6.4 Section 5.2.6 System Description Table Header
+fn header(signature: &[u8; 4], revision: u8) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN);
+    out.extend_from_slice(signature);
+    // length and checksum are filled in later
+    out.extend_from_slice(&[0; 4]);
+    out.push(revision);
+    out.push(0);
+    out.extend_from_slice(OEM_ID);
+    out.extend_from_slice(OEM_TABLE_ID);
+    out.extend_from_slice(&OEM_REVISION.to_le_bytes());
+    out.extend_from_slice(CREATOR_ID);
+    out.extend_from_slice(&CREATOR_REVISION.to_le_bytes());
+    out
+}
+
+/// Records the final length of a table in its header.
+fn finish(mut table: Vec<u8>) -> Vec<u8> {
+    let len = table.len() as u32;
+    table[4..8].copy_from_slice(&len.to_le_bytes());
+    table
+}
+
+/// Computes the value which causes `data` to sum to zero.
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg()
+}
+
+/// Address space of a Generic Address Structure.
+#[derive(Copy, Clone, Debug)]
+pub enum AddrSpace {
+    Memory = 0,
+    Io = 1,
+}
+
+/// Encodes a Generic Address Structure.
+///
+/// See ACPI 6.4 Section 5.2.3.2 Generic Address Structure
+fn gas(space: AddrSpace, bits: u8, access_size: u8, addr: u64) -> [u8; 12] {
+    let mut out = [0u8; 12];
+    out[0] = space as u8;
+    out[1] = bits;
+    out[2] = 0;
+    out[3] = access_size;
+    out[4..].copy_from_slice(&addr.to_le_bytes());
+    out
+}
+
+/// Root System Description Pointer, referring to the XSDT.
+///
+/// See ACPI 6.4 Section 5.2.5.3 Root System Description Pointer (RSDP)
+/// Structure
+pub mod rsdp {
+    pub const LEN: usize = 36;
+    /// Checksum of the first 20 bytes (the ACPI 1.0 structure)
+    pub const CHECKSUM_OFFSET: usize = 8;
+    pub const CHECKSUM_LEN: usize = 20;
+    pub const EXT_CHECKSUM_OFFSET: usize = 32;
+    pub const XSDT_OFFSET: usize = 24;
+
+    pub fn build() -> Vec<u8> {
+        let mut out = Vec::with_capacity(LEN);
+        out.extend_from_slice(b"RSD PTR ");
+        out.push(0);
+        out.extend_from_slice(super::OEM_ID);
+        // revision 2, indicating the presence of the XSDT
+        out.push(2);
+        // RSDT address unused
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&(LEN as u32).to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out
+    }
+}
+
+/// Extended System Description Table, with entries to be filled in with the
+/// addresses of the other tables.
+///
+/// See ACPI 6.4 Section 5.2.8 Extended System Description Table (XSDT)
+pub mod xsdt {
+    pub const ENTRY_SIZE: usize = 8;
+
+    pub fn build(entries: usize) -> Vec<u8> {
+        let mut out = super::header(b"XSDT", 1);
+        out.resize(super::HEADER_LEN + entries * ENTRY_SIZE, 0);
+        super::finish(out)
+    }
+
+    pub fn entry_offset(idx: usize) -> usize {
+        super::HEADER_LEN + idx * ENTRY_SIZE
+    }
+}
+
+/// Firmware ACPI Control Structure.
+///
+/// See ACPI 6.4 Section 5.2.10 Firmware ACPI Control Structure (FACS)
+pub mod facs {
+    pub const LEN: usize = 64;
+    /// The FACS must be aligned on a 64-byte boundary.
+    pub const ALIGN: usize = 64;
+
+    pub fn build() -> Vec<u8> {
+        let mut out = vec![0u8; LEN];
+        out[0..4].copy_from_slice(b"FACS");
+        out[4..8].copy_from_slice(&(LEN as u32).to_le_bytes());
+        // version
+        out[32] = 2;
+        out
+    }
+}
+
+/// Differentiated System Description Table, wrapping the AML definition
+/// block.
+pub mod dsdt {
+    pub fn build(aml: &[u8]) -> Vec<u8> {
+        // Revision 2 enables 64-bit AML integers
+        let mut out = super::header(b"DSDT", 2);
+        out.extend_from_slice(aml);
+        super::finish(out)
+    }
+}
+
+/// Fixed ACPI Description Table.
+///
+/// See ACPI 6.4 Section 5.2.9 Fixed ACPI Description Table (FADT)
+pub mod fadt {
+    use super::{gas, AddrSpace};
+
+    pub const LEN: usize = 276;
+    pub const FIRMWARE_CTRL_OFFSET: usize = 36;
+    pub const DSDT_OFFSET: usize = 40;
+    pub const X_DSDT_OFFSET: usize = 140;
+
+    bitflags! {
+        pub struct Flags: u32 {
+            const WBINVD = 1 << 0;
+            const PROC_C1 = 1 << 2;
+            /// Power button is a control method device, rather than using
+            /// the fixed hardware registers.
+            const PWR_BUTTON = 1 << 4;
+            /// Sleep button is a control method device (or absent)
+            const SLP_BUTTON = 1 << 5;
+            /// RTC wake status is not in the fixed hardware registers
+            const FIX_RTC = 1 << 6;
+            const TMR_VAL_EXT = 1 << 8;
+            const RESET_REG_SUP = 1 << 10;
+        }
+    }
+
+    bitflags! {
+        pub struct BootArch: u16 {
+            const LEGACY_DEVICES = 1 << 0;
+            const I8042 = 1 << 1;
+        }
+    }
+
+    /// Fixed hardware register blocks, all in I/O port space.
+    pub struct Config {
+        pub sci_irq: u16,
+        pub pm1a_evt_blk: u16,
+        pub pm1a_cnt_blk: u16,
+        pub pm_tmr_blk: u16,
+        pub gpe0_blk: u16,
+        pub gpe0_blk_len: u8,
+        pub flags: Flags,
+        pub boot_arch: BootArch,
+        /// CMOS RAM index of the RTC century
+        pub century: u8,
+    }
+
+    const PM1_EVT_LEN: u8 = 4;
+    const PM1_CNT_LEN: u8 = 2;
+    const PM_TMR_LEN: u8 = 4;
+
+    pub fn build(cfg: &Config) -> Vec<u8> {
+        // FADT revision 6, minor version 0
+        let mut out = super::header(b"FACP", 6);
+        out.resize(LEN, 0);
+
+        // FIRMWARE_CTRL and DSDT are filled in by the loader
+        out[46..48].copy_from_slice(&cfg.sci_irq.to_le_bytes());
+        // SMI_CMD left as zero: the hardware is always in ACPI mode
+
+        out[56..60].copy_from_slice(&(cfg.pm1a_evt_blk as u32).to_le_bytes());
+        out[64..68].copy_from_slice(&(cfg.pm1a_cnt_blk as u32).to_le_bytes());
+        out[76..80].copy_from_slice(&(cfg.pm_tmr_blk as u32).to_le_bytes());
+        out[80..84].copy_from_slice(&(cfg.gpe0_blk as u32).to_le_bytes());
+        out[88] = PM1_EVT_LEN;
+        out[89] = PM1_CNT_LEN;
+        out[91] = PM_TMR_LEN;
+        out[92] = cfg.gpe0_blk_len;
+
+        // C2/C3 are unsupported
+        out[96..98].copy_from_slice(&101u16.to_le_bytes());
+        out[98..100].copy_from_slice(&1001u16.to_le_bytes());
+        out[108] = cfg.century;
+        out[109..111].copy_from_slice(&cfg.boot_arch.bits().to_le_bytes());
+        out[112..116].copy_from_slice(&cfg.flags.bits().to_le_bytes());
+
+        let blocks = [
+            (148, cfg.pm1a_evt_blk, PM1_EVT_LEN, 2),
+            (172, cfg.pm1a_cnt_blk, PM1_CNT_LEN, 2),
+            (208, cfg.pm_tmr_blk, PM_TMR_LEN, 3),
+            (220, cfg.gpe0_blk, cfg.gpe0_blk_len, 1),
+        ];
+        for (off, addr, len, access) in blocks.iter() {
+            out[*off..*off + 12].copy_from_slice(&gas(
+                AddrSpace::Io,
+                len * 8,
+                *access,
+                *addr as u64,
+            ));
+        }
+        super::finish(out)
+    }
+}
+
+/// Multiple APIC Description Table.
+///
+/// See ACPI 6.4 Section 5.2.12 Multiple APIC Description Table (MADT)
+pub mod madt {
+    pub const LAPIC_ADDR: u32 = 0xfee0_0000;
+    const PCAT_COMPAT: u32 = 1;
+
+    const TYPE_LAPIC: u8 = 0;
+    const TYPE_IOAPIC: u8 = 1;
+    const TYPE_INT_SRC_OVERRIDE: u8 = 2;
+    const TYPE_LAPIC_NMI: u8 = 4;
+
+    const LAPIC_ENABLED: u32 = 1;
+
+    /// Polarity and trigger mode of an interrupt source override.
+    #[derive(Copy, Clone, Debug)]
+    pub enum IntMode {
+        /// Conforms to the specifications of the bus
+        Conforming = 0,
+        EdgeHigh = 0b0101,
+        LevelLow = 0b1111,
+    }
+
+    pub struct IoApic {
+        pub id: u8,
+        pub addr: u32,
+        pub gsi_base: u32,
+    }
+
+    pub struct Config {
+        pub num_cpus: u8,
+        pub ioapic: IoApic,
+        /// ISA IRQs connected to a different IOAPIC input, or with
+        /// non-standard polarity/trigger: (irq, gsi, mode)
+        pub overrides: Vec<(u8, u32, IntMode)>,
+    }
+
+    pub fn build(cfg: &Config) -> Vec<u8> {
+        let mut out = super::header(b"APIC", 5);
+        out.extend_from_slice(&LAPIC_ADDR.to_le_bytes());
+        out.extend_from_slice(&PCAT_COMPAT.to_le_bytes());
+
+        for cpu in 0..cfg.num_cpus {
+            // processor UID, APIC ID
+            out.extend_from_slice(&[TYPE_LAPIC, 8, cpu, cpu]);
+            out.extend_from_slice(&LAPIC_ENABLED.to_le_bytes());
+        }
+
+        out.extend_from_slice(&[TYPE_IOAPIC, 12, cfg.ioapic.id, 0]);
+        out.extend_from_slice(&cfg.ioapic.addr.to_le_bytes());
+        out.extend_from_slice(&cfg.ioapic.gsi_base.to_le_bytes());
+
+        for (irq, gsi, mode) in cfg.overrides.iter() {
+            // bus 0 (ISA)
+            out.extend_from_slice(&[TYPE_INT_SRC_OVERRIDE, 10, 0, *irq]);
+            out.extend_from_slice(&gsi.to_le_bytes());
+            out.extend_from_slice(&(*mode as u16).to_le_bytes());
+        }
+
+        // NMIs arrive on LINT1 of all processors
+        out.extend_from_slice(&[TYPE_LAPIC_NMI, 6, 0xff]);
+        out.extend_from_slice(&(IntMode::Conforming as u16).to_le_bytes());
+        out.push(1);
+
+        super::finish(out)
+    }
+}
+
+/// IA-PC High Precision Event Timer Table.
+pub mod hpet {
+    use super::{gas, AddrSpace};
+
+    pub fn build(block_id: u32, addr: u64) -> Vec<u8> {
+        let mut out = super::header(b"HPET", 1);
+        out.extend_from_slice(&block_id.to_le_bytes());
+        out.extend_from_slice(&gas(AddrSpace::Memory, 64, 0, addr));
+        // HPET number, minimum clock tick, page protection
+        out.push(0);
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.push(0);
+        super::finish(out)
+    }
+}
+
+/// PCI Express Memory-mapped Configuration Space base address description
+/// table.
+pub mod mcfg {
+    pub struct Ecam {
+        pub base: u64,
+        pub segment: u16,
+        pub start_bus: u8,
+        pub end_bus: u8,
+    }
+
+    pub fn build(regions: &[Ecam]) -> Vec<u8> {
+        let mut out = super::header(b"MCFG", 1);
+        out.extend_from_slice(&[0; 8]);
+        for ecam in regions {
+            out.extend_from_slice(&ecam.base.to_le_bytes());
+            out.extend_from_slice(&ecam.segment.to_le_bytes());
+            out.push(ecam.start_bus);
+            out.push(ecam.end_bus);
+            out.extend_from_slice(&[0; 4]);
+        }
+        super::finish(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn table_lengths() {
+        let cfg = fadt::Config {
+            sci_irq: 9,
+            pm1a_evt_blk: 0xb000,
+            pm1a_cnt_blk: 0xb004,
+            pm_tmr_blk: 0xb008,
+            gpe0_blk: 0xb00c,
+            gpe0_blk_len: 4,
+            flags: fadt::Flags::WBINVD,
+            boot_arch: fadt::BootArch::I8042,
+            century: 0x32,
+        };
+        let fadt = fadt::build(&cfg);
+        assert_eq!(fadt.len(), fadt::LEN);
+        assert_eq!(&fadt[4..8], &(fadt::LEN as u32).to_le_bytes());
+        assert_eq!(&fadt[10..16], OEM_ID);
+
+        assert_eq!(rsdp::build().len(), rsdp::LEN);
+        assert_eq!(facs::build().len(), facs::LEN);
+        assert_eq!(hpet::build(0, 0xfed0_0000).len(), 56);
+        assert_eq!(xsdt::build(3).len(), HEADER_LEN + 24);
+
+        let madt = madt::build(&madt::Config {
+            num_cpus: 2,
+            ioapic: madt::IoApic { id: 0, addr: 0xfec0_0000, gsi_base: 0 },
+            overrides: vec![(0, 2, madt::IntMode::Conforming)],
+        });
+        assert_eq!(madt.len(), HEADER_LEN + 8 + 2 * 8 + 12 + 10 + 6);
+    }
+
+    #[test]
+    fn checksums() {
+        let mut table = dsdt::build(&[1, 2, 3]);
+        table[CHECKSUM_OFFSET] = checksum(&table);
+        assert_eq!(table.iter().fold(0u8, |s, b| s.wrapping_add(*b)), 0);
+    }
+}
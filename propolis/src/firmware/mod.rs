@@ -0,0 +1,2 @@
+pub mod acpi;
+pub mod smbios;
@@ -0,0 +1,468 @@
+//! Generation of SMBIOS tables identifying the virtual platform.
+//!
+//! As with QEMU, the structure table is provided to the guest firmware via
+//! fw_cfg in `etc/smbios/smbios-tables`, with the SMBIOS 3.x entry point in
+//! `etc/smbios/smbios-anchor`.  The firmware is responsible for placing them
+//! in memory and filling in the structure table address.
+//!
+//! See the DMTF System Management BIOS (SMBIOS) Reference Specification,
+//! version 3.1.
+
+use crate::hw::qemu::fwcfg::{self, FixedItem, FwCfgBuilder};
+
+const TABLES_FILE: &str = "etc/smbios/smbios-tables";
+const ANCHOR_FILE: &str = "etc/smbios/smbios-anchor";
+
+const VERSION_MAJOR: u8 = 3;
+const VERSION_MINOR: u8 = 1;
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_CHASSIS: u8 = 3;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEM_ARRAY: u8 = 16;
+const TYPE_MEM_DEVICE: u8 = 17;
+const TYPE_MEM_ARRAY_MAPPED: u8 = 19;
+const TYPE_BOOT_INFO: u8 = 32;
+const TYPE_END: u8 = 127;
+
+/// Handles are assigned per structure type, leaving room for multiple
+/// instances of each.
+const fn handle(stype: u8, idx: u8) -> u16 {
+    (stype as u16) << 8 | idx as u16
+}
+
+/// Handle value indicating that no structure is referenced
+const HANDLE_NONE: u16 = 0xffff;
+/// Handle value indicating that error information is not provided
+const HANDLE_NO_ERR_INFO: u16 = 0xfffe;
+
+/// Identification of the platform firmware (type 0).
+#[derive(Clone, Debug, Default)]
+pub struct BiosInfo {
+    pub vendor: String,
+    pub version: String,
+    /// Release date, in mm/dd/yyyy form
+    pub release_date: String,
+}
+
+/// Identification of the system as a whole (type 1).
+#[derive(Clone, Debug, Default)]
+pub struct SystemInfo {
+    pub manufacturer: String,
+    pub product: String,
+    pub version: String,
+    pub serial_number: String,
+    /// UUID, in its big-endian (RFC 4122) byte order
+    pub uuid: [u8; 16],
+    pub sku: String,
+    pub family: String,
+}
+
+/// Identification of the system enclosure (type 3).
+#[derive(Clone, Debug, Default)]
+pub struct ChassisInfo {
+    pub manufacturer: String,
+    pub version: String,
+    pub serial_number: String,
+    pub asset_tag: String,
+    pub sku: String,
+}
+
+/// Description of the platform to be conveyed through SMBIOS.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub bios: BiosInfo,
+    pub system: SystemInfo,
+    pub chassis: ChassisInfo,
+    pub num_cpus: u8,
+    /// Regions of guest-physical memory, as (base, length) in bytes
+    pub mem_regions: Vec<(u64, u64)>,
+}
+
+/// A single SMBIOS structure: its formatted area followed by its strings.
+struct Structure {
+    data: Vec<u8>,
+    strings: Vec<u8>,
+    nstrings: u8,
+}
+impl Structure {
+    fn new(stype: u8, len: u8, handle: u16) -> Self {
+        let mut data = vec![0u8; len as usize];
+        data[0] = stype;
+        data[1] = len;
+        data[2..4].copy_from_slice(&handle.to_le_bytes());
+        Self { data, strings: Vec::new(), nstrings: 0 }
+    }
+
+    fn put_u8(&mut self, off: usize, val: u8) {
+        self.data[off] = val;
+    }
+    fn put_u16(&mut self, off: usize, val: u16) {
+        self.data[off..off + 2].copy_from_slice(&val.to_le_bytes());
+    }
+    fn put_u32(&mut self, off: usize, val: u32) {
+        self.data[off..off + 4].copy_from_slice(&val.to_le_bytes());
+    }
+    fn put_u64(&mut self, off: usize, val: u64) {
+        self.data[off..off + 8].copy_from_slice(&val.to_le_bytes());
+    }
+
+    /// Adds `val` to the string-set, storing its (one-based) number at
+    /// `off`.  Empty strings are instead denoted by a string number of 0.
+    fn put_str(&mut self, off: usize, val: &str) {
+        // Strings are null-terminated, so must not contain nulls themselves
+        let val: Vec<u8> = val.bytes().filter(|b| *b != 0).collect();
+        if val.is_empty() {
+            self.data[off] = 0;
+            return;
+        }
+        self.strings.extend(val);
+        self.strings.push(0);
+        self.nstrings += 1;
+        self.data[off] = self.nstrings;
+    }
+
+    fn finish(mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.data);
+        if self.strings.is_empty() {
+            // An empty string-set is still terminated by a pair of nulls
+            out.extend_from_slice(&[0, 0]);
+        } else {
+            out.append(&mut self.strings);
+            out.push(0);
+        }
+    }
+}
+
+/// Converts a UUID to the SMBIOS encoding, in which the first three fields
+/// are little-endian.
+fn smbios_uuid(uuid: &[u8; 16]) -> [u8; 16] {
+    let mut out = *uuid;
+    out[0..4].reverse();
+    out[4..6].reverse();
+    out[6..8].reverse();
+    out
+}
+
+fn bios_info(cfg: &BiosInfo) -> Structure {
+    // BIOS characteristics are not supported
+    const CHARACTERISTICS: u64 = 1 << 3;
+    // UEFI is supported, and this is a virtual machine
+    const CHAR_EXT2: u8 = (1 << 3) | (1 << 4);
+
+    let mut s = Structure::new(TYPE_BIOS, 0x1a, handle(TYPE_BIOS, 0));
+    s.put_str(0x4, &cfg.vendor);
+    s.put_str(0x5, &cfg.version);
+    // Starting address segment
+    s.put_u16(0x6, 0xe800);
+    s.put_str(0x8, &cfg.release_date);
+    s.put_u64(0xa, CHARACTERISTICS);
+    s.put_u8(0x13, CHAR_EXT2);
+    // No embedded controller
+    s.put_u8(0x16, 0xff);
+    s.put_u8(0x17, 0xff);
+    s
+}
+
+fn system_info(cfg: &SystemInfo) -> Structure {
+    const WAKE_POWER_SWITCH: u8 = 6;
+
+    let mut s = Structure::new(TYPE_SYSTEM, 0x1b, handle(TYPE_SYSTEM, 0));
+    s.put_str(0x4, &cfg.manufacturer);
+    s.put_str(0x5, &cfg.product);
+    s.put_str(0x6, &cfg.version);
+    s.put_str(0x7, &cfg.serial_number);
+    s.data[0x8..0x18].copy_from_slice(&smbios_uuid(&cfg.uuid));
+    s.put_u8(0x18, WAKE_POWER_SWITCH);
+    s.put_str(0x19, &cfg.sku);
+    s.put_str(0x1a, &cfg.family);
+    s
+}
+
+fn chassis_info(cfg: &ChassisInfo) -> Structure {
+    const TYPE_OTHER: u8 = 1;
+    const STATE_SAFE: u8 = 3;
+    const SECURITY_UNKNOWN: u8 = 2;
+
+    let mut s = Structure::new(TYPE_CHASSIS, 0x16, handle(TYPE_CHASSIS, 0));
+    s.put_str(0x4, &cfg.manufacturer);
+    s.put_u8(0x5, TYPE_OTHER);
+    s.put_str(0x6, &cfg.version);
+    s.put_str(0x7, &cfg.serial_number);
+    s.put_str(0x8, &cfg.asset_tag);
+    // Boot-up, power supply and thermal state
+    s.put_u8(0x9, STATE_SAFE);
+    s.put_u8(0xa, STATE_SAFE);
+    s.put_u8(0xb, STATE_SAFE);
+    s.put_u8(0xc, SECURITY_UNKNOWN);
+    // No contained elements, so the SKU immediately follows their count and
+    // record length.
+    s.put_str(0x15, &cfg.sku);
+    s
+}
+
+fn processor_info(cpu: u8) -> Structure {
+    const TYPE_CENTRAL: u8 = 3;
+    const FAMILY_OTHER: u8 = 1;
+    // Socket populated, CPU enabled
+    const STATUS: u8 = 0x41;
+    const UPGRADE_OTHER: u8 = 1;
+    const CHARACTERISTICS_UNKNOWN: u16 = 1 << 1;
+
+    let mut s =
+        Structure::new(TYPE_PROCESSOR, 0x30, handle(TYPE_PROCESSOR, cpu));
+    s.put_str(0x4, &format!("CPU {}", cpu));
+    s.put_u8(0x5, TYPE_CENTRAL);
+    s.put_u8(0x6, FAMILY_OTHER);
+    s.put_u8(0x17, STATUS);
+    s.put_u8(0x18, UPGRADE_OTHER);
+    // No cache information
+    s.put_u16(0x1a, HANDLE_NONE);
+    s.put_u16(0x1c, HANDLE_NONE);
+    s.put_u16(0x1e, HANDLE_NONE);
+    // Each vCPU is presented as a single-core, single-thread socket
+    s.put_u8(0x23, 1);
+    s.put_u8(0x24, 1);
+    s.put_u8(0x25, 1);
+    s.put_u16(0x26, CHARACTERISTICS_UNKNOWN);
+    s.put_u16(0x28, FAMILY_OTHER as u16);
+    s.put_u16(0x2a, 1);
+    s.put_u16(0x2c, 1);
+    s.put_u16(0x2e, 1);
+    s
+}
+
+fn mem_array(total: u64) -> Structure {
+    const LOCATION_OTHER: u8 = 1;
+    const USE_SYSTEM: u8 = 3;
+    const ECC_MULTI_BIT: u8 = 6;
+    // Maximum capacity (in KiB) indicating the extended field is used
+    const CAPACITY_EXTENDED: u32 = 0x8000_0000;
+
+    let mut s = Structure::new(TYPE_MEM_ARRAY, 0x17, handle(TYPE_MEM_ARRAY, 0));
+    s.put_u8(0x4, LOCATION_OTHER);
+    s.put_u8(0x5, USE_SYSTEM);
+    s.put_u8(0x6, ECC_MULTI_BIT);
+    let kib = total / 1024;
+    if kib < CAPACITY_EXTENDED as u64 {
+        s.put_u32(0x7, kib as u32);
+    } else {
+        s.put_u32(0x7, CAPACITY_EXTENDED);
+        s.put_u64(0xf, total);
+    }
+    s.put_u16(0xb, HANDLE_NO_ERR_INFO);
+    // Number of memory devices
+    s.put_u16(0xd, 1);
+    s
+}
+
+fn mem_device(total: u64) -> Structure {
+    const WIDTH_UNKNOWN: u16 = 0xffff;
+    // Size (in MiB) indicating the extended field is used
+    const SIZE_EXTENDED: u16 = 0x7fff;
+    const FORM_DIMM: u8 = 9;
+    const TYPE_RAM: u8 = 7;
+    const DETAIL_OTHER: u16 = 1 << 1;
+
+    let mut s =
+        Structure::new(TYPE_MEM_DEVICE, 0x28, handle(TYPE_MEM_DEVICE, 0));
+    s.put_u16(0x4, handle(TYPE_MEM_ARRAY, 0));
+    s.put_u16(0x6, HANDLE_NO_ERR_INFO);
+    s.put_u16(0x8, WIDTH_UNKNOWN);
+    s.put_u16(0xa, WIDTH_UNKNOWN);
+    let mib = total / (1024 * 1024);
+    if mib < SIZE_EXTENDED as u64 {
+        s.put_u16(0xc, mib as u16);
+    } else {
+        s.put_u16(0xc, SIZE_EXTENDED);
+        s.put_u32(0x1c, mib as u32);
+    }
+    s.put_u8(0xe, FORM_DIMM);
+    s.put_str(0x10, "DIMM 0");
+    s.put_u8(0x12, TYPE_RAM);
+    s.put_u16(0x13, DETAIL_OTHER);
+    s
+}
+
+fn mem_array_mapped(idx: u8, base: u64, len: u64) -> Structure {
+    // Address (in KiB) indicating the extended fields are used
+    const ADDR_EXTENDED: u32 = 0xffff_ffff;
+
+    let mut s = Structure::new(
+        TYPE_MEM_ARRAY_MAPPED,
+        0x1f,
+        handle(TYPE_MEM_ARRAY_MAPPED, idx),
+    );
+    let end = base + len - 1;
+    if end / 1024 < ADDR_EXTENDED as u64 {
+        s.put_u32(0x4, (base / 1024) as u32);
+        s.put_u32(0x8, (end / 1024) as u32);
+    } else {
+        s.put_u32(0x4, ADDR_EXTENDED);
+        s.put_u32(0x8, ADDR_EXTENDED);
+        s.put_u64(0xf, base);
+        s.put_u64(0x17, end);
+    }
+    s.put_u16(0xc, handle(TYPE_MEM_ARRAY, 0));
+    // Partition width
+    s.put_u8(0xe, 1);
+    s
+}
+
+fn boot_info() -> Structure {
+    // Boot status (at 0xa) of 0 indicates no errors were detected
+    Structure::new(TYPE_BOOT_INFO, 0xb, handle(TYPE_BOOT_INFO, 0))
+}
+
+/// The SMBIOS 3.0 (64-bit) entry point structure.
+fn anchor(table_len: u32) -> Vec<u8> {
+    const LEN: usize = 0x18;
+    const ENTRY_POINT_REV: u8 = 1;
+
+    let mut out = vec![0u8; LEN];
+    out[0..5].copy_from_slice(b"_SM3_");
+    out[6] = LEN as u8;
+    out[7] = VERSION_MAJOR;
+    out[8] = VERSION_MINOR;
+    out[0xa] = ENTRY_POINT_REV;
+    out[0xc..0x10].copy_from_slice(&table_len.to_le_bytes());
+    // The structure table address (at 0x10) is filled in by the firmware,
+    // which then recomputes the checksum.
+    let sum = out.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    out[5] = 0u8.wrapping_sub(sum);
+    out
+}
+
+/// SMBIOS entry point and structure table.
+pub struct SmbiosTables {
+    anchor: Vec<u8>,
+    tables: Vec<u8>,
+}
+impl SmbiosTables {
+    /// Exposes the structure table and its SMBIOS 3.0 entry point as the
+    /// `etc/smbios` fw_cfg files, from which OVMF installs the structures.
+    pub fn attach(self, builder: &mut FwCfgBuilder) -> fwcfg::Result {
+        builder.add_named(TABLES_FILE, FixedItem::new_raw(self.tables))?;
+        builder.add_named(ANCHOR_FILE, FixedItem::new_raw(self.anchor))?;
+        Ok(())
+    }
+}
+
+/// Generates the SMBIOS structure table and its entry point.
+pub fn build(cfg: &Config) -> SmbiosTables {
+    let total_mem: u64 = cfg.mem_regions.iter().map(|(_, len)| *len).sum();
+
+    let mut structs = vec![
+        bios_info(&cfg.bios),
+        system_info(&cfg.system),
+        chassis_info(&cfg.chassis),
+    ];
+    structs.extend((0..cfg.num_cpus).map(processor_info));
+    structs.push(mem_array(total_mem));
+    structs.push(mem_device(total_mem));
+    structs.extend(
+        cfg.mem_regions
+            .iter()
+            .filter(|(_, len)| *len != 0)
+            .enumerate()
+            .map(|(i, (base, len))| mem_array_mapped(i as u8, *base, *len)),
+    );
+    structs.push(boot_info());
+    structs.push(Structure::new(TYPE_END, 4, handle(TYPE_END, 0)));
+
+    let mut tables = Vec::new();
+    for s in structs {
+        s.finish(&mut tables);
+    }
+    SmbiosTables { anchor: anchor(tables.len() as u32), tables }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Splits a structure table into (type, formatted area, strings).
+    fn parse(mut data: &[u8]) -> Vec<(u8, &[u8], Vec<&[u8]>)> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let len = data[1] as usize;
+            let (fmt, rest) = data.split_at(len);
+            let end = rest.windows(2).position(|w| w == [0, 0]).unwrap();
+            let strings = rest[..end]
+                .split(|b| *b == 0)
+                .filter(|s| !s.is_empty())
+                .collect();
+            out.push((fmt[0], fmt, strings));
+            data = &rest[end + 2..];
+        }
+        out
+    }
+
+    /// Builds tables for a two-CPU guest with 4GiB of memory split around
+    /// the PCI hole.
+    fn test_tables() -> SmbiosTables {
+        build(&Config {
+            system: SystemInfo {
+                manufacturer: "Oxide".to_string(),
+                serial_number: "1234".to_string(),
+                uuid: [
+                    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99,
+                    0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
+                ],
+                ..Default::default()
+            },
+            num_cpus: 2,
+            mem_regions: vec![(0, 3 << 30), (1 << 32, 1 << 30), (0, 0)],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn structure_types() {
+        let smbios = test_tables();
+        let types: Vec<u8> =
+            parse(&smbios.tables).iter().map(|(t, _, _)| *t).collect();
+        assert_eq!(types, [0, 1, 3, 4, 4, 16, 17, 19, 19, 32, 127]);
+    }
+
+    #[test]
+    fn system_strings() {
+        let smbios = test_tables();
+        let structs = parse(&smbios.tables);
+        let (_, fmt, strings) = &structs[1];
+        // Manufacturer and serial are present; product and version are not
+        assert_eq!(&fmt[4..8], &[1, 0, 0, 2]);
+        assert_eq!(strings, &[&b"Oxide"[..], b"1234"]);
+        assert_eq!(
+            &fmt[8..12],
+            &[0x33, 0x22, 0x11, 0x00],
+            "UUID time_low is little-endian"
+        );
+    }
+
+    #[test]
+    fn memory_size() {
+        let smbios = test_tables();
+        let structs = parse(&smbios.tables);
+        let (_, dev, _) = structs.iter().find(|(t, _, _)| *t == 17).unwrap();
+        assert_eq!(u16::from_le_bytes([dev[0xc], dev[0xd]]), 4096);
+    }
+
+    #[test]
+    fn anchor_checksum() {
+        let smbios = test_tables();
+        assert_eq!(&smbios.anchor[..5], b"_SM3_");
+        assert_eq!(
+            smbios.anchor.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)),
+            0
+        );
+        let len = u32::from_le_bytes([
+            smbios.anchor[0xc],
+            smbios.anchor[0xd],
+            smbios.anchor[0xe],
+            smbios.anchor[0xf],
+        ]);
+        assert_eq!(len as usize, smbios.tables.len());
+    }
+}
@@ -0,0 +1,212 @@
+//! ACPI-based hotplug of devices on the root PCI bus.
+//!
+//! The register interface follows that of the PIIX4 hotplug controller in
+//! QEMU: the guest AML consults bitmaps of slots with devices pending
+//! insertion or removal when notified via a general purpose event, and ejects
+//! devices by writing to the eject register from the `_EJ0` method of the
+//! slot.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex};
+
+use super::pm::{AcpiPm, GpEvt};
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::hw::pci::{self, Bdf};
+use crate::util::regmap::RegMap;
+
+use lazy_static::lazy_static;
+
+pub const PCIHP_BASE: u16 = 0xae00;
+pub const PCIHP_LEN: u16 = 0x10;
+
+// Offsets of the registers within the hotplug I/O block
+pub const PCIHP_UP_OFFSET: u16 = 0x0;
+pub const PCIHP_DOWN_OFFSET: u16 = 0x4;
+pub const PCIHP_EJECT_OFFSET: u16 = 0x8;
+pub const PCIHP_REMOVABLE_OFFSET: u16 = 0xc;
+
+const FUNCS_PER_SLOT: u8 = 8;
+
+/// Invoked once the device in a hotplug slot has been removed from the bus,
+/// to release whatever else was set up alongside it.
+pub type EjectFn = dyn FnOnce(&DispCtx) + Send + 'static;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum HpReg {
+    Up,
+    Down,
+    Eject,
+    Removable,
+}
+lazy_static! {
+    static ref HP_REGS: RegMap<HpReg> = {
+        let layout = [
+            (HpReg::Up, 4),
+            (HpReg::Down, 4),
+            (HpReg::Eject, 4),
+            (HpReg::Removable, 4),
+        ];
+        RegMap::create_packed(PCIHP_LEN as usize, &layout, None)
+    };
+}
+
+#[derive(Default)]
+struct HpState {
+    /// Slots with a newly inserted device, not yet seen by the guest
+    up: u32,
+    /// Slots with a device the guest has been asked to eject
+    down: u32,
+    /// Functions to run as the device in each slot is ejected
+    on_eject: BTreeMap<u8, Box<EjectFn>>,
+}
+
+/// Hotplug controller for the slots of the root PCI bus.
+pub struct PciHotplug {
+    /// Bitmap of the slots which support hotplug
+    slots: u32,
+    state: Mutex<HpState>,
+    pm: Arc<AcpiPm>,
+}
+impl PciHotplug {
+    /// Creates a controller for the slots in the `slots` bitmap, notifying the
+    /// guest of events through the GPE block of `pm`.
+    pub fn new(slots: u32, pm: Arc<AcpiPm>) -> Self {
+        Self { slots, state: Mutex::new(HpState::default()), pm }
+    }
+
+    fn check_slot(&self, bdf: &Bdf) -> Result<u32> {
+        if bdf.bus() != 0 || bdf.func() != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not the first function of a root bus slot", bdf),
+            ));
+        }
+        let bit = 1 << bdf.dev();
+        if self.slots & bit == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("slot {} does not support hotplug", bdf.dev()),
+            ));
+        }
+        Ok(bit)
+    }
+
+    /// Checks that a device may be plugged in at `bdf`, which must be an empty
+    /// hotplug slot on `bus`.
+    pub fn check_plug(&self, bus: &pci::Bus, bdf: &Bdf) -> Result<()> {
+        let bit = self.check_slot(bdf)?;
+        let pending = self.state.lock().unwrap().down & bit != 0;
+        if pending || bus.device_at(bdf.dev(), 0).is_some() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("slot {} is occupied", bdf.dev()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Notifies the guest of a device newly plugged in at `bdf`.
+    pub fn plugged(&self, bdf: &Bdf) {
+        let bit = 1 << bdf.dev();
+        let mut state = self.state.lock().unwrap();
+        state.up |= bit;
+        drop(state);
+        self.pm.raise_gpe(GpEvt::PCI_HOTPLUG);
+    }
+
+    /// Arranges for `func` to be invoked once the device at `bdf` has been
+    /// ejected, replacing any function previously registered for the slot.
+    pub fn on_eject(&self, bdf: &Bdf, func: Box<EjectFn>) -> Result<()> {
+        self.check_slot(bdf)?;
+        let mut state = self.state.lock().unwrap();
+        state.on_eject.insert(bdf.dev(), func);
+        Ok(())
+    }
+
+    /// Requests that the guest release the device at `bdf` on `bus`.
+    ///
+    /// The device is removed from the bus once the guest ejects it, or the
+    /// machine is reset.
+    pub fn request_unplug(&self, bus: &pci::Bus, bdf: &Bdf) -> Result<()> {
+        let bit = self.check_slot(bdf)?;
+        if bus.device_at(bdf.dev(), 0).is_none() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("slot {} is empty", bdf.dev()),
+            ));
+        }
+        let mut state = self.state.lock().unwrap();
+        state.up &= !bit;
+        state.down |= bit;
+        drop(state);
+        self.pm.raise_gpe(GpEvt::PCI_HOTPLUG);
+        Ok(())
+    }
+
+    /// Detaches the devices in the slots of `mask` from `bus`, releasing any
+    /// resources they hold.
+    fn eject(&self, mask: u32, bus: &Mutex<pci::Bus>, ctx: &DispCtx) {
+        for slot in (0..32u8).filter(|slot| mask & (1 << slot) != 0) {
+            let mut guard = bus.lock().unwrap();
+            let devs: Vec<_> = (0..FUNCS_PER_SLOT)
+                .filter_map(|func| guard.detach(slot, func))
+                .collect();
+            drop(guard);
+
+            for dev in devs {
+                if let Some(devinst) = dev.as_devinst() {
+                    devinst.detach(ctx);
+                }
+            }
+
+            let func = self.state.lock().unwrap().on_eject.remove(&slot);
+            if let Some(func) = func {
+                func(ctx);
+            }
+        }
+    }
+
+    /// Handles an access to the hotplug I/O block, ejecting devices from
+    /// `bus` as requested by the guest.
+    pub fn pio_rw(&self, mut rwo: RWOp, bus: &Mutex<pci::Bus>, ctx: &DispCtx) {
+        HP_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => {
+                let mut state = self.state.lock().unwrap();
+                match id {
+                    HpReg::Up => {
+                        // Insertions are reported to the guest only once
+                        ro.write_u32(state.up);
+                        state.up = 0;
+                    }
+                    HpReg::Down => ro.write_u32(state.down),
+                    HpReg::Eject => ro.write_u32(0),
+                    HpReg::Removable => ro.write_u32(self.slots),
+                }
+            }
+            RWOp::Write(wo) => {
+                if *id != HpReg::Eject {
+                    return;
+                }
+                let mut state = self.state.lock().unwrap();
+                // Only devices which were asked to leave may do so
+                let mask = wo.read_u32() & state.down;
+                state.down &= !mask;
+                drop(state);
+                self.eject(mask, bus, ctx);
+            }
+        });
+    }
+
+    /// Completes any removals which the guest did not get to, as the machine
+    /// is reset.
+    pub fn reset(&self, bus: &Mutex<pci::Bus>, ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        let mask = state.down;
+        state.up = 0;
+        state.down = 0;
+        drop(state);
+        self.eject(mask, bus, ctx);
+    }
+}
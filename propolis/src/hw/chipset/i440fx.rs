@@ -1,11 +1,17 @@
+use std::io::{Error, ErrorKind, Result as IoResult};
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::{Arc, Mutex, Weak};
 
-use super::Chipset;
+use super::hotplug::{self, PciHotplug};
+use super::pm::AcpiPm;
+use super::{
+    lnk_route, valid_pir_irq, Chipset, LNKPin, PIR_END, PIR_LEN,
+    PIR_MASK_DISABLE, PIR_MASK_IRQ, PIR_OFFSET, SCI_IRQ,
+};
 use crate::common::*;
 use crate::dispatch::DispCtx;
 use crate::hw::ibmpc;
-use crate::hw::pci::{self, Bdf, INTxPinID, PioCfgDecoder};
+use crate::hw::pci::{self, Bdf, INTxPinID, LintrRouter, PioCfgDecoder};
 use crate::instance;
 use crate::intr_pins::{IntrPin, LegacyPIC, LegacyPin};
 use crate::pio::{PioBus, PioDev};
@@ -17,21 +23,22 @@ use lazy_static::lazy_static;
 
 const HB_DEV: u8 = 0;
 const HB_FUNC: u8 = 0;
-const LPC_DEV: u8 = 1;
-const LPC_FUNC: u8 = 0;
+pub const LPC_DEV: u8 = 1;
+pub const LPC_FUNC: u8 = 0;
 const PM_DEV: u8 = 1;
 const PM_FUNC: u8 = 3;
 
+/// Root bus slots which support hotplug: all but those of the chipset itself
+pub const HOTPLUG_SLOTS: u32 = !(1 << HB_DEV | 1 << LPC_DEV);
+
 pub struct I440Fx {
     pic: Arc<LegacyPIC>,
     pci_bus: Mutex<pci::Bus>,
     pci_cfg: PioCfgDecoder,
 
     lnk_pins: [Arc<LNKPin>; 4],
-
-    #[allow(unused)]
-    // XXX: wire up SCI notifications
     sci_pin: Arc<LNKPin>,
+    hotplug: PciHotplug,
 
     sa_cell: SelfArcCell<Self>,
 }
@@ -41,6 +48,7 @@ impl I440Fx {
 
         let sci_pin = Arc::new(LNKPin::new());
         sci_pin.reassign(pic.pin_handle(SCI_IRQ));
+        let pm = AcpiPm::create(Arc::clone(&sci_pin) as Arc<dyn IntrPin>);
 
         let mut this = Arc::new(Self {
             pic,
@@ -54,6 +62,7 @@ impl I440Fx {
                 Arc::new(LNKPin::new()),
             ],
             sci_pin,
+            hotplug: PciHotplug::new(HOTPLUG_SLOTS, Arc::clone(&pm)),
 
             sa_cell: SelfArcCell::new(),
         });
@@ -61,11 +70,11 @@ impl I440Fx {
 
         let hbdev = Piix4HostBridge::create();
         let lpcdev = Piix3Lpc::create(Arc::downgrade(&this));
-        let pmdev = Piix3PM::create();
+        let pmdev = Piix3PM::create(pm);
 
-        this.pci_attach(Bdf::new(0, HB_DEV, HB_FUNC), hbdev);
-        this.pci_attach(Bdf::new(0, LPC_DEV, LPC_FUNC), lpcdev);
-        this.pci_attach(Bdf::new(0, PM_DEV, PM_FUNC), pmdev);
+        this.pci_attach(Bdf::new(0, HB_DEV, HB_FUNC), hbdev).unwrap();
+        this.pci_attach(Bdf::new(0, LPC_DEV, LPC_FUNC), lpcdev).unwrap();
+        this.pci_attach(Bdf::new(0, PM_DEV, PM_FUNC), pmdev).unwrap();
 
         this
     }
@@ -83,6 +92,11 @@ impl I440Fx {
         pm.as_devinst().unwrap().with_inner(|pm: Arc<Piix3PM>| {
             pm.attach(mctx);
         });
+
+        let hp_pio = self.self_weak() as Weak<dyn PioDev>;
+        mctx.pio()
+            .register(hotplug::PCIHP_BASE, hotplug::PCIHP_LEN, hp_pio, 0)
+            .unwrap();
     }
 
     fn set_lnk_route(&self, idx: usize, irq: Option<u8>) {
@@ -91,28 +105,50 @@ impl I440Fx {
     }
 
     fn route_lintr(&self, bdf: &Bdf) -> (INTxPinID, Arc<dyn IntrPin>) {
-        let intx_pin = match (bdf.func() + 1) % 4 {
-            1 => INTxPinID::IntA,
-            2 => INTxPinID::IntB,
-            3 => INTxPinID::IntC,
-            4 => INTxPinID::IntD,
-            _ => panic!(),
-        };
-        // D->A->B->C starting at 0:0.0
-        let pin_route = (bdf.dev() + intx_pin as u8 + 2) % 4;
-        (
-            intx_pin,
-            Arc::clone(&self.lnk_pins[pin_route as usize]) as Arc<dyn IntrPin>,
-        )
+        let intx_pin = INTxPinID::for_func(bdf.func());
+        (intx_pin, self.lintr_router(bdf.dev())(intx_pin))
+    }
+
+    /// Routing of the INTx pins of the device in a root bus slot.  For a
+    /// bridge, this is where the (swizzled) pins of the devices behind it are
+    /// routed as well.
+    fn lintr_router(&self, dev: u8) -> LintrRouter {
+        let lnk_pins = self.lnk_pins.clone();
+        Arc::new(move |pin: INTxPinID| {
+            let route = lnk_route(dev, pin);
+            Arc::clone(&lnk_pins[route as usize]) as Arc<dyn IntrPin>
+        })
     }
 }
 impl Chipset for I440Fx {
-    fn pci_attach(&self, bdf: Bdf, dev: Arc<dyn pci::Endpoint>) {
-        assert!(bdf.bus() == 0);
+    fn pci_attach(
+        &self,
+        bdf: Bdf,
+        dev: Arc<dyn pci::Endpoint>,
+    ) -> IoResult<()> {
+        if bdf.bus() != 0 {
+            let bus = self.pci_bus.lock().unwrap();
+            let bridge = bus.find_bridge(bdf.bus()).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("no bridge provides bus {}", bdf.bus()),
+                )
+            })?;
+            drop(bus);
+            return bridge.as_bridge().unwrap().attach_device(
+                bdf.dev(),
+                bdf.func(),
+                dev,
+            );
+        }
 
+        if let Some(bridge) = dev.as_bridge() {
+            bridge.set_router(self.lintr_router(bdf.dev()));
+        }
         dev.attach(&|| self.route_lintr(&bdf));
         let mut bus = self.pci_bus.lock().unwrap();
         bus.attach(bdf.dev(), bdf.func(), dev);
+        Ok(())
     }
     fn pci_finalize(&self, mctx: &MachineCtx) {
         let cfg_pio = self.self_weak() as Weak<dyn PioDev>;
@@ -124,6 +160,44 @@ impl Chipset for I440Fx {
     fn irq_pin(&self, irq: u8) -> Option<LegacyPin> {
         self.pic.pin_handle(irq)
     }
+    fn pci_hotplug_check(&self, bdf: Bdf) -> IoResult<()> {
+        self.hotplug.check_plug(&self.pci_bus.lock().unwrap(), &bdf)
+    }
+    fn pci_hotplug(
+        &self,
+        bdf: Bdf,
+        dev: Arc<dyn pci::Endpoint>,
+    ) -> IoResult<()> {
+        self.pci_hotplug_check(bdf)?;
+        self.pci_attach(bdf, dev)?;
+        self.hotplug.plugged(&bdf);
+        Ok(())
+    }
+    fn pci_unplug(&self, bdf: Bdf) -> IoResult<()> {
+        let bus = self.pci_bus.lock().unwrap();
+        self.hotplug.request_unplug(&bus, &bdf)
+    }
+    fn pci_on_eject(
+        &self,
+        bdf: Bdf,
+        func: Box<hotplug::EjectFn>,
+    ) -> IoResult<()> {
+        self.hotplug.on_eject(&bdf, func)
+    }
+    fn press_power_button(&self, reboot: bool) {
+        let bus = self.pci_bus.lock().unwrap();
+        let pm = bus.device_at(PM_DEV, PM_FUNC).unwrap();
+        pm.as_devinst().unwrap().with_inner(|pm: Arc<Piix3PM>| {
+            pm.press_power_button(reboot);
+        });
+    }
+    fn cancel_power_button(&self) {
+        let bus = self.pci_bus.lock().unwrap();
+        let pm = bus.device_at(PM_DEV, PM_FUNC).unwrap();
+        pm.as_devinst().unwrap().with_inner(|pm: Arc<Piix3PM>| {
+            pm.cancel_power_button();
+        });
+    }
 }
 impl PioDev for I440Fx {
     fn pio_rw(&self, port: u16, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
@@ -133,12 +207,8 @@ impl PioDev for I440Fx {
             }
             pci::PORT_PCI_CONFIG_DATA => {
                 self.pci_cfg.service_data(rwo, |bdf, rwo| {
-                    if bdf.bus() != 0 {
-                        return None;
-                    }
                     let bus = self.pci_bus.lock().unwrap();
-                    if let Some(dev) = bus.device_at(bdf.dev(), bdf.func()) {
-                        let dev = Arc::clone(dev);
+                    if let Some(dev) = bus.find(0, bdf) {
                         drop(bus);
                         dev.cfg_rw(rwo, ctx);
                         // let opname = match rwo {
@@ -160,6 +230,9 @@ impl PioDev for I440Fx {
                     }
                 });
             }
+            hotplug::PCIHP_BASE => {
+                self.hotplug.pio_rw(rwo, &self.pci_bus, ctx);
+            }
             _ => {
                 panic!();
             }
@@ -182,75 +255,12 @@ impl Entity for I440Fx {
         let bus = self.pci_bus.lock().unwrap();
         let pm = bus.device_at(PM_DEV, PM_FUNC).unwrap().as_devinst().unwrap();
         pm.state_transition(next, target, ctx);
-    }
-}
+        drop(bus);
 
-struct LNKPin {
-    inner: Mutex<LNKPinInner>,
-}
-struct LNKPinInner {
-    asserted: bool,
-    pin: Option<LegacyPin>,
-}
-impl LNKPin {
-    fn new() -> Self {
-        Self { inner: Mutex::new(LNKPinInner { asserted: false, pin: None }) }
-    }
-    fn reassign(&self, new_pin: Option<LegacyPin>) {
-        let mut inner = self.inner.lock().unwrap();
-        if let Some(old_pin) = inner.pin.as_ref() {
-            if inner.asserted {
-                old_pin.deassert()
-            }
-        }
-
-        if let Some(pin) = new_pin.as_ref() {
-            if inner.asserted {
-                pin.assert()
-            }
-        }
-        inner.pin = new_pin;
-    }
-}
-impl IntrPin for LNKPin {
-    fn assert(&self) {
-        let mut inner = self.inner.lock().unwrap();
-        inner.asserted = true;
-        if let Some(pin) = inner.pin.as_ref() {
-            pin.assert();
-        }
-    }
-    fn deassert(&self) {
-        let mut inner = self.inner.lock().unwrap();
-        inner.asserted = false;
-        if let Some(pin) = inner.pin.as_ref() {
-            pin.deassert();
-        }
-    }
-    fn pulse(&self) {
-        let inner = self.inner.lock().unwrap();
-        if let Some(pin) = inner.pin.as_ref() {
-            pin.pulse();
+        if matches!(next, instance::State::Reset) {
+            self.hotplug.reset(&self.pci_bus, ctx);
         }
     }
-    fn is_asserted(&self) -> bool {
-        let inner = self.inner.lock().unwrap();
-        inner.asserted
-    }
-}
-
-const PIR_OFFSET: usize = 0x60;
-const PIR_LEN: usize = 4;
-const PIR_END: usize = PIR_OFFSET + PIR_LEN;
-
-const PIR_MASK_DISABLE: u8 = 0x80;
-const PIR_MASK_IRQ: u8 = 0x0f;
-
-const SCI_IRQ: u8 = 0x9;
-
-fn valid_pir_irq(irq: u8) -> bool {
-    // Existing ACPI tables allow 3-7, 9-12, 14-15
-    matches!(irq, 3..=7 | 9..=12 | 14 | 15)
 }
 
 struct Piix4HostBridge {}
@@ -380,9 +390,6 @@ impl PioDev for Piix3Lpc {
 const PMCFG_OFFSET: usize = 0x40;
 const PMCFG_LEN: usize = 0x98;
 
-const PMBASE_DEFAULT: u16 = 0xb000;
-const PMBASE_LEN: u16 = 0x40;
-
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum PmCfg {
     PmBase,
@@ -446,118 +453,12 @@ lazy_static! {
     };
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
-enum PmReg {
-    PmSts,
-    PmEn,
-    PmCntrl,
-    PmTmr,
-    GpSts,
-    GpEn,
-    PCntrl,
-    PLvl2,
-    PLvl3,
-    GlbSts,
-    DevSts,
-    GlbEn,
-    GlbCtl,
-    DevCtl,
-    GpiReg,
-    GpoReg,
-    Reserved,
-}
-
-lazy_static! {
-    static ref PM_REGS: RegMap<PmReg> = {
-        let layout = [
-            (PmReg::PmSts, 2),
-            (PmReg::PmEn, 2),
-            (PmReg::PmCntrl, 2),
-            (PmReg::Reserved, 2),
-            (PmReg::PmTmr, 4),
-            (PmReg::GpSts, 2),
-            (PmReg::GpEn, 2),
-            (PmReg::PCntrl, 4),
-            (PmReg::PLvl2, 1),
-            (PmReg::PLvl3, 1),
-            (PmReg::Reserved, 2),
-            (PmReg::GlbSts, 2),
-            (PmReg::Reserved, 2),
-            (PmReg::DevSts, 4),
-            (PmReg::GlbEn, 2),
-            (PmReg::Reserved, 6),
-            (PmReg::GlbCtl, 4),
-            (PmReg::DevCtl, 4),
-            (PmReg::GpiReg, 4),
-            (PmReg::GpoReg, 4),
-            (PmReg::Reserved, 8),
-        ];
-        RegMap::create_packed(
-            PMBASE_LEN as usize,
-            &layout,
-            Some(PmReg::Reserved),
-        )
-    };
-}
-bitflags! {
-    #[derive(Default)]
-    struct PmSts: u16 {
-        const PWRBTN_STS = 1 << 8;
-    }
-}
-bitflags! {
-    #[derive(Default)]
-    struct PmEn: u16 {
-        const PWRBTN_EN = 1 << 8;
-    }
-}
-bitflags! {
-    #[derive(Default)]
-    struct PmCntrl: u16 {
-        const SCI_EN = 1;
-        const SUS_TYP = 0b111 << 10;
-        const SUS_EN = 1 << 13;
-
-    }
-}
-
-// Offset within PMBASE region corresponding to PmTmr register
-const PM_TMR_OFFSET: u16 = 0x8;
-
-struct PMRegs {
-    pm_base: u16,
-    pm_status: PmSts,
-    pm_ena: PmEn,
-    pm_ctrl: PmCntrl,
-}
-impl Default for PMRegs {
-    fn default() -> Self {
-        Self {
-            pm_base: PMBASE_DEFAULT,
-            pm_status: PmSts::empty(),
-            pm_ena: PmEn::empty(),
-            pm_ctrl: PmCntrl::empty(),
-        }
-    }
-}
-impl PMRegs {
-    fn reset(&mut self) {
-        *self = Self::default();
-    }
-}
-
 pub struct Piix3PM {
-    regs: Mutex<PMRegs>,
-    sa_cell: SelfArcCell<Self>,
+    pm: Arc<AcpiPm>,
 }
 impl Piix3PM {
-    pub fn create() -> Arc<pci::DeviceInst> {
-        let regs = PMRegs::default();
-        let mut this = Arc::new(Self {
-            regs: Mutex::new(regs),
-            sa_cell: SelfArcCell::new(),
-        });
-        SelfArc::self_arc_init(&mut this);
+    pub fn create(pm: Arc<AcpiPm>) -> Arc<pci::DeviceInst> {
+        let this = Arc::new(Self { pm });
 
         pci::Builder::new(pci::Ident {
             vendor_id: 0x8086,
@@ -570,17 +471,19 @@ impl Piix3PM {
         .finish(this)
     }
 
-    fn attach(self: &Arc<Self>, mctx: &MachineCtx) {
-        // XXX: static registration for now
-        mctx.pio()
-            .register(
-                PMBASE_DEFAULT,
-                PMBASE_LEN,
-                Arc::downgrade(self) as Weak<dyn PioDev>,
-                0,
-            )
-            .unwrap();
-        mctx.hdl().pmtmr_locate(PMBASE_DEFAULT + PM_TMR_OFFSET).unwrap();
+    fn attach(&self, mctx: &MachineCtx) {
+        self.pm.attach(mctx);
+    }
+
+    /// Latches a power button press, notifying the guest via SCI if it has
+    /// enabled such events.
+    pub fn press_power_button(&self, reboot: bool) {
+        self.pm.press_power_button(reboot);
+    }
+
+    /// Withdraws an unacknowledged power button press.
+    pub fn cancel_power_button(&self) {
+        self.pm.cancel_power_button();
     }
 
     fn pmcfg_read(&self, id: &PmCfg, ro: &mut ReadOp) {
@@ -590,10 +493,8 @@ impl Piix3PM {
                 ro.write_u8(0x1);
             }
             PmCfg::PmBase => {
-                let regs = self.regs.lock().unwrap();
-
                 // LSB hardwired to 1 to indicate PMBase in IO space
-                ro.write_u32(regs.pm_base as u32 | 0x1);
+                ro.write_u32(self.pm.pm_base() as u32 | 0x1);
             }
             _ => {
                 // XXX: report everything else as zeroed
@@ -605,93 +506,6 @@ impl Piix3PM {
         // XXX: ignore writes for now
         println!("ignored PM cfg write to {:?}", id);
     }
-    fn pmreg_read(&self, id: &PmReg, ro: &mut ReadOp) {
-        let regs = self.regs.lock().unwrap();
-        match id {
-            PmReg::PmSts => {
-                ro.write_u16(regs.pm_status.bits());
-            }
-            PmReg::PmEn => {
-                ro.write_u16(regs.pm_ena.bits());
-            }
-            PmReg::PmCntrl => {
-                ro.write_u16(regs.pm_ctrl.bits());
-            }
-
-            PmReg::PmTmr
-            | PmReg::GpSts
-            | PmReg::GpEn
-            | PmReg::PCntrl
-            | PmReg::PLvl2
-            | PmReg::PLvl3
-            | PmReg::GlbSts
-            | PmReg::DevSts
-            | PmReg::GlbEn
-            | PmReg::GlbCtl
-            | PmReg::DevCtl
-            | PmReg::GpiReg
-            | PmReg::GpoReg => {
-                // TODO: flesh out the rest of PM emulation
-                println!("unhandled PM read {:x}", ro.offset());
-                ro.fill(0);
-            }
-            PmReg::Reserved => {
-                ro.fill(0);
-            }
-        }
-    }
-    fn pmreg_write(&self, id: &PmReg, wo: &mut WriteOp, ctx: &DispCtx) {
-        let mut regs = self.regs.lock().unwrap();
-        match id {
-            PmReg::PmSts => {
-                let val = PmSts::from_bits_truncate(wo.read_u16());
-                // status bits are W1C
-                regs.pm_status.remove(val);
-            }
-            PmReg::PmEn => {
-                regs.pm_ena = PmEn::from_bits_truncate(wo.read_u16());
-            }
-            PmReg::PmCntrl => {
-                regs.pm_ctrl = PmCntrl::from_bits_truncate(wo.read_u16());
-                if regs.pm_ctrl.contains(PmCntrl::SUS_EN) {
-                    // SUS_EN is write-only and should always read 0
-                    regs.pm_ctrl.remove(PmCntrl::SUS_EN);
-
-                    let suspend_type = (regs.pm_ctrl & PmCntrl::SUS_TYP).bits();
-                    if suspend_type == 0 {
-                        // 0b000 corresponds to soft-off
-                        ctx.trigger_suspend(
-                            instance::SuspendKind::Halt,
-                            instance::SuspendSource::Device("ACPI PmCntrl"),
-                        );
-                    }
-                }
-            }
-            PmReg::PmTmr
-            | PmReg::GpSts
-            | PmReg::GpEn
-            | PmReg::PCntrl
-            | PmReg::PLvl2
-            | PmReg::PLvl3
-            | PmReg::GlbSts
-            | PmReg::DevSts
-            | PmReg::GlbEn
-            | PmReg::GlbCtl
-            | PmReg::DevCtl
-            | PmReg::GpiReg
-            | PmReg::GpoReg => {
-                println!("unhandled PM write {:x}", wo.offset());
-            }
-            PmReg::Reserved => {}
-        }
-    }
-    fn reset(&self, ctx: &DispCtx) {
-        let mut regs = self.regs.lock().unwrap();
-        regs.reset();
-        // Make sure PM timer is attached to the right IO port
-        // TODO: error handling?
-        ctx.mctx.hdl().pmtmr_locate(PMBASE_DEFAULT + PM_TMR_OFFSET).unwrap();
-    }
 }
 impl pci::Device for Piix3PM {
     fn cfg_rw(&self, region: u8, mut rwo: RWOp) {
@@ -711,20 +525,7 @@ impl Entity for Piix3PM {
         ctx: &DispCtx,
     ) {
         if matches!(next, instance::State::Reset) {
-            self.reset(ctx);
+            self.pm.reset(ctx);
         }
     }
 }
-impl PioDev for Piix3PM {
-    fn pio_rw(&self, _port: u16, _ident: usize, mut rwo: RWOp, ctx: &DispCtx) {
-        PM_REGS.process(&mut rwo, |id, rwo| match rwo {
-            RWOp::Read(ro) => self.pmreg_read(id, ro),
-            RWOp::Write(wo) => self.pmreg_write(id, wo, ctx),
-        });
-    }
-}
-impl SelfArc for Piix3PM {
-    fn self_arc_cell(&self) -> &SelfArcCell<Self> {
-        &self.sa_cell
-    }
-}
@@ -1,13 +1,165 @@
-use std::sync::Arc;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
-use crate::hw::pci::{Bdf, Endpoint};
-use crate::intr_pins::LegacyPin;
+use crate::hw::pci::{Bdf, Endpoint, INTxPinID};
+use crate::intr_pins::{IntrPin, LegacyPin};
 use crate::vmm::MachineCtx;
 
+pub mod hotplug;
 pub mod i440fx;
+pub mod pm;
+pub mod q35;
 
-pub trait Chipset {
-    fn pci_attach(&self, bdf: Bdf, dev: Arc<dyn Endpoint>);
+pub trait Chipset: Send + Sync {
+    /// Attaches `dev` at `bdf`, failing if `bdf` cannot hold a device.
+    fn pci_attach(&self, bdf: Bdf, dev: Arc<dyn Endpoint>) -> IoResult<()>;
     fn pci_finalize(&self, mctx: &MachineCtx);
     fn irq_pin(&self, irq: u8) -> Option<LegacyPin>;
+
+    /// Checks that `bdf` is an empty hotplug slot of the root bus, into which
+    /// a device may be plugged with [`Chipset::pci_hotplug`].
+    fn pci_hotplug_check(&self, bdf: Bdf) -> IoResult<()>;
+
+    /// Attaches `dev` at `bdf`, an empty hotplug slot of the root bus, while
+    /// the machine is running, and notifies the guest of its arrival.
+    fn pci_hotplug(&self, bdf: Bdf, dev: Arc<dyn Endpoint>) -> IoResult<()>;
+
+    /// Requests that the guest release the device at `bdf`, a hotplug slot of
+    /// the root bus.  The device is detached once the guest ejects it, or the
+    /// machine is reset.
+    fn pci_unplug(&self, bdf: Bdf) -> IoResult<()>;
+
+    /// Registers `func` to be invoked once the device at `bdf`, a hotplug slot
+    /// of the root bus, has been ejected by the guest or removed as the
+    /// machine is reset.
+    fn pci_on_eject(
+        &self,
+        bdf: Bdf,
+        func: Box<hotplug::EjectFn>,
+    ) -> IoResult<()>;
+
+    /// Presses the ACPI power button, requesting that the guest shut down.
+    ///
+    /// If `reboot` is set, the guest entering the soft-off state in response
+    /// will reset the instance, rather than halting it.
+    fn press_power_button(&self, reboot: bool);
+
+    /// Withdraws a power button press to which the guest has not responded.
+    fn cancel_power_button(&self);
+}
+
+/// The chipsets which a machine may be built around.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ChipsetKind {
+    /// i440FX host bridge with PIIX3/PIIX4 southbridge, conventional PCI only
+    I440Fx,
+    /// Q35 (MCH) host bridge with ICH9 southbridge, supporting PCI Express
+    Q35,
+}
+impl Default for ChipsetKind {
+    fn default() -> Self {
+        ChipsetKind::I440Fx
+    }
+}
+impl FromStr for ChipsetKind {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "i440fx" => Ok(ChipsetKind::I440Fx),
+            "q35" => Ok(ChipsetKind::Q35),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown chipset: {}", s),
+            )),
+        }
+    }
+}
+impl Display for ChipsetKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ChipsetKind::I440Fx => write!(f, "i440fx"),
+            ChipsetKind::Q35 => write!(f, "q35"),
+        }
+    }
+}
+
+/// Returns the index of the PCI link (LNKA-LNKD) to which an INTx pin of
+/// a device on the root bus is routed.
+pub fn lnk_route(dev: u8, intx_pin: INTxPinID) -> u8 {
+    // D->A->B->C starting at 0:0.0
+    (dev + intx_pin as u8 + 2) % 4
+}
+
+struct LNKPin {
+    inner: Mutex<LNKPinInner>,
+}
+struct LNKPinInner {
+    asserted: bool,
+    pin: Option<LegacyPin>,
+}
+impl LNKPin {
+    fn new() -> Self {
+        Self { inner: Mutex::new(LNKPinInner { asserted: false, pin: None }) }
+    }
+    fn reassign(&self, new_pin: Option<LegacyPin>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(old_pin) = inner.pin.as_ref() {
+            if inner.asserted {
+                old_pin.deassert()
+            }
+        }
+
+        if let Some(pin) = new_pin.as_ref() {
+            if inner.asserted {
+                pin.assert()
+            }
+        }
+        inner.pin = new_pin;
+    }
+}
+impl IntrPin for LNKPin {
+    fn assert(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.asserted = true;
+        if let Some(pin) = inner.pin.as_ref() {
+            pin.assert();
+        }
+    }
+    fn deassert(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.asserted = false;
+        if let Some(pin) = inner.pin.as_ref() {
+            pin.deassert();
+        }
+    }
+    fn pulse(&self) {
+        let inner = self.inner.lock().unwrap();
+        if let Some(pin) = inner.pin.as_ref() {
+            pin.pulse();
+        }
+    }
+    fn is_asserted(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.asserted
+    }
+}
+
+/// Offset of the PIRQ route control registers in the LPC config space
+pub const PIR_OFFSET: usize = 0x60;
+pub const PIR_LEN: usize = 4;
+const PIR_END: usize = PIR_OFFSET + PIR_LEN;
+
+const PIR_MASK_DISABLE: u8 = 0x80;
+const PIR_MASK_IRQ: u8 = 0x0f;
+
+pub const SCI_IRQ: u8 = 0x9;
+
+/// IRQs to which PCI links may be routed: 3-7, 9-12, 14-15
+pub const PIR_VALID_IRQS: u16 = 0b1101_1110_1111_1000;
+
+fn valid_pir_irq(irq: u8) -> bool {
+    irq < 16 && PIR_VALID_IRQS & (1 << irq) != 0
 }
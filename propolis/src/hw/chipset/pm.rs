@@ -0,0 +1,328 @@
+//! ACPI power management I/O registers, as found in the PIIX4 and its
+//! descendants.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::instance;
+use crate::intr_pins::IntrPin;
+use crate::pio::PioDev;
+use crate::util::regmap::RegMap;
+use crate::vmm::MachineCtx;
+
+use lazy_static::lazy_static;
+
+pub const PMBASE_DEFAULT: u16 = 0xb000;
+const PMBASE_LEN: u16 = 0x40;
+
+// Offsets of the ACPI fixed hardware register blocks within PMBASE
+pub const PM1_EVT_OFFSET: u16 = 0x0;
+pub const PM1_CNT_OFFSET: u16 = 0x4;
+pub const GPE0_OFFSET: u16 = 0xc;
+pub const GPE0_LEN: u8 = 4;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum PmReg {
+    PmSts,
+    PmEn,
+    PmCntrl,
+    PmTmr,
+    GpSts,
+    GpEn,
+    PCntrl,
+    PLvl2,
+    PLvl3,
+    GlbSts,
+    DevSts,
+    GlbEn,
+    GlbCtl,
+    DevCtl,
+    GpiReg,
+    GpoReg,
+    Reserved,
+}
+
+lazy_static! {
+    static ref PM_REGS: RegMap<PmReg> = {
+        let layout = [
+            (PmReg::PmSts, 2),
+            (PmReg::PmEn, 2),
+            (PmReg::PmCntrl, 2),
+            (PmReg::Reserved, 2),
+            (PmReg::PmTmr, 4),
+            (PmReg::GpSts, 2),
+            (PmReg::GpEn, 2),
+            (PmReg::PCntrl, 4),
+            (PmReg::PLvl2, 1),
+            (PmReg::PLvl3, 1),
+            (PmReg::Reserved, 2),
+            (PmReg::GlbSts, 2),
+            (PmReg::Reserved, 2),
+            (PmReg::DevSts, 4),
+            (PmReg::GlbEn, 2),
+            (PmReg::Reserved, 6),
+            (PmReg::GlbCtl, 4),
+            (PmReg::DevCtl, 4),
+            (PmReg::GpiReg, 4),
+            (PmReg::GpoReg, 4),
+            (PmReg::Reserved, 8),
+        ];
+        RegMap::create_packed(
+            PMBASE_LEN as usize,
+            &layout,
+            Some(PmReg::Reserved),
+        )
+    };
+}
+bitflags! {
+    #[derive(Default)]
+    struct PmSts: u16 {
+        const PWRBTN_STS = 1 << 8;
+    }
+}
+bitflags! {
+    #[derive(Default)]
+    struct PmEn: u16 {
+        const PWRBTN_EN = 1 << 8;
+    }
+}
+bitflags! {
+    /// General purpose events, as reported in the GPE0 block
+    #[derive(Default)]
+    pub struct GpEvt: u16 {
+        /// PCI hotplug, handled by `\_GPE._E01`
+        const PCI_HOTPLUG = 1 << 1;
+    }
+}
+bitflags! {
+    #[derive(Default)]
+    struct PmCntrl: u16 {
+        const SCI_EN = 1;
+        const SUS_TYP = 0b111 << 10;
+        const SUS_EN = 1 << 13;
+
+    }
+}
+
+// Offset within PMBASE region corresponding to PmTmr register
+pub const PM_TMR_OFFSET: u16 = 0x8;
+
+struct PMRegs {
+    pm_base: u16,
+    pm_status: PmSts,
+    pm_ena: PmEn,
+    pm_ctrl: PmCntrl,
+    gp_status: GpEvt,
+    gp_ena: GpEvt,
+    /// Reset, rather than halt, when the guest enters soft-off
+    reboot_on_off: bool,
+}
+impl Default for PMRegs {
+    fn default() -> Self {
+        Self {
+            pm_base: PMBASE_DEFAULT,
+            pm_status: PmSts::empty(),
+            pm_ena: PmEn::empty(),
+            // With no SMI command port for the OS to request a transition,
+            // the platform is always in ACPI mode.
+            pm_ctrl: PmCntrl::SCI_EN,
+            gp_status: GpEvt::empty(),
+            gp_ena: GpEvt::empty(),
+            reboot_on_off: false,
+        }
+    }
+}
+impl PMRegs {
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Is an enabled event pending, such that the SCI should be asserted?
+    fn sci_pending(&self) -> bool {
+        let pending = PmEn::from_bits_truncate(self.pm_status.bits());
+        self.pm_ctrl.contains(PmCntrl::SCI_EN)
+            && (self.pm_ena.intersects(pending)
+                || self.gp_ena.intersects(self.gp_status))
+    }
+}
+
+/// The PM I/O register block, decoded at [`PMBASE_DEFAULT`].
+pub struct AcpiPm {
+    regs: Mutex<PMRegs>,
+    sci_pin: Arc<dyn IntrPin>,
+}
+impl AcpiPm {
+    pub fn create(sci_pin: Arc<dyn IntrPin>) -> Arc<Self> {
+        Arc::new(Self { regs: Mutex::new(PMRegs::default()), sci_pin })
+    }
+
+    pub fn attach(self: &Arc<Self>, mctx: &MachineCtx) {
+        // XXX: static registration for now
+        mctx.pio()
+            .register(
+                PMBASE_DEFAULT,
+                PMBASE_LEN,
+                Arc::downgrade(self) as Weak<dyn PioDev>,
+                0,
+            )
+            .unwrap();
+        mctx.hdl().pmtmr_locate(PMBASE_DEFAULT + PM_TMR_OFFSET).unwrap();
+    }
+
+    /// Current base of the PM I/O registers
+    pub fn pm_base(&self) -> u16 {
+        self.regs.lock().unwrap().pm_base
+    }
+
+    /// Latches a power button press, notifying the guest via SCI if it has
+    /// enabled such events.
+    pub fn press_power_button(&self, reboot: bool) {
+        let mut regs = self.regs.lock().unwrap();
+        regs.pm_status.insert(PmSts::PWRBTN_STS);
+        regs.reboot_on_off = reboot;
+        self.update_sci(&regs);
+    }
+
+    /// Withdraws an unacknowledged power button press, so that the guest
+    /// later entering soft-off of its own accord halts the instance.
+    pub fn cancel_power_button(&self) {
+        let mut regs = self.regs.lock().unwrap();
+        regs.pm_status.remove(PmSts::PWRBTN_STS);
+        regs.reboot_on_off = false;
+        self.update_sci(&regs);
+    }
+
+    /// Latches a general purpose event, notifying the guest via SCI if it has
+    /// enabled such events.
+    pub fn raise_gpe(&self, evt: GpEvt) {
+        let mut regs = self.regs.lock().unwrap();
+        regs.gp_status.insert(evt);
+        self.update_sci(&regs);
+    }
+
+    fn update_sci(&self, regs: &PMRegs) {
+        if regs.sci_pending() {
+            self.sci_pin.assert();
+        } else {
+            self.sci_pin.deassert();
+        }
+    }
+
+    fn pmreg_read(&self, id: &PmReg, ro: &mut ReadOp) {
+        let regs = self.regs.lock().unwrap();
+        match id {
+            PmReg::PmSts => {
+                ro.write_u16(regs.pm_status.bits());
+            }
+            PmReg::PmEn => {
+                ro.write_u16(regs.pm_ena.bits());
+            }
+            PmReg::PmCntrl => {
+                ro.write_u16(regs.pm_ctrl.bits());
+            }
+            PmReg::GpSts => {
+                ro.write_u16(regs.gp_status.bits());
+            }
+            PmReg::GpEn => {
+                ro.write_u16(regs.gp_ena.bits());
+            }
+
+            PmReg::PmTmr
+            | PmReg::PCntrl
+            | PmReg::PLvl2
+            | PmReg::PLvl3
+            | PmReg::GlbSts
+            | PmReg::DevSts
+            | PmReg::GlbEn
+            | PmReg::GlbCtl
+            | PmReg::DevCtl
+            | PmReg::GpiReg
+            | PmReg::GpoReg => {
+                // TODO: flesh out the rest of PM emulation
+                println!("unhandled PM read {:x}", ro.offset());
+                ro.fill(0);
+            }
+            PmReg::Reserved => {
+                ro.fill(0);
+            }
+        }
+    }
+    fn pmreg_write(&self, id: &PmReg, wo: &mut WriteOp, ctx: &DispCtx) {
+        let mut regs = self.regs.lock().unwrap();
+        match id {
+            PmReg::PmSts => {
+                let val = PmSts::from_bits_truncate(wo.read_u16());
+                // status bits are W1C
+                regs.pm_status.remove(val);
+                self.update_sci(&regs);
+            }
+            PmReg::PmEn => {
+                regs.pm_ena = PmEn::from_bits_truncate(wo.read_u16());
+                self.update_sci(&regs);
+            }
+            PmReg::PmCntrl => {
+                regs.pm_ctrl = PmCntrl::from_bits_truncate(wo.read_u16());
+                if regs.pm_ctrl.contains(PmCntrl::SUS_EN) {
+                    // SUS_EN is write-only and should always read 0
+                    regs.pm_ctrl.remove(PmCntrl::SUS_EN);
+
+                    let suspend_type = (regs.pm_ctrl & PmCntrl::SUS_TYP).bits();
+                    if suspend_type == 0 {
+                        // 0b000 corresponds to soft-off
+                        let kind = if regs.reboot_on_off {
+                            instance::SuspendKind::Reset
+                        } else {
+                            instance::SuspendKind::Halt
+                        };
+                        ctx.trigger_suspend(
+                            kind,
+                            instance::SuspendSource::Device("ACPI PmCntrl"),
+                        );
+                    }
+                }
+                self.update_sci(&regs);
+            }
+            PmReg::GpSts => {
+                let val = GpEvt::from_bits_truncate(wo.read_u16());
+                // status bits are W1C
+                regs.gp_status.remove(val);
+                self.update_sci(&regs);
+            }
+            PmReg::GpEn => {
+                regs.gp_ena = GpEvt::from_bits_truncate(wo.read_u16());
+                self.update_sci(&regs);
+            }
+            PmReg::PmTmr
+            | PmReg::PCntrl
+            | PmReg::PLvl2
+            | PmReg::PLvl3
+            | PmReg::GlbSts
+            | PmReg::DevSts
+            | PmReg::GlbEn
+            | PmReg::GlbCtl
+            | PmReg::DevCtl
+            | PmReg::GpiReg
+            | PmReg::GpoReg => {
+                println!("unhandled PM write {:x}", wo.offset());
+            }
+            PmReg::Reserved => {}
+        }
+    }
+    pub fn reset(&self, ctx: &DispCtx) {
+        let mut regs = self.regs.lock().unwrap();
+        regs.reset();
+        self.update_sci(&regs);
+        // Make sure PM timer is attached to the right IO port
+        // TODO: error handling?
+        ctx.mctx.hdl().pmtmr_locate(PMBASE_DEFAULT + PM_TMR_OFFSET).unwrap();
+    }
+}
+impl PioDev for AcpiPm {
+    fn pio_rw(&self, _port: u16, _ident: usize, mut rwo: RWOp, ctx: &DispCtx) {
+        PM_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.pmreg_read(id, ro),
+            RWOp::Write(wo) => self.pmreg_write(id, wo, ctx),
+        });
+    }
+}
@@ -0,0 +1,466 @@
+//! Q35 host bridge (MCH) with an ICH9 southbridge.
+//!
+//! Unlike the i440fx, config space is also accessible through the MMCONFIG
+//! (ECAM) window, giving devices the full 4K of extended config space, and
+//! PCI Express root ports are provided for devices to be attached behind.
+
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use super::hotplug::{self, PciHotplug};
+use super::pm::AcpiPm;
+use super::{
+    lnk_route, valid_pir_irq, Chipset, LNKPin, PIR_END, PIR_LEN,
+    PIR_MASK_DISABLE, PIR_MASK_IRQ, PIR_OFFSET, SCI_IRQ,
+};
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::hw::ibmpc;
+use crate::hw::pci::{
+    self, Bdf, Bridge, INTxPinID, LintrRouter, PioCfgDecoder,
+};
+use crate::instance;
+use crate::intr_pins::{IntrPin, LegacyPIC, LegacyPin};
+use crate::mmio::MmioDev;
+use crate::pio::{PioBus, PioDev};
+use crate::util::self_arc::*;
+use crate::vmm::{MachineCtx, VmmHdl};
+
+const HB_DEV: u8 = 0;
+const HB_FUNC: u8 = 0;
+pub const LPC_DEV: u8 = 0x1f;
+pub const LPC_FUNC: u8 = 0;
+
+/// Slot on the root bus occupied by the first PCIe root port
+const ROOT_PORT_DEV: u8 = 0x18;
+/// Number of PCIe root ports.  Root port `n` (counting from 1) provides the
+/// logical bus `n`, behind which a single device may be attached at `n.0.x`.
+pub const ROOT_PORTS: u8 = 4;
+/// Device ID of the first ICH9 root port, with subsequent ports at increments
+/// of 2.
+const ROOT_PORT_DEVICE_ID: u16 = 0x2940;
+
+/// Root bus slots which support hotplug: all but those of the chipset itself
+pub const HOTPLUG_SLOTS: u32 =
+    !(1 << HB_DEV | ((1 << ROOT_PORTS) - 1) << ROOT_PORT_DEV | 1 << LPC_DEV);
+
+/// Location of the ECAM window, matching the `pcicfg` region of the machine
+pub const ECAM_BASE: usize = 0xe000_0000;
+/// Size of the ECAM window, covering all 256 buses
+pub const ECAM_LEN: usize = 0x1000_0000;
+
+pub struct Q35 {
+    pic: Arc<LegacyPIC>,
+    pci_bus: Mutex<pci::Bus>,
+    pci_cfg: PioCfgDecoder,
+    root_ports: Vec<Arc<Bridge>>,
+
+    lnk_pins: [Arc<LNKPin>; 4],
+    pm: Arc<AcpiPm>,
+    hotplug: PciHotplug,
+
+    sa_cell: SelfArcCell<Self>,
+}
+impl Q35 {
+    pub fn create(hdl: Arc<VmmHdl>) -> Arc<Self> {
+        let pic = LegacyPIC::new(hdl);
+
+        let sci_pin = Arc::new(LNKPin::new());
+        sci_pin.reassign(pic.pin_handle(SCI_IRQ));
+        let pm = AcpiPm::create(sci_pin as Arc<dyn IntrPin>);
+
+        let root_ports = (0..ROOT_PORTS)
+            .map(|i| {
+                Bridge::new_root_port(
+                    0x8086,
+                    ROOT_PORT_DEVICE_ID + 2 * i as u16,
+                    i + 1,
+                    i + 1,
+                )
+            })
+            .collect();
+
+        let mut this = Arc::new(Self {
+            pic,
+            pci_bus: Mutex::new(pci::Bus::new()),
+            pci_cfg: PioCfgDecoder::new(),
+            root_ports,
+
+            lnk_pins: [
+                Arc::new(LNKPin::new()),
+                Arc::new(LNKPin::new()),
+                Arc::new(LNKPin::new()),
+                Arc::new(LNKPin::new()),
+            ],
+            hotplug: PciHotplug::new(HOTPLUG_SLOTS, Arc::clone(&pm)),
+            pm,
+
+            sa_cell: SelfArcCell::new(),
+        });
+        SelfArc::self_arc_init(&mut this);
+
+        let hbdev = MchHostBridge::create();
+        let lpcdev =
+            Ich9Lpc::create(Arc::downgrade(&this), Arc::clone(&this.pm));
+
+        this.pci_attach(Bdf::new(0, HB_DEV, HB_FUNC), hbdev).unwrap();
+        this.pci_attach(Bdf::new(0, LPC_DEV, LPC_FUNC), lpcdev).unwrap();
+        for (i, port) in this.root_ports.iter().enumerate() {
+            let bdf = Bdf::new(0, ROOT_PORT_DEV + i as u8, 0);
+            this.pci_attach(bdf, Arc::clone(port) as Arc<dyn pci::Endpoint>)
+                .unwrap();
+        }
+
+        this
+    }
+
+    pub fn attach(&self, mctx: &MachineCtx) {
+        let bus = self.pci_bus.lock().unwrap();
+
+        let lpc = bus.device_at(LPC_DEV, LPC_FUNC).unwrap();
+        lpc.as_devinst().unwrap().with_inner(|lpc: Arc<Ich9Lpc>| {
+            lpc.attach(mctx.pio());
+        });
+
+        self.pm.attach(mctx);
+
+        let hp_pio = self.self_weak() as Weak<dyn PioDev>;
+        mctx.pio()
+            .register(hotplug::PCIHP_BASE, hotplug::PCIHP_LEN, hp_pio, 0)
+            .unwrap();
+    }
+
+    fn set_lnk_route(&self, idx: usize, irq: Option<u8>) {
+        assert!(idx <= 3);
+        self.lnk_pins[idx].reassign(irq.and_then(|i| self.pic.pin_handle(i)));
+    }
+
+    /// Routing of the INTx pins of the device (or bridge) in a root bus slot.
+    fn lintr_router(&self, dev: u8) -> LintrRouter {
+        let lnk_pins = self.lnk_pins.clone();
+        Arc::new(move |pin: INTxPinID| {
+            let route = lnk_route(dev, pin);
+            Arc::clone(&lnk_pins[route as usize]) as Arc<dyn IntrPin>
+        })
+    }
+
+    fn cfg_rw(&self, bdf: &Bdf, rwo: RWOp, ctx: &DispCtx) -> Option<()> {
+        let bus = self.pci_bus.lock().unwrap();
+        let dev = bus.find(0, bdf)?;
+        drop(bus);
+
+        dev.cfg_rw(rwo, ctx);
+        Some(())
+    }
+}
+impl Chipset for Q35 {
+    fn pci_attach(
+        &self,
+        bdf: Bdf,
+        dev: Arc<dyn pci::Endpoint>,
+    ) -> IoResult<()> {
+        if bdf.bus() != 0 {
+            let bus = self.pci_bus.lock().unwrap();
+            let bridge = bus.find_bridge(bdf.bus()).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("no bridge provides bus {}", bdf.bus()),
+                )
+            })?;
+            drop(bus);
+            return bridge.as_bridge().unwrap().attach_device(
+                bdf.dev(),
+                bdf.func(),
+                dev,
+            );
+        }
+
+        let router = self.lintr_router(bdf.dev());
+        if let Some(bridge) = dev.as_bridge() {
+            bridge.set_router(Arc::clone(&router));
+        }
+        dev.attach(&|| {
+            let pin = INTxPinID::for_func(bdf.func());
+            (pin, router(pin))
+        });
+        let mut bus = self.pci_bus.lock().unwrap();
+        bus.attach(bdf.dev(), bdf.func(), dev);
+        Ok(())
+    }
+    fn pci_finalize(&self, mctx: &MachineCtx) {
+        let cfg_pio = self.self_weak() as Weak<dyn PioDev>;
+        let pio = mctx.pio();
+        let cfg_pio2 = Weak::clone(&cfg_pio);
+        pio.register(pci::PORT_PCI_CONFIG_ADDR, 4, cfg_pio, 0).unwrap();
+        pio.register(pci::PORT_PCI_CONFIG_DATA, 4, cfg_pio2, 0).unwrap();
+
+        let ecam = self.self_weak() as Weak<dyn MmioDev>;
+        mctx.mmio().register(ECAM_BASE, ECAM_LEN, ecam, 0).unwrap();
+    }
+    fn irq_pin(&self, irq: u8) -> Option<LegacyPin> {
+        self.pic.pin_handle(irq)
+    }
+    fn pci_hotplug_check(&self, bdf: Bdf) -> IoResult<()> {
+        self.hotplug.check_plug(&self.pci_bus.lock().unwrap(), &bdf)
+    }
+    fn pci_hotplug(
+        &self,
+        bdf: Bdf,
+        dev: Arc<dyn pci::Endpoint>,
+    ) -> IoResult<()> {
+        self.pci_hotplug_check(bdf)?;
+        self.pci_attach(bdf, dev)?;
+        self.hotplug.plugged(&bdf);
+        Ok(())
+    }
+    fn pci_unplug(&self, bdf: Bdf) -> IoResult<()> {
+        let bus = self.pci_bus.lock().unwrap();
+        self.hotplug.request_unplug(&bus, &bdf)
+    }
+    fn pci_on_eject(
+        &self,
+        bdf: Bdf,
+        func: Box<hotplug::EjectFn>,
+    ) -> IoResult<()> {
+        self.hotplug.on_eject(&bdf, func)
+    }
+    fn press_power_button(&self, reboot: bool) {
+        self.pm.press_power_button(reboot);
+    }
+    fn cancel_power_button(&self) {
+        self.pm.cancel_power_button();
+    }
+}
+impl PioDev for Q35 {
+    fn pio_rw(&self, port: u16, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
+        match port {
+            pci::PORT_PCI_CONFIG_ADDR => {
+                self.pci_cfg.service_addr(rwo);
+            }
+            pci::PORT_PCI_CONFIG_DATA => {
+                self.pci_cfg
+                    .service_data(rwo, |bdf, rwo| self.cfg_rw(bdf, rwo, ctx));
+            }
+            hotplug::PCIHP_BASE => {
+                self.hotplug.pio_rw(rwo, &self.pci_bus, ctx);
+            }
+            _ => {
+                panic!();
+            }
+        }
+    }
+}
+impl MmioDev for Q35 {
+    fn mmio_rw(&self, _addr: usize, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
+        let (bdf, off) = pci::ecam_decode(rwo.offset());
+        match rwo {
+            RWOp::Read(ro) => {
+                let mut cro = ReadOp::new_child(off, ro, ..);
+                if self.cfg_rw(&bdf, RWOp::Read(&mut cro), ctx).is_none() {
+                    cro.fill(0xff);
+                }
+            }
+            RWOp::Write(wo) => {
+                let mut cwo = WriteOp::new_child(off, wo, ..);
+                let _ = self.cfg_rw(&bdf, RWOp::Write(&mut cwo), ctx);
+            }
+        }
+    }
+}
+impl SelfArc for Q35 {
+    fn self_arc_cell(&self) -> &SelfArcCell<Self> {
+        &self.sa_cell
+    }
+}
+impl Entity for Q35 {
+    fn state_transition(
+        &self,
+        next: instance::State,
+        target: Option<instance::State>,
+        ctx: &DispCtx,
+    ) {
+        for port in self.root_ports.iter() {
+            port.state_transition(next, target, ctx);
+        }
+        if matches!(next, instance::State::Reset) {
+            self.pm.reset(ctx);
+            self.hotplug.reset(&self.pci_bus, ctx);
+        }
+    }
+}
+
+/// Offset of the PCIEXBAR register in the MCH config space
+const PCIEXBAR_OFFSET: u8 = 0x60;
+const PCIEXBAR_LEN: u8 = 8;
+const PCIEXBAR_EN: u64 = 1;
+
+struct MchHostBridge {}
+impl MchHostBridge {
+    pub fn create() -> Arc<pci::DeviceInst> {
+        pci::Builder::new(pci::Ident {
+            vendor_id: 0x8086,
+            device_id: 0x29c0,
+            class: pci::bits::CLASS_BRIDGE,
+            subclass: pci::bits::SUBCLASS_BRIDGE_HOST,
+            ..Default::default()
+        })
+        .add_custom_cfg(PCIEXBAR_OFFSET, PCIEXBAR_LEN)
+        .finish(Arc::new(Self {}))
+    }
+}
+impl pci::Device for MchHostBridge {
+    fn cfg_rw(&self, region: u8, rwo: RWOp) {
+        assert_eq!(region, PCIEXBAR_OFFSET);
+
+        match rwo {
+            RWOp::Read(ro) => {
+                // The ECAM window is fixed in place, covering 256 buses (an
+                // encoded length of 0).
+                let val = (ECAM_BASE as u64 | PCIEXBAR_EN).to_le_bytes();
+                let off = ro.offset();
+                ro.write_bytes(&val[off..(off + ro.len())]);
+            }
+            RWOp::Write(_) => {
+                // XXX: relocating the ECAM window is not supported
+            }
+        }
+    }
+}
+impl Entity for MchHostBridge {}
+
+/// Offset of the PMBASE and ACPI_CNTL registers in the LPC config space
+const ACPI_CFG_OFFSET: u8 = 0x40;
+const ACPI_CFG_LEN: u8 = 8;
+const ACPI_CNTL_OFFSET: usize = 0x4;
+/// ACPI_EN, with the SCI routed to IRQ 9
+const ACPI_CNTL_EN: u8 = 0x80;
+
+pub struct Ich9Lpc {
+    reg_pir: Mutex<[u8; PIR_LEN]>,
+    post_code: AtomicU8,
+    chipset: Weak<Q35>,
+    pm: Arc<AcpiPm>,
+}
+impl Ich9Lpc {
+    pub fn create(chipset: Weak<Q35>, pm: Arc<AcpiPm>) -> Arc<pci::DeviceInst> {
+        let this = Arc::new(Self {
+            reg_pir: Mutex::new([0u8; PIR_LEN]),
+            post_code: AtomicU8::new(0),
+            chipset,
+            pm,
+        });
+
+        pci::Builder::new(pci::Ident {
+            vendor_id: 0x8086,
+            device_id: 0x2918,
+            class: pci::bits::CLASS_BRIDGE,
+            subclass: pci::bits::SUBCLASS_BRIDGE_ISA,
+            ..Default::default()
+        })
+        .add_custom_cfg(ACPI_CFG_OFFSET, ACPI_CFG_LEN)
+        .add_custom_cfg(PIR_OFFSET as u8, PIR_LEN as u8)
+        .finish(this)
+    }
+
+    fn attach(self: &Arc<Self>, pio: &PioBus) {
+        pio.register(
+            ibmpc::PORT_FAST_A20,
+            ibmpc::LEN_FAST_A20,
+            Arc::downgrade(self) as Weak<dyn PioDev>,
+            0,
+        )
+        .unwrap();
+        pio.register(
+            ibmpc::PORT_POST_CODE,
+            ibmpc::LEN_POST_CODE,
+            Arc::downgrade(self) as Weak<dyn PioDev>,
+            0,
+        )
+        .unwrap();
+    }
+
+    fn write_pir(&self, idx: usize, val: u8) {
+        assert!(idx < PIR_LEN);
+
+        let mut regs = self.reg_pir.lock().unwrap();
+        if regs[idx] != val {
+            let disabled = (val & PIR_MASK_DISABLE) != 0;
+            let irq = val & PIR_MASK_IRQ;
+
+            let chipset = Weak::upgrade(&self.chipset).unwrap();
+            if !disabled && valid_pir_irq(irq) {
+                chipset.set_lnk_route(idx, Some(irq));
+            } else {
+                chipset.set_lnk_route(idx, None);
+            }
+            regs[idx] = val;
+        }
+    }
+
+    fn acpi_cfg_rw(&self, rwo: RWOp) {
+        match rwo {
+            RWOp::Read(ro) => {
+                let mut regs = [0u8; ACPI_CFG_LEN as usize];
+                // LSB hardwired to 1 to indicate PMBASE in IO space
+                let pm_base = self.pm.pm_base() as u32 | 0x1;
+                regs[..4].copy_from_slice(&pm_base.to_le_bytes());
+                regs[ACPI_CNTL_OFFSET] = ACPI_CNTL_EN;
+
+                let off = ro.offset();
+                ro.write_bytes(&regs[off..(off + ro.len())]);
+            }
+            RWOp::Write(_) => {
+                // XXX: PMBASE is fixed and ACPI always enabled
+            }
+        }
+    }
+}
+impl pci::Device for Ich9Lpc {
+    fn cfg_rw(&self, region: u8, rwo: RWOp) {
+        if region == ACPI_CFG_OFFSET {
+            return self.acpi_cfg_rw(rwo);
+        }
+        assert_eq!(region as usize, PIR_OFFSET);
+        assert!(rwo.offset() + rwo.len() <= PIR_END - PIR_OFFSET);
+
+        match rwo {
+            RWOp::Read(ro) => {
+                let off = ro.offset();
+                let reg = self.reg_pir.lock().unwrap();
+                ro.write_bytes(&reg[off..(off + ro.len())]);
+            }
+            RWOp::Write(wo) => {
+                let off = wo.offset();
+                for i in 0..wo.len() {
+                    self.write_pir(off + i, wo.read_u8());
+                }
+            }
+        }
+    }
+}
+impl Entity for Ich9Lpc {}
+impl PioDev for Ich9Lpc {
+    fn pio_rw(&self, port: u16, _ident: usize, rwo: RWOp, _ctx: &DispCtx) {
+        match port {
+            ibmpc::PORT_FAST_A20 => match rwo {
+                RWOp::Read(ro) => {
+                    // A20 is always enabled
+                    ro.write_u8(0x02);
+                }
+                RWOp::Write(wo) => {
+                    let _ = wo.read_u8();
+                }
+            },
+            ibmpc::PORT_POST_CODE => match rwo {
+                RWOp::Read(ro) => {
+                    ro.write_u8(self.post_code.load(Ordering::SeqCst));
+                }
+                RWOp::Write(wo) => {
+                    self.post_code.store(wo.read_u8(), Ordering::SeqCst);
+                }
+            },
+            _ => {}
+        }
+    }
+}
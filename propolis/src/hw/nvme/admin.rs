@@ -2,7 +2,7 @@ use std::cmp::min;
 use std::mem::size_of;
 
 use super::bits::{self, *};
-use crate::common::GuestAddr;
+use crate::common::{GuestAddr, GuestRegion, PAGE_OFFSET};
 use crate::{common::PAGE_SIZE, dispatch::DispCtx};
 
 use super::{cmds, NvmeCtrl, NvmeError, MAX_NUM_IO_QUEUES};
@@ -97,14 +97,19 @@ impl NvmeCtrl {
         cmd: &cmds::GetLogPageCmd,
         ctx: &DispCtx,
     ) -> cmds::Completion {
-        assert!((cmd.len as usize) < PAGE_SIZE);
-        let buf = cmd
-            .data(ctx.mctx.memctx())
-            .next()
-            .expect("missing prp entry for log page response");
+        if (cmd.len as usize) >= PAGE_SIZE {
+            return cmds::Completion::generic_err(STS_INVAL_FIELD);
+        }
+        let buf = match cmd.data(ctx.mctx.memctx()).next() {
+            Some(buf) => buf,
+            None => return cmds::Completion::generic_err(STS_DATA_XFER_ERR),
+        };
         // TODO: actually keep a log that we can write back instead of all zeros
-        assert!(ctx.mctx.memctx().write_byte(buf.0, 0, cmd.len as usize));
-        cmds::Completion::success()
+        if ctx.mctx.memctx().write_byte(buf.0, 0, cmd.len as usize) {
+            cmds::Completion::success()
+        } else {
+            cmds::Completion::generic_err(STS_DATA_XFER_ERR)
+        }
     }
 
     /// Service Identify command.
@@ -118,16 +123,12 @@ impl NvmeCtrl {
         match cmd.cns {
             IDENT_CNS_NAMESPACE => match cmd.nsid {
                 n if n > 0 && n <= super::ns::MAX_NUM_NAMESPACES as u32 => {
-                    assert!(size_of::<bits::IdentifyNamespace>() <= PAGE_SIZE);
-                    let buf = cmd
-                        .data(ctx.mctx.memctx())
-                        .next()
-                        .expect("missing prp entry for ident response");
-                    if let Ok(ns) = self.get_ns(n) {
-                        assert!(ctx.mctx.memctx().write(buf.0, &ns.ident));
-                        cmds::Completion::success()
-                    } else {
-                        cmds::Completion::generic_err(STS_INVALID_NS)
+                    debug_assert!(
+                        size_of::<bits::IdentifyNamespace>() <= PAGE_SIZE
+                    );
+                    match self.get_ns(n) {
+                        Ok(ns) => Self::write_ident(cmd, &ns.ident, ctx),
+                        Err(_) => cmds::Completion::generic_err(STS_INVALID_NS),
                     }
                 }
                 // 0 is not a valid NSID (See NVMe 1.0e, Section 6.1 Namespaces)
@@ -138,13 +139,10 @@ impl NvmeCtrl {
                 _ => cmds::Completion::generic_err(STS_INVALID_NS),
             },
             IDENT_CNS_CONTROLLER => {
-                assert!(size_of::<bits::IdentifyController>() <= PAGE_SIZE);
-                let buf = cmd
-                    .data(ctx.mctx.memctx())
-                    .next()
-                    .expect("missing prp entry for ident response");
-                assert!(ctx.mctx.memctx().write(buf.0, &self.ident));
-                cmds::Completion::success()
+                debug_assert!(
+                    size_of::<bits::IdentifyController>() <= PAGE_SIZE
+                );
+                Self::write_ident(cmd, &self.ident, ctx)
             }
             // We currently present NVMe version 1.0 in which CNS is a 1-bit field
             // and hence only need to support the NAMESPACE and CONTROLLER cases
@@ -152,13 +150,30 @@ impl NvmeCtrl {
         }
     }
 
+    /// Writes the given Identify data structure out to the host buffer.
+    fn write_ident<T: Copy>(
+        cmd: &cmds::IdentifyCmd,
+        ident: &T,
+        ctx: &DispCtx,
+    ) -> cmds::Completion {
+        let buf = match cmd.data(ctx.mctx.memctx()).next() {
+            Some(buf) => buf,
+            None => return cmds::Completion::generic_err(STS_DATA_XFER_ERR),
+        };
+        if ctx.mctx.memctx().write(buf.0, ident) {
+            cmds::Completion::success()
+        } else {
+            cmds::Completion::generic_err(STS_DATA_XFER_ERR)
+        }
+    }
+
     /// Service Set Features command.
     ///
     /// See NVMe 1.0e Section 5.12 Set Features command
     pub(super) fn acmd_set_features(
         &self,
         cmd: &cmds::SetFeaturesCmd,
-        _ctx: &DispCtx,
+        ctx: &DispCtx,
     ) -> cmds::Completion {
         match cmd.fid {
             cmds::FeatureIdent::NumberOfQueues { ncqr, nsqr } => {
@@ -174,6 +189,17 @@ impl NvmeCtrl {
                 // `ncqa`/`nsqa` are 0-based values so subtract 1
                 cmds::Completion::success_val((ncqa - 1) << 16 | (nsqa - 1))
             }
+            cmds::FeatureIdent::InterruptCoalescing { thr, time } => {
+                self.intr_coalescing.set_params(thr, time, ctx);
+                cmds::Completion::success()
+            }
+            cmds::FeatureIdent::InterruptVectorConfiguration { iv, cd } => {
+                if iv >= super::NVME_MSIX_COUNT {
+                    return cmds::Completion::generic_err(STS_INVAL_FIELD);
+                }
+                self.intr_coalescing.set_disabled(iv, cd);
+                cmds::Completion::success()
+            }
             cmds::FeatureIdent::Reserved
             | cmds::FeatureIdent::Arbitration
             | cmds::FeatureIdent::PowerManagement
@@ -181,8 +207,6 @@ impl NvmeCtrl {
             | cmds::FeatureIdent::TemperatureThreshold
             | cmds::FeatureIdent::ErrorRecovery
             | cmds::FeatureIdent::VolatileWriteCache
-            | cmds::FeatureIdent::InterruptCoalescing
-            | cmds::FeatureIdent::InterruptVectorConfiguration
             | cmds::FeatureIdent::WriteAtomicity
             | cmds::FeatureIdent::AsynchronousEventConfiguration
             | cmds::FeatureIdent::SoftwareProgressMarker
@@ -191,4 +215,91 @@ impl NvmeCtrl {
             }
         }
     }
+
+    /// Service Get Features command.
+    ///
+    /// See NVMe 1.0e Section 5.9 Get Features command
+    pub(super) fn acmd_get_features(
+        &self,
+        cmd: &cmds::GetFeaturesCmd,
+        _ctx: &DispCtx,
+    ) -> cmds::Completion {
+        match cmd.fid {
+            cmds::FeatureIdent::InterruptCoalescing { .. } => {
+                let (thr, time) = self.intr_coalescing.params();
+                cmds::Completion::success_val((time as u32) << 8 | thr as u32)
+            }
+            cmds::FeatureIdent::InterruptVectorConfiguration { iv, .. } => {
+                if iv >= super::NVME_MSIX_COUNT {
+                    return cmds::Completion::generic_err(STS_INVAL_FIELD);
+                }
+                let cd = self.intr_coalescing.is_disabled(iv);
+                cmds::Completion::success_val((cd as u32) << 16 | iv as u32)
+            }
+            cmds::FeatureIdent::Reserved
+            | cmds::FeatureIdent::Arbitration
+            | cmds::FeatureIdent::PowerManagement
+            | cmds::FeatureIdent::LbaRangeType
+            | cmds::FeatureIdent::TemperatureThreshold
+            | cmds::FeatureIdent::ErrorRecovery
+            | cmds::FeatureIdent::VolatileWriteCache
+            | cmds::FeatureIdent::NumberOfQueues { .. }
+            | cmds::FeatureIdent::WriteAtomicity
+            | cmds::FeatureIdent::AsynchronousEventConfiguration
+            | cmds::FeatureIdent::SoftwareProgressMarker
+            | cmds::FeatureIdent::Vendor(_) => {
+                cmds::Completion::generic_err(STS_INVAL_FIELD)
+            }
+        }
+    }
+
+    /// Service Doorbell Buffer Config command.
+    ///
+    /// Both buffers must be page-aligned and are each a single page, large
+    /// enough to cover the doorbells of every queue given CAP.DSTRD = 0.
+    /// Any I/O queues which already exist begin using the Shadow Doorbells
+    /// immediately.
+    ///
+    /// See NVMe 1.3 Section 5.8 Doorbell Buffer Config command
+    pub(super) fn acmd_doorbell_buf_cfg(
+        &mut self,
+        cmd: &cmds::DoorbellBufCfgCmd,
+        ctx: &DispCtx,
+    ) -> cmds::Completion {
+        let memctx = ctx.mctx.memctx();
+        let valid = |addr: u64| {
+            addr != 0
+                && (addr & PAGE_OFFSET as u64) == 0
+                && memctx
+                    .writable_region(&GuestRegion(GuestAddr(addr), PAGE_SIZE))
+                    .is_some()
+        };
+        if !valid(cmd.shadow_db) || !valid(cmd.event_idx) {
+            return cmds::Completion::generic_err(STS_INVAL_FIELD);
+        }
+        self.dbbuf = Some((GuestAddr(cmd.shadow_db), GuestAddr(cmd.event_idx)));
+
+        for (qid, (sq, cq)) in self.sqs.iter().zip(self.cqs.iter()).enumerate()
+        {
+            let qid = qid as u16;
+            if let Some(sq) = sq {
+                let shadow = self.shadow_doorbell(qid, false);
+                if let Some(shadow) = shadow {
+                    if sq.lock().unwrap().set_shadow(shadow, ctx).is_err() {
+                        return cmds::Completion::generic_err(STS_INTERNAL_ERR);
+                    }
+                }
+            }
+            if let Some(cq) = cq {
+                let shadow = self.shadow_doorbell(qid, true);
+                if let Some(shadow) = shadow {
+                    if cq.lock().unwrap().set_shadow(shadow, ctx).is_err() {
+                        return cmds::Completion::generic_err(STS_INTERNAL_ERR);
+                    }
+                }
+            }
+        }
+
+        cmds::Completion::success()
+    }
 }
@@ -95,6 +95,19 @@ impl RawSubmission {
     pub fn opcode(&self) -> u8 {
         self.cdw0 as u8
     }
+
+    /// Returns the Fused Operation (FUSE) field of this Submission Queue Entry.
+    pub fn fuse(&self) -> u8 {
+        ((self.cdw0 >> 8) & 0b11) as u8
+    }
+
+    /// Returns the PRP or SGL for Data Transfer (PSDT) field of this
+    /// Submission Queue Entry.
+    ///
+    /// See NVMe 1.1 Section 4.2, Figure 7 Command Dword 0
+    pub fn psdt(&self) -> u8 {
+        ((self.cdw0 >> 14) & 0b11) as u8
+    }
 }
 
 /// A Completion Queue Entry as represented in memory.
@@ -153,6 +166,62 @@ pub struct RawCompletion {
     pub status_phase: u16,
 }
 
+/// A Scatter Gather List (SGL) Descriptor as represented in memory.
+///
+/// See NVMe 1.1 Section 4.4 Scatter Gather List (SGL)
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct RawSglDescriptor {
+    /// Address (bytes 07:00)
+    ///
+    /// The starting address of the data block or SGL segment described
+    /// by this descriptor. Unused for Bit Bucket descriptors.
+    pub addr: u64,
+
+    /// Length (bytes 11:08)
+    ///
+    /// The length in bytes of the data block, SGL segment or the amount
+    /// of data to discard.
+    pub len: u32,
+
+    /// Reserved - Bytes 14:12
+    pub rsvd: [u8; 3],
+
+    /// SGL Identifier (byte 15)
+    ///
+    /// Bits
+    /// 07:04 - SGL Descriptor Type
+    /// 03:00 - SGL Descriptor Sub Type
+    ///
+    /// See NVMe 1.1 Section 4.4, Figure 20 SGL Identifier
+    pub ident: u8,
+}
+
+impl RawSglDescriptor {
+    /// Construct the descriptor held in the Data Pointer (DPTR) of a command.
+    ///
+    /// When PSDT selects SGLs, the 16 bytes normally occupied by PRP1 and
+    /// PRP2 instead contain the first SGL descriptor.
+    pub fn from_dptr(prp1: u64, prp2: u64) -> Self {
+        Self {
+            addr: prp1,
+            len: prp2 as u32,
+            rsvd: [(prp2 >> 32) as u8, (prp2 >> 40) as u8, (prp2 >> 48) as u8],
+            ident: (prp2 >> 56) as u8,
+        }
+    }
+
+    /// Returns the SGL Descriptor Type
+    pub fn desc_type(&self) -> u8 {
+        self.ident >> 4
+    }
+
+    /// Returns the SGL Descriptor Sub Type
+    pub fn desc_subtype(&self) -> u8 {
+        self.ident & 0xF
+    }
+}
+
 // Register bits
 
 bitstruct! {
@@ -198,8 +267,13 @@ bitstruct! {
         /// Size between each completion/submission queue doorbell. Specified as 2^(2 + DSTRD) bytes.
         pub dstrd: u8 = 32..36;
 
-        /// Reserved
-        reserved2: u8 = 36;
+        /// NVM Subsystem Reset Supported (NSSRS)
+        ///
+        /// Whether or not the controller supports the NVM Subsystem Reset
+        /// feature via the NSSR register.
+        ///
+        /// See NVMe 1.1 Section 3.1.1
+        pub nssrs: bool = 36;
 
         /// Command Sets Supported (CSS)
         ///
@@ -227,7 +301,18 @@ bitstruct! {
         pub mpsmax: u8 = 52..56;
 
         /// Reserved
-        reserved4: u8 = 56..64;
+        reserved4: bool = 56;
+
+        /// Controller Memory Buffer Supported (CMBS)
+        ///
+        /// Whether or not the controller supports the Controller Memory Buffer
+        /// Memory Space Control (CMBMSC) register.
+        ///
+        /// See NVMe 1.4 Section 3.1.1
+        pub cmbs: bool = 57;
+
+        /// Reserved
+        reserved5: u8 = 58..64;
     }
 }
 
@@ -402,8 +487,16 @@ bitstruct! {
         /// Indicates the current shutdown processing state.
         pub shst: ShutdownStatus = 2..4;
 
+        /// NVM Subsystem Reset Occurred (NSSRO)
+        ///
+        /// Controller sets this field to 1 when an NVM Subsystem Reset occurs.
+        /// The host clears it by writing 1.
+        ///
+        /// See NVMe 1.1 Section 3.1.6
+        pub nssro: bool = 4;
+
         /// Reserved
-        reserved: u32 = 4..32;
+        reserved: u32 = 5..32;
     }
 }
 
@@ -465,6 +558,97 @@ bitstruct! {
     }
 }
 
+bitstruct! {
+    /// Representation of the Controller Memory Buffer Location (CMBLOC) register.
+    ///
+    /// See NVMe 1.2 Section 3.1.11 Offset 38h: CMBLOC - Controller Memory Buffer Location
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct CmbLocation(pub u32) {
+        /// Base Indicator Register (BIR)
+        ///
+        /// The BAR which contains the Controller Memory Buffer.
+        pub bir: u8 = 0..3;
+
+        /// Reserved
+        reserved: u16 = 3..12;
+
+        /// Offset (OFST)
+        ///
+        /// The offset of the Controller Memory Buffer within the BAR,
+        /// in units of CMBSZ.SZU.
+        pub ofst: u32 = 12..32;
+    }
+}
+
+bitstruct! {
+    /// Representation of the Controller Memory Buffer Size (CMBSZ) register.
+    ///
+    /// See NVMe 1.2 Section 3.1.12 Offset 3Ch: CMBSZ - Controller Memory Buffer Size
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct CmbSize(pub u32) {
+        /// Submission Queue Support (SQS)
+        ///
+        /// Whether or not the Controller Memory Buffer may hold I/O
+        /// Submission Queues.
+        pub sqs: bool = 0;
+
+        /// Completion Queue Support (CQS)
+        pub cqs: bool = 1;
+
+        /// PRP SGL List Support (LISTS)
+        pub lists: bool = 2;
+
+        /// Read Data Support (RDS)
+        pub rds: bool = 3;
+
+        /// Write Data Support (WDS)
+        pub wds: bool = 4;
+
+        /// Reserved
+        reserved: u8 = 5..8;
+
+        /// Size Units (SZU)
+        ///
+        /// The granularity of the Size field, specified as 4KiB * 16^SZU.
+        pub szu: u8 = 8..12;
+
+        /// Size (SZ)
+        ///
+        /// The size of the Controller Memory Buffer in units of SZU.
+        pub sz: u32 = 12..32;
+    }
+}
+
+bitstruct! {
+    /// Representation of the Controller Memory Buffer Memory Space Control
+    /// (CMBMSC) register.
+    ///
+    /// See NVMe 1.4 Section 3.1.17 Offset 50h: CMBMSC - Controller Memory Buffer Memory Space Control
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct CmbMemSpaceCtrl(pub u64) {
+        /// Capabilities Registers Enabled (CRE)
+        ///
+        /// When set, the CMBLOC and CMBSZ registers report the Controller
+        /// Memory Buffer. Otherwise they are cleared to 0.
+        pub cre: bool = 0;
+
+        /// Controller Memory Space Enable (CMSE)
+        ///
+        /// When set, the controller accesses addresses within the Controller
+        /// Memory Buffer (as given by CBA) from the buffer itself.
+        pub cmse: bool = 1;
+
+        /// Reserved
+        reserved: u16 = 2..12;
+
+        /// Controller Base Address (CBA)
+        ///
+        /// Bits 63:12 of the address the host has placed the Controller
+        /// Memory Buffer at.
+        pub cba: u64 = 12..64;
+    }
+}
+
 // Version definitions
 
 /// Controller Version NVM Express 1.0
@@ -475,6 +659,62 @@ bitstruct! {
 /// See NVMe 1.0e Section 3.1.2 Offset 08h: VS - Version
 pub const NVME_VER_1_0: u32 = 0x00010000;
 
+/// The value written to the NSSR register to initiate an NVM Subsystem Reset,
+/// "NVMe" in ASCII.
+///
+/// See NVMe 1.1 Section 3.1.7 Offset 20h: NSSR - NVM Subsystem Reset
+pub const NVM_SUBSYS_RESET_SIG: u32 = 0x4E564D65;
+
+// Fused Operation (FUSE) values
+// See NVMe 1.0e Section 4.2, Figure 6 Command Dword 0
+
+/// Normal operation
+pub const FUSE_NORMAL: u8 = 0b00;
+/// First command of a fused operation
+pub const FUSE_FIRST: u8 = 0b01;
+/// Second command of a fused operation
+pub const FUSE_SECOND: u8 = 0b10;
+
+// PRP or SGL for Data Transfer (PSDT) values
+// See NVMe 1.1 Section 4.2, Figure 7 Command Dword 0
+
+/// PRPs are used for this transfer
+pub const PSDT_PRP: u8 = 0b00;
+/// SGLs are used for this transfer, MPTR contains the address of a
+/// contiguous metadata buffer
+pub const PSDT_SGL_MPTR_CONTIG: u8 = 0b01;
+/// SGLs are used for this transfer, MPTR contains the address of an
+/// SGL segment holding exactly one SGL descriptor
+pub const PSDT_SGL_MPTR_SGL: u8 = 0b10;
+
+// SGL Descriptor Types
+// See NVMe 1.1 Section 4.4, Figure 21 SGL Descriptor Type
+
+/// SGL Data Block descriptor
+pub const SGL_DESC_DATA_BLOCK: u8 = 0x0;
+/// SGL Bit Bucket descriptor
+pub const SGL_DESC_BIT_BUCKET: u8 = 0x1;
+/// SGL Segment descriptor
+pub const SGL_DESC_SEGMENT: u8 = 0x2;
+/// SGL Last Segment descriptor
+pub const SGL_DESC_LAST_SEGMENT: u8 = 0x3;
+
+/// SGL Descriptor Sub Type - Address
+///
+/// The Address field specifies the starting 64-bit memory byte address.
+pub const SGL_SUBTYPE_ADDRESS: u8 = 0x0;
+
+// SGL Support (SGLS) bits in the Identify Controller structure
+// See NVMe 1.2 Section 5.15, Figure 90 Identify - Identify Controller Data Structure
+
+/// The controller supports SGLs for the NVM Command Set
+pub const SGLS_SUPPORTED: u32 = 1 << 0;
+/// The controller supports the SGL Bit Bucket descriptor
+pub const SGLS_BIT_BUCKET: u32 = 1 << 16;
+/// The controller supports commands with a data SGL longer than the
+/// amount of data to be transferred
+pub const SGLS_EXCESS_LEN: u32 = 1 << 18;
+
 // Admin Command Opcodes
 // See NVMe 1.0e Section 5, Figure 25 Opcodes for Admin Commands
 
@@ -498,6 +738,10 @@ pub const ADMIN_OPC_SET_FEATURES: u8 = 0x09;
 pub const ADMIN_OPC_GET_FEATURES: u8 = 0x0A;
 /// Asynchronous Event Request Command Opcode
 pub const ADMIN_OPC_ASYNC_EVENT_REQ: u8 = 0x0c;
+/// Doorbell Buffer Config Command Opcode
+///
+/// See NVMe 1.3 Section 5.8 Doorbell Buffer Config command
+pub const ADMIN_OPC_DOORBELL_BUF_CFG: u8 = 0x7C;
 
 // NVM Command Opcodes
 // See NVMe 1.0e Section 6, Figure 99 Opcodes for NVM Commands
@@ -508,6 +752,14 @@ pub const NVM_OPC_FLUSH: u8 = 0x00;
 pub const NVM_OPC_WRITE: u8 = 0x01;
 /// Read Command Opcode
 pub const NVM_OPC_READ: u8 = 0x02;
+/// Write Uncorrectable Command Opcode
+pub const NVM_OPC_WRITE_UNC: u8 = 0x04;
+/// Compare Command Opcode
+pub const NVM_OPC_COMPARE: u8 = 0x05;
+/// Verify Command Opcode
+///
+/// See NVMe 1.4 Section 6.16 Verify command
+pub const NVM_OPC_VERIFY: u8 = 0x0C;
 
 // Generic Command Status values
 // See NVMe 1.0e Section 4.5.1.2.1, Figure 17 Status Code - Generic Command Status Values
@@ -577,6 +829,36 @@ pub const STS_INVALID_NS: u8 = 0xB;
 /// The command was aborted due to a protocol violation in a multi-command sequence.
 pub const STS_COMMAND_SEQ_ERR: u8 = 0xC;
 
+/// Invalid SGL Segment Descriptor
+///
+/// The command includes an invalid SGL Last Segment or SGL Segment descriptor.
+pub const STS_INVAL_SGL_SEG_DESC: u8 = 0xD;
+
+/// Invalid Number of SGL Descriptors
+///
+/// There is an SGL Last Segment descriptor or an SGL Segment descriptor in a
+/// location other than the last descriptor of a segment, or the number of
+/// SGL descriptors in a segment is otherwise invalid.
+pub const STS_INVAL_NUM_SGL_DESC: u8 = 0xE;
+
+/// Data SGL Length Invalid
+///
+/// The length of a data SGL is too short.
+pub const STS_DATA_SGL_LEN_INVAL: u8 = 0xF;
+
+/// SGL Descriptor Type Invalid
+///
+/// The type of an SGL descriptor is a type not supported by the controller.
+pub const STS_SGL_DESC_TYPE_INVAL: u8 = 0x11;
+
+// Generic Command Status values, NVM Command Set
+// See NVMe 1.0e Section 4.5.1.2.1, Figure 18 Status Code - Generic Command Status Values, NVM Command Set
+
+/// LBA Out of Range
+///
+/// The command references an LBA that exceeds the size of the namespace.
+pub const STS_LBA_OUT_OF_RANGE: u8 = 0x80;
+
 // Command Specific Status values
 // See NVMe 1.0e Section 4.5.1.2.2, Figure 19 Status Code - Command Specific Status Values
 
@@ -604,6 +886,39 @@ pub const STS_READ_INVALID_PROT_INFO: u8 = 0x81;
 /// Attempted to Write Read Only Range
 pub const STS_WRITE_READ_ONLY_RANGE: u8 = 0x82;
 
+// Media Error Status values
+// See NVMe 1.0e Section 4.5.1.2.3, Figure 21 Status Code - Media Error Values
+
+/// Unrecovered Read Error
+///
+/// The read data could not be recovered from the media.
+pub const STS_UNRECOVERED_READ_ERR: u8 = 0x81;
+
+/// Compare Failure
+///
+/// The command failed due to a miscompare during a Compare command.
+pub const STS_COMPARE_FAILURE: u8 = 0x85;
+
+/// Optional Admin Command Support (OACS) bit indicating the controller
+/// supports the Doorbell Buffer Config command.
+///
+/// See NVMe 1.3 Section 5.15, Figure 109 Identify - Identify Controller Data Structure
+pub const OACS_DOORBELL_BUF_CFG: u16 = 1 << 8;
+
+// Optional NVM Command Support (ONCS) bits in the Identify Controller structure
+// See NVMe 1.4 Section 5.15.2, Figure 247 Identify - Identify Controller Data Structure
+
+/// The controller supports the Compare command
+pub const ONCS_COMPARE: u16 = 1 << 0;
+/// The controller supports the Write Uncorrectable command
+pub const ONCS_WRITE_UNC: u16 = 1 << 1;
+/// The controller supports the Verify command
+pub const ONCS_VERIFY: u16 = 1 << 7;
+
+/// Fused Operation Support (FUSES) bit indicating the controller supports
+/// the Compare and Write fused operation.
+pub const FUSES_COMPARE_WRITE: u16 = 1 << 0;
+
 // Feature identifiers
 // See NVMe 1.0e Section 5.12.1, Figure 73 Set Features - Feature Identifiers
 
@@ -932,10 +1247,22 @@ pub struct IdentifyController {
     /// See NVMe 1.0e Section 4.2, Figure 8 Command Format - Admin and NVM Vendor Specific Commands (Optional)
     /// See NVMe 1.0e Section 8.7 Standard Vendor Specific Command Format
     pub nvscc: u8,
-    /// Reserved - Bytes 703:531
-    pub _resv4: [u8; 173],
+    /// Reserved - Bytes 535:531
+    pub _resv4: [u8; 5],
+    /// SGL Support (SGLS)
+    ///
+    /// Bits 31:19 are reserved.
+    /// Bit 18 indicates support for a data SGL longer than the transfer length.
+    /// Bit 17 indicates support for byte aligned contiguous metadata buffers.
+    /// Bit 16 indicates SGL Bit Bucket descriptor support.
+    /// Bits 15:1 are reserved.
+    /// Bit 0 indicates SGL support for the NVM Command Set.
+    /// See NVMe 1.1 Section 5.11, Figure 90 Identify - Identify Controller Data Structure
+    pub sgls: u32,
+    /// Reserved - Bytes 703:540
+    pub _resv5: [u8; 164],
     /// Reserved (I/O Command Set Attributes) - Bytes 2047:704
-    pub _resv5: [u8; 1344],
+    pub _resv6: [u8; 1344],
 
     // bytes 2048-3071 - Power State Descriptors
     /// Power State Descriptors (PSD0-PSD31)
@@ -978,14 +1305,16 @@ impl Default for IdentifyController {
             awun: 0,
             awupf: 0,
             nvscc: 0,
+            sgls: 0,
             psd: [PowerStateDescriptor::default(); 32],
             vs: [0; 1024],
 
             _resv1: [0; 178],
             _resv2: [0; 246],
             _resv3: [0; 2],
-            _resv4: [0; 173],
-            _resv5: [0; 1344],
+            _resv4: [0; 5],
+            _resv5: [0; 164],
+            _resv6: [0; 1344],
         }
     }
 }
@@ -1135,6 +1464,7 @@ mod test {
     fn entry_sizing() {
         assert_eq!(size_of::<RawSubmission>(), 64);
         assert_eq!(size_of::<RawCompletion>(), 16);
+        assert_eq!(size_of::<RawSglDescriptor>(), 16);
         assert_eq!(size_of::<PowerStateDescriptor>(), 32);
         assert_eq!(size_of::<IdentifyController>(), 4096);
         assert_eq!(size_of::<LbaFormat>(), 4);
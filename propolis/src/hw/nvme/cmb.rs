@@ -0,0 +1,61 @@
+use std::mem::size_of;
+use std::sync::Mutex;
+
+use crate::common::*;
+
+/// A Controller Memory Buffer (CMB) exposed through a PCI BAR.
+///
+/// The host may place I/O Submission Queues within the buffer, saving the
+/// controller from having to fetch commands out of guest memory.
+///
+/// See NVMe 1.2 Section 4.7 Controller Memory Buffer
+pub struct ControllerMemBuf {
+    /// The contents of the buffer
+    buf: Mutex<Box<[u8]>>,
+}
+
+impl ControllerMemBuf {
+    /// Creates a new zeroed Controller Memory Buffer of the given size.
+    pub fn new(size: usize) -> Self {
+        Self { buf: Mutex::new(vec![0u8; size].into_boxed_slice()) }
+    }
+
+    /// Returns the size of the buffer in bytes.
+    pub fn size(&self) -> usize {
+        self.buf.lock().unwrap().len()
+    }
+
+    /// Reads a value of type `T` at the given offset into the buffer.
+    ///
+    /// Returns [`None`] if the value would not lie entirely within the buffer.
+    pub fn read<T: Copy>(&self, off: usize) -> Option<T> {
+        let buf = self.buf.lock().unwrap();
+        let end = off.checked_add(size_of::<T>())?;
+        if end > buf.len() {
+            return None;
+        }
+        // Safety: the range was bounds checked above and `T: Copy` is only
+        // used with plain-old-data structures like `RawSubmission`.
+        Some(unsafe { std::ptr::read_unaligned(buf[off..].as_ptr().cast()) })
+    }
+
+    /// Services a host access to the BAR backing the buffer.
+    pub fn bar_rw(&self, rwo: RWOp) {
+        let mut buf = self.buf.lock().unwrap();
+        let off = rwo.offset();
+        let end = match off.checked_add(rwo.len()) {
+            Some(end) if end <= buf.len() => end,
+            _ => {
+                // Out of bounds, reads return 0s and writes are dropped
+                if let RWOp::Read(ro) = rwo {
+                    ro.fill(0);
+                }
+                return;
+            }
+        };
+        match rwo {
+            RWOp::Read(ro) => ro.write_bytes(&buf[off..end]),
+            RWOp::Write(wo) => wo.read_bytes(&mut buf[off..end]),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::bits::{self, RawSubmission, StatusCodeType};
+use super::bits::{self, RawSglDescriptor, RawSubmission, StatusCodeType};
 use super::queue::{QueueCreateErr, QueueId};
 use crate::common::*;
 use crate::vmm::MemCtx;
@@ -15,6 +15,10 @@ pub enum ParseErr {
     /// An invalid value was specified in the FUSE bits of `CDW0`.
     #[error("reserved FUSE value specified")]
     ReservedFuse,
+
+    /// An invalid value was specified in the PSDT bits of `CDW0`.
+    #[error("reserved PSDT value specified")]
+    ReservedPsdt,
 }
 
 /// A parsed Admin Command
@@ -37,9 +41,11 @@ pub enum AdminCmd {
     /// Set Features Command
     SetFeatures(SetFeaturesCmd),
     /// Get Features Command
-    GetFeatures,
+    GetFeatures(GetFeaturesCmd),
     /// Asynchronous Event Request Command
     AsyncEventReq,
+    /// Doorbell Buffer Config Command
+    DoorbellBufferConfig(DoorbellBufCfgCmd),
     /// An unknown admin command
     Unknown(RawSubmission),
 }
@@ -104,17 +110,49 @@ impl AdminCmd {
                     fid: FeatureIdent::from((raw.cdw10 as u8, raw.cdw11)),
                 })
             }
-            bits::ADMIN_OPC_GET_FEATURES => AdminCmd::GetFeatures,
+            bits::ADMIN_OPC_GET_FEATURES => {
+                AdminCmd::GetFeatures(GetFeaturesCmd {
+                    fid: FeatureIdent::from((raw.cdw10 as u8, raw.cdw11)),
+                })
+            }
             bits::ADMIN_OPC_ASYNC_EVENT_REQ => AdminCmd::AsyncEventReq,
+            bits::ADMIN_OPC_DOORBELL_BUF_CFG => {
+                AdminCmd::DoorbellBufferConfig(DoorbellBufCfgCmd {
+                    shadow_db: raw.prp1,
+                    event_idx: raw.prp2,
+                })
+            }
             _ => AdminCmd::Unknown(raw),
         };
-        let _fuse = match (raw.cdw0 >> 8) & 0b11 {
-            0b00 => Ok(()),               // Normal (non-fused) operation
-            0b01 => Err(ParseErr::Fused), // First fused op
-            0b10 => Err(ParseErr::Fused), // Second fused op
+        match FuseOp::parse(&raw)? {
+            FuseOp::Normal => Ok(cmd),
+            FuseOp::First | FuseOp::Second => Err(ParseErr::Fused),
+        }
+    }
+}
+
+/// Whether a command is part of a fused operation.
+///
+/// See NVMe 1.0e Section 6.1 Fused Operations
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FuseOp {
+    /// Normal (non-fused) operation
+    Normal,
+    /// First command of a fused operation
+    First,
+    /// Second command of a fused operation
+    Second,
+}
+
+impl FuseOp {
+    /// Pull the Fused Operation out of a raw Submission Entry.
+    pub fn parse(raw: &RawSubmission) -> Result<Self, ParseErr> {
+        match raw.fuse() {
+            bits::FUSE_NORMAL => Ok(FuseOp::Normal),
+            bits::FUSE_FIRST => Ok(FuseOp::First),
+            bits::FUSE_SECOND => Ok(FuseOp::Second),
             _ => Err(ParseErr::ReservedFuse),
-        }?;
-        Ok(cmd)
+        }
     }
 }
 
@@ -320,6 +358,36 @@ pub struct SetFeaturesCmd {
     pub fid: FeatureIdent,
 }
 
+/// Doorbell Buffer Config Command Parameters
+///
+/// See NVMe 1.3 Section 5.8 Doorbell Buffer Config command
+#[derive(Debug)]
+pub struct DoorbellBufCfgCmd {
+    /// Shadow Doorbell Buffer (PRP1)
+    ///
+    /// The page-aligned address of the buffer the host writes new doorbell
+    /// values to, laid out as the doorbell registers are.
+    pub shadow_db: u64,
+
+    /// EventIdx Buffer (PRP2)
+    ///
+    /// The page-aligned address of the buffer the controller writes the
+    /// EventIdx values to, laid out as the doorbell registers are.
+    pub event_idx: u64,
+}
+
+/// Get Features Command Parameters
+#[derive(Debug)]
+pub struct GetFeaturesCmd {
+    /// Feature Identifier (FID)
+    ///
+    /// The feature whose attributes are being requested. Any parameters
+    /// parsed out of CDW11 are only meaningful for features which use it to
+    /// further qualify the request (e.g. the vector for Interrupt Vector
+    /// Configuration).
+    pub fid: FeatureIdent,
+}
+
 /// Feature Identifiers
 ///
 /// See NVMe 1.0e Section 5.12.1, Figure 73 Set Features - Feature Identifiers
@@ -370,11 +438,33 @@ pub enum FeatureIdent {
     /// Interrupt Coalescing
     ///
     /// Allows configuring interrupt coalescing settings.
-    InterruptCoalescing,
+    /// See NVMe 1.0e Section 5.12.1.8 Interrupt Coalescing
+    InterruptCoalescing {
+        /// Aggregation Threshold (THR)
+        ///
+        /// The minimum number of completion queue entries to aggregate per
+        /// interrupt vector before signaling an interrupt. 0's based.
+        thr: u8,
+        /// Aggregation Time (TIME)
+        ///
+        /// The maximum time to delay an interrupt due to aggregation,
+        /// specified in 100 microsecond increments. 0 indicates no delay.
+        time: u8,
+    },
     /// Interrupt Vector Configuration
     ///
     /// Allows confuring settings specific to a particular interrupt vector.
-    InterruptVectorConfiguration,
+    /// See NVMe 1.0e Section 5.12.1.9 Interrupt Vector Configuration
+    InterruptVectorConfiguration {
+        /// Interrupt Vector (IV)
+        ///
+        /// The interrupt vector for which the configuration settings apply.
+        iv: u16,
+        /// Coalescing Disable (CD)
+        ///
+        /// Whether interrupt coalescing is disabled for the vector.
+        cd: bool,
+    },
     /// Write Atomicity
     ///
     /// Control write atomicity.
@@ -408,8 +498,14 @@ impl From<(u8, u32)> for FeatureIdent {
                 ncqr: (cdw11 >> 16) as u16 + 1,
                 nsqr: cdw11 as u16 + 1,
             },
-            8 => InterruptCoalescing,
-            9 => InterruptVectorConfiguration,
+            8 => InterruptCoalescing {
+                thr: cdw11 as u8,
+                time: (cdw11 >> 8) as u8,
+            },
+            9 => InterruptVectorConfiguration {
+                iv: cdw11 as u16,
+                cd: (cdw11 >> 16) & 0b1 != 0,
+            },
             0xA => WriteAtomicity,
             0xB => AsynchronousEventConfiguration,
             0xC..=0x7F => Reserved,
@@ -429,34 +525,53 @@ pub enum NvmCmd {
     Write(WriteCmd),
     /// Read data and metadata
     Read(ReadCmd),
+    /// Mark logical blocks as invalid
+    WriteUncorrectable(WriteUncorrectableCmd),
+    /// Compare data and metadata against the namespace
+    Compare(CompareCmd),
+    /// Verify integrity of stored data and metadata
+    Verify(VerifyCmd),
     /// An unknown NVM command
     Unknown(RawSubmission),
 }
 
 impl NvmCmd {
     /// Triy to parse an `NvmCmd` out of a raw Submission Entry.
+    ///
+    /// Whether the command is part of a fused operation is left for the
+    /// caller to determine with [`FuseOp::parse`].
     pub fn parse(raw: RawSubmission) -> Result<Self, ParseErr> {
-        let _fuse = match (raw.cdw0 >> 8) & 0b11 {
-            0b00 => Ok(()),               // Normal (non-fused) operation
-            0b01 => Err(ParseErr::Fused), // First fused op
-            0b10 => Err(ParseErr::Fused), // Second fused op
-            _ => Err(ParseErr::ReservedFuse),
-        }?;
         let cmd = match raw.opcode() {
             bits::NVM_OPC_FLUSH => NvmCmd::Flush,
             bits::NVM_OPC_WRITE => NvmCmd::Write(WriteCmd {
                 slba: (raw.cdw11 as u64) << 32 | raw.cdw10 as u64,
                 // Convert from 0's based value
                 nlb: raw.cdw12 as u16 + 1,
-                prp1: raw.prp1,
-                prp2: raw.prp2,
+                dptr: DataPtr::parse(&raw)?,
             }),
             bits::NVM_OPC_READ => NvmCmd::Read(ReadCmd {
                 slba: (raw.cdw11 as u64) << 32 | raw.cdw10 as u64,
                 // Convert from 0's based value
                 nlb: raw.cdw12 as u16 + 1,
-                prp1: raw.prp1,
-                prp2: raw.prp2,
+                dptr: DataPtr::parse(&raw)?,
+            }),
+            bits::NVM_OPC_WRITE_UNC => {
+                NvmCmd::WriteUncorrectable(WriteUncorrectableCmd {
+                    slba: (raw.cdw11 as u64) << 32 | raw.cdw10 as u64,
+                    // Convert from 0's based value
+                    nlb: raw.cdw12 as u16 + 1,
+                })
+            }
+            bits::NVM_OPC_COMPARE => NvmCmd::Compare(CompareCmd {
+                slba: (raw.cdw11 as u64) << 32 | raw.cdw10 as u64,
+                // Convert from 0's based value
+                nlb: raw.cdw12 as u16 + 1,
+                dptr: DataPtr::parse(&raw)?,
+            }),
+            bits::NVM_OPC_VERIFY => NvmCmd::Verify(VerifyCmd {
+                slba: (raw.cdw11 as u64) << 32 | raw.cdw10 as u64,
+                // Convert from 0's based value
+                nlb: raw.cdw12 as u16 + 1,
             }),
             _ => NvmCmd::Unknown(raw),
         };
@@ -477,22 +592,17 @@ pub struct WriteCmd {
     /// The number of logical blocks to be written.
     pub nlb: u16,
 
-    /// PRP Entry 1 (PRP1)
+    /// Data Pointer (DPTR)
     ///
-    /// The first PRP entry specifying the start of the data buffer to be transferred from.
-    prp1: u64,
-
-    /// PRP Entry 2 (PRP2)
-    ///
-    /// If PRP1 specifies enough space, then PRP2 is reserved. Otherwise
-    /// PRP2 may either be another PRP entry or a PRP list as necessary.
-    prp2: u64,
+    /// The PRP entries or SGL descriptor specifying the data buffer to be transferred from.
+    dptr: DataPtr,
 }
 
 impl WriteCmd {
-    /// Returns an Iterator that yields [`GuestRegion`]'s to read the data to tranfer out.
-    pub fn data<'a>(&'a self, sz: u64, mem: MemCtx<'a>) -> PrpIter<'a> {
-        PrpIter::new(sz, self.prp1, self.prp2, mem)
+    /// Returns an Iterator that yields [`DataRegion`]'s to read the data to tranfer out.
+    pub fn data<'a>(&'a self, sz: u64, mem: MemCtx<'a>) -> DataIter<'a> {
+        // Bit Bucket descriptors only make sense for data read from the controller
+        self.dptr.iter(sz, false, mem)
     }
 }
 
@@ -509,22 +619,148 @@ pub struct ReadCmd {
     /// The number of logical blocks to be read.
     pub nlb: u16,
 
-    /// PRP Entry 1 (PRP1)
+    /// Data Pointer (DPTR)
     ///
-    /// The first PRP entry specifying the start of the data buffer to be transferred to.
-    prp1: u64,
+    /// The PRP entries or SGL descriptor specifying the data buffer to be transferred to.
+    dptr: DataPtr,
+}
 
-    /// PRP Entry 2 (PRP2)
+impl ReadCmd {
+    /// Returns an Iterator that yields [`DataRegion`]'s to write the data to transfer in.
+    pub fn data<'a>(&'a self, sz: u64, mem: MemCtx<'a>) -> DataIter<'a> {
+        self.dptr.iter(sz, true, mem)
+    }
+}
+
+/// Write Uncorrectable Command Parameters
+#[derive(Debug)]
+pub struct WriteUncorrectableCmd {
+    /// Starting LBA (SLBA)
+    ///
+    /// 64-bit base address of the first logical block to be marked invalid.
+    pub slba: u64,
+
+    /// Number of Logical Blocks (NLB)
+    ///
+    /// The number of logical blocks to be marked invalid.
+    pub nlb: u16,
+}
+
+/// Compare Command Parameters
+#[derive(Debug)]
+pub struct CompareCmd {
+    /// Starting LBA (SLBA)
+    ///
+    /// 64-bit base address of the first logical block to be compared.
+    pub slba: u64,
+
+    /// Number of Logical Blocks (NLB)
+    ///
+    /// The number of logical blocks to be compared.
+    pub nlb: u16,
+
+    /// Data Pointer (DPTR)
+    ///
+    /// The PRP entries or SGL descriptor specifying the data buffer to be compared against.
+    dptr: DataPtr,
+}
+
+impl CompareCmd {
+    /// Returns an Iterator that yields [`DataRegion`]'s to read the data to compare against.
+    pub fn data<'a>(&'a self, sz: u64, mem: MemCtx<'a>) -> DataIter<'a> {
+        self.dptr.iter(sz, false, mem)
+    }
+}
+
+/// Verify Command Parameters
+#[derive(Debug)]
+pub struct VerifyCmd {
+    /// Starting LBA (SLBA)
+    ///
+    /// 64-bit base address of the first logical block to be verified.
+    pub slba: u64,
+
+    /// Number of Logical Blocks (NLB)
+    ///
+    /// The number of logical blocks to be verified.
+    pub nlb: u16,
+}
+
+/// The Data Pointer (DPTR) of an NVM command.
+///
+/// Depending on the PSDT field of the command, the data buffer is described
+/// either by a pair of PRP entries or by the first descriptor of an SGL.
+///
+/// See NVMe 1.1 Section 4.2, Figure 11 Command Format - NVM Command Set
+#[derive(Debug)]
+pub enum DataPtr {
+    /// PRP Entry 1 (PRP1) and PRP Entry 2 (PRP2)
     ///
     /// If PRP1 specifies enough space, then PRP2 is reserved. Otherwise
     /// PRP2 may either be another PRP entry or a PRP list as necessary.
-    prp2: u64,
+    Prp(u64, u64),
+    /// SGL Entry 1 (SGL1)
+    ///
+    /// The first SGL descriptor for the data transfer.
+    Sgl(RawSglDescriptor),
 }
 
-impl ReadCmd {
-    /// Returns an Iterator that yields [`GuestRegion`]'s to write the data to transfer in.
-    pub fn data<'a>(&'a self, sz: u64, mem: MemCtx<'a>) -> PrpIter<'a> {
-        PrpIter::new(sz, self.prp1, self.prp2, mem)
+impl DataPtr {
+    /// Pull the Data Pointer out of a raw Submission Entry.
+    fn parse(raw: &RawSubmission) -> Result<Self, ParseErr> {
+        match raw.psdt() {
+            bits::PSDT_PRP => Ok(DataPtr::Prp(raw.prp1, raw.prp2)),
+            // We don't support metadata so how MPTR is interpreted is moot
+            bits::PSDT_SGL_MPTR_CONTIG | bits::PSDT_SGL_MPTR_SGL => Ok(
+                DataPtr::Sgl(RawSglDescriptor::from_dptr(raw.prp1, raw.prp2)),
+            ),
+            _ => Err(ParseErr::ReservedPsdt),
+        }
+    }
+
+    /// Returns an Iterator over the regions making up a transfer of `size` bytes.
+    fn iter<'a>(
+        &self,
+        size: u64,
+        allow_bit_bucket: bool,
+        mem: MemCtx<'a>,
+    ) -> DataIter<'a> {
+        match self {
+            DataPtr::Prp(prp1, prp2) => {
+                DataIter::Prp(PrpIter::new(size, *prp1, *prp2, mem))
+            }
+            DataPtr::Sgl(desc) => {
+                DataIter::Sgl(SglIter::new(size, *desc, allow_bit_bucket, mem))
+            }
+        }
+    }
+}
+
+/// A single piece of a data transfer.
+#[derive(Copy, Clone, Debug)]
+pub enum DataRegion {
+    /// Guest memory to transfer data to or from.
+    Guest(GuestRegion),
+    /// A number of bytes read from the namespace which are to be discarded.
+    BitBucket(usize),
+}
+
+/// An Iterator over the [`DataRegion`]'s described by a [`DataPtr`].
+pub enum DataIter<'a> {
+    Prp(PrpIter<'a>),
+    Sgl(SglIter<'a>),
+}
+
+impl Iterator for DataIter<'_> {
+    type Item = Result<DataRegion, SglErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            DataIter::Prp(iter) => {
+                iter.next().map(|r| Ok(DataRegion::Guest(r)))
+            }
+            DataIter::Sgl(iter) => iter.next(),
+        }
     }
 }
 
@@ -713,6 +949,232 @@ impl Iterator for PrpIter<'_> {
     }
 }
 
+/// Errors that may be encountered while walking a Scatter Gather List.
+#[derive(Copy, Clone, Debug, Error, Eq, PartialEq)]
+pub enum SglErr {
+    /// A descriptor had a reserved or unsupported type or sub type.
+    #[error("unsupported SGL descriptor identifier {0:#x}")]
+    InvalidType(u8),
+
+    /// A Segment or Last Segment descriptor was malformed or misplaced.
+    #[error("invalid SGL segment descriptor")]
+    InvalidSegment,
+
+    /// An SGL segment contained an invalid number of descriptors.
+    #[error("invalid number of SGL descriptors")]
+    InvalidCount,
+
+    /// The SGL described less data than needed for the transfer.
+    #[error("data SGL too short")]
+    InvalidLength,
+
+    /// An SGL segment could not be read from guest memory.
+    #[error("unable to read SGL segment")]
+    Unreadable,
+}
+
+/// Indicates the possible states of an [`SglWalker`].
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum SglNext {
+    /// The descriptor contained in the command itself.
+    Cmd,
+    /// The next descriptor in an SGL segment in guest memory.
+    Segment {
+        /// Guest address of the next descriptor
+        addr: u64,
+        /// Number of descriptors left in this segment
+        left: u32,
+        /// Whether this segment was pointed to by a Last Segment descriptor
+        last: bool,
+    },
+    Done,
+}
+
+/// The maximum number of SGL descriptors we'll process for a single command.
+///
+/// Segments are free to chain from one to another (and possibly back again)
+/// so bound the amount of work a single command can cause.
+const SGL_MAX_DESCRIPTORS: usize = 1 << 16;
+
+/// The size of a single SGL descriptor in bytes.
+const SGL_DESC_SIZE: u32 = 16;
+
+/// State machine which walks an SGL, independent of how descriptors in SGL
+/// segments are retrieved.
+///
+/// See NVMe 1.1 Section 4.4 Scatter Gather List (SGL)
+struct SglWalker {
+    /// The descriptor held in the command
+    first: RawSglDescriptor,
+
+    /// How many bytes remaining to be read/written
+    remain: u64,
+
+    /// Whether Bit Bucket descriptors are acceptable for this transfer
+    allow_bit_bucket: bool,
+
+    /// Number of descriptors processed so far
+    count: usize,
+
+    /// The next SGL state
+    next: SglNext,
+}
+
+impl SglWalker {
+    fn new(size: u64, first: RawSglDescriptor, allow_bit_bucket: bool) -> Self {
+        Self {
+            first,
+            remain: size,
+            allow_bit_bucket,
+            count: 0,
+            next: SglNext::Cmd,
+        }
+    }
+
+    /// Grab the next region to read/write, using `fetch` to read any descriptors
+    /// held in SGL segments.
+    fn get_next<F>(&mut self, mut fetch: F) -> Result<DataRegion, SglErr>
+    where
+        F: FnMut(GuestAddr) -> Option<RawSglDescriptor>,
+    {
+        assert!(self.remain > 0);
+
+        loop {
+            // Alongside the descriptor, note whether it came from an SGL
+            // segment and if so: whether that was the last segment and
+            // whether it is the final descriptor within that segment.
+            let (desc, seg) = match self.next {
+                SglNext::Cmd => {
+                    self.next = SglNext::Done;
+                    (self.first, None)
+                }
+                SglNext::Segment { addr, left, last } => {
+                    let desc =
+                        fetch(GuestAddr(addr)).ok_or(SglErr::Unreadable)?;
+                    self.next = if left == 1 {
+                        SglNext::Done
+                    } else {
+                        SglNext::Segment {
+                            addr: addr + SGL_DESC_SIZE as u64,
+                            left: left - 1,
+                            last,
+                        }
+                    };
+                    (desc, Some((last, left == 1)))
+                }
+                SglNext::Done => {
+                    // We've exhausted the SGL without transferring everything
+                    return Err(SglErr::InvalidLength);
+                }
+            };
+
+            self.count += 1;
+            if self.count > SGL_MAX_DESCRIPTORS {
+                return Err(SglErr::InvalidCount);
+            }
+
+            if desc.desc_subtype() != bits::SGL_SUBTYPE_ADDRESS {
+                return Err(SglErr::InvalidType(desc.ident));
+            }
+
+            match desc.desc_type() {
+                bits::SGL_DESC_BIT_BUCKET if !self.allow_bit_bucket => {
+                    return Err(SglErr::InvalidType(desc.ident));
+                }
+                ty
+                @ (bits::SGL_DESC_DATA_BLOCK | bits::SGL_DESC_BIT_BUCKET) => {
+                    if desc.len == 0 {
+                        // Nothing to transfer, move on to the next descriptor
+                        continue;
+                    }
+
+                    let size = u64::min(desc.len as u64, self.remain);
+                    self.remain -= size;
+                    if self.remain == 0 {
+                        // Any descriptors past this point are ignored
+                        self.next = SglNext::Done;
+                    }
+
+                    return Ok(if ty == bits::SGL_DESC_DATA_BLOCK {
+                        DataRegion::Guest(GuestRegion(
+                            GuestAddr(desc.addr),
+                            size as usize,
+                        ))
+                    } else {
+                        DataRegion::BitBucket(size as usize)
+                    });
+                }
+                ty @ (bits::SGL_DESC_SEGMENT | bits::SGL_DESC_LAST_SEGMENT) => {
+                    match seg {
+                        // The last segment may only hold data descriptors
+                        Some((true, _)) => return Err(SglErr::InvalidSegment),
+                        // Segment descriptors must come last in a segment
+                        Some((false, false)) => {
+                            return Err(SglErr::InvalidCount)
+                        }
+                        _ => {}
+                    }
+                    if desc.len == 0 || desc.len % SGL_DESC_SIZE != 0 {
+                        return Err(SglErr::InvalidSegment);
+                    }
+                    self.next = SglNext::Segment {
+                        addr: desc.addr,
+                        left: desc.len / SGL_DESC_SIZE,
+                        last: ty == bits::SGL_DESC_LAST_SEGMENT,
+                    };
+                }
+                _ => return Err(SglErr::InvalidType(desc.ident)),
+            }
+        }
+    }
+}
+
+/// A helper object for iterating over the data regions described by an SGL.
+pub struct SglIter<'a> {
+    /// The SGL state machine
+    walker: SglWalker,
+
+    /// Handle to Guest's [`MemCtx`]
+    mem: MemCtx<'a>,
+
+    /// Whether we've already hit the end of the SGL or an error
+    done: bool,
+}
+
+impl<'a> SglIter<'a> {
+    /// Create a new `SglIter` object.
+    ///
+    /// See corresponding `data` methods on any relevant commands.
+    pub fn new(
+        size: u64,
+        first: RawSglDescriptor,
+        allow_bit_bucket: bool,
+        mem: MemCtx<'a>,
+    ) -> Self {
+        Self {
+            walker: SglWalker::new(size, first, allow_bit_bucket),
+            mem,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for SglIter<'_> {
+    type Item = Result<DataRegion, SglErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.walker.remain == 0 {
+            return None;
+        }
+        let mem = &self.mem;
+        let res = self.walker.get_next(|addr| mem.read(addr));
+        if res.is_err() {
+            self.done = true;
+        }
+        Some(res)
+    }
+}
+
 /// A Command Completion result
 #[derive(Debug)]
 pub struct Completion {
@@ -785,3 +1247,213 @@ impl From<QueueCreateErr> for Completion {
         }
     }
 }
+
+impl From<SglErr> for Completion {
+    fn from(e: SglErr) -> Self {
+        match e {
+            SglErr::InvalidType(_) => {
+                Completion::generic_err(bits::STS_SGL_DESC_TYPE_INVAL)
+            }
+            SglErr::InvalidSegment => {
+                Completion::generic_err(bits::STS_INVAL_SGL_SEG_DESC)
+            }
+            SglErr::InvalidCount => {
+                Completion::generic_err(bits::STS_INVAL_NUM_SGL_DESC)
+            }
+            SglErr::InvalidLength => {
+                Completion::generic_err(bits::STS_DATA_SGL_LEN_INVAL)
+            }
+            SglErr::Unreadable => {
+                Completion::generic_err(bits::STS_DATA_XFER_ERR)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn desc(ty: u8, addr: u64, len: u32) -> RawSglDescriptor {
+        RawSglDescriptor { addr, len, rsvd: [0; 3], ident: ty << 4 }
+    }
+
+    /// Walk an SGL to completion, pulling segment contents out of `segs`,
+    /// and return each region as `(is_bit_bucket, addr, len)`.
+    fn walk(
+        size: u64,
+        first: RawSglDescriptor,
+        allow_bit_bucket: bool,
+        segs: &HashMap<u64, RawSglDescriptor>,
+    ) -> Result<Vec<(bool, u64, usize)>, SglErr> {
+        let mut walker = SglWalker::new(size, first, allow_bit_bucket);
+        let mut res = Vec::new();
+        while walker.remain > 0 {
+            match walker.get_next(|addr| segs.get(&addr.0).copied())? {
+                DataRegion::Guest(GuestRegion(addr, len)) => {
+                    res.push((false, addr.0, len))
+                }
+                DataRegion::BitBucket(len) => res.push((true, 0, len)),
+            }
+        }
+        Ok(res)
+    }
+
+    /// Lay out the given descriptors contiguously as an SGL segment at `base`.
+    fn segment(
+        segs: &mut HashMap<u64, RawSglDescriptor>,
+        base: u64,
+        descs: &[RawSglDescriptor],
+    ) -> RawSglDescriptor {
+        for (i, d) in descs.iter().enumerate() {
+            segs.insert(base + i as u64 * 16, *d);
+        }
+        desc(bits::SGL_DESC_LAST_SEGMENT, base, descs.len() as u32 * 16)
+    }
+
+    #[test]
+    fn sgl_single_data_block() {
+        let segs = HashMap::new();
+        let first = desc(bits::SGL_DESC_DATA_BLOCK, 0x2000, 4096);
+        assert_eq!(
+            walk(4096, first, false, &segs),
+            Ok(vec![(false, 0x2000, 4096)])
+        );
+        // Excess SGL length is ignored
+        assert_eq!(
+            walk(512, first, false, &segs),
+            Ok(vec![(false, 0x2000, 512)])
+        );
+    }
+
+    #[test]
+    fn sgl_chained_segments() {
+        let mut segs = HashMap::new();
+        let last = segment(
+            &mut segs,
+            0x3000,
+            &[
+                desc(bits::SGL_DESC_BIT_BUCKET, 0, 512),
+                desc(bits::SGL_DESC_DATA_BLOCK, 0x5000, 512),
+            ],
+        );
+        let mut seg1 = segment(
+            &mut segs,
+            0x1000,
+            &[desc(bits::SGL_DESC_DATA_BLOCK, 0x4000, 1024), last],
+        );
+        seg1.ident = bits::SGL_DESC_SEGMENT << 4;
+
+        assert_eq!(
+            walk(2048, seg1, true, &segs),
+            Ok(vec![
+                (false, 0x4000, 1024),
+                (true, 0, 512),
+                (false, 0x5000, 512)
+            ])
+        );
+    }
+
+    #[test]
+    fn sgl_too_short() {
+        let mut segs = HashMap::new();
+        let first = desc(bits::SGL_DESC_DATA_BLOCK, 0x2000, 511);
+        assert_eq!(walk(512, first, false, &segs), Err(SglErr::InvalidLength));
+
+        let last = segment(
+            &mut segs,
+            0x1000,
+            &[desc(bits::SGL_DESC_DATA_BLOCK, 0x2000, 256)],
+        );
+        assert_eq!(walk(512, last, false, &segs), Err(SglErr::InvalidLength));
+    }
+
+    #[test]
+    fn sgl_invalid_type() {
+        let segs = HashMap::new();
+
+        // Keyed SGL Data Block descriptors are unsupported
+        let keyed = desc(0x4, 0x2000, 512);
+        assert_eq!(
+            walk(512, keyed, false, &segs),
+            Err(SglErr::InvalidType(0x40))
+        );
+
+        // As are non-Address sub types
+        let mut offset = desc(bits::SGL_DESC_DATA_BLOCK, 0x2000, 512);
+        offset.ident |= 0x1;
+        assert_eq!(
+            walk(512, offset, false, &segs),
+            Err(SglErr::InvalidType(0x01))
+        );
+
+        // Bit Buckets are only valid when reading
+        let bb = desc(bits::SGL_DESC_BIT_BUCKET, 0, 512);
+        assert_eq!(walk(512, bb, false, &segs), Err(SglErr::InvalidType(0x10)));
+        assert_eq!(walk(512, bb, true, &segs), Ok(vec![(true, 0, 512)]));
+    }
+
+    #[test]
+    fn sgl_invalid_segment() {
+        let mut segs = HashMap::new();
+
+        // Segment lengths must be a non-zero multiple of the descriptor size
+        let short = desc(bits::SGL_DESC_LAST_SEGMENT, 0x1000, 8);
+        assert_eq!(walk(512, short, false, &segs), Err(SglErr::InvalidSegment));
+        let empty = desc(bits::SGL_DESC_SEGMENT, 0x1000, 0);
+        assert_eq!(walk(512, empty, false, &segs), Err(SglErr::InvalidSegment));
+
+        // The last segment may not point to further segments
+        let nested = desc(bits::SGL_DESC_SEGMENT, 0x3000, 16);
+        let last = segment(&mut segs, 0x1000, &[nested]);
+        assert_eq!(walk(512, last, false, &segs), Err(SglErr::InvalidSegment));
+    }
+
+    #[test]
+    fn sgl_invalid_count() {
+        let mut segs = HashMap::new();
+
+        // Segment descriptors must be the final entry of a segment
+        let last = segment(
+            &mut segs,
+            0x1000,
+            &[
+                desc(bits::SGL_DESC_LAST_SEGMENT, 0x3000, 16),
+                desc(bits::SGL_DESC_DATA_BLOCK, 0x2000, 512),
+            ],
+        );
+        let mut seg = last;
+        seg.ident = bits::SGL_DESC_SEGMENT << 4;
+        assert_eq!(walk(512, seg, false, &segs), Err(SglErr::InvalidCount));
+
+        // A segment pointing back at itself shouldn't spin forever
+        segs.clear();
+        let looped = desc(bits::SGL_DESC_SEGMENT, 0x1000, 16);
+        segs.insert(0x1000, looped);
+        assert_eq!(walk(512, looped, false, &segs), Err(SglErr::InvalidCount));
+    }
+
+    #[test]
+    fn sgl_unreadable_segment() {
+        let segs = HashMap::new();
+        let last = desc(bits::SGL_DESC_LAST_SEGMENT, 0x1000, 32);
+        assert_eq!(walk(512, last, false, &segs), Err(SglErr::Unreadable));
+    }
+
+    #[test]
+    fn parse_intr_features() {
+        match FeatureIdent::from((8, 0x0000_1403)) {
+            FeatureIdent::InterruptCoalescing { thr, time } => {
+                assert_eq!((thr, time), (0x03, 0x14));
+            }
+            fid => panic!("unexpected feature {:?}", fid),
+        }
+        match FeatureIdent::from((9, 0x0001_0005)) {
+            FeatureIdent::InterruptVectorConfiguration { iv, cd } => {
+                assert_eq!((iv, cd), (5, true));
+            }
+            fid => panic!("unexpected feature {:?}", fid),
+        }
+    }
+}
@@ -1,12 +1,13 @@
 use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::mem::size_of;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
 
 use crate::common::*;
 use crate::dispatch::DispCtx;
 use crate::hw::pci;
 use crate::util::regmap::RegMap;
+use crate::util::self_arc::*;
 
 use lazy_static::lazy_static;
 use thiserror::Error;
@@ -15,13 +16,15 @@ pub use ns::{NvmeNs, Request};
 
 mod admin;
 mod bits;
+mod cmb;
 mod cmds;
 mod ns;
 mod queue;
 
 use bits::*;
+use cmb::ControllerMemBuf;
 use ns::MAX_NUM_NAMESPACES;
-use queue::{CompQueue, QueueId, SubQueue};
+use queue::{CompQueue, IntrCoalescing, QueueId, ShadowDoorbell, SubQueue};
 
 /// The max number of MSI-X interrupts we support
 const NVME_MSIX_COUNT: u16 = 1024;
@@ -64,10 +67,18 @@ pub enum NvmeError {
     /// The specified Namespace ID did not correspond to a valid Namespace
     #[error("the namespace specified ({0}) is invalid")]
     InvalidNamespace(u32),
+
+    /// The host wrote an invalid value to a doorbell register
+    #[error("invalid doorbell write: {0}")]
+    InvalidDoorbell(&'static str),
+
+    /// The Shadow Doorbell or EventIdx buffers could not be accessed
+    #[error("shadow doorbell access failed: {0}")]
+    ShadowDoorbell(&'static str),
 }
 
 /// Internal NVMe Controller State
-#[derive(Debug, Default)]
+#[derive(Clone, Copy, Debug, Default)]
 struct CtrlState {
     /// Controller Capabilities
     cap: Capabilities,
@@ -92,6 +103,9 @@ struct CtrlState {
     /// ACQB
     /// See NVMe 1.0e Section 3.1.9 Offset 30h: ACQ - Admin Completion Queue Base Address
     admin_cq_base: u64,
+
+    /// Controller Memory Buffer Memory Space Control
+    cmbmsc: CmbMemSpaceCtrl,
 }
 
 /// The max number of completion or submission queues we support.
@@ -121,6 +135,24 @@ struct NvmeCtrl {
 
     /// The Identify structure returned for Identify controller commands
     ident: IdentifyController,
+
+    /// Completion Queues torn down by a Controller Reset which may still be
+    /// referenced by requests outstanding to the underlying block devices
+    retired_cqs: Vec<Weak<Mutex<CompQueue>>>,
+
+    /// Tracks the namespace flushes issued in response to a shutdown
+    /// notification, which are done once no strong references remain
+    shutdown_flush: Option<Weak<()>>,
+
+    /// Interrupt Coalescing state shared with the I/O Completion Queues
+    intr_coalescing: Arc<IntrCoalescing>,
+
+    /// The Shadow Doorbell and EventIdx buffers configured by the host with
+    /// the Doorbell Buffer Config command, if any
+    dbbuf: Option<(GuestAddr, GuestAddr)>,
+
+    /// The Controller Memory Buffer exposed through BAR2, if any
+    cmb: Option<Arc<ControllerMemBuf>>,
 }
 
 impl NvmeCtrl {
@@ -170,8 +202,22 @@ impl NvmeCtrl {
             .as_ref()
             .ok_or(NvmeError::MsixHdlUnavailable)?
             .clone();
-        let cq = CompQueue::new(cqid, iv, size, base, ctx, msix_hdl)?;
-        self.cqs[cqid as usize] = Some(Arc::new(Mutex::new(cq)));
+        // Interrupt Coalescing only applies to I/O Completion Queues
+        let coalescing = if cqid == queue::ADMIN_QUEUE_ID {
+            None
+        } else {
+            Some(Arc::clone(&self.intr_coalescing))
+        };
+        let mut cq =
+            CompQueue::new(cqid, iv, size, base, ctx, msix_hdl, coalescing)?;
+        if let Some(shadow) = self.shadow_doorbell(cqid, true) {
+            cq.set_shadow(shadow, ctx).map_err(NvmeError::ShadowDoorbell)?;
+        }
+        let cq = Arc::new(Mutex::new(cq));
+        if cqid != queue::ADMIN_QUEUE_ID {
+            self.intr_coalescing.add_cq(&cq);
+        }
+        self.cqs[cqid as usize] = Some(cq);
         Ok(())
     }
 
@@ -198,11 +244,64 @@ impl NvmeCtrl {
         if self.sqs[sqid as usize].is_some() {
             return Err(NvmeError::SubQueueAlreadyExists(sqid));
         }
-        let sq = SubQueue::new(sqid, cqid, size, base, ctx)?;
+        let mut sq = match self.cmb_offset(base) {
+            Some(off) => {
+                let cmb = Arc::clone(self.cmb.as_ref().unwrap());
+                SubQueue::new_in_cmb(sqid, cqid, size, cmb, off)?
+            }
+            None => SubQueue::new(sqid, cqid, size, base, ctx)?,
+        };
+        if let Some(shadow) = self.shadow_doorbell(sqid, false) {
+            sq.set_shadow(shadow, ctx).map_err(NvmeError::ShadowDoorbell)?;
+        }
         self.sqs[sqid as usize] = Some(Arc::new(Mutex::new(sq)));
         Ok(())
     }
 
+    /// Returns the offset into the Controller Memory Buffer corresponding to
+    /// the given address, if the host has enabled the buffer and placed it
+    /// such that it covers the address.
+    ///
+    /// See NVMe 1.4 Section 8.7 Controller Memory Buffer
+    fn cmb_offset(&self, addr: GuestAddr) -> Option<usize> {
+        let cmb = self.cmb.as_ref()?;
+        let cmbmsc = self.ctrl.cmbmsc;
+        if !cmbmsc.cmse() {
+            return None;
+        }
+        let off = addr.0.checked_sub(cmbmsc.cba() << 12)?;
+        if off < cmb.size() as u64 {
+            Some(off as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the Shadow Doorbell for the given I/O queue, if the host has
+    /// configured the Doorbell Buffer.
+    ///
+    /// Both buffers are laid out as the doorbell registers are, with the
+    /// entry for Submission Queue y at (2y * 4) and Completion Queue y at
+    /// ((2y + 1) * 4), given CAP.DSTRD = 0.
+    ///
+    /// See NVMe 1.3 Section 5.8 Doorbell Buffer Config command
+    fn shadow_doorbell(
+        &self,
+        qid: QueueId,
+        is_cq: bool,
+    ) -> Option<ShadowDoorbell> {
+        if qid == queue::ADMIN_QUEUE_ID {
+            // Only the I/O queues make use of the Shadow Doorbells
+            return None;
+        }
+        let (db, ei) = self.dbbuf?;
+        let off = (2 * qid as u64 + is_cq as u64) * 4;
+        Some(ShadowDoorbell {
+            db: GuestAddr(db.0 + off),
+            ei: GuestAddr(ei.0 + off),
+        })
+    }
+
     /// Returns a reference to the [`CompQueue`] which corresponds to the given completion queue id (`cqid`).
     fn get_cq(
         &self,
@@ -256,12 +355,20 @@ impl NvmeCtrl {
 
     /// Returns a reference to the [`NvmeNs`] which corresponds to the given namespace id (`nsid`).
     fn get_ns(&self, nsid: u32) -> Result<&NvmeNs, NvmeError> {
-        debug_assert!((nsid as usize) <= MAX_NUM_NAMESPACES);
-        self.nss[nsid as usize - 1]
-            .as_ref()
+        // NSIDs are 1-based
+        nsid.checked_sub(1)
+            .and_then(|idx| self.nss.get(idx as usize))
+            .and_then(Option::as_ref)
             .ok_or(NvmeError::InvalidNamespace(nsid))
     }
 
+    /// Returns whether the controller is currently processing commands.
+    fn is_running(&self) -> bool {
+        self.ctrl.cc.enabled()
+            && self.ctrl.csts.ready()
+            && !self.ctrl.csts.cfs()
+    }
+
     /// Performs a Controller Reset.
     ///
     /// The reset deletes all I/O Submission & Completion Queues, resets
@@ -273,14 +380,88 @@ impl NvmeCtrl {
     /// The controller shall ensure that there is no data loss for commands
     /// that have had corresponding completion queue entries posted to an I/O
     /// Completion Queue prior to the reset operation.
+    ///
+    /// See NVMe 1.0e Section 7.3.1 Controller Level
     fn reset(&mut self) {
-        // TODO: handle any pending commands
-
         for sq in &mut self.sqs {
             *sq = None;
         }
+
+        // Any requests still outstanding to the block devices hold onto their
+        // Completion Queue, so keep track of them until those requests finish.
+        // Only then is the reset considered complete (see `refresh_status`).
+        self.retired_cqs.retain(|cq| cq.strong_count() > 0);
         for cq in &mut self.cqs {
-            *cq = None;
+            if let Some(cq) = cq.take() {
+                self.retired_cqs.push(Arc::downgrade(&cq));
+            }
+        }
+
+        self.ctrl.csts.set_cfs(false);
+        self.ctrl.csts.set_shst(ShutdownStatus::Normal);
+        self.shutdown_flush = None;
+        self.intr_coalescing.reset();
+        self.dbbuf = None;
+    }
+
+    /// Performs an NVM Subsystem Reset.
+    ///
+    /// In addition to a Controller Reset, all controller registers (including
+    /// the Admin Queue registers) are returned to their initial values and the
+    /// occurrence of the reset is noted in CSTS.NSSRO.
+    ///
+    /// See NVMe 1.1 Section 7.3.1 NVM Subsystem Reset
+    fn subsys_reset(&mut self) {
+        self.reset();
+
+        let cur = self.ctrl;
+        self.ctrl = CtrlState {
+            cap: cur.cap,
+            cc: Configuration(0)
+                .with_iosqes(cur.cc.iosqes())
+                .with_iocqes(cur.cc.iocqes()),
+            // CSTS.RDY remains set until the reset completes
+            csts: Status(0).with_ready(cur.csts.ready()).with_nssro(true),
+            ..Default::default()
+        };
+    }
+
+    /// Begins processing a Shutdown Notification from the host.
+    ///
+    /// All namespaces have their underlying block devices flushed, with
+    /// CSTS.SHST indicating the shutdown is complete once they are done.
+    /// As requests are processed in order, this covers any commands already
+    /// submitted for both normal and abrupt shutdowns.
+    ///
+    /// See NVMe 1.0e Section 7.6.2 Shutdown
+    fn shutdown(&mut self) {
+        let token = Arc::new(());
+        for ns in self.nss.iter().flatten() {
+            ns.flush_internal(Arc::clone(&token));
+        }
+        self.shutdown_flush = Some(Arc::downgrade(&token));
+        self.ctrl.csts.set_shst(ShutdownStatus::Processing);
+    }
+
+    /// Updates the Controller Status to reflect the progress of any pending
+    /// Controller Reset or shutdown.
+    fn refresh_status(&mut self) {
+        if !self.ctrl.cc.enabled() && self.ctrl.csts.ready() {
+            self.retired_cqs.retain(|cq| cq.strong_count() > 0);
+            if self.retired_cqs.is_empty() {
+                self.ctrl.csts.set_ready(false);
+            }
+        }
+
+        if self.ctrl.csts.shst() == ShutdownStatus::Processing {
+            let done = self
+                .shutdown_flush
+                .as_ref()
+                .map_or(true, |flush| flush.strong_count() == 0);
+            if done {
+                self.shutdown_flush = None;
+                self.ctrl.csts.set_shst(ShutdownStatus::Complete);
+            }
         }
     }
 }
@@ -289,11 +470,37 @@ impl NvmeCtrl {
 pub struct PciNvme {
     /// NVMe Controller
     state: Mutex<NvmeCtrl>,
+
+    /// The Controller Memory Buffer, accessed directly for BAR2 operations
+    cmb: Option<Arc<ControllerMemBuf>>,
+
+    sa_cell: SelfArcCell<Self>,
 }
 
 impl PciNvme {
     /// Create a new pci-nvme device with the given values
-    pub fn create(vendor: u16, device: u16) -> Arc<pci::DeviceInst> {
+    ///
+    /// If `cmb_size` is given, a Controller Memory Buffer of that many bytes
+    /// is exposed through BAR2 for the host to place I/O Submission Queues in.
+    /// The size must be a power of two no smaller than a page, or an error is
+    /// returned.
+    pub fn create(
+        vendor: u16,
+        device: u16,
+        cmb_size: Option<usize>,
+    ) -> std::io::Result<Arc<pci::DeviceInst>> {
+        if let Some(size) = cmb_size {
+            if !size.is_power_of_two() || size < PAGE_SIZE {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "CMB size {} is not a power of two of at least {}",
+                        size, PAGE_SIZE
+                    ),
+                ));
+            }
+        }
+
         let builder = pci::Builder::new(pci::Ident {
             vendor_id: vendor,
             device_id: device,
@@ -327,6 +534,15 @@ impl PciNvme {
             nn: 0,
             // bit 0 indicates volatile write cache is present
             vwc: 1,
+            // SGLs may be used for NVM command data transfers, including Bit
+            // Bucket descriptors and SGLs longer than the transfer itself
+            sgls: SGLS_SUPPORTED | SGLS_BIT_BUCKET | SGLS_EXCESS_LEN,
+            // Optional NVM commands supported beyond the mandatory set
+            oncs: ONCS_COMPARE | ONCS_WRITE_UNC | ONCS_VERIFY,
+            // Compare and Write may be issued as a fused operation
+            fuses: FUSES_COMPARE_WRITE,
+            // Optional Admin commands supported
+            oacs: OACS_DOORBELL_BUF_CFG,
             ..Default::default()
         };
 
@@ -343,7 +559,11 @@ impl PciNvme {
             // I/O Queues must be physically contiguous
             .with_cqr(true)
             // We support the NVM command set
-            .with_css_nvm(true);
+            .with_css_nvm(true)
+            // NVM Subsystem Reset may be initiated via the NSSR register
+            .with_nssrs(true)
+            // The Controller Memory Buffer is controlled through CMBMSC
+            .with_cmbs(cmb_size.is_some());
 
         // Initialize the CC "register" leaving most values
         // at their defaults (0):
@@ -365,6 +585,8 @@ impl PciNvme {
         //  SHST    = 0 => no shutdown in process, normal operation
         let csts = Status(0);
 
+        let cmb = cmb_size.map(|size| Arc::new(ControllerMemBuf::new(size)));
+
         let state = NvmeCtrl {
             ctrl: CtrlState { cap, cc, csts, ..Default::default() },
             msix_hdl: None,
@@ -372,22 +594,38 @@ impl PciNvme {
             sqs: Default::default(),
             nss: Default::default(),
             ident,
+            retired_cqs: Vec::new(),
+            shutdown_flush: None,
+            intr_coalescing: Arc::new(IntrCoalescing::default()),
+            dbbuf: None,
+            cmb: cmb.clone(),
         };
 
-        let nvme = PciNvme { state: Mutex::new(state) };
+        let mut nvme = Arc::new(PciNvme {
+            state: Mutex::new(state),
+            cmb,
+            sa_cell: SelfArcCell::new(),
+        });
+        SelfArc::self_arc_init(&mut nvme);
 
-        builder
+        let mut builder = builder
             // XXX: add room for doorbells
-            .add_bar_mmio64(pci::BarN::BAR0, CONTROLLER_REG_SZ as u64)
-            // BAR0/1 are used for the main config and doorbell registers
-            // BAR2 is for the optional index/data registers
+            .add_bar_mmio64(pci::BarN::BAR0, CONTROLLER_REG_SZ as u64);
+        // BAR0/1 are used for the main config and doorbell registers
+        // BAR2/3 hold the Controller Memory Buffer, if any
+        if let Some(size) = cmb_size {
+            builder = builder.add_bar_mmio64(pci::BarN::BAR2, size as u64);
+        }
+        Ok(builder
             // Place MSIX in BAR4 for now
             .add_cap_msix(pci::BarN::BAR4, NVME_MSIX_COUNT)
-            .finish(Arc::new(nvme))
+            .add_cap_pcie(pci::pcie::PortType::Endpoint)
+            .finish(nvme))
     }
 
     /// Add a new namespace to the controller
-    pub fn add_ns(&self, ns: NvmeNs) -> Result<(), NvmeError> {
+    pub fn add_ns(&self, mut ns: NvmeNs) -> Result<(), NvmeError> {
+        ns.ctrl = self.self_weak();
         let mut state = self.state.lock().unwrap();
         state.add_ns(ns)
     }
@@ -399,35 +637,41 @@ impl PciNvme {
         ctx: &DispCtx,
     ) -> Result<(), NvmeError> {
         let mut state = self.state.lock().unwrap();
+        state.refresh_status();
         let cur = state.ctrl.cc;
 
-        if !cur.enabled() {
-            // TODO: apply any necessary config changes
-        }
-
         if new.enabled() && !cur.enabled() {
-            state.ctrl.cc.set_enabled(true);
+            if state.ctrl.csts.ready() {
+                // The host must wait for a prior reset to complete (indicated
+                // by CSTS.RDY clearing) before enabling the controller again
+                return Ok(());
+            }
+            state.ctrl.cc = new;
 
             // Create the Admin Completion and Submission queues
             state.create_admin_queues(ctx)?;
 
             state.ctrl.csts.set_ready(true);
         } else if !new.enabled() && cur.enabled() {
-            state.ctrl.cc.set_enabled(false);
-            state.ctrl.csts.set_ready(false);
-
+            // Transitioning from 1 to 0 indicates a Controller Reset
+            state.ctrl.cc = new;
             state.reset();
+            state.refresh_status();
+            return Ok(());
+        } else if !cur.enabled() {
+            // Besides SHN, fields may only be changed while disabled
+            state.ctrl.cc = new;
         }
 
-        let shutdown = new.shn() != ShutdownNotification::None;
+        state.ctrl.cc.set_shn(new.shn());
+        let shutdown = matches!(
+            new.shn(),
+            ShutdownNotification::Normal | ShutdownNotification::Abrupt
+        );
         if shutdown && state.ctrl.csts.shst() == ShutdownStatus::Normal {
             // Host has indicated to shutdown
-            // TODO: Cleanup properly but for now just immediately indicate
-            //       we're done shutting down.
-            state.ctrl.csts.set_shst(ShutdownStatus::Complete);
-        } else if !shutdown && state.ctrl.csts.shst() != ShutdownStatus::Normal
-        {
-            state.ctrl.csts.set_shst(ShutdownStatus::Normal);
+            state.shutdown();
+            state.refresh_status();
         }
 
         Ok(())
@@ -459,9 +703,14 @@ impl PciNvme {
                 ro.write_u32(state.ctrl.cc.0);
             }
             CtrlrReg::CtrlrStatus => {
-                let state = self.state.lock().unwrap();
+                let mut state = self.state.lock().unwrap();
+                state.refresh_status();
                 ro.write_u32(state.ctrl.csts.0);
             }
+            CtrlrReg::NvmSubsysReset => {
+                // Reads of NSSR always return 0
+                ro.write_u32(0);
+            }
             CtrlrReg::AdminQueueAttr => {
                 let state = self.state.lock().unwrap();
                 ro.write_u32(state.ctrl.aqa.0);
@@ -474,6 +723,34 @@ impl PciNvme {
                 let state = self.state.lock().unwrap();
                 ro.write_u64(state.ctrl.admin_cq_base);
             }
+            CtrlrReg::CmbLocation => {
+                let state = self.state.lock().unwrap();
+                let cmbloc = match &self.cmb {
+                    Some(_) if state.ctrl.cmbmsc.cre() => {
+                        // The buffer occupies all of BAR2
+                        CmbLocation(0).with_bir(2).with_ofst(0)
+                    }
+                    _ => CmbLocation(0),
+                };
+                ro.write_u32(cmbloc.0);
+            }
+            CtrlrReg::CmbSize => {
+                let state = self.state.lock().unwrap();
+                let cmbsz = match &self.cmb {
+                    Some(cmb) if state.ctrl.cmbmsc.cre() => CmbSize(0)
+                        // Only I/O Submission Queues may be placed in the CMB
+                        .with_sqs(true)
+                        // SZU = 0 => 4KiB units
+                        .with_szu(0)
+                        .with_sz((cmb.size() / PAGE_SIZE) as u32),
+                    _ => CmbSize(0),
+                };
+                ro.write_u32(cmbsz.0);
+            }
+            CtrlrReg::CmbMemSpaceCtrl => {
+                let state = self.state.lock().unwrap();
+                ro.write_u64(state.ctrl.cmbmsc.0);
+            }
             CtrlrReg::Reserved => {
                 ro.fill(0);
             }
@@ -499,10 +776,39 @@ impl PciNvme {
         match id {
             CtrlrReg::CtrlrCaps
             | CtrlrReg::Version
-            | CtrlrReg::CtrlrStatus
+            | CtrlrReg::CmbLocation
+            | CtrlrReg::CmbSize
             | CtrlrReg::Reserved => {
                 // Read-only registers
             }
+            CtrlrReg::CmbMemSpaceCtrl => {
+                let mut cmbmsc = CmbMemSpaceCtrl(wo.read_u64());
+                let mut state = self.state.lock().unwrap();
+                if self.cmb.is_some() {
+                    // The buffer may only be enabled along with the
+                    // CMBLOC & CMBSZ registers
+                    if !cmbmsc.cre() {
+                        cmbmsc.set_cmse(false);
+                    }
+                    state.ctrl.cmbmsc = cmbmsc;
+                }
+            }
+            CtrlrReg::CtrlrStatus => {
+                // All fields are read-only besides NSSRO, which is cleared
+                // by writing 1 to it
+                if Status(wo.read_u32()).nssro() {
+                    let mut state = self.state.lock().unwrap();
+                    state.ctrl.csts.set_nssro(false);
+                }
+            }
+            CtrlrReg::NvmSubsysReset => {
+                // Any value besides the signature is ignored
+                if wo.read_u32() == NVM_SUBSYS_RESET_SIG {
+                    let mut state = self.state.lock().unwrap();
+                    state.subsys_reset();
+                    state.refresh_status();
+                }
+            }
             CtrlrReg::IntrMaskSet | CtrlrReg::IntrMaskClear => {
                 // Only MSI-X is exposed for now, so this is undefined
             }
@@ -530,27 +836,28 @@ impl PciNvme {
             }
 
             CtrlrReg::DoorBellAdminSQ => {
-                let val = wo.read_u32().try_into().unwrap();
+                let val = Self::doorbell_val(wo)?;
                 let state = self.state.lock().unwrap();
+                if !state.is_running() {
+                    // Doorbell writes are ignored until the controller is ready
+                    return Ok(());
+                }
                 let admin_sq = state.get_admin_sq();
                 let mut sq = admin_sq.lock().unwrap();
-                match sq.notify_tail(val) {
-                    Ok(_) => {}
-                    Err(_) => todo!("set controller error state"),
-                }
+                sq.notify_tail(val).map_err(NvmeError::InvalidDoorbell)?;
 
                 // Process any new SQ entries
                 self.process_admin_queue(state, sq, ctx)?;
             }
             CtrlrReg::DoorBellAdminCQ => {
-                let val = wo.read_u32().try_into().unwrap();
+                let val = Self::doorbell_val(wo)?;
                 let state = self.state.lock().unwrap();
+                if !state.is_running() {
+                    return Ok(());
+                }
                 let admin_cq = state.get_admin_cq();
                 let mut cq = admin_cq.lock().unwrap();
-                match cq.notify_head(val) {
-                    Ok(_) => {}
-                    Err(_) => todo!("set controller error state"),
-                }
+                cq.notify_head(val).map_err(NvmeError::InvalidDoorbell)?;
                 // TODO: post any entries to the CQ now that it has more space
             }
 
@@ -565,28 +872,25 @@ impl PciNvme {
                 // But note that we only support CAP.DSTRD = 0
                 let off = wo.offset() - 0x1000;
 
-                let val: u16 = wo.read_u32().try_into().unwrap();
+                let val = Self::doorbell_val(wo)?;
                 let state = self.state.lock().unwrap();
+                if !state.is_running() {
+                    return Ok(());
+                }
 
                 if (off >> 2) & 0b1 == 0b1 {
                     // Completion Queue y Head Doorbell
                     let y = (off - 4) >> 3;
                     let io_cq = state.get_cq(y as u16)?;
                     let mut cq = io_cq.lock().unwrap();
-                    match cq.notify_head(val) {
-                        Ok(_) => {}
-                        Err(_) => todo!("set controller error state"),
-                    }
+                    cq.notify_head(val).map_err(NvmeError::InvalidDoorbell)?;
                     // TODO: post any entries to the CQ now that it has more space
                 } else {
                     // Submission Queue y Tail Doorbell
                     let y = off >> 3;
                     let io_sq = state.get_sq(y as u16)?;
                     let mut sq = io_sq.lock().unwrap();
-                    match sq.notify_tail(val) {
-                        Ok(_) => {}
-                        Err(_) => todo!("set controller error state"),
-                    }
+                    sq.notify_tail(val).map_err(NvmeError::InvalidDoorbell)?;
                     drop(sq);
                     self.process_io_queue(state, io_sq, ctx)?;
                 }
@@ -596,6 +900,17 @@ impl PciNvme {
         Ok(())
     }
 
+    /// Reads the new value written to a doorbell register.
+    ///
+    /// Only the lower 16 bits are defined, with the rest reserved.
+    ///
+    /// See NVMe 1.0e Section 3.1.10 & 3.1.11
+    fn doorbell_val(wo: &mut WriteOp) -> Result<u16, NvmeError> {
+        wo.read_u32()
+            .try_into()
+            .map_err(|_| NvmeError::InvalidDoorbell("reserved bits set"))
+    }
+
     /// Process any new entries in the Admin Submission Queue
     fn process_admin_queue(
         &self,
@@ -610,12 +925,24 @@ impl PciNvme {
         while let Some(sub) = sq.pop(ctx) {
             use cmds::AdminCmd;
 
-            let parsed = AdminCmd::parse(sub);
-            if parsed.is_err() {
-                // XXX: set controller error state?
-                continue;
-            }
-            let cmd = parsed.unwrap();
+            let cmd = match AdminCmd::parse(sub) {
+                Ok(cmd) => cmd,
+                Err(_) => {
+                    // Still complete the command so the host isn't left waiting
+                    let comp = cmds::Completion::generic_err(STS_INVAL_FIELD);
+                    let completion = RawCompletion {
+                        dw0: comp.dw0,
+                        rsvd: 0,
+                        sqhd: sq.head(),
+                        sqid: sq.id(),
+                        cid: sub.cid(),
+                        status_phase: comp.status | cq.phase(),
+                    };
+                    cq.push(completion, ctx);
+                    sq.retire();
+                    continue;
+                }
+            };
             let comp = match cmd {
                 AdminCmd::CreateIOCompQ(cmd) => {
                     state.acmd_create_io_cq(&cmd, ctx)
@@ -628,10 +955,15 @@ impl PciNvme {
                 AdminCmd::SetFeatures(cmd) => {
                     state.acmd_set_features(&cmd, ctx)
                 }
+                AdminCmd::GetFeatures(cmd) => {
+                    state.acmd_get_features(&cmd, ctx)
+                }
+                AdminCmd::DoorbellBufferConfig(cmd) => {
+                    state.acmd_doorbell_buf_cfg(&cmd, ctx)
+                }
                 AdminCmd::DeleteIOSubQ(_)
                 | AdminCmd::DeleteIOCompQ(_)
                 | AdminCmd::Abort
-                | AdminCmd::GetFeatures
                 | AdminCmd::AsyncEventReq
                 | AdminCmd::Unknown(_) => {
                     cmds::Completion::generic_err(bits::STS_INTERNAL_ERR)
@@ -647,6 +979,7 @@ impl PciNvme {
                 status_phase: comp.status | cq.phase(),
             };
             cq.push(completion, ctx);
+            sq.retire();
         }
 
         // Notify for any newly added completions
@@ -662,39 +995,101 @@ impl PciNvme {
         io_sq: Arc<Mutex<SubQueue>>,
         ctx: &DispCtx,
     ) -> Result<(), NvmeError> {
-        let mut sq = io_sq.lock().unwrap();
-
         // Grab the corresponding CQ
-        let io_cq = state.get_cq(sq.cqid())?;
+        let io_cq = state.get_cq(io_sq.lock().unwrap().cqid())?;
 
-        // Collect all the IO SQ entries, per namespace
-        let mut io_cmds = BTreeMap::new();
-        while let Some(sub) = sq.pop(ctx) {
-            io_cmds.entry(sub.nsid).or_insert_with(Vec::new).push(sub);
-        }
+        loop {
+            let mut sq = io_sq.lock().unwrap();
 
-        drop(sq);
+            // Collect all the IO SQ entries, per namespace, including any the
+            // host added through the Shadow Doorbell meanwhile
+            let mut io_cmds = BTreeMap::new();
+            while sq.poll_shadow(ctx).map_err(NvmeError::ShadowDoorbell)? {
+                while let Some(sub) = sq.pop(ctx) {
+                    io_cmds.entry(sub.nsid).or_insert_with(Vec::new).push(sub);
+                }
+            }
+
+            drop(sq);
+
+            if io_cmds.is_empty() {
+                break;
+            }
 
-        // Queue up said IO entries to the underlying block device, per namespace
-        for (nsid, io_cmds) in io_cmds {
-            state.get_ns(nsid)?.queue_io_cmds(
-                io_cmds,
-                io_cq.clone(),
-                io_sq.clone(),
-                ctx,
-            )?;
+            // Queue up said IO entries to the underlying block device, per
+            // namespace. Those completed here, without involving the block
+            // device, may leave the queue idle: polling it again on the next
+            // pass is then what lets the host know to ring the doorbell.
+            for (nsid, io_cmds) in io_cmds {
+                match state.get_ns(nsid) {
+                    Ok(ns) => ns.queue_io_cmds(
+                        io_cmds,
+                        io_cq.clone(),
+                        io_sq.clone(),
+                        ctx,
+                    ),
+                    Err(_) => {
+                        for sub in io_cmds {
+                            let comp =
+                                cmds::Completion::generic_err(STS_INVALID_NS);
+                            ns::complete_now(
+                                sub.cid(),
+                                comp,
+                                &io_cq,
+                                &io_sq,
+                                ctx,
+                            );
+                        }
+                    }
+                }
+            }
         }
 
         // Notify for any newly added completions
-        let cq = io_cq.lock().unwrap();
+        let mut cq = io_cq.lock().unwrap();
         cq.fire_interrupt(ctx);
 
         Ok(())
     }
+
+    /// Process an I/O Submission Queue to which the host has added entries
+    /// through its Shadow Doorbell, without writing the doorbell register.
+    ///
+    /// This is noticed as commands complete, on behalf of block devices which
+    /// may be holding locks needed to queue up more, so the processing itself
+    /// is left to a separate task.
+    fn kick_io_queue(&self, io_sq: Arc<Mutex<SubQueue>>, ctx: &DispCtx) {
+        let this = self.self_arc();
+        ctx.spawn_async(move |actx| async move {
+            let ctx = match actx.dispctx().await {
+                Some(ctx) => ctx,
+                None => return,
+            };
+            let state = this.state.lock().unwrap();
+            if !state.is_running() {
+                return;
+            }
+            if let Err(err) = this.process_io_queue(state, io_sq, &ctx) {
+                eprintln!("nvme queue processing failed: {}", err);
+                this.state.lock().unwrap().ctrl.csts.set_cfs(true);
+            }
+        });
+    }
+}
+
+impl SelfArc for PciNvme {
+    fn self_arc_cell(&self) -> &SelfArcCell<Self> {
+        &self.sa_cell
+    }
 }
 
 impl pci::Device for PciNvme {
     fn bar_rw(&self, bar: pci::BarN, mut rwo: RWOp, ctx: &DispCtx) {
+        if bar == pci::BarN::BAR2 {
+            // Only present when configured with a Controller Memory Buffer
+            self.cmb.as_ref().unwrap().bar_rw(rwo);
+            return;
+        }
         assert_eq!(bar, pci::BarN::BAR0);
         let f = |id: &CtrlrReg, rwo: RWOp<'_, '_>| {
             let res = match rwo {
@@ -704,6 +1099,9 @@ impl pci::Device for PciNvme {
             // TODO: is there a better way to report errors
             if let Err(err) = res {
                 eprintln!("nvme reg read/write failed: {}", err);
+                // Treat any failure to service the host as fatal, leaving
+                // the controller idle until it's reset
+                self.state.lock().unwrap().ctrl.csts.set_cfs(true);
             }
         };
 
@@ -760,6 +1158,10 @@ enum CtrlrReg {
     ///
     /// See NVMe 1.0e Section 3.1.6 Offset 1Ch: CSTS - Controller Status
     CtrlrStatus,
+    /// NVM Subsystem Reset (NSSR)
+    ///
+    /// See NVMe 1.1 Section 3.1.7 Offset 20h: NSSR - NVM Subsystem Reset
+    NvmSubsysReset,
     /// Admin Queue Attributes (AQA)
     ///
     /// See NVMe 1.0e Section 3.1.7 Offset 24h: AQA - Admin Queue Attributes
@@ -772,6 +1174,18 @@ enum CtrlrReg {
     ///
     /// See NVMe 1.0e Section 3.1.9 Offset 30h: ACQ - Admin Completion Queue Base Addres
     AdminCompQAddr,
+    /// Controller Memory Buffer Location (CMBLOC)
+    ///
+    /// See NVMe 1.2 Section 3.1.11 Offset 38h: CMBLOC - Controller Memory Buffer Location
+    CmbLocation,
+    /// Controller Memory Buffer Size (CMBSZ)
+    ///
+    /// See NVMe 1.2 Section 3.1.12 Offset 3Ch: CMBSZ - Controller Memory Buffer Size
+    CmbSize,
+    /// Controller Memory Buffer Memory Space Control (CMBMSC)
+    ///
+    /// See NVMe 1.4 Section 3.1.17 Offset 50h: CMBMSC - Controller Memory Buffer Memory Space Control
+    CmbMemSpaceCtrl,
 
     /// Admin Submission Queue Tail Doorbell
     ///
@@ -801,11 +1215,16 @@ lazy_static! {
             (CtrlrReg::CtrlrCfg, 4),
             (CtrlrReg::Reserved, 4),
             (CtrlrReg::CtrlrStatus, 4),
-            (CtrlrReg::Reserved, 4),
+            (CtrlrReg::NvmSubsysReset, 4),
             (CtrlrReg::AdminQueueAttr, 4),
             (CtrlrReg::AdminSubQAddr, 8),
             (CtrlrReg::AdminCompQAddr, 8),
-            (CtrlrReg::Reserved, 0xec8),
+            (CtrlrReg::CmbLocation, 4),
+            (CtrlrReg::CmbSize, 4),
+            // Boot Partition registers are unsupported
+            (CtrlrReg::Reserved, 0x10),
+            (CtrlrReg::CmbMemSpaceCtrl, 8),
+            (CtrlrReg::Reserved, 0xea8),
             (CtrlrReg::Reserved, 0x100),
             // CAP.DSTRD = 0 hence 0 stride and doorbells are 4 bytes apart
             (CtrlrReg::DoorBellAdminSQ, 4),
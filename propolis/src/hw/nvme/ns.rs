@@ -1,5 +1,6 @@
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 
 use crate::block::*;
 use crate::hw::nvme::bits::RawCompletion;
@@ -7,9 +8,9 @@ use crate::hw::nvme::cmds::{self, NvmCmd};
 use crate::{common, dispatch::DispCtx};
 
 use super::bits::{self, RawSubmission};
-use super::cmds::{Completion, ReadCmd, WriteCmd};
+use super::cmds::{Completion, DataRegion, FuseOp, ReadCmd, SglErr, WriteCmd};
 use super::queue::{CompQueue, SubQueue};
-use super::NvmeError;
+use super::PciNvme;
 
 /// Max number of namespaces we support
 pub const MAX_NUM_NAMESPACES: usize = 16;
@@ -28,6 +29,12 @@ pub struct NvmeNs {
 
     /// Whether the underlying block device readonly
     is_ro: bool,
+
+    /// Logical blocks marked invalid by the Write Uncorrectable command
+    bad_lbas: Mutex<LbaRanges>,
+
+    /// The controller the namespace is attached to
+    pub(super) ctrl: Weak<PciNvme>,
 }
 
 impl NvmeNs {
@@ -58,7 +65,13 @@ impl NvmeNs {
         );
         ident.lbaf[0].lbads = BLOCK_SZ.trailing_zeros() as u8;
 
-        NvmeNs { ident, bdev, is_ro: !binfo.writable }
+        NvmeNs {
+            ident,
+            bdev,
+            is_ro: !binfo.writable,
+            bad_lbas: Mutex::new(LbaRanges::default()),
+            ctrl: Weak::new(),
+        }
     }
 
     /// Convert some number of logical blocks to bytes with the currently active LBA data size
@@ -68,33 +81,87 @@ impl NvmeNs {
 
     /// Takes the given list of raw IO commands and queues up reads and writes to the underlying
     /// block device as appropriate.
+    ///
+    /// Malformed commands are the guest's doing, and are simply failed.
     pub(super) fn queue_io_cmds(
         &self,
         cmds: Vec<RawSubmission>,
         cq: Arc<Mutex<CompQueue>>,
         sq: Arc<Mutex<SubQueue>>,
         ctx: &DispCtx,
-    ) -> Result<(), NvmeError> {
-        for sub in cmds {
-            let cmd = NvmCmd::parse(sub)?;
+    ) {
+        let mut subs = cmds.into_iter().peekable();
+        while let Some(sub) = subs.next() {
+            let fuse = match FuseOp::parse(&sub) {
+                Ok(fuse) => fuse,
+                Err(_) => {
+                    let comp = Completion::generic_err(bits::STS_INVAL_FIELD);
+                    complete_now(sub.cid(), comp, &cq, &sq, ctx);
+                    continue;
+                }
+            };
+            let cmd = NvmCmd::parse(sub);
+            match fuse {
+                FuseOp::Normal => {}
+                FuseOp::First => {
+                    // Fused commands must be submitted adjacent to one another
+                    let second = subs.next_if(|next| {
+                        matches!(FuseOp::parse(next), Ok(FuseOp::Second))
+                    });
+                    let second = match second {
+                        Some(second) => second,
+                        None => {
+                            let comp = Completion::generic_err(
+                                bits::STS_MISSING_FUSED,
+                            );
+                            complete_now(sub.cid(), comp, &cq, &sq, ctx);
+                            continue;
+                        }
+                    };
+                    match pair_fused(cmd, NvmCmd::parse(second)) {
+                        Ok((cmp, wr)) => self.compare_write_cmd(
+                            (sub.cid(), cmp),
+                            (second.cid(), wr),
+                            ctx,
+                            cq.clone(),
+                            sq.clone(),
+                        ),
+                        Err(comp) => fail_fused(
+                            sub.cid(),
+                            comp,
+                            second.cid(),
+                            &cq,
+                            &sq,
+                            ctx,
+                        ),
+                    }
+                    continue;
+                }
+                FuseOp::Second => {
+                    // There was no first command to go along with this one
+                    let comp = Completion::generic_err(bits::STS_MISSING_FUSED);
+                    complete_now(sub.cid(), comp, &cq, &sq, ctx);
+                    continue;
+                }
+            }
+
+            let cmd = match cmd {
+                Ok(cmd) => cmd,
+                Err(_) => {
+                    let comp = Completion::generic_err(bits::STS_INVAL_FIELD);
+                    complete_now(sub.cid(), comp, &cq, &sq, ctx);
+                    continue;
+                }
+            };
             match cmd {
-                NvmCmd::Write(_) if self.is_ro => {
-                    let mut cq = cq.lock().unwrap();
-                    let sq = sq.lock().unwrap();
+                NvmCmd::Write(_) | NvmCmd::WriteUncorrectable(_)
+                    if self.is_ro =>
+                {
                     let comp = Completion::specific_err(
                         bits::StatusCodeType::CmdSpecific,
                         bits::STS_WRITE_READ_ONLY_RANGE,
                     );
-                    let completion = RawCompletion {
-                        dw0: comp.dw0,
-                        rsvd: 0,
-                        sqhd: sq.head(),
-                        sqid: sq.id(),
-                        cid: sub.cid(),
-                        status_phase: comp.status | cq.phase(),
-                    };
-
-                    cq.push(completion, ctx);
+                    complete_now(sub.cid(), comp, &cq, &sq, ctx);
                 }
                 NvmCmd::Write(cmd) => {
                     self.write_cmd(sub.cid(), cmd, ctx, cq.clone(), sq.clone())
@@ -105,27 +172,133 @@ impl NvmeNs {
                 NvmCmd::Flush => {
                     self.flush_cmd(sub.cid(), cq.clone(), sq.clone())
                 }
+                NvmCmd::WriteUncorrectable(cmd) => {
+                    let comp = self.write_unc_cmd(cmd);
+                    complete_now(sub.cid(), comp, &cq, &sq, ctx);
+                }
+                NvmCmd::Compare(cmd) => self.compare_cmd(
+                    sub.cid(),
+                    cmd,
+                    ctx,
+                    cq.clone(),
+                    sq.clone(),
+                ),
+                NvmCmd::Verify(cmd) => {
+                    let comp = self.verify_cmd(cmd);
+                    complete_now(sub.cid(), comp, &cq, &sq, ctx);
+                }
                 NvmCmd::Unknown(_) => {
                     // For any other command, just immediately complete it
-                    let mut cq = cq.lock().unwrap();
-                    let sq = sq.lock().unwrap();
-
                     let comp = Completion::generic_err(bits::STS_INTERNAL_ERR);
-                    let completion = RawCompletion {
-                        dw0: comp.dw0,
-                        rsvd: 0,
-                        sqhd: sq.head(),
-                        sqid: sq.id(),
-                        cid: sub.cid(),
-                        status_phase: comp.status | cq.phase(),
-                    };
+                    complete_now(sub.cid(), comp, &cq, &sq, ctx);
+                }
+            }
+        }
+    }
 
-                    cq.push(completion, ctx);
+    /// Returns the `[start, end)` range of logical blocks accessed by a command,
+    /// or an appropriate error completion if it lies outside the namespace or
+    /// touches any blocks marked invalid with Write Uncorrectable.
+    fn check_lbas(
+        &self,
+        slba: u64,
+        nlb: u16,
+        reading: bool,
+    ) -> Result<(u64, u64), Completion> {
+        let end = slba
+            .checked_add(nlb as u64)
+            .filter(|end| *end <= self.ident.nsze)
+            .ok_or_else(|| {
+                Completion::generic_err(bits::STS_LBA_OUT_OF_RANGE)
+            })?;
+        if reading && self.bad_lbas.lock().unwrap().intersects(slba, end) {
+            return Err(Completion::specific_err(
+                bits::StatusCodeType::MediaDataIntegrity,
+                bits::STS_UNRECOVERED_READ_ERR,
+            ));
+        }
+        Ok((slba, end))
+    }
+
+    /// Gathers up the regions of a transfer starting at the byte offset `off`
+    /// into the namespace, returning a list of `(offset, buffers)` pairs to
+    /// issue to the underlying block device.
+    ///
+    /// A transfer described without any Bit Bucket descriptors results in a
+    /// single entry. Otherwise, the transfer is split at each Bit Bucket with
+    /// the discarded range simply skipped over.
+    fn gather_bufs(
+        off: usize,
+        regions: impl Iterator<Item = Result<DataRegion, SglErr>>,
+    ) -> Result<Vec<(usize, VecDeque<common::GuestRegion>)>, SglErr> {
+        let mut xfers = vec![(off, VecDeque::new())];
+        let mut cur_off = off;
+        for region in regions {
+            match region? {
+                DataRegion::Guest(buf) => {
+                    xfers.last_mut().unwrap().1.push_back(buf);
+                    cur_off += buf.1;
+                }
+                DataRegion::BitBucket(len) => {
+                    cur_off += len;
+                    if xfers.last().unwrap().1.is_empty() {
+                        xfers.last_mut().unwrap().0 = cur_off;
+                    } else {
+                        xfers.push((cur_off, VecDeque::new()));
+                    }
                 }
             }
         }
+        // Drop a trailing empty entry, e.g. the transfer ended in a Bit Bucket
+        if xfers.last().unwrap().1.is_empty() {
+            xfers.pop();
+        }
+        Ok(xfers)
+    }
 
-        Ok(())
+    /// Enqueues the given transfers to the underlying block device, grouping
+    /// them together such that only a single completion is posted.
+    fn enqueue_xfers(
+        &self,
+        op: BlockOp,
+        cid: u16,
+        xfers: Vec<(usize, VecDeque<common::GuestRegion>)>,
+        ctx: &DispCtx,
+        cq: Arc<Mutex<CompQueue>>,
+        sq: Arc<Mutex<SubQueue>>,
+    ) {
+        if xfers.is_empty() {
+            // Everything read was discarded, so there's nothing left to do
+            complete_now(cid, Completion::success(), &cq, &sq, ctx);
+            return;
+        }
+
+        let group = if xfers.len() > 1 {
+            Some(Arc::new(ReqGroup {
+                pending: AtomicUsize::new(xfers.len()),
+                failed: AtomicBool::new(false),
+            }))
+        } else {
+            None
+        };
+        for (off, bufs) in xfers {
+            let xfer_left = bufs.iter().map(|buf| buf.1).sum();
+            // TODO: handles if it gets unmapped?
+            self.bdev.enqueue(Request {
+                op,
+                off,
+                xfer_left,
+                bufs,
+                target: ReqTarget::Cmd {
+                    cid,
+                    fused_cid: None,
+                    cq: cq.clone(),
+                    sq: sq.clone(),
+                    group: group.clone(),
+                    ctrl: self.ctrl.clone(),
+                },
+            });
+        }
     }
 
     /// Enqueues a flush to the underlying block device
@@ -141,9 +314,14 @@ impl NvmeNs {
             off: 0,
             xfer_left: 0,
             bufs: VecDeque::new(),
-            cid,
-            cq,
-            sq,
+            target: ReqTarget::Cmd {
+                cid,
+                fused_cid: None,
+                cq,
+                sq,
+                group: None,
+                ctrl: self.ctrl.clone(),
+            },
         });
     }
 
@@ -157,19 +335,18 @@ impl NvmeNs {
         sq: Arc<Mutex<SubQueue>>,
     ) {
         probe_nvme_read_enqueue!(|| (cid, cmd.slba, cmd.nlb));
+        if let Err(comp) = self.check_lbas(cmd.slba, cmd.nlb, true) {
+            complete_now(cid, comp, &cq, &sq, ctx);
+            return;
+        }
         let off = self.nlb_to_size(cmd.slba as usize);
         let size = self.nlb_to_size(cmd.nlb as usize);
-        // TODO: handles if it gets unmapped?
-        let bufs = cmd.data(size as u64, ctx.mctx.memctx()).collect();
-        self.bdev.enqueue(Request {
-            op: BlockOp::Read,
-            off,
-            xfer_left: size,
-            bufs,
-            cid,
-            cq,
-            sq,
-        });
+        match Self::gather_bufs(off, cmd.data(size as u64, ctx.mctx.memctx())) {
+            Ok(xfers) => {
+                self.enqueue_xfers(BlockOp::Read, cid, xfers, ctx, cq, sq)
+            }
+            Err(e) => complete_now(cid, e.into(), &cq, &sq, ctx),
+        }
     }
 
     /// Enqueues a write to the underlying block device
@@ -182,22 +359,291 @@ impl NvmeNs {
         sq: Arc<Mutex<SubQueue>>,
     ) {
         probe_nvme_write_enqueue!(|| (cid, cmd.slba, cmd.nlb));
+        let (start, end) = match self.check_lbas(cmd.slba, cmd.nlb, false) {
+            Ok(range) => range,
+            Err(comp) => {
+                complete_now(cid, comp, &cq, &sq, ctx);
+                return;
+            }
+        };
         let off = self.nlb_to_size(cmd.slba as usize);
         let size = self.nlb_to_size(cmd.nlb as usize);
+        match Self::gather_bufs(off, cmd.data(size as u64, ctx.mctx.memctx())) {
+            Ok(xfers) => {
+                // Writing to a block makes it valid again
+                self.bad_lbas.lock().unwrap().remove(start, end);
+                self.enqueue_xfers(BlockOp::Write, cid, xfers, ctx, cq, sq)
+            }
+            Err(e) => complete_now(cid, e.into(), &cq, &sq, ctx),
+        }
+    }
+
+    /// Marks the given range of logical blocks as invalid such that subsequent
+    /// reads of them fail, until they are next written.
+    fn write_unc_cmd(&self, cmd: cmds::WriteUncorrectableCmd) -> Completion {
+        match self.check_lbas(cmd.slba, cmd.nlb, false) {
+            Ok((start, end)) => {
+                self.bad_lbas.lock().unwrap().insert(start, end);
+                Completion::success()
+            }
+            Err(comp) => comp,
+        }
+    }
+
+    /// Verifies the given range of logical blocks.
+    ///
+    /// There's no stored data integrity information to check beyond any blocks
+    /// marked invalid with Write Uncorrectable.
+    fn verify_cmd(&self, cmd: cmds::VerifyCmd) -> Completion {
+        match self.check_lbas(cmd.slba, cmd.nlb, true) {
+            Ok(_) => Completion::success(),
+            Err(comp) => comp,
+        }
+    }
+
+    /// Enqueues a compare to the underlying block device
+    fn compare_cmd(
+        &self,
+        cid: u16,
+        cmd: cmds::CompareCmd,
+        ctx: &DispCtx,
+        cq: Arc<Mutex<CompQueue>>,
+        sq: Arc<Mutex<SubQueue>>,
+    ) {
+        if let Err(comp) = self.check_lbas(cmd.slba, cmd.nlb, true) {
+            complete_now(cid, comp, &cq, &sq, ctx);
+            return;
+        }
+        let off = self.nlb_to_size(cmd.slba as usize);
+        let size = self.nlb_to_size(cmd.nlb as usize);
+        match Self::gather_bufs(off, cmd.data(size as u64, ctx.mctx.memctx())) {
+            Ok(xfers) => {
+                self.enqueue_xfers(BlockOp::Compare, cid, xfers, ctx, cq, sq)
+            }
+            Err(e) => complete_now(cid, e.into(), &cq, &sq, ctx),
+        }
+    }
+
+    /// Enqueues a fused Compare and Write operation to the underlying block device.
+    ///
+    /// See NVMe 1.0e Section 6.1 Fused Operations
+    fn compare_write_cmd(
+        &self,
+        (cmp_cid, cmp): (u16, cmds::CompareCmd),
+        (wr_cid, wr): (u16, WriteCmd),
+        ctx: &DispCtx,
+        cq: Arc<Mutex<CompQueue>>,
+        sq: Arc<Mutex<SubQueue>>,
+    ) {
+        // Both commands must operate on the same range of blocks
+        if cmp.slba != wr.slba || cmp.nlb != wr.nlb {
+            let comp = Completion::generic_err(bits::STS_INVAL_FIELD);
+            fail_fused(cmp_cid, comp, wr_cid, &cq, &sq, ctx);
+            return;
+        }
+        if self.is_ro {
+            let comp = Completion::specific_err(
+                bits::StatusCodeType::CmdSpecific,
+                bits::STS_WRITE_READ_ONLY_RANGE,
+            );
+            fail_fused(cmp_cid, comp, wr_cid, &cq, &sq, ctx);
+            return;
+        }
+        if let Err(comp) = self.check_lbas(cmp.slba, cmp.nlb, true) {
+            fail_fused(cmp_cid, comp, wr_cid, &cq, &sq, ctx);
+            return;
+        }
+
+        let off = self.nlb_to_size(cmp.slba as usize);
+        let size = self.nlb_to_size(cmp.nlb as usize);
+        // Neither command allows Bit Buckets so expect at most one transfer each
+        let cmp_bufs =
+            Self::gather_bufs(off, cmp.data(size as u64, ctx.mctx.memctx()));
+        let wr_bufs =
+            Self::gather_bufs(off, wr.data(size as u64, ctx.mctx.memctx()));
+        let bufs = match (cmp_bufs, wr_bufs) {
+            (Ok(mut cmp_bufs), Ok(mut wr_bufs)) => {
+                let mut bufs = cmp_bufs.pop().unwrap_or_default().1;
+                bufs.extend(wr_bufs.pop().unwrap_or_default().1);
+                bufs
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                fail_fused(cmp_cid, e.into(), wr_cid, &cq, &sq, ctx);
+                return;
+            }
+        };
+
         // TODO: handles if it gets unmapped?
-        let bufs = cmd.data(size as u64, ctx.mctx.memctx()).collect();
         self.bdev.enqueue(Request {
-            op: BlockOp::Write,
+            op: BlockOp::CompareAndWrite,
             off,
-            xfer_left: size,
+            xfer_left: size * 2,
             bufs,
-            cid,
-            cq,
-            sq,
+            target: ReqTarget::Cmd {
+                cid: cmp_cid,
+                fused_cid: Some(wr_cid),
+                cq,
+                sq,
+                group: None,
+                ctrl: self.ctrl.clone(),
+            },
+        });
+    }
+
+    /// Enqueues a flush to the underlying block device on behalf of the
+    /// controller itself, rather than any guest command.
+    ///
+    /// The given `token` is held until the flush has completed.
+    pub(super) fn flush_internal(&self, token: Arc<()>) {
+        self.bdev.enqueue(Request {
+            op: BlockOp::Flush,
+            off: 0,
+            xfer_left: 0,
+            bufs: VecDeque::new(),
+            target: ReqTarget::Internal(token),
         });
     }
 }
 
+/// Immediately post a completion for the given command, bypassing the block device.
+pub(super) fn complete_now(
+    cid: u16,
+    comp: Completion,
+    cq: &Arc<Mutex<CompQueue>>,
+    sq: &Arc<Mutex<SubQueue>>,
+    ctx: &DispCtx,
+) {
+    let mut sq = sq.lock().unwrap();
+    let mut cq = cq.lock().unwrap();
+
+    let completion = RawCompletion {
+        dw0: comp.dw0,
+        rsvd: 0,
+        sqhd: sq.head(),
+        sqid: sq.id(),
+        cid,
+        status_phase: comp.status | cq.phase(),
+    };
+
+    cq.push(completion, ctx);
+    sq.retire();
+}
+
+/// Immediately fail both commands of a fused operation, completing the first
+/// with the given result.
+fn fail_fused(
+    first_cid: u16,
+    first_comp: Completion,
+    second_cid: u16,
+    cq: &Arc<Mutex<CompQueue>>,
+    sq: &Arc<Mutex<SubQueue>>,
+    ctx: &DispCtx,
+) {
+    complete_now(first_cid, first_comp, cq, sq, ctx);
+    let second_comp = Completion::generic_err(bits::STS_FAILED_FUSED);
+    complete_now(second_cid, second_comp, cq, sq, ctx);
+}
+
+/// Pairs up the two halves of a fused operation, returning the completion
+/// with which to fail both should either be malformed, or should they not
+/// form a Compare and Write, the only fused operation defined.
+fn pair_fused(
+    first: Result<NvmCmd, cmds::ParseErr>,
+    second: Result<NvmCmd, cmds::ParseErr>,
+) -> Result<(cmds::CompareCmd, WriteCmd), Completion> {
+    match (first, second) {
+        (Ok(NvmCmd::Compare(cmp)), Ok(NvmCmd::Write(wr))) => Ok((cmp, wr)),
+        _ => Err(Completion::generic_err(bits::STS_INVAL_FIELD)),
+    }
+}
+
+/// A set of logical blocks, tracked as sorted, non-overlapping `[start, end)` ranges.
+#[derive(Debug, Default)]
+struct LbaRanges(BTreeMap<u64, u64>);
+
+impl LbaRanges {
+    /// Adds the blocks `[start, end)` to the set.
+    fn insert(&mut self, mut start: u64, mut end: u64) {
+        // Merge with any ranges overlapping or adjacent to the new one
+        let merge: Vec<(u64, u64)> = self
+            .0
+            .range(..=end)
+            .rev()
+            .take_while(|(_, e)| **e >= start)
+            .map(|(s, e)| (*s, *e))
+            .collect();
+        for (s, e) in merge {
+            self.0.remove(&s);
+            start = u64::min(start, s);
+            end = u64::max(end, e);
+        }
+        self.0.insert(start, end);
+    }
+
+    /// Removes the blocks `[start, end)` from the set.
+    fn remove(&mut self, start: u64, end: u64) {
+        let overlap: Vec<(u64, u64)> = self
+            .0
+            .range(..end)
+            .rev()
+            .take_while(|(_, e)| **e > start)
+            .map(|(s, e)| (*s, *e))
+            .collect();
+        for (s, e) in overlap {
+            self.0.remove(&s);
+            if s < start {
+                self.0.insert(s, start);
+            }
+            if e > end {
+                self.0.insert(end, e);
+            }
+        }
+    }
+
+    /// Returns whether any of the blocks `[start, end)` are in the set.
+    fn intersects(&self, start: u64, end: u64) -> bool {
+        self.0.range(..end).next_back().map_or(false, |(_, e)| *e > start)
+    }
+}
+
+/// Tracks a single NVMe command which was split across multiple block device requests.
+struct ReqGroup {
+    /// Number of requests which have yet to complete
+    pending: AtomicUsize,
+
+    /// Whether any of the requests failed
+    failed: AtomicBool,
+}
+
+/// What to notify once a block device request has completed
+enum ReqTarget {
+    /// Post a completion for the guest command which issued the request
+    Cmd {
+        /// The associated command id
+        cid: u16,
+
+        /// The command id of the second command of a fused operation
+        fused_cid: Option<u16>,
+
+        /// The associated Completion Queue
+        cq: Arc<Mutex<CompQueue>>,
+
+        /// The associated Submission Queue
+        sq: Arc<Mutex<SubQueue>>,
+
+        /// The group of requests this is a part of, if the command was split up
+        group: Option<Arc<ReqGroup>>,
+
+        /// The controller, to process any entries added to the Submission
+        /// Queue meanwhile
+        ctrl: Weak<PciNvme>,
+    },
+
+    /// A request issued by the controller itself (e.g. during shutdown), which
+    /// is tracked by the lifetime of the held token rather than any completion.
+    Internal(Arc<()>),
+}
+
 /// I/O Request to block device
 pub struct Request {
     /// The operation type
@@ -212,14 +658,8 @@ pub struct Request {
     /// The buffers to read/write from/to
     bufs: VecDeque<common::GuestRegion>,
 
-    /// The associated command id
-    cid: u16,
-
-    /// The associated Completion Queue
-    cq: Arc<Mutex<CompQueue>>,
-
-    /// The associated Submission Queue
-    sq: Arc<Mutex<SubQueue>>,
+    /// Who to notify upon completion
+    target: ReqTarget,
 }
 
 impl BlockReq for Request {
@@ -248,6 +688,31 @@ impl BlockReq for Request {
     }
 
     fn complete(self, res: BlockResult, ctx: &DispCtx) {
+        let (cid, fused_cid, cq, io_sq, group, ctrl) = match self.target {
+            ReqTarget::Cmd { cid, fused_cid, cq, sq, group, ctrl } => {
+                (cid, fused_cid, cq, sq, group, ctrl)
+            }
+            // Dropping the token is all that's needed to signal completion
+            ReqTarget::Internal(_) => return,
+        };
+        let res = match &group {
+            Some(group) => {
+                if !matches!(res, BlockResult::Success) {
+                    group.failed.store(true, Ordering::Release);
+                }
+                if group.pending.fetch_sub(1, Ordering::AcqRel) != 1 {
+                    // Only the last request of the group posts a completion
+                    return;
+                }
+                if group.failed.load(Ordering::Acquire) {
+                    BlockResult::Failure
+                } else {
+                    BlockResult::Success
+                }
+            }
+            None => res,
+        };
+
         let comp = match res {
             BlockResult::Success => cmds::Completion::success(),
             BlockResult::Failure => {
@@ -257,33 +722,154 @@ impl BlockReq for Request {
                 bits::StatusCodeType::CmdSpecific,
                 bits::STS_READ_CONFLICTING_ATTRS,
             ),
+            BlockResult::Miscompare => cmds::Completion::specific_err(
+                bits::StatusCodeType::MediaDataIntegrity,
+                bits::STS_COMPARE_FAILURE,
+            ),
         };
 
         match self.op {
             BlockOp::Read => {
-                probe_nvme_read_complete!(|| (self.cid));
+                probe_nvme_read_complete!(|| (cid));
             }
             BlockOp::Write => {
-                probe_nvme_write_complete!(|| (self.cid));
+                probe_nvme_write_complete!(|| (cid));
             }
             _ => {}
         }
 
-        let sq = self.sq.lock().unwrap();
-        let mut cq = self.cq.lock().unwrap();
+        let mut sq = io_sq.lock().unwrap();
+        let mut cq = cq.lock().unwrap();
 
         let completion = bits::RawCompletion {
             dw0: comp.dw0,
             rsvd: 0,
             sqhd: sq.head(),
             sqid: sq.id(),
-            cid: self.cid,
+            cid,
             status_phase: comp.status | cq.phase(),
         };
 
         cq.push(completion, ctx);
+        sq.retire();
+
+        if let Some(fused_cid) = fused_cid {
+            // The second command only succeeds if the whole operation did
+            let comp = match res {
+                BlockResult::Success => cmds::Completion::success(),
+                _ => cmds::Completion::generic_err(bits::STS_FAILED_FUSED),
+            };
+            let completion = bits::RawCompletion {
+                dw0: comp.dw0,
+                rsvd: 0,
+                sqhd: sq.head(),
+                sqid: sq.id(),
+                cid: fused_cid,
+                status_phase: comp.status | cq.phase(),
+            };
+            cq.push(completion, ctx);
+            sq.retire();
+        }
 
         // TODO: should this be done here?
         cq.fire_interrupt(ctx);
+
+        // With a Shadow Doorbell, the host may have added entries without
+        // ringing the doorbell register. Any error is left for processing
+        // the queue to report.
+        if sq.has_shadow() && !matches!(sq.poll_shadow(ctx), Ok(false)) {
+            drop(cq);
+            drop(sq);
+            if let Some(ctrl) = ctrl.upgrade() {
+                ctrl.kick_io_queue(io_sq, ctx);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pair_fused, LbaRanges};
+    use crate::hw::nvme::bits::{self, RawSubmission};
+    use crate::hw::nvme::cmds::{Completion, NvmCmd};
+
+    fn ranges(set: &LbaRanges) -> Vec<(u64, u64)> {
+        set.0.iter().map(|(s, e)| (*s, *e)).collect()
+    }
+
+    #[test]
+    fn lba_ranges_insert_merges() {
+        let mut set = LbaRanges::default();
+        set.insert(10, 20);
+        set.insert(30, 40);
+        assert_eq!(ranges(&set), vec![(10, 20), (30, 40)]);
+
+        // Adjacent ranges coalesce
+        set.insert(20, 25);
+        assert_eq!(ranges(&set), vec![(10, 25), (30, 40)]);
+
+        // As do ones spanning multiple existing ranges
+        set.insert(5, 35);
+        assert_eq!(ranges(&set), vec![(5, 40)]);
+    }
+
+    #[test]
+    fn lba_ranges_remove_splits() {
+        let mut set = LbaRanges::default();
+        set.insert(0, 100);
+        set.remove(10, 20);
+        assert_eq!(ranges(&set), vec![(0, 10), (20, 100)]);
+
+        set.remove(5, 30);
+        assert_eq!(ranges(&set), vec![(0, 5), (30, 100)]);
+
+        set.remove(0, 200);
+        assert!(ranges(&set).is_empty());
+    }
+
+    #[test]
+    fn lba_ranges_intersects() {
+        let mut set = LbaRanges::default();
+        set.insert(10, 20);
+        assert!(!set.intersects(0, 10));
+        assert!(set.intersects(0, 11));
+        assert!(set.intersects(12, 13));
+        assert!(set.intersects(19, 30));
+        assert!(!set.intersects(20, 30));
+    }
+
+    /// Builds a submission with the given opcode, FUSE and PSDT bits.
+    fn submission(cid: u16, opc: u8, fuse: u8, psdt: u8) -> RawSubmission {
+        RawSubmission {
+            cdw0: (cid as u32) << 16
+                | (psdt as u32) << 14
+                | (fuse as u32) << 8
+                | opc as u32,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fused_pair_malformed_second() {
+        let inval = Completion::generic_err(bits::STS_INVAL_FIELD).status;
+        let cmp = submission(1, bits::NVM_OPC_COMPARE, bits::FUSE_FIRST, 0);
+        let wr = submission(2, bits::NVM_OPC_WRITE, bits::FUSE_SECOND, 0);
+        assert!(pair_fused(NvmCmd::parse(cmp), NvmCmd::parse(wr)).is_ok());
+
+        // A reserved PSDT in the write fails the pair as a whole, rather than
+        // leaving the compare without a completion
+        let bad = submission(2, bits::NVM_OPC_WRITE, bits::FUSE_SECOND, 0b11);
+        assert!(NvmCmd::parse(bad).is_err());
+        match pair_fused(NvmCmd::parse(cmp), NvmCmd::parse(bad)) {
+            Err(comp) => assert_eq!(comp.status, inval),
+            Ok(_) => panic!("malformed fused pair accepted"),
+        }
+
+        // As does a pair which isn't a Compare and Write
+        let rd = submission(2, bits::NVM_OPC_READ, bits::FUSE_SECOND, 0);
+        match pair_fused(NvmCmd::parse(cmp), NvmCmd::parse(rd)) {
+            Err(comp) => assert_eq!(comp.status, inval),
+            Ok(_) => panic!("fused compare and read accepted"),
+        }
     }
 }
@@ -1,11 +1,18 @@
+use std::collections::BTreeSet;
+use std::convert::TryInto;
 use std::marker::PhantomData;
+use std::sync::atomic::{fence, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
 use super::bits::{self, RawCompletion, RawSubmission};
+use super::cmb::ControllerMemBuf;
 use crate::common::*;
-use crate::dispatch::DispCtx;
+use crate::dispatch::{AsyncCtx, AsyncTaskId, DispCtx};
 use crate::hw::pci;
 
 use thiserror::Error;
+use tokio::time::sleep;
 
 /// Each queue is identified by a 16-bit ID.
 ///
@@ -222,6 +229,57 @@ pub enum QueueCreateErr {
     InvalidSize,
 }
 
+/// The locations of the Shadow Doorbell and EventIdx for a single queue, as
+/// set up by the host with the Doorbell Buffer Config command.
+///
+/// See NVMe 1.3 Section 5.8 Doorbell Buffer Config command
+#[derive(Copy, Clone, Debug)]
+pub struct ShadowDoorbell {
+    /// Where the host writes new doorbell values for the queue
+    pub db: GuestAddr,
+
+    /// Where the controller indicates when the host must next write to the
+    /// doorbell register itself.
+    pub ei: GuestAddr,
+}
+
+impl ShadowDoorbell {
+    /// Reads the doorbell value most recently written by the host.
+    fn read(&self, ctx: &DispCtx) -> Result<u16, &'static str> {
+        let val: u32 = ctx
+            .mctx
+            .memctx()
+            .read(self.db)
+            .ok_or("shadow doorbell unreadable")?;
+        // As with the doorbell registers, only the lower 16 bits are used
+        val.try_into().map_err(|_| "reserved bits set")
+    }
+
+    /// Publishes `val`, the doorbell value last consumed, as the EventIdx and
+    /// returns the doorbell value as it stands afterwards.
+    ///
+    /// Having seen the EventIdx match the doorbell value it's replacing, the
+    /// host writes the doorbell register for its next update. An update made
+    /// before the host could see it will have skipped doing so, which is why
+    /// the Shadow Doorbell is read again.
+    fn publish(&self, val: u16, ctx: &DispCtx) -> Result<u16, &'static str> {
+        let mem = ctx.mctx.memctx();
+        if !mem.write(self.ei, &(val as u32)) {
+            return Err("eventidx unwritable");
+        }
+        fence(Ordering::SeqCst);
+        self.read(ctx)
+    }
+}
+
+/// Where the entries of a Submission Queue reside.
+enum SubQueueMem {
+    /// In guest memory at the given address
+    Guest(GuestAddr),
+    /// In the Controller Memory Buffer at the given offset
+    Cmb(Arc<ControllerMemBuf>, usize),
+}
+
 /// Type for manipulating Submission Queues.
 pub struct SubQueue {
     /// The ID of queue in question.
@@ -233,8 +291,15 @@ pub struct SubQueue {
     /// Queue state such as the size and current head/tail entry pointers.
     state: QueueState<SubmissionQueueType>,
 
-    /// The [`GuestAddr`] at which the Queue is mapped.
-    base: GuestAddr,
+    /// Where the Queue is mapped.
+    mem: SubQueueMem,
+
+    /// The Shadow Doorbell for the Queue, if the host has configured one.
+    shadow: Option<ShadowDoorbell>,
+
+    /// The number of entries popped off the Queue whose completions have
+    /// yet to be posted.
+    outstanding: u32,
 }
 
 impl SubQueue {
@@ -248,7 +313,43 @@ impl SubQueue {
         ctx: &DispCtx,
     ) -> Result<Self, QueueCreateErr> {
         Self::validate(id, base, size, ctx)?;
-        Ok(Self { id, cqid, state: QueueState::new(size, 0, 0), base })
+        Ok(Self {
+            id,
+            cqid,
+            state: QueueState::new(size, 0, 0),
+            mem: SubQueueMem::Guest(base),
+            shadow: None,
+            outstanding: 0,
+        })
+    }
+
+    /// Create a Submission Queue object backed by the Controller Memory
+    /// Buffer, starting at the given offset into it.
+    pub fn new_in_cmb(
+        id: QueueId,
+        cqid: QueueId,
+        size: u32,
+        cmb: Arc<ControllerMemBuf>,
+        off: usize,
+    ) -> Result<Self, QueueCreateErr> {
+        if id == ADMIN_QUEUE_ID || (off & PAGE_OFFSET) != 0 {
+            return Err(QueueCreateErr::InvalidBaseAddr);
+        }
+        if size < MIN_QUEUE_SIZE || size > MAX_QUEUE_SIZE {
+            return Err(QueueCreateErr::InvalidSize);
+        }
+        let queue_size = size as usize * std::mem::size_of::<RawSubmission>();
+        if off + queue_size > cmb.size() {
+            return Err(QueueCreateErr::InvalidBaseAddr);
+        }
+        Ok(Self {
+            id,
+            cqid,
+            state: QueueState::new(size, 0, 0),
+            mem: SubQueueMem::Cmb(cmb, off),
+            shadow: None,
+            outstanding: 0,
+        })
     }
 
     /// Attempt to move the Tail entry pointer forward to the given index.
@@ -256,18 +357,76 @@ impl SubQueue {
         self.state.push_tail_to(idx)
     }
 
+    /// Sets up a Shadow Doorbell for the Queue.
+    ///
+    /// Both the Shadow Doorbell and EventIdx are initialized to the current
+    /// Tail entry pointer.
+    pub fn set_shadow(
+        &mut self,
+        shadow: ShadowDoorbell,
+        ctx: &DispCtx,
+    ) -> Result<(), &'static str> {
+        let mem = ctx.mctx.memctx();
+        let tail = self.state.tail as u32;
+        if !mem.write(shadow.db, &tail) || !mem.write(shadow.ei, &tail) {
+            return Err("shadow doorbell unwritable");
+        }
+        self.shadow = Some(shadow);
+        Ok(())
+    }
+
+    /// Returns whether the host has configured a Shadow Doorbell for the Queue.
+    pub fn has_shadow(&self) -> bool {
+        self.shadow.is_some()
+    }
+
+    /// Picks up any new Tail entry pointer written to the Shadow Doorbell,
+    /// returning whether the Queue holds entries to be processed.
+    ///
+    /// The EventIdx is left behind the Tail entry pointer for as long as
+    /// commands from the Queue are outstanding, so the host doesn't write the
+    /// doorbell register itself: new entries are instead picked up here as
+    /// those commands are processed and completed. Only once the Queue goes
+    /// idle is the EventIdx advanced, for the host to ring the doorbell with
+    /// its next entry.
+    pub fn poll_shadow(&mut self, ctx: &DispCtx) -> Result<bool, &'static str> {
+        if let Some(shadow) = self.shadow {
+            self.state.push_tail_to(shadow.read(ctx)?)?;
+            if self.state.is_empty() && self.outstanding == 0 {
+                let tail = shadow.publish(self.state.tail, ctx)?;
+                self.state.push_tail_to(tail)?;
+            }
+        }
+        Ok(!self.state.is_empty())
+    }
+
     /// Returns the next entry off of the Queue or [`None`] if it is empty.
+    ///
+    /// The entry is counted as outstanding until [`SubQueue::retire`] is
+    /// called for it.
     pub fn pop(&mut self, ctx: &DispCtx) -> Option<bits::RawSubmission> {
         if let Some(idx) = self.state.pop_head() {
-            let mem = ctx.mctx.memctx();
-            let ent: Option<RawSubmission> = mem.read(self.entry_addr(idx));
-            // XXX: handle a guest addr that becomes unmapped later
-            ent
+            self.outstanding += 1;
+            let off = idx as usize * std::mem::size_of::<RawSubmission>();
+            match &self.mem {
+                SubQueueMem::Guest(base) => {
+                    let mem = ctx.mctx.memctx();
+                    // XXX: handle a guest addr that becomes unmapped later
+                    mem.read(GuestAddr(base.0 + off as u64))
+                }
+                SubQueueMem::Cmb(cmb, base) => cmb.read(base + off),
+            }
         } else {
             None
         }
     }
 
+    /// Records that the completion for an entry popped off the Queue has been
+    /// posted.
+    pub fn retire(&mut self) {
+        self.outstanding = self.outstanding.saturating_sub(1);
+    }
+
     /// Returns the current Head entry pointer.
     pub fn head(&self) -> u16 {
         self.state.head
@@ -284,14 +443,6 @@ impl SubQueue {
         self.cqid
     }
 
-    /// Returns the corresponding [`GuestAddr`] for a given entry in
-    /// the Submission Queue.
-    fn entry_addr(&self, idx: u16) -> GuestAddr {
-        let res = self.base.0
-            + idx as u64 * std::mem::size_of::<RawSubmission>() as u64;
-        GuestAddr(res)
-    }
-
     /// Validates whether the given parameters may be used to create
     /// a Submission Queue object.
     fn validate(
@@ -320,12 +471,131 @@ impl SubQueue {
     }
 }
 
+/// Interrupt Coalescing state shared by all the I/O Completion Queues of a
+/// controller.
+///
+/// See NVMe 1.0e Section 5.12.1.8 Interrupt Coalescing
+/// See NVMe 1.0e Section 5.12.1.9 Interrupt Vector Configuration
+#[derive(Default)]
+pub struct IntrCoalescing {
+    /// The Aggregation Time (bits 15:08) and Threshold (bits 07:00), as
+    /// laid out in CDW11 of the Set Features command.
+    params: AtomicU16,
+
+    /// The interrupt vectors for which the host has disabled coalescing.
+    disabled: Mutex<BTreeSet<u16>>,
+
+    /// The I/O Completion Queues which may be holding back interrupts.
+    cqs: Mutex<Vec<Weak<Mutex<CompQueue>>>>,
+
+    /// The dispatcher task which flushes interrupts held back for longer
+    /// than the Aggregation Time, if running.
+    timer: Mutex<Option<AsyncTaskId>>,
+}
+
+impl IntrCoalescing {
+    /// Returns the current Aggregation Threshold (0's based) and Time.
+    pub fn params(&self) -> (u8, u8) {
+        let params = self.params.load(Ordering::Acquire);
+        (params as u8, (params >> 8) as u8)
+    }
+
+    /// Updates the Aggregation Threshold (0's based) and Time, starting the
+    /// timer to flush aggregated interrupts if necessary.
+    pub fn set_params(self: &Arc<Self>, thr: u8, time: u8, ctx: &DispCtx) {
+        self.params.store((time as u16) << 8 | thr as u16, Ordering::Release);
+
+        let mut timer = self.timer.lock().unwrap();
+        if time != 0 && timer.is_none() {
+            let this = Arc::clone(self);
+            *timer = Some(ctx.spawn_async(move |actx| async move {
+                this.run_timer(&actx).await;
+            }));
+        }
+    }
+
+    /// Returns whether coalescing has been disabled for the given vector.
+    pub fn is_disabled(&self, iv: u16) -> bool {
+        self.disabled.lock().unwrap().contains(&iv)
+    }
+
+    /// Enables or disables coalescing for the given vector.
+    pub fn set_disabled(&self, iv: u16, cd: bool) {
+        let mut disabled = self.disabled.lock().unwrap();
+        if cd {
+            disabled.insert(iv);
+        } else {
+            disabled.remove(&iv);
+        }
+    }
+
+    /// Registers a newly created I/O Completion Queue.
+    pub fn add_cq(&self, cq: &Arc<Mutex<CompQueue>>) {
+        let mut cqs = self.cqs.lock().unwrap();
+        cqs.retain(|cq| cq.strong_count() > 0);
+        cqs.push(Arc::downgrade(cq));
+    }
+
+    /// Returns all settings to their defaults, as on a Controller Reset.
+    ///
+    /// Any running timer exits on its own once it sees a zero Aggregation Time.
+    pub fn reset(&self) {
+        self.params.store(0, Ordering::Release);
+        self.disabled.lock().unwrap().clear();
+        self.cqs.lock().unwrap().clear();
+    }
+
+    /// Periodically signals any interrupts which have been held back for at
+    /// least the Aggregation Time.
+    async fn run_timer(&self, actx: &AsyncCtx) {
+        loop {
+            let time = {
+                let mut timer = self.timer.lock().unwrap();
+                let (_, time) = self.params();
+                if time == 0 {
+                    // Coalescing was turned off
+                    *timer = None;
+                    return;
+                }
+                aggregation_time(time)
+            };
+
+            sleep(time).await;
+
+            let ctx = match actx.dispctx().await {
+                Some(ctx) => ctx,
+                None => return,
+            };
+            let cqs = self.cqs.lock().unwrap();
+            for cq in cqs.iter().filter_map(Weak::upgrade) {
+                cq.lock().unwrap().flush_interrupt(time, &ctx);
+            }
+        }
+    }
+}
+
+/// Converts an Aggregation Time, in 100 microsecond increments, to a [`Duration`].
+fn aggregation_time(time: u8) -> Duration {
+    Duration::from_micros(time as u64 * 100)
+}
+
 /// Type for manipulating Completion Queues.
 pub struct CompQueue {
     /// The Interrupt Vector used to signal to the host (VM) upon pushing
     /// entries onto the Completion Queue.
     iv: u16,
 
+    /// Interrupt Coalescing state, if applicable to this queue.
+    ///
+    /// The Admin Completion Queue is never subject to coalescing.
+    coalescing: Option<Arc<IntrCoalescing>>,
+
+    /// The number of entries pushed since an interrupt was last signaled.
+    unsignaled: u32,
+
+    /// When the oldest entry not yet signaled was pushed, if any.
+    unsignaled_since: Option<Instant>,
+
     /// Queue state such as the size and current head/tail entry pointers.
     state: QueueState<CompletionQueueType>,
 
@@ -340,6 +610,9 @@ pub struct CompQueue {
 
     /// MSI-X object associated with PCIe device to signal host (VM).
     hdl: pci::MsixHdl,
+
+    /// The Shadow Doorbell for the Queue, if the host has configured one.
+    shadow: Option<ShadowDoorbell>,
 }
 
 impl CompQueue {
@@ -352,14 +625,19 @@ impl CompQueue {
         base: GuestAddr,
         ctx: &DispCtx,
         hdl: pci::MsixHdl,
+        coalescing: Option<Arc<IntrCoalescing>>,
     ) -> Result<Self, QueueCreateErr> {
         Self::validate(id, base, size, ctx)?;
         Ok(Self {
             iv,
+            coalescing,
+            unsignaled: 0,
+            unsignaled_since: None,
             state: QueueState::new(size, 0, 0),
             base,
             phase: true,
             hdl,
+            shadow: None,
         })
     }
 
@@ -368,10 +646,50 @@ impl CompQueue {
         self.state.pop_head_to(idx)
     }
 
+    /// Sets up a Shadow Doorbell for the Queue.
+    ///
+    /// Both the Shadow Doorbell and EventIdx are initialized to the current
+    /// Head entry pointer.
+    pub fn set_shadow(
+        &mut self,
+        shadow: ShadowDoorbell,
+        ctx: &DispCtx,
+    ) -> Result<(), &'static str> {
+        let mem = ctx.mctx.memctx();
+        let head = self.state.head as u32;
+        if !mem.write(shadow.db, &head) || !mem.write(shadow.ei, &head) {
+            return Err("shadow doorbell unwritable");
+        }
+        self.shadow = Some(shadow);
+        Ok(())
+    }
+
+    /// Picks up any new Head entry pointer written to the Shadow Doorbell.
+    ///
+    /// The Head entry pointer only matters once the Queue fills up, so the
+    /// EventIdx is left where it is (and the host doesn't write the doorbell
+    /// register itself) unless the Queue is still full. It's then advanced
+    /// for the host to ring the doorbell as soon as it consumes an entry.
+    fn poll_shadow(&mut self, ctx: &DispCtx) -> Result<(), &'static str> {
+        if let Some(shadow) = self.shadow {
+            self.state.pop_head_to(shadow.read(ctx)?)?;
+            if self.state.is_full() {
+                let head = shadow.publish(self.state.head, ctx)?;
+                self.state.pop_head_to(head)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Attempt to add a new entry to the Completion Queue.
     ///
     /// TODO: handle the case where the queue may be currently full.
     pub fn push(&mut self, entry: RawCompletion, ctx: &DispCtx) {
+        if self.state.is_full() {
+            // The host may have consumed entries without writing to the
+            // doorbell register itself. Any error leaves the queue full.
+            let _ = self.poll_shadow(ctx);
+        }
         if let Some((idx, wrapped)) = self.state.push_tail() {
             let mem = ctx.mctx.memctx();
             let addr = self.entry_addr(idx);
@@ -379,6 +697,8 @@ impl CompQueue {
             if wrapped {
                 self.phase = !self.phase;
             }
+            self.unsignaled += 1;
+            self.unsignaled_since.get_or_insert_with(Instant::now);
             // XXX: handle a guest addr that becomes unmapped later
             // XXX: figure out interrupts
         }
@@ -396,7 +716,43 @@ impl CompQueue {
 
     /// Fires an interrupt to the guest with the associated interrupt vector
     /// if the queue is not currently empty.
-    pub fn fire_interrupt(&self, ctx: &DispCtx) {
+    ///
+    /// If Interrupt Coalescing applies to this queue, the interrupt may instead
+    /// be held back until either enough entries have been aggregated or the
+    /// coalescing timer flushes it.
+    pub fn fire_interrupt(&mut self, ctx: &DispCtx) {
+        if let Some(coalescing) = &self.coalescing {
+            let (thr, time) = coalescing.params();
+            // A masked vector already holds delivery in its pending bit until
+            // it's unmasked, so there's nothing to gain by aggregating further
+            let masked = self.hdl.read(self.iv).masked;
+            if time != 0
+                && !masked
+                && !coalescing.is_disabled(self.iv)
+                // THR is 0's based
+                && self.unsignaled <= thr as u32
+            {
+                return;
+            }
+        }
+        self.signal(ctx);
+    }
+
+    /// Fires an interrupt for any entries which have been held back for at
+    /// least the given Aggregation Time.
+    fn flush_interrupt(&mut self, time: Duration, ctx: &DispCtx) {
+        let expired = self
+            .unsignaled_since
+            .map_or(false, |since| since.elapsed() >= time);
+        if expired {
+            self.signal(ctx);
+        }
+    }
+
+    /// Unconditionally fires an interrupt if the queue is not currently empty.
+    fn signal(&mut self, ctx: &DispCtx) {
+        self.unsignaled = 0;
+        self.unsignaled_since = None;
         if !self.state.is_empty() {
             self.hdl.fire(self.iv, ctx);
         }
@@ -4,6 +4,8 @@
 
 pub const LEN_CFG: usize = 0x100;
 pub const LEN_CFG_STD: usize = 0x40;
+/// Size of the extended config space, as accessed via ECAM (PCIe)
+pub const LEN_CFG_ECAM: usize = 0x1000;
 
 bitflags! {
     pub struct RegCmd: u16 {
@@ -38,8 +40,13 @@ pub const BAR_TYPE_MEM64: u32 = 0b100;
 
 pub const CAP_ID_MSI: u8 = 0x05;
 pub const CAP_ID_VENDOR: u8 = 0x09;
+pub const CAP_ID_PCIE: u8 = 0x10;
 pub const CAP_ID_MSIX: u8 = 0x11;
 
+pub const HEADER_TYPE_DEVICE: u8 = 0b0;
+pub const HEADER_TYPE_BRIDGE: u8 = 0b1;
+pub const HEADER_TYPE_MULTIFUNC: u8 = 0b1000_0000;
+
 pub const CLASS_UNCLASSIFIED: u8 = 0;
 pub const CLASS_STORAGE: u8 = 1;
 pub const CLASS_NETWORK: u8 = 2;
@@ -50,4 +57,9 @@ pub const CLASS_BRIDGE: u8 = 6;
 
 pub const SUBCLASS_NVM: u8 = 8;
 
+pub const SUBCLASS_BRIDGE_HOST: u8 = 0;
+pub const SUBCLASS_BRIDGE_ISA: u8 = 1;
+pub const SUBCLASS_BRIDGE_PCI: u8 = 4;
+pub const SUBCLASS_BRIDGE_OTHER: u8 = 0x80;
+
 pub const PROGIF_ENTERPRISE_NVME: u8 = 2;
@@ -0,0 +1,494 @@
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex};
+
+use super::bits::*;
+use super::pcie::{self, PcieCap, PortType};
+use super::{BarDefine, BarN, Bdf, Bus, Endpoint, INTxPinID, LintrRouter};
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::instance;
+use crate::intr_pins::IntrPin;
+use crate::inventory::Entity;
+use crate::util::regmap::{Flags, RegMap};
+
+use lazy_static::lazy_static;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum BridgeReg {
+    VendorId,
+    DeviceId,
+    Command,
+    Status,
+    RevisionId,
+    ProgIf,
+    Subclass,
+    Class,
+    CacheLineSize,
+    LatencyTimer,
+    HeaderType,
+    Bist,
+    Bar(BarN),
+    PrimaryBus,
+    SecondaryBus,
+    SubordinateBus,
+    SecondaryLatency,
+    IoBase,
+    IoLimit,
+    SecondaryStatus,
+    MemBase,
+    MemLimit,
+    PrefMemBase,
+    PrefMemLimit,
+    PrefBaseUpper,
+    PrefLimitUpper,
+    IoBaseUpper,
+    IoLimitUpper,
+    CapPtr,
+    Reserved,
+    ExpansionRomAddr,
+    IntrLine,
+    IntrPin,
+    BridgeControl,
+}
+lazy_static! {
+    static ref BRIDGE_CFG_MAP: RegMap<BridgeReg> = {
+        let layout = [
+            (BridgeReg::VendorId, 2),
+            (BridgeReg::DeviceId, 2),
+            (BridgeReg::Command, 2),
+            (BridgeReg::Status, 2),
+            (BridgeReg::RevisionId, 1),
+            (BridgeReg::ProgIf, 1),
+            (BridgeReg::Subclass, 1),
+            (BridgeReg::Class, 1),
+            (BridgeReg::CacheLineSize, 1),
+            (BridgeReg::LatencyTimer, 1),
+            (BridgeReg::HeaderType, 1),
+            (BridgeReg::Bist, 1),
+            (BridgeReg::Bar(BarN::BAR0), 4),
+            (BridgeReg::Bar(BarN::BAR1), 4),
+            (BridgeReg::PrimaryBus, 1),
+            (BridgeReg::SecondaryBus, 1),
+            (BridgeReg::SubordinateBus, 1),
+            (BridgeReg::SecondaryLatency, 1),
+            (BridgeReg::IoBase, 1),
+            (BridgeReg::IoLimit, 1),
+            (BridgeReg::SecondaryStatus, 2),
+            (BridgeReg::MemBase, 2),
+            (BridgeReg::MemLimit, 2),
+            (BridgeReg::PrefMemBase, 2),
+            (BridgeReg::PrefMemLimit, 2),
+            (BridgeReg::PrefBaseUpper, 4),
+            (BridgeReg::PrefLimitUpper, 4),
+            (BridgeReg::IoBaseUpper, 2),
+            (BridgeReg::IoLimitUpper, 2),
+            (BridgeReg::CapPtr, 1),
+            (BridgeReg::Reserved, 3),
+            (BridgeReg::ExpansionRomAddr, 4),
+            (BridgeReg::IntrLine, 1),
+            (BridgeReg::IntrPin, 1),
+            (BridgeReg::BridgeControl, 2),
+        ];
+        RegMap::create_packed(LEN_CFG_STD, &layout, Some(BridgeReg::Reserved))
+    };
+}
+
+enum CfgReg {
+    Std,
+    PcieId,
+    PcieNext,
+    PcieBody,
+    Unused,
+}
+
+/// Offset of the PCIe capability, when present, immediately following the
+/// standard header.
+const PCIE_CAP_OFFSET: usize = LEN_CFG_STD;
+
+/// Window base/limit registers only implement the upper 12 bits
+const MASK_WINDOW: u16 = 0xfff0;
+/// Low bits of the prefetchable window registers, indicating 64-bit support
+const PREF_WINDOW_64: u16 = 0b0001;
+const MASK_IO_WINDOW: u8 = 0xf0;
+
+#[derive(Default)]
+struct State {
+    reg_command: u16,
+    primary_bus: u8,
+    secondary_bus: u8,
+    subordinate_bus: u8,
+    io_base: u8,
+    io_limit: u8,
+    mem_base: u16,
+    mem_limit: u16,
+    pref_base: u16,
+    pref_limit: u16,
+    pref_base_upper: u32,
+    pref_limit_upper: u32,
+    intr_line: u8,
+    bridge_control: u16,
+}
+
+/// A PCI-to-PCI bridge, or PCIe root port, with its own secondary bus.
+///
+/// The bridge presents a type 1 config header to the guest.  Config accesses
+/// are forwarded downstream according to the bus numbers programmed by the
+/// guest, while the windows are recorded but not otherwise enforced: devices
+/// behind the bridge register their BARs directly with the machine.
+pub struct Bridge {
+    vendor_id: u16,
+    device_id: u16,
+    /// Bus number used to address the secondary bus when attaching devices,
+    /// independent of the numbering chosen by the guest.
+    logical_bus: u8,
+    pcie_cfg: Option<PcieCap>,
+    cfg_space: RegMap<CfgReg>,
+
+    state: Mutex<State>,
+    bus: Mutex<Bus>,
+    router: Mutex<Option<LintrRouter>>,
+}
+impl Bridge {
+    /// Create a conventional PCI-to-PCI bridge
+    pub fn new(vendor_id: u16, device_id: u16, logical_bus: u8) -> Arc<Self> {
+        Arc::new(Self::create(vendor_id, device_id, logical_bus, None))
+    }
+
+    /// Create a PCI Express root port, numbered `port_num` within the root
+    /// complex.
+    pub fn new_root_port(
+        vendor_id: u16,
+        device_id: u16,
+        logical_bus: u8,
+        port_num: u8,
+    ) -> Arc<Self> {
+        let cap = PcieCap::new(PortType::RootPort, port_num);
+        Arc::new(Self::create(vendor_id, device_id, logical_bus, Some(cap)))
+    }
+
+    fn create(
+        vendor_id: u16,
+        device_id: u16,
+        logical_bus: u8,
+        pcie_cfg: Option<PcieCap>,
+    ) -> Self {
+        let mut cfg_space = RegMap::new(LEN_CFG_ECAM);
+        cfg_space.define_with_flags(
+            0,
+            LEN_CFG_STD,
+            CfgReg::Std,
+            Flags::PASSTHRU,
+        );
+        let mut unused_start = LEN_CFG_STD;
+        if pcie_cfg.is_some() {
+            let body_len = pcie::CAP_PCIE_BODY_LEN as usize;
+            cfg_space.define(PCIE_CAP_OFFSET, 1, CfgReg::PcieId);
+            cfg_space.define(PCIE_CAP_OFFSET + 1, 1, CfgReg::PcieNext);
+            cfg_space.define_with_flags(
+                PCIE_CAP_OFFSET + 2,
+                body_len,
+                CfgReg::PcieBody,
+                Flags::PASSTHRU,
+            );
+            unused_start = PCIE_CAP_OFFSET + 2 + body_len;
+        }
+        cfg_space.define_with_flags(
+            unused_start,
+            LEN_CFG_ECAM - unused_start,
+            CfgReg::Unused,
+            Flags::PASSTHRU,
+        );
+
+        Self {
+            vendor_id,
+            device_id,
+            logical_bus,
+            pcie_cfg,
+            cfg_space,
+
+            state: Mutex::new(State::default()),
+            bus: Mutex::new(Bus::new()),
+            router: Mutex::new(None),
+        }
+    }
+
+    /// Bus number by which devices on the secondary bus are attached.
+    pub fn logical_bus(&self) -> u8 {
+        self.logical_bus
+    }
+
+    /// Connect the bridge to the interrupt routing of its upstream bus.
+    ///
+    /// This must occur before any devices are attached behind the bridge.
+    pub fn set_router(&self, router: LintrRouter) {
+        *self.router.lock().unwrap() = Some(router);
+    }
+
+    /// Attach a device to the secondary bus of this bridge.
+    ///
+    /// Its INTx pins are swizzled onto those of the bridge slot, as are those
+    /// of any devices behind it, should it also be a bridge.
+    ///
+    /// Fails if `slot` is not 0 for a PCIe root port, which has a single
+    /// link.
+    ///
+    /// # Panics
+    ///
+    /// If the bridge has not been connected upstream via [`Bridge::set_router`].
+    pub fn attach_device(
+        &self,
+        slot: u8,
+        func: u8,
+        dev: Arc<dyn Endpoint>,
+    ) -> Result<()> {
+        let router = self.router.lock().unwrap().clone().unwrap();
+        if let Some(pcie) = self.pcie_cfg.as_ref() {
+            if slot != 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "root port for bus {} has no slot {}",
+                        self.logical_bus, slot
+                    ),
+                ));
+            }
+            pcie.set_occupied(true);
+        }
+
+        if let Some(bridge) = dev.as_bridge() {
+            let upstream = Arc::clone(&router);
+            bridge.set_router(Arc::new(move |pin: INTxPinID| {
+                upstream(pin.swizzle(slot))
+            }));
+        }
+        dev.attach(&|| {
+            let pin = INTxPinID::for_func(func);
+            (pin, router(pin.swizzle(slot)))
+        });
+        self.bus.lock().unwrap().attach(slot, func, dev);
+        Ok(())
+    }
+
+    /// Find the device at `bdf` on the secondary bus, or behind any bridges
+    /// attached to it.
+    pub fn find(&self, bdf: &Bdf) -> Option<Arc<dyn Endpoint>> {
+        let state = self.state.lock().unwrap();
+        let (secondary, subordinate) =
+            (state.secondary_bus, state.subordinate_bus);
+        drop(state);
+
+        // Bus 0 is always the root, so a secondary bus number of 0 indicates
+        // the bridge has yet to be configured.
+        if secondary == 0 || bdf.bus() < secondary || bdf.bus() > subordinate {
+            return None;
+        }
+        self.bus.lock().unwrap().find(secondary, bdf)
+    }
+
+    /// Find the bridge behind this one which provides the logical bus `bus`.
+    pub fn find_bridge(&self, bus: u8) -> Option<Arc<dyn Endpoint>> {
+        self.bus.lock().unwrap().find_bridge(bus)
+    }
+
+    fn cfg_std_read(&self, id: &BridgeReg, ro: &mut ReadOp) {
+        let state = self.state.lock().unwrap();
+        match id {
+            BridgeReg::VendorId => ro.write_u16(self.vendor_id),
+            BridgeReg::DeviceId => ro.write_u16(self.device_id),
+            BridgeReg::Command => ro.write_u16(state.reg_command),
+            BridgeReg::Status => {
+                let mut val = RegStatus::empty();
+                if self.pcie_cfg.is_some() {
+                    val.insert(RegStatus::CAP_LIST);
+                }
+                ro.write_u16(val.bits());
+            }
+            BridgeReg::Class => ro.write_u8(CLASS_BRIDGE),
+            BridgeReg::Subclass => ro.write_u8(SUBCLASS_BRIDGE_PCI),
+            BridgeReg::HeaderType => ro.write_u8(HEADER_TYPE_BRIDGE),
+            BridgeReg::PrimaryBus => ro.write_u8(state.primary_bus),
+            BridgeReg::SecondaryBus => ro.write_u8(state.secondary_bus),
+            BridgeReg::SubordinateBus => ro.write_u8(state.subordinate_bus),
+            BridgeReg::IoBase => ro.write_u8(state.io_base),
+            BridgeReg::IoLimit => ro.write_u8(state.io_limit),
+            BridgeReg::MemBase => ro.write_u16(state.mem_base),
+            BridgeReg::MemLimit => ro.write_u16(state.mem_limit),
+            BridgeReg::PrefMemBase => {
+                ro.write_u16(state.pref_base | PREF_WINDOW_64)
+            }
+            BridgeReg::PrefMemLimit => {
+                ro.write_u16(state.pref_limit | PREF_WINDOW_64)
+            }
+            BridgeReg::PrefBaseUpper => ro.write_u32(state.pref_base_upper),
+            BridgeReg::PrefLimitUpper => ro.write_u32(state.pref_limit_upper),
+            BridgeReg::CapPtr => {
+                if self.pcie_cfg.is_some() {
+                    ro.write_u8(PCIE_CAP_OFFSET as u8);
+                } else {
+                    ro.write_u8(0);
+                }
+            }
+            BridgeReg::IntrLine => ro.write_u8(state.intr_line),
+            BridgeReg::BridgeControl => ro.write_u16(state.bridge_control),
+
+            BridgeReg::RevisionId
+            | BridgeReg::ProgIf
+            | BridgeReg::CacheLineSize
+            | BridgeReg::LatencyTimer
+            | BridgeReg::Bist
+            | BridgeReg::Bar(_)
+            | BridgeReg::SecondaryLatency
+            | BridgeReg::SecondaryStatus
+            | BridgeReg::IoBaseUpper
+            | BridgeReg::IoLimitUpper
+            | BridgeReg::Reserved
+            | BridgeReg::ExpansionRomAddr
+            | BridgeReg::IntrPin => {
+                // Only 16-bit IO windows, no BARs, ROM, or interrupt of
+                // its own: zeroed
+                ro.fill(0);
+            }
+        }
+    }
+
+    fn cfg_std_write(&self, id: &BridgeReg, wo: &mut WriteOp) {
+        let mut state = self.state.lock().unwrap();
+        match id {
+            BridgeReg::Command => {
+                let val = RegCmd::from_bits_truncate(wo.read_u16());
+                state.reg_command = val.bits();
+            }
+            BridgeReg::PrimaryBus => state.primary_bus = wo.read_u8(),
+            BridgeReg::SecondaryBus => state.secondary_bus = wo.read_u8(),
+            BridgeReg::SubordinateBus => state.subordinate_bus = wo.read_u8(),
+            BridgeReg::IoBase => state.io_base = wo.read_u8() & MASK_IO_WINDOW,
+            BridgeReg::IoLimit => {
+                state.io_limit = wo.read_u8() & MASK_IO_WINDOW
+            }
+            BridgeReg::MemBase => state.mem_base = wo.read_u16() & MASK_WINDOW,
+            BridgeReg::MemLimit => {
+                state.mem_limit = wo.read_u16() & MASK_WINDOW
+            }
+            BridgeReg::PrefMemBase => {
+                state.pref_base = wo.read_u16() & MASK_WINDOW
+            }
+            BridgeReg::PrefMemLimit => {
+                state.pref_limit = wo.read_u16() & MASK_WINDOW
+            }
+            BridgeReg::PrefBaseUpper => state.pref_base_upper = wo.read_u32(),
+            BridgeReg::PrefLimitUpper => state.pref_limit_upper = wo.read_u32(),
+            BridgeReg::IntrLine => state.intr_line = wo.read_u8(),
+            BridgeReg::BridgeControl => state.bridge_control = wo.read_u16(),
+            _ => {
+                // Everything else is read-only
+            }
+        }
+    }
+
+    fn reset(&self) {
+        *self.state.lock().unwrap() = State::default();
+        if let Some(pcie) = self.pcie_cfg.as_ref() {
+            pcie.reset();
+        }
+    }
+}
+
+impl Endpoint for Bridge {
+    fn cfg_rw(&self, mut rwo: RWOp, _ctx: &DispCtx) {
+        self.cfg_space.process(&mut rwo, |id, mut rwo| match id {
+            CfgReg::Std => {
+                BRIDGE_CFG_MAP.process(&mut rwo, |id, rwo| match rwo {
+                    RWOp::Read(ro) => self.cfg_std_read(id, ro),
+                    RWOp::Write(wo) => self.cfg_std_write(id, wo),
+                });
+            }
+            CfgReg::PcieId => {
+                if let RWOp::Read(ro) = rwo {
+                    ro.write_u8(CAP_ID_PCIE);
+                }
+            }
+            CfgReg::PcieNext => {
+                if let RWOp::Read(ro) = rwo {
+                    ro.write_u8(0);
+                }
+            }
+            CfgReg::PcieBody => self.pcie_cfg.as_ref().unwrap().cfg_rw(rwo),
+            CfgReg::Unused => {
+                if let RWOp::Read(ro) = rwo {
+                    ro.fill(0);
+                }
+            }
+        });
+    }
+    fn attach(&self, _get_lintr: &dyn Fn() -> (INTxPinID, Arc<dyn IntrPin>)) {
+        // The bridge itself does not raise interrupts
+    }
+    fn bar_for_each(&self, _cb: &mut dyn FnMut(BarN, &BarDefine)) {}
+    fn bar_place(&self, _bar: BarN, _addr: u64) {
+        panic!("bridge has no BARs");
+    }
+    fn as_devinst(&self) -> Option<&super::DeviceInst> {
+        None
+    }
+    fn as_bridge(&self) -> Option<&Bridge> {
+        Some(self)
+    }
+}
+
+impl Entity for Bridge {
+    fn state_transition(
+        &self,
+        next: instance::State,
+        _target: Option<instance::State>,
+        _ctx: &DispCtx,
+    ) {
+        if matches!(next, instance::State::Reset) {
+            self.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_std(bridge: &Bridge, id: BridgeReg) -> u16 {
+        let mut buf = [0u8; 2];
+        let mut ro = ReadOp::from_buf(0, &mut buf);
+        bridge.cfg_std_read(&id, &mut ro);
+        u16::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn header() {
+        let bridge = Bridge::new(0x1b36, 0x0001, 1);
+        assert_eq!(read_std(&bridge, BridgeReg::VendorId), 0x1b36);
+        assert_eq!(
+            read_std(&bridge, BridgeReg::HeaderType) as u8,
+            HEADER_TYPE_BRIDGE
+        );
+        assert_eq!(read_std(&bridge, BridgeReg::CapPtr) as u8, 0);
+
+        let port = Bridge::new_root_port(0x1b36, 0x000c, 1, 1);
+        assert_eq!(read_std(&port, BridgeReg::CapPtr) as u8, 0x40);
+    }
+
+    #[test]
+    fn find_by_bus_number() {
+        let bridge = Bridge::new(0x1b36, 0x0001, 1);
+        let bdf = Bdf::new(1, 0, 0);
+
+        // Unconfigured bridges forward nothing
+        assert!(bridge.find(&bdf).is_none());
+
+        let mut state = bridge.state.lock().unwrap();
+        state.secondary_bus = 1;
+        state.subordinate_bus = 1;
+        drop(state);
+        assert!(bridge.find(&bdf).is_none());
+        assert!(bridge.find(&Bdf::new(2, 0, 0)).is_none());
+
+        bridge.reset();
+        assert_eq!(bridge.state.lock().unwrap().secondary_bus, 0);
+    }
+}
@@ -4,6 +4,7 @@ use std::marker::PhantomData;
 use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
 
 use super::bits::*;
+use super::pcie::{self, PcieCap, PortType};
 use super::{Endpoint, INTxPinID};
 use crate::common::*;
 use crate::dispatch::DispCtx;
@@ -24,6 +25,8 @@ enum CfgReg {
     CapId(u8),
     CapNext(u8),
     CapBody(u8),
+    /// Extended (PCIe) config space beyond the first 256 bytes
+    Extended,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -342,6 +345,7 @@ pub struct DeviceInst {
     lintr_req: bool,
     cfg_space: RegMap<CfgReg>,
     msix_cfg: Option<Arc<MsixCfg>>,
+    pcie_cfg: Option<Arc<PcieCap>>,
     caps: Vec<Cap>,
 
     state: Mutex<State>,
@@ -360,6 +364,7 @@ impl DeviceInst {
         ident: Ident,
         cfg_space: RegMap<CfgReg>,
         msix_cfg: Option<Arc<MsixCfg>>,
+        pcie_cfg: Option<Arc<PcieCap>>,
         caps: Vec<Cap>,
         bars: Bars,
         inner: Arc<dyn Device>,
@@ -370,6 +375,7 @@ impl DeviceInst {
             lintr_req: false,
             cfg_space,
             msix_cfg,
+            pcie_cfg,
             caps,
 
             state: Mutex::new(State {
@@ -722,6 +728,9 @@ impl DeviceInst {
                     );
                 }
             }
+            CAP_ID_PCIE => {
+                self.pcie_cfg.as_ref().unwrap().cfg_rw(rwo);
+            }
             _ => {
                 println!(
                     "unhandled cap access id:{:x} off:{:x}",
@@ -742,6 +751,11 @@ impl DeviceInst {
         let inner = Arc::clone(&self.inner_any);
         f(Arc::downcast(inner).unwrap())
     }
+    /// Release the BARs and interrupt state of the device, as it is removed
+    /// from its bus.
+    pub fn detach(&self, ctx: &DispCtx) {
+        self.do_reset(ctx);
+    }
     fn do_reset(&self, ctx: &DispCtx) {
         let state = self.state.lock().unwrap();
         self.affects_intr_mode(state, |state| {
@@ -750,6 +764,9 @@ impl DeviceInst {
                 msix.reset();
             }
         });
+        if let Some(pcie) = &self.pcie_cfg {
+            pcie.reset();
+        }
         self.bars.reset(
             |_bar, _def, _addr| {
                 // TODO: notify device of unregistered BARs
@@ -772,6 +789,12 @@ impl Endpoint for DeviceInst {
             CfgReg::CapId(_) | CfgReg::CapNext(_) | CfgReg::CapBody(_) => {
                 self.cfg_cap_rw(id, rwo, ctx)
             }
+            CfgReg::Extended => {
+                // No extended capabilities are exposed
+                if let RWOp::Read(ro) = rwo {
+                    ro.fill(0);
+                }
+            }
         });
     }
     fn attach(&self, get_lintr: &dyn Fn() -> (INTxPinID, Arc<dyn IntrPin>)) {
@@ -830,6 +853,9 @@ impl Entity for DeviceInst {
         }
         self.inner.state_transition(next, target, ctx);
     }
+    fn unplug(&self, ctx: &DispCtx) {
+        self.inner.unplug(ctx);
+    }
 }
 
 impl SelfArc for DeviceInst {
@@ -1280,6 +1306,7 @@ pub struct Builder<I> {
     ident: Ident,
     lintr_req: bool,
     msix_cfg: Option<Arc<MsixCfg>>,
+    pcie_cfg: Option<Arc<PcieCap>>,
     bars: [Option<BarDefine>; 6],
     cfgmap: RegMap<CfgReg>,
 
@@ -1291,12 +1318,19 @@ pub struct Builder<I> {
 
 impl<I: Device + 'static> Builder<I> {
     pub fn new(ident: Ident) -> Self {
-        let mut cfgmap = RegMap::new(LEN_CFG);
+        let mut cfgmap = RegMap::new(LEN_CFG_ECAM);
         cfgmap.define_with_flags(0, LEN_CFG_STD, CfgReg::Std, Flags::PASSTHRU);
+        cfgmap.define_with_flags(
+            LEN_CFG,
+            LEN_CFG_ECAM - LEN_CFG,
+            CfgReg::Extended,
+            Flags::PASSTHRU,
+        );
         Self {
             ident,
             lintr_req: false,
             msix_cfg: None,
+            pcie_cfg: None,
             bars: [None; 6],
             cfgmap,
 
@@ -1419,6 +1453,20 @@ impl<I: Device + 'static> Builder<I> {
         self
     }
 
+    /// Add a PCI Express capability, identifying the device as `port_type`.
+    ///
+    /// # Panics
+    ///
+    /// If a PCIe capability has already been added.
+    pub fn add_cap_pcie(mut self, port_type: PortType) -> Self {
+        assert!(self.pcie_cfg.is_none());
+
+        self.pcie_cfg = Some(Arc::new(PcieCap::new(port_type, 0)));
+        self.add_cap_raw(CAP_ID_PCIE, pcie::CAP_PCIE_BODY_LEN);
+
+        self
+    }
+
     pub fn finish(self, inner: Arc<I>) -> Arc<DeviceInst> {
         let bars = Bars::new(&self.bars);
 
@@ -1429,6 +1477,7 @@ impl<I: Device + 'static> Builder<I> {
             self.ident,
             self.cfgmap,
             self.msix_cfg,
+            self.pcie_cfg,
             self.caps,
             bars,
             inner,
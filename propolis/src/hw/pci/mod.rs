@@ -9,8 +9,11 @@ use crate::dispatch::DispCtx;
 use crate::intr_pins::IntrPin;
 
 pub mod bits;
+mod bridge;
 mod device;
+pub mod pcie;
 
+pub use bridge::Bridge;
 pub use device::*;
 
 pub const PORT_PCI_CONFIG_ADDR: u16 = 0xcf8;
@@ -116,6 +119,27 @@ pub enum INTxPinID {
     IntC = 3,
     IntD = 4,
 }
+impl INTxPinID {
+    /// The INTx pin used by a given function of a multi-function device.
+    pub fn for_func(func: u8) -> Self {
+        match func % 4 {
+            0 => INTxPinID::IntA,
+            1 => INTxPinID::IntB,
+            2 => INTxPinID::IntC,
+            _ => INTxPinID::IntD,
+        }
+    }
+
+    /// The pin on the upstream side of a bridge to which this pin, belonging
+    /// to a device in slot `dev` on the secondary bus, is connected.
+    pub fn swizzle(self, dev: u8) -> Self {
+        Self::for_func((self as u8 - 1 + dev) % 4)
+    }
+}
+
+/// Routes an INTx pin of a device (or of devices behind a bridge) to the
+/// interrupt pin it is connected to upstream.
+pub type LintrRouter = Arc<dyn Fn(INTxPinID) -> Arc<dyn IntrPin> + Send + Sync>;
 
 pub trait Endpoint: Send + Sync {
     fn cfg_rw(&self, op: RWOp<'_, '_>, ctx: &DispCtx);
@@ -123,6 +147,9 @@ pub trait Endpoint: Send + Sync {
     fn bar_for_each(&self, cb: &mut dyn FnMut(BarN, &BarDefine));
     fn bar_place(&self, bar: BarN, addr: u64);
     fn as_devinst(&self) -> Option<&DeviceInst>;
+    fn as_bridge(&self) -> Option<&Bridge> {
+        None
+    }
 }
 
 const SLOTS_PER_BUS: usize = 32;
@@ -152,6 +179,14 @@ impl Bus {
         self.slots[slot as usize].funcs[func as usize] = Some(dev);
     }
 
+    /// Remove the device (if any) attached at `slot` and `func`.
+    pub fn detach(&mut self, slot: u8, func: u8) -> Option<Arc<dyn Endpoint>> {
+        assert!((slot as usize) < SLOTS_PER_BUS);
+        assert!((func as usize) < FUNCS_PER_SLOT);
+
+        self.slots[slot as usize].funcs[func as usize].take()
+    }
+
     pub fn iter(&self) -> Iter {
         Iter::new(self)
     }
@@ -162,6 +197,33 @@ impl Bus {
 
         self.slots[slot as usize].funcs[func as usize].as_ref()
     }
+
+    /// Find the device at `bdf`, where this bus is numbered `bus_num`.
+    ///
+    /// Devices on other buses are located by descending through any bridges
+    /// attached to this one, according to the bus numbers which the guest has
+    /// programmed into them.
+    pub fn find(&self, bus_num: u8, bdf: &Bdf) -> Option<Arc<dyn Endpoint>> {
+        if bdf.bus() == bus_num {
+            return self.device_at(bdf.dev(), bdf.func()).map(Arc::clone);
+        }
+        self.iter().find_map(|(_slot, _func, dev)| {
+            dev.as_bridge().and_then(|bridge| bridge.find(bdf))
+        })
+    }
+
+    /// Find the bridge, attached to this bus or to any bridge behind it, which
+    /// provides the logical bus `bus` for device attachment.
+    pub fn find_bridge(&self, bus: u8) -> Option<Arc<dyn Endpoint>> {
+        self.iter().find_map(|(_slot, _func, dev)| {
+            let bridge = dev.as_bridge()?;
+            if bridge.logical_bus() == bus {
+                Some(Arc::clone(dev))
+            } else {
+                bridge.find_bridge(bus)
+            }
+        })
+    }
 }
 
 pub struct Iter<'a> {
@@ -212,6 +274,16 @@ fn cfg_addr_parse(addr: u32) -> Option<(Bdf, u8)> {
     }
 }
 
+/// Decode an offset into an ECAM (memory-mapped config) region into the
+/// addressed function and the offset into its config space.
+pub fn ecam_decode(off: usize) -> (Bdf, usize) {
+    let bus = (off >> 20) as u8 & MASK_BUS;
+    let device = (off >> 15) as u8 & MASK_DEV;
+    let func = (off >> 12) as u8 & MASK_FUNC;
+
+    (Bdf::new(bus, device, func), off & (bits::LEN_CFG_ECAM - 1))
+}
+
 pub struct PioCfgDecoder {
     addr: Mutex<u32>,
 }
@@ -256,3 +328,43 @@ impl PioCfgDecoder {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ecam_decoding() {
+        assert_eq!(ecam_decode(0), (Bdf::new(0, 0, 0), 0));
+        assert_eq!(ecam_decode(0xf_8104), (Bdf::new(0, 0x1f, 0), 0x104));
+        assert_eq!(ecam_decode(0x0ff_ffffc), (Bdf::new(0xff, 0x1f, 7), 0xffc));
+    }
+
+    #[test]
+    fn bridge_lookup() {
+        let mut bus = Bus::new();
+        bus.attach(3, 0, Bridge::new(0x1b36, 0x0001, 7));
+
+        let found = bus.find_bridge(7).unwrap();
+        assert_eq!(found.as_bridge().unwrap().logical_bus(), 7);
+        assert!(bus.find_bridge(1).is_none());
+    }
+
+    #[test]
+    fn detach() {
+        let mut bus = Bus::new();
+        bus.attach(3, 0, Bridge::new(0x1b36, 0x0001, 7));
+
+        assert!(bus.detach(3, 0).is_some());
+        assert!(bus.device_at(3, 0).is_none());
+        assert!(bus.detach(3, 0).is_none());
+    }
+
+    #[test]
+    fn intx_swizzle() {
+        assert!(matches!(INTxPinID::IntA.swizzle(0), INTxPinID::IntA));
+        assert!(matches!(INTxPinID::IntA.swizzle(1), INTxPinID::IntB));
+        assert!(matches!(INTxPinID::IntD.swizzle(1), INTxPinID::IntA));
+        assert!(matches!(INTxPinID::IntC.swizzle(6), INTxPinID::IntA));
+    }
+}
@@ -0,0 +1,285 @@
+//! PCI Express capability structure.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::common::*;
+use crate::util::regmap::RegMap;
+
+use lazy_static::lazy_static;
+
+/// Length of the PCIe capability body, excluding the ID and next pointer.
+pub const CAP_PCIE_BODY_LEN: u8 = 0x3a;
+
+/// Device/port type reported in the PCIe capabilities register.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(u8)]
+pub enum PortType {
+    Endpoint = 0b0000,
+    LegacyEndpoint = 0b0001,
+    RootPort = 0b0100,
+    RcIntegrated = 0b1001,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum PcieReg {
+    Capabilities,
+    DevCap,
+    DevCtl,
+    DevSts,
+    LinkCap,
+    LinkCtl,
+    LinkSts,
+    SlotCap,
+    SlotCtl,
+    SlotSts,
+    RootCtl,
+    RootCap,
+    RootSts,
+    DevCap2,
+    DevCtl2,
+    DevSts2,
+    LinkCap2,
+    LinkCtl2,
+    LinkSts2,
+    SlotCap2,
+    SlotCtl2,
+    SlotSts2,
+}
+lazy_static! {
+    static ref CAP_PCIE_MAP: RegMap<PcieReg> = {
+        // Offsets are relative to the start of the capability body, which
+        // follows the ID and next-pointer bytes.
+        let layout = [
+            (PcieReg::Capabilities, 2),
+            (PcieReg::DevCap, 4),
+            (PcieReg::DevCtl, 2),
+            (PcieReg::DevSts, 2),
+            (PcieReg::LinkCap, 4),
+            (PcieReg::LinkCtl, 2),
+            (PcieReg::LinkSts, 2),
+            (PcieReg::SlotCap, 4),
+            (PcieReg::SlotCtl, 2),
+            (PcieReg::SlotSts, 2),
+            (PcieReg::RootCtl, 2),
+            (PcieReg::RootCap, 2),
+            (PcieReg::RootSts, 4),
+            (PcieReg::DevCap2, 4),
+            (PcieReg::DevCtl2, 2),
+            (PcieReg::DevSts2, 2),
+            (PcieReg::LinkCap2, 4),
+            (PcieReg::LinkCtl2, 2),
+            (PcieReg::LinkSts2, 2),
+            (PcieReg::SlotCap2, 4),
+            (PcieReg::SlotCtl2, 2),
+            (PcieReg::SlotSts2, 2),
+        ];
+        RegMap::create_packed(CAP_PCIE_BODY_LEN as usize, &layout, None)
+    };
+}
+
+const CAP_VERSION: u16 = 2;
+const CAP_SLOT_IMPL: u16 = 1 << 8;
+
+const DEVCAP_ROLE_ERR: u32 = 1 << 15;
+
+/// Relaxed ordering, no snoop, 512-byte max read request
+const DEVCTL_DEFAULT: u16 = 0x2810;
+
+/// 2.5GT/s link speed
+const LINK_SPEED_GEN1: u32 = 0b0001;
+/// x1 link width
+const LINK_WIDTH_X1: u32 = 0b0001 << 4;
+const LINKCAP_DLL_ACTIVE_REPORT: u32 = 1 << 20;
+const LINKSTS_DLL_ACTIVE: u16 = 1 << 13;
+/// Supported link speeds vector: 2.5GT/s only
+const LINKCAP2_SPEEDS: u32 = 0b10;
+
+const SLOTSTS_PRESENCE: u16 = 1 << 6;
+
+#[derive(Default)]
+struct PcieCapState {
+    dev_ctl: u16,
+    link_ctl: u16,
+    slot_ctl: u16,
+    root_ctl: u16,
+    dev_ctl2: u16,
+    link_ctl2: u16,
+}
+impl PcieCapState {
+    fn new() -> Self {
+        Self {
+            dev_ctl: DEVCTL_DEFAULT,
+            link_ctl2: LINK_SPEED_GEN1 as u16,
+            ..Default::default()
+        }
+    }
+}
+
+/// Emulated PCI Express capability structure.
+///
+/// The link is presented as a fixed 2.5GT/s x1 connection, with no support for
+/// advanced error reporting or power management beyond what the guest can
+/// store in the control registers.
+pub struct PcieCap {
+    port_type: PortType,
+    port_num: u8,
+    state: Mutex<PcieCapState>,
+    occupied: AtomicBool,
+}
+impl PcieCap {
+    pub fn new(port_type: PortType, port_num: u8) -> Self {
+        Self {
+            port_type,
+            port_num,
+            state: Mutex::new(PcieCapState::new()),
+            occupied: AtomicBool::new(false),
+        }
+    }
+
+    pub fn port_type(&self) -> PortType {
+        self.port_type
+    }
+
+    /// Record whether a device is present downstream of this (root) port.
+    pub fn set_occupied(&self, occupied: bool) {
+        self.occupied.store(occupied, Ordering::Release);
+    }
+
+    fn is_root_port(&self) -> bool {
+        self.port_type == PortType::RootPort
+    }
+
+    fn link_cap(&self) -> u32 {
+        let mut val = LINK_SPEED_GEN1 | LINK_WIDTH_X1;
+        if self.is_root_port() {
+            val |= LINKCAP_DLL_ACTIVE_REPORT;
+        }
+        val | (self.port_num as u32) << 24
+    }
+
+    fn link_sts(&self) -> u16 {
+        let mut val = (LINK_SPEED_GEN1 | LINK_WIDTH_X1) as u16;
+        if self.is_root_port() && self.occupied.load(Ordering::Acquire) {
+            val |= LINKSTS_DLL_ACTIVE;
+        }
+        val
+    }
+
+    pub fn cfg_rw(&self, mut rwo: RWOp) {
+        CAP_PCIE_MAP.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.reg_read(id, ro),
+            RWOp::Write(wo) => self.reg_write(id, wo),
+        });
+    }
+
+    fn reg_read(&self, id: &PcieReg, ro: &mut ReadOp) {
+        let state = self.state.lock().unwrap();
+        match id {
+            PcieReg::Capabilities => {
+                let mut val = CAP_VERSION | (self.port_type as u16) << 4;
+                if self.is_root_port() {
+                    val |= CAP_SLOT_IMPL;
+                }
+                ro.write_u16(val);
+            }
+            PcieReg::DevCap => ro.write_u32(DEVCAP_ROLE_ERR),
+            PcieReg::DevCtl => ro.write_u16(state.dev_ctl),
+            PcieReg::LinkCap => ro.write_u32(self.link_cap()),
+            PcieReg::LinkCtl => ro.write_u16(state.link_ctl),
+            PcieReg::LinkSts => ro.write_u16(self.link_sts()),
+            PcieReg::SlotCap if self.is_root_port() => {
+                // Physical slot number matches the port number
+                ro.write_u32((self.port_num as u32) << 19);
+            }
+            PcieReg::SlotCtl if self.is_root_port() => {
+                ro.write_u16(state.slot_ctl)
+            }
+            PcieReg::SlotSts if self.is_root_port() => {
+                let occupied = self.occupied.load(Ordering::Acquire);
+                ro.write_u16(if occupied { SLOTSTS_PRESENCE } else { 0 });
+            }
+            PcieReg::RootCtl if self.is_root_port() => {
+                ro.write_u16(state.root_ctl)
+            }
+            PcieReg::DevCtl2 => ro.write_u16(state.dev_ctl2),
+            PcieReg::LinkCap2 => ro.write_u32(LINKCAP2_SPEEDS),
+            PcieReg::LinkCtl2 => ro.write_u16(state.link_ctl2),
+            _ => {
+                // Status registers have nothing to report, and slot/root
+                // registers are only implemented for root ports.
+                ro.fill(0);
+            }
+        }
+    }
+
+    fn reg_write(&self, id: &PcieReg, wo: &mut WriteOp) {
+        let mut state = self.state.lock().unwrap();
+        match id {
+            PcieReg::DevCtl => state.dev_ctl = wo.read_u16(),
+            PcieReg::LinkCtl => state.link_ctl = wo.read_u16(),
+            PcieReg::SlotCtl if self.is_root_port() => {
+                state.slot_ctl = wo.read_u16()
+            }
+            PcieReg::RootCtl if self.is_root_port() => {
+                state.root_ctl = wo.read_u16()
+            }
+            PcieReg::DevCtl2 => state.dev_ctl2 = wo.read_u16(),
+            PcieReg::LinkCtl2 => state.link_ctl2 = wo.read_u16(),
+            _ => {
+                // Everything else is read-only or W1C with nothing to clear
+            }
+        }
+    }
+
+    pub fn reset(&self) {
+        *self.state.lock().unwrap() = PcieCapState::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_u16(cap: &PcieCap, off: usize) -> u16 {
+        let mut buf = [0u8; 2];
+        let mut ro = ReadOp::from_buf(off, &mut buf);
+        cap.cfg_rw(RWOp::Read(&mut ro));
+        u16::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn port_type() {
+        let ep = PcieCap::new(PortType::Endpoint, 0);
+        assert_eq!(read_u16(&ep, 0), 0x0002);
+
+        let rp = PcieCap::new(PortType::RootPort, 1);
+        assert_eq!(read_u16(&rp, 0), 0x0142);
+    }
+
+    #[test]
+    fn root_port_presence() {
+        // Slot status lives at 0x1a in the capability, 0x18 in its body
+        let rp = PcieCap::new(PortType::RootPort, 1);
+        assert_eq!(read_u16(&rp, 0x18), 0);
+        rp.set_occupied(true);
+        assert_eq!(read_u16(&rp, 0x18), SLOTSTS_PRESENCE);
+
+        // Endpoints have no slot to report on
+        let ep = PcieCap::new(PortType::Endpoint, 0);
+        ep.set_occupied(true);
+        assert_eq!(read_u16(&ep, 0x18), 0);
+    }
+
+    #[test]
+    fn ctl_reset() {
+        let ep = PcieCap::new(PortType::Endpoint, 0);
+        let buf = 0x1234u16.to_le_bytes();
+        let mut wo = WriteOp::from_buf(0x6, &buf);
+        ep.cfg_rw(RWOp::Write(&mut wo));
+        assert_eq!(read_u16(&ep, 0x6), 0x1234);
+
+        ep.reset();
+        assert_eq!(read_u16(&ep, 0x6), DEVCTL_DEFAULT);
+    }
+}
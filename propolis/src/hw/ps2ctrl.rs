@@ -109,6 +109,28 @@ impl PS2Ctrl {
         state.aux_pin = Some(chipset.irq_pin(ibmpc::IRQ_PS2_AUX).unwrap());
     }
 
+    /// Injects a key press or release from the host.
+    ///
+    /// Keys are identified by their scan code set 1 "make" code, with
+    /// extended keys carrying the `0xe0` prefix in the upper byte (e.g.
+    /// `0xe048` for the Up arrow).  The code is translated as necessary for
+    /// the scan code set the guest has selected.
+    pub fn key_event(&self, code: u16, pressed: bool) {
+        let mut state = self.state.lock().unwrap();
+        let xlate = state.ctrl_cfg.contains(CtrlCfg::PRI_XLATE_EN);
+        state.pri_port.key_event(code, pressed, xlate);
+        self.update_intr(&mut state);
+    }
+
+    /// Injects mouse movement and button state from the host.
+    ///
+    /// Movement is relative, with positive `dy` moving the pointer up.
+    pub fn mouse_event(&self, dx: i32, dy: i32, buttons: PS2MButtons) {
+        let mut state = self.state.lock().unwrap();
+        state.aux_port.mouse_event(dx, dy, buttons);
+        self.update_intr(&mut state);
+    }
+
     fn data_write(&self, v: u8) {
         let mut state = self.state.lock().unwrap();
         let cmd_prefix = replace(&mut state.cmd_prefix, None);
@@ -297,9 +319,73 @@ const PS2K_R_SELF_TEST_PASS: u8 = 0xaa;
 
 const PS2K_TYPEMATIC_MASK: u8 = 0x7f;
 
+const PS2K_EXT_PREFIX: u8 = 0xe0;
+const PS2K_SET1_BREAK: u8 = 0x80;
+const PS2K_SET2_BREAK_PREFIX: u8 = 0xf0;
+
+// Translation of scan code set 1 make codes (the index) into their set 2
+// equivalents.  Extended keys use the same translation after their 0xe0 prefix.
+const SET1_TO_SET2: [u8; 0x5e] = [
+    0x00, 0x76, 0x16, 0x1e, 0x26, 0x25, 0x2e, 0x36, // 0x00
+    0x3d, 0x3e, 0x46, 0x45, 0x4e, 0x55, 0x66, 0x0d, // 0x08
+    0x15, 0x1d, 0x24, 0x2d, 0x2c, 0x35, 0x3c, 0x43, // 0x10
+    0x44, 0x4d, 0x54, 0x5b, 0x5a, 0x14, 0x1c, 0x1b, // 0x18
+    0x23, 0x2b, 0x34, 0x33, 0x3b, 0x42, 0x4b, 0x4c, // 0x20
+    0x52, 0x0e, 0x12, 0x5d, 0x1a, 0x22, 0x21, 0x2a, // 0x28
+    0x32, 0x31, 0x3a, 0x41, 0x49, 0x4a, 0x59, 0x7c, // 0x30
+    0x11, 0x29, 0x58, 0x05, 0x06, 0x04, 0x0c, 0x03, // 0x38
+    0x0b, 0x83, 0x0a, 0x01, 0x09, 0x77, 0x7e, 0x6c, // 0x40
+    0x75, 0x7d, 0x7b, 0x6b, 0x73, 0x74, 0x79, 0x69, // 0x48
+    0x72, 0x7a, 0x70, 0x71, 0x84, 0x00, 0x61, 0x78, // 0x50
+    0x07, 0x00, 0x00, 0x1f, 0x27, 0x2f, // 0x58
+];
+
 const PS2_KBD_BUFSZ: usize = 16;
+/// Limit on key data injected by the host which is waiting for room in the
+/// keyboard buffer.
+const PS2K_PENDING_MAX: usize = 1024;
+
+/// Decodes a sequence of scan codes into (set 1 make code, pressed) events,
+/// suitable for injection with [`PS2Ctrl::key_event`].
+///
+/// Returns [`None`] if the sequence is truncated or contains a code with no
+/// corresponding key.
+pub fn decode_scancodes(
+    set: PS2ScanCodeSet,
+    seq: &[u8],
+) -> Option<Vec<(u16, bool)>> {
+    let mut events = Vec::new();
+    let mut iter = seq.iter().copied();
+    while let Some(mut b) = iter.next() {
+        let mut ext = 0u16;
+        if b == PS2K_EXT_PREFIX {
+            ext = (PS2K_EXT_PREFIX as u16) << 8;
+            b = iter.next()?;
+        }
+        let (make, pressed) = match set {
+            PS2ScanCodeSet::Set1 => {
+                (b & !PS2K_SET1_BREAK, b & PS2K_SET1_BREAK == 0)
+            }
+            PS2ScanCodeSet::Set2 => {
+                let pressed = b != PS2K_SET2_BREAK_PREFIX;
+                if !pressed {
+                    b = iter.next()?;
+                }
+                let make =
+                    SET1_TO_SET2.iter().position(|&c| c != 0 && c == b)?;
+                (make as u8, pressed)
+            }
+        };
+        if make == 0 || make as usize >= SET1_TO_SET2.len() {
+            return None;
+        }
+        events.push((ext | make as u16, pressed));
+    }
+    Some(events)
+}
 
-enum PS2ScanCodeSet {
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PS2ScanCodeSet {
     Set1,
     Set2,
     // ignoring fancy set3
@@ -317,6 +403,7 @@ impl PS2ScanCodeSet {
 #[allow(unused)]
 struct PS2Kbd {
     buf: VecDeque<u8>,
+    pending: VecDeque<u8>,
     cur_cmd: Option<u8>,
     enabled: bool,
     led_status: u8,
@@ -327,6 +414,7 @@ impl PS2Kbd {
     fn new() -> Self {
         Self {
             buf: VecDeque::with_capacity(PS2_KBD_BUFSZ),
+            pending: VecDeque::new(),
             cur_cmd: None,
             enabled: true,
             led_status: 0,
@@ -435,16 +523,69 @@ impl PS2Kbd {
         self.typematic = 0;
         self.scan_code_set = PS2ScanCodeSet::Set1;
         self.buf.clear();
+        self.pending.clear();
     }
     fn has_output(&self) -> bool {
         !self.buf.is_empty()
     }
     fn read_output(&mut self) -> Option<u8> {
-        self.buf.pop_front()
+        let val = self.buf.pop_front();
+        self.fill();
+        val
+    }
+    /// Moves pending key data into the output buffer as room allows, leaving
+    /// space for a command response (or overrun indication).
+    fn fill(&mut self) {
+        while self.buf.len() < PS2_KBD_BUFSZ - 1 {
+            match self.pending.pop_front() {
+                Some(v) => self.buf.push_back(v),
+                None => break,
+            }
+        }
     }
     fn loopback(&mut self, v: u8) {
         self.resp(v);
     }
+    fn key_event(&mut self, code: u16, pressed: bool, xlate: bool) {
+        if !self.enabled {
+            return;
+        }
+        let extended = (code >> 8) as u8 == PS2K_EXT_PREFIX;
+        let make = code as u8;
+        let mut seq = Vec::with_capacity(3);
+        if extended {
+            seq.push(PS2K_EXT_PREFIX);
+        }
+        // Set 3 is never selected, as requests to switch to it are ignored,
+        // so only sets 1 and 2 need be handled.
+        match self.scan_code_set {
+            // With translation enabled, the controller would convert set 2
+            // codes from the keyboard back into set 1 for the guest.
+            PS2ScanCodeSet::Set2 if !xlate => {
+                let set2 = match SET1_TO_SET2.get(make as usize) {
+                    Some(&c) if c != 0 => c,
+                    _ => return,
+                };
+                if !pressed {
+                    seq.push(PS2K_SET2_BREAK_PREFIX);
+                }
+                seq.push(set2);
+            }
+            PS2ScanCodeSet::Set1 | PS2ScanCodeSet::Set2 => {
+                if pressed {
+                    seq.push(make);
+                } else {
+                    seq.push(make | PS2K_SET1_BREAK);
+                }
+            }
+        }
+        // Events are dropped whole, rather than leaving a partial sequence
+        if self.pending.len() + seq.len() > PS2K_PENDING_MAX {
+            return;
+        }
+        self.pending.extend(seq);
+        self.fill();
+    }
 }
 impl Default for PS2Kbd {
     fn default() -> Self {
@@ -487,12 +628,35 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Mouse buttons held down, as reported in movement packets
+    #[derive(Default)]
+    pub struct PS2MButtons: u8 {
+        const LEFT = 1 << 0;
+        const RIGHT = 1 << 1;
+        const MIDDLE = 1 << 2;
+    }
+}
+
+const PS2M_PKT_SZ: usize = 3;
+// Always-one bit in the first byte of a movement packet
+const PS2M_PKT_ALWAYS_ONE: u8 = 1 << 3;
+const PS2M_PKT_X_SIGN: u8 = 1 << 4;
+const PS2M_PKT_Y_SIGN: u8 = 1 << 5;
+// Movement is reported as a 9-bit two's complement value
+const PS2M_MOVE_MIN: i32 = -256;
+const PS2M_MOVE_MAX: i32 = 255;
+
 struct PS2Mouse {
     buf: VecDeque<u8>,
     cur_cmd: Option<u8>,
     status: PS2MStatus,
     resolution: u8,
     sample_rate: u8,
+    buttons: PS2MButtons,
+    // movement not yet reported to the guest
+    dx: i32,
+    dy: i32,
 }
 impl PS2Mouse {
     fn new() -> Self {
@@ -502,6 +666,9 @@ impl PS2Mouse {
             status: PS2MStatus::empty(),
             resolution: 0,
             sample_rate: 10,
+            buttons: PS2MButtons::empty(),
+            dx: 0,
+            dy: 0,
         }
     }
     fn cmd_input(&mut self, v: u8) {
@@ -611,18 +778,54 @@ impl PS2Mouse {
         !self.buf.is_empty()
     }
     fn read_output(&mut self) -> Option<u8> {
-        self.buf.pop_front()
+        let val = self.buf.pop_front();
+        // Report any movement which did not previously fit
+        self.report(false);
+        val
     }
     fn loopback(&mut self, v: u8) {
         self.resp(v);
     }
     fn movement(&mut self) {
-        // no buttons, just the always-one bit
-        self.resp(0b00001000);
-        // no X movement
-        self.resp(0x00);
-        // no Y movement
-        self.resp(0x00);
+        let dx = self.dx.clamp(PS2M_MOVE_MIN, PS2M_MOVE_MAX);
+        let dy = self.dy.clamp(PS2M_MOVE_MIN, PS2M_MOVE_MAX);
+        self.dx -= dx;
+        self.dy -= dy;
+
+        let mut hdr = self.buttons.bits() | PS2M_PKT_ALWAYS_ONE;
+        if dx < 0 {
+            hdr |= PS2M_PKT_X_SIGN;
+        }
+        if dy < 0 {
+            hdr |= PS2M_PKT_Y_SIGN;
+        }
+        self.resp(hdr);
+        // low 8 bits of each 9-bit movement value
+        self.resp(dx as u8);
+        self.resp(dy as u8);
+    }
+    fn mouse_event(&mut self, dx: i32, dy: i32, buttons: PS2MButtons) {
+        let changed = buttons != self.buttons;
+        self.buttons = buttons;
+        self.dx = self.dx.saturating_add(dx);
+        self.dy = self.dy.saturating_add(dy);
+        self.report(changed);
+    }
+    fn report(&mut self, mut changed: bool) {
+        // In remote mode, movement is only reported when the guest asks
+        if !self.status.contains(PS2MStatus::ENABLE)
+            || self.status.contains(PS2MStatus::REMOTE)
+        {
+            return;
+        }
+        // Movement too large for a single packet is split across several,
+        // with anything which does not fit in the buffer reported later.
+        while (changed || self.dx != 0 || self.dy != 0)
+            && PS2_KBD_BUFSZ - self.buf.len() > PS2M_PKT_SZ
+        {
+            self.movement();
+            changed = false;
+        }
     }
 }
 impl Default for PS2Mouse {
@@ -630,3 +833,51 @@ impl Default for PS2Mouse {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn drain(kbd: &mut PS2Kbd) -> Vec<u8> {
+        std::iter::from_fn(|| kbd.read_output()).collect()
+    }
+
+    #[test]
+    fn decode_sequences() {
+        // 'a' press/release, then Up arrow press/release
+        let set1 = [0x1e, 0x9e, 0xe0, 0x48, 0xe0, 0xc8];
+        let set2 = [0x1c, 0xf0, 0x1c, 0xe0, 0x75, 0xe0, 0xf0, 0x75];
+        let expected =
+            vec![(0x1e, true), (0x1e, false), (0xe048, true), (0xe048, false)];
+        assert_eq!(
+            decode_scancodes(PS2ScanCodeSet::Set1, &set1),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            decode_scancodes(PS2ScanCodeSet::Set2, &set2),
+            Some(expected)
+        );
+
+        // truncated sequences
+        assert_eq!(decode_scancodes(PS2ScanCodeSet::Set1, &[0xe0]), None);
+        assert_eq!(decode_scancodes(PS2ScanCodeSet::Set2, &[0xf0]), None);
+    }
+
+    #[test]
+    fn key_events_queued() {
+        let mut kbd = PS2Kbd::new();
+        for _ in 0..20 {
+            kbd.key_event(0x1e, true, false);
+            kbd.key_event(0x1e, false, false);
+        }
+        // The guest sees everything, despite the small output buffer
+        let out = drain(&mut kbd);
+        assert_eq!(out.len(), 40);
+        assert!(out.chunks(2).all(|c| c == [0x1e, 0x9e]));
+
+        kbd.scan_code_set = PS2ScanCodeSet::Set2;
+        kbd.key_event(0xe048, true, false);
+        kbd.key_event(0xe048, false, false);
+        assert_eq!(drain(&mut kbd), vec![0xe0, 0x75, 0xe0, 0xf0, 0x75]);
+    }
+}
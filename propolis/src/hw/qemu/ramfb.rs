@@ -32,15 +32,86 @@ lazy_static! {
     };
 }
 
-fn fourcc_bytepp(fourcc: u32) -> Option<u32> {
-    match fourcc {
-        // edk2 default - xRGB, 4 bytes per pixels
-        0x34325258 => Some(4),
-        _ => None,
+/// Pixel formats supported for the framebuffer, named after their DRM FourCC.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Format {
+    /// edk2 default - xRGB, 4 bytes per pixel
+    Xrgb8888,
+    Argb8888,
+    Xbgr8888,
+    Abgr8888,
+    Rgb888,
+    Bgr888,
+    Rgb565,
+    Xrgb1555,
+}
+impl Format {
+    fn from_fourcc(fourcc: u32) -> Option<Self> {
+        match &fourcc.to_le_bytes() {
+            b"XR24" => Some(Format::Xrgb8888),
+            b"AR24" => Some(Format::Argb8888),
+            b"XB24" => Some(Format::Xbgr8888),
+            b"AB24" => Some(Format::Abgr8888),
+            b"RG24" => Some(Format::Rgb888),
+            b"BG24" => Some(Format::Bgr888),
+            b"RG16" => Some(Format::Rgb565),
+            b"XR15" => Some(Format::Xrgb1555),
+            _ => None,
+        }
+    }
+
+    fn bytes_per_pixel(&self) -> u32 {
+        match self {
+            Format::Xrgb8888
+            | Format::Argb8888
+            | Format::Xbgr8888
+            | Format::Abgr8888 => 4,
+            Format::Rgb888 | Format::Bgr888 => 3,
+            Format::Rgb565 | Format::Xrgb1555 => 2,
+        }
+    }
+
+    /// Converts a pixel, stored little-endian as is the convention for DRM
+    /// formats, into a `0x00RRGGBB` value.
+    fn to_xrgb(&self, px: &[u8]) -> u32 {
+        // Widen a channel of `bits` to 8 bits, replicating the high bits
+        // into the low ones so that full intensity remains so.
+        fn widen(val: u32, bits: u32) -> u32 {
+            let val = val << (8 - bits);
+            val | (val >> bits)
+        }
+        match self {
+            Format::Xrgb8888 | Format::Argb8888 => {
+                u32::from_le_bytes([px[0], px[1], px[2], 0])
+            }
+            Format::Xbgr8888 | Format::Abgr8888 => {
+                u32::from_le_bytes([px[2], px[1], px[0], 0])
+            }
+            Format::Rgb888 => u32::from_le_bytes([px[0], px[1], px[2], 0]),
+            Format::Bgr888 => u32::from_le_bytes([px[2], px[1], px[0], 0]),
+            Format::Rgb565 => {
+                let v = u16::from_le_bytes([px[0], px[1]]) as u32;
+                let r = widen(v >> 11, 5);
+                let g = widen((v >> 5) & 0x3f, 6);
+                let b = widen(v & 0x1f, 5);
+                (r << 16) | (g << 8) | b
+            }
+            Format::Xrgb1555 => {
+                let v = u16::from_le_bytes([px[0], px[1]]) as u32;
+                let r = widen((v >> 10) & 0x1f, 5);
+                let g = widen((v >> 5) & 0x1f, 5);
+                let b = widen(v & 0x1f, 5);
+                (r << 16) | (g << 8) | b
+            }
+        }
     }
 }
 
-#[derive(Default, Debug)]
+fn fourcc_bytepp(fourcc: u32) -> Option<u32> {
+    Format::from_fourcc(fourcc).map(|f| f.bytes_per_pixel())
+}
+
+#[derive(Default, Debug, Clone, Copy)]
 pub struct Config {
     addr: u64,
     fourcc: u32,
@@ -58,10 +129,13 @@ impl Config {
         let bypp = fourcc_bytepp(self.fourcc)?;
 
         let mem = ctx.mctx.memctx();
-        let line_sz = if self.stride == 0 { self.width } else { self.stride };
-        let total_sz = u32::checked_mul(self.height - 1, line_sz)?
-            .checked_add(self.width)?
-            .checked_mul(bypp)?;
+        let row_sz = self.width.checked_mul(bypp)?;
+        let line_sz = self.line_size()?;
+        if line_sz < row_sz {
+            return None;
+        }
+        let total_sz =
+            u32::checked_mul(self.height - 1, line_sz)?.checked_add(row_sz)?;
         let _ = mem.readable_region(&GuestRegion(
             GuestAddr(self.addr),
             total_sz as usize,
@@ -69,6 +143,37 @@ impl Config {
 
         Some(())
     }
+
+    /// Size in bytes of each line of the framebuffer, including any padding.
+    ///
+    /// A stride of 0 indicates the lines are tightly packed.
+    fn line_size(&self) -> Option<u32> {
+        if self.stride == 0 {
+            self.width.checked_mul(fourcc_bytepp(self.fourcc)?)
+        } else {
+            Some(self.stride)
+        }
+    }
+}
+
+/// A snapshot of the framebuffer contents.
+///
+/// Pixels are stored row-major, each as a 32-bit `0x00RRGGBB` value
+/// regardless of the format the guest has configured.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u32>,
+}
+impl Frame {
+    /// Creates an all-black frame of the given dimensions.
+    pub fn blank(width: u32, height: u32) -> Self {
+        let len = (width as usize)
+            .checked_mul(height as usize)
+            .expect("frame dimensions overflow");
+        Self { width, height, pixels: vec![0; len] }
+    }
 }
 #[derive(Default)]
 pub struct RamFb {
@@ -83,6 +188,33 @@ impl RamFb {
             .add_named("etc/ramfb", Arc::clone(self) as Arc<dyn Item>)
             .unwrap();
     }
+
+    /// Reads the current contents of the framebuffer out of guest memory.
+    ///
+    /// Returns [`None`] if the guest has yet to configure a valid framebuffer.
+    pub fn read_frame(&self, ctx: &DispCtx) -> Option<Frame> {
+        let config = *self.config.lock().unwrap();
+        config.verify(ctx)?;
+
+        let format = Format::from_fourcc(config.fourcc)?;
+        let bypp = format.bytes_per_pixel() as usize;
+        let line_sz = config.line_size()? as u64;
+        let row_sz = config.width as usize * bypp;
+        let mem = ctx.mctx.memctx();
+
+        let mut row = vec![0u8; row_sz];
+        let mut pixels =
+            Vec::with_capacity(config.width as usize * config.height as usize);
+        for y in 0..config.height as u64 {
+            let addr = GuestAddr(config.addr + y * line_sz);
+            if mem.read_into(addr, &mut row, row_sz)? != row_sz {
+                return None;
+            }
+            pixels.extend(row.chunks_exact(bypp).map(|px| format.to_xrgb(px)));
+        }
+
+        Some(Frame { width: config.width, height: config.height, pixels })
+    }
 }
 impl Item for RamFb {
     fn size(&self) -> u32 {
@@ -133,3 +265,38 @@ impl Item for RamFb {
     }
 }
 impl Entity for RamFb {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fourcc(code: &[u8; 4]) -> u32 {
+        u32::from_le_bytes(*code)
+    }
+
+    #[test]
+    fn fourcc_formats() {
+        assert_eq!(fourcc(b"XR24"), 0x34325258);
+        assert_eq!(fourcc_bytepp(fourcc(b"XR24")), Some(4));
+        assert_eq!(fourcc_bytepp(fourcc(b"BG24")), Some(3));
+        assert_eq!(fourcc_bytepp(fourcc(b"RG16")), Some(2));
+        assert_eq!(fourcc_bytepp(fourcc(b"YUYV")), None);
+    }
+
+    #[test]
+    fn pixel_conversion() {
+        let cases: [(Format, &[u8], u32); 8] = [
+            (Format::Xrgb8888, &[0x33, 0x22, 0x11, 0xaa], 0x112233),
+            (Format::Argb8888, &[0x33, 0x22, 0x11, 0xaa], 0x112233),
+            (Format::Xbgr8888, &[0x11, 0x22, 0x33, 0xaa], 0x112233),
+            (Format::Rgb888, &[0x33, 0x22, 0x11], 0x112233),
+            (Format::Bgr888, &[0x11, 0x22, 0x33], 0x112233),
+            (Format::Rgb565, &[0xff, 0xff], 0xffffff),
+            (Format::Rgb565, &[0x00, 0xf8], 0xff0000),
+            (Format::Xrgb1555, &[0x1f, 0x00], 0x0000ff),
+        ];
+        for (format, px, expected) in cases.iter() {
+            assert_eq!(format.to_xrgb(px), *expected, "{:?}", format);
+        }
+    }
+}
@@ -138,6 +138,11 @@ impl Uart {
             }
         }
     }
+    /// Whether a write to the register at `offset` transmits its data, rather
+    /// than configuring the uart or being looped back to it
+    pub fn is_transmit(&self, offset: u8) -> bool {
+        offset == REG_THR && !self.is_dlab() && !self.is_loopback()
+    }
     /// Read data transmitted from the uart
     pub fn data_read(&mut self) -> Option<u8> {
         if let Some(d) = self.tx_fifo.read() {
@@ -404,6 +409,20 @@ mod tests {
         assert_eq!(uart.reg_read(REG_ISR) & MASK_ISRC, ISRC_NONE);
     }
     #[test]
+    fn transmit_only_thr() {
+        let mut uart = Uart::new();
+        assert!(uart.is_transmit(REG_THR));
+        assert!(!uart.is_transmit(REG_IER));
+
+        // THR doubles as the low divisor latch
+        uart.reg_write(REG_LCR, LCR_DLAB);
+        assert!(!uart.is_transmit(REG_THR));
+        uart.reg_write(REG_LCR, 0);
+
+        uart.reg_write(REG_MCR, MCR_LOOP);
+        assert!(!uart.is_transmit(REG_THR));
+    }
+    #[test]
     fn safe_read_write_all() {
         let mut uart = Uart::new();
 
@@ -30,6 +30,8 @@ pub struct LpcUart {
     state: Mutex<UartState>,
     notify_readable: NotifierCell<dyn Source>,
     notify_writable: NotifierCell<dyn Sink>,
+    /// Observes the data transmitted by the guest, as it is written
+    consumer: ConsumerCell,
 }
 
 impl LpcUart {
@@ -42,6 +44,7 @@ impl LpcUart {
             }),
             notify_readable: NotifierCell::new(),
             notify_writable: NotifierCell::new(),
+            consumer: ConsumerCell::new(),
         })
     }
     pub fn attach(self: &Arc<Self>, bus: &PioBus, port: u16) {
@@ -99,6 +102,11 @@ impl Source for LpcUart {
         state.auto_discard = active;
     }
 }
+impl BlockingSource for LpcUart {
+    fn set_consumer(&self, f: Option<BlockingSourceConsumer>) {
+        self.consumer.set(f);
+    }
+}
 
 impl PioDev for LpcUart {
     fn pio_rw(&self, _port: u16, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
@@ -107,13 +115,18 @@ impl PioDev for LpcUart {
         let mut state = self.state.lock().unwrap();
         let readable_before = state.uart.is_readable();
         let writable_before = state.uart.is_writable();
+        let mut transmitted = None;
 
         match rwo {
             RWOp::Read(ro) => {
                 ro.write_u8(state.uart.reg_read(ro.offset() as u8));
             }
             RWOp::Write(wo) => {
-                state.uart.reg_write(wo.offset() as u8, wo.read_u8());
+                let data = wo.read_u8();
+                if state.uart.is_transmit(wo.offset() as u8) {
+                    transmitted = Some(data);
+                }
+                state.uart.reg_write(wo.offset() as u8, data);
             }
         }
         if state.auto_discard {
@@ -128,6 +141,9 @@ impl PioDev for LpcUart {
         // The uart state lock cannot be held while dispatching notifications since those callbacks
         // could immediately attempt to read/write the pending data.
         drop(state);
+        if let Some(data) = transmitted {
+            self.consumer.consume(&[data], ctx);
+        }
         if read_notify {
             self.notify_readable.notify(self as &dyn Source, ctx);
         }
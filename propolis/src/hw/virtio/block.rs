@@ -199,6 +199,8 @@ impl BlockReq for Request {
             BlockOp::Flush => return None,
             BlockOp::Read => self.chain.writable_buf(self.xfer_left),
             BlockOp::Write => self.chain.readable_buf(self.xfer_left),
+            // virtio-block does not issue compare operations
+            BlockOp::Compare | BlockOp::CompareAndWrite => return None,
         };
         if let Some(region) = res.as_ref() {
             assert!(self.xfer_left >= region.1);
@@ -213,7 +215,9 @@ impl BlockReq for Request {
         let mem = &ctx.mctx.memctx();
         match res {
             BlockResult::Success => self.chain.write(&VIRTIO_BLK_S_OK, mem),
-            BlockResult::Failure => self.chain.write(&VIRTIO_BLK_S_IOERR, mem),
+            BlockResult::Failure | BlockResult::Miscompare => {
+                self.chain.write(&VIRTIO_BLK_S_IOERR, mem)
+            }
             BlockResult::Unsupported => {
                 self.chain.write(&VIRTIO_BLK_S_UNSUPP, mem)
             }
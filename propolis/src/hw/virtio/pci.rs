@@ -487,6 +487,9 @@ impl Entity for PciVirtio {
         }
         self.dev.state_transition(next, target, ctx)
     }
+    fn unplug(&self, ctx: &DispCtx) {
+        self.dev.unplug(ctx)
+    }
 }
 
 struct IsrIntr {
@@ -242,6 +242,19 @@ impl Entity for VirtioViona {
             _ => {}
         }
     }
+    fn unplug(&self, ctx: &DispCtx) {
+        // The rings are stopped and the poller task (which holds only a weak
+        // reference to the device) is cancelled, so the viona handle is closed
+        // once the last reference to the device is dropped.
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((poller, task)) = inner.poller.take() {
+            ctx.cancel_async(task);
+            drop(poller);
+        }
+        for vq in inner.queues.iter() {
+            let _ = self.hdl.ring_reset(vq.id);
+        }
+    }
 }
 impl SelfArc for VirtioViona {
     fn self_arc_cell(&self) -> &SelfArcCell<Self> {
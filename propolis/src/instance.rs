@@ -2,13 +2,14 @@
 
 #![allow(unused)]
 
+use std::collections::BTreeSet;
 use std::io;
 use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread::{self, JoinHandle};
 
 use crate::dispatch::*;
-use crate::inventory::{self, Inventory};
-use crate::vcpu::VcpuRunFunc;
+use crate::inventory::{self, EntityID, Inventory};
+use crate::vcpu::{VcpuHdl, VcpuRunFunc};
 use crate::vmm::*;
 
 use tokio::runtime::Handle;
@@ -78,7 +79,11 @@ impl State {
                 _ => State::Quiesce,
             },
             State::Halt => State::Destroy,
-            State::Reset => State::Boot,
+            State::Reset => match target {
+                // The instance is being held, rather than booted again
+                Some(State::Quiesce) => State::Quiesce,
+                _ => State::Boot,
+            },
             State::Destroy => State::Destroy,
         };
 
@@ -109,6 +114,7 @@ struct Inner {
     machine: Option<Arc<Machine>>,
     inv: Inventory,
     transition_funcs: Vec<Box<TransitionFunc>>,
+    hold_on_reset: bool,
 }
 
 /// A single virtual machine.
@@ -138,6 +144,7 @@ impl Instance {
                 machine: Some(machine),
                 inv: Inventory::new(),
                 transition_funcs: Vec::new(),
+                hold_on_reset: false,
             }),
             cv: Condvar::new(),
             disp,
@@ -178,6 +185,87 @@ impl Instance {
         func(machine, &mctx, &self.disp, &state.inv)
     }
 
+    /// Invokes `func`, which may attach devices to an instance which has
+    /// already started to boot.
+    ///
+    /// Any entities which `func` registers with the inventory are driven
+    /// through the state transitions which the instance has already made, so
+    /// they are prepared to run alongside those registered at initialization.
+    ///
+    /// Returns an error if the instance is not initializing, booting or
+    /// running.
+    pub fn hotplug<F, R>(&self, func: F) -> io::Result<R>
+    where
+        F: FnOnce(
+            &Machine,
+            &MachineCtx,
+            &Dispatcher,
+            &Inventory,
+        ) -> io::Result<R>,
+    {
+        let state = self.inner.lock().unwrap();
+        let replay: &[State] = match state.state_current {
+            State::Initialize => &[],
+            State::Boot => &[State::Boot],
+            State::Run => &[State::Boot, State::Run],
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "instance is not running",
+                ))
+            }
+        };
+        let machine = state.machine.as_ref().unwrap();
+        let mctx = MachineCtx::new(machine);
+
+        let mut existing = BTreeSet::new();
+        state.inv.for_each_node(inventory::Order::Pre, |id, _rec| {
+            existing.insert(id);
+        });
+        let res = func(machine, &mctx, &self.disp, &state.inv)?;
+
+        self.disp.with_ctx(|ctx| {
+            state.inv.for_each_node(inventory::Order::Pre, |id, rec| {
+                if existing.contains(&id) {
+                    return;
+                }
+                for next in replay {
+                    rec.entity().state_transition(*next, None, ctx);
+                }
+            });
+        });
+        Ok(res)
+    }
+
+    /// Removes the entity `id`, and any children, from the inventory once the
+    /// device it provides has been detached from a running instance.
+    ///
+    /// The entity is first given the chance to release its resources through
+    /// [`inventory::Entity::unplug`].
+    pub fn unplug(&self, id: EntityID) -> io::Result<()> {
+        let state = self.inner.lock().unwrap();
+        self.disp.with_ctx(|ctx| {
+            state.inv.for_record(id, |rec| {
+                if let Some(rec) = rec {
+                    rec.entity().unplug(ctx);
+                }
+            });
+        });
+        state.inv.deregister(id)?;
+        Ok(())
+    }
+
+    /// Returns a handle to virtual CPU `id`, or `None` if the instance has no
+    /// such CPU or has been destroyed.
+    pub fn vcpu(&self, id: usize) -> Option<VcpuHdl> {
+        let state = self.inner.lock().unwrap();
+        let machine = state.machine.as_ref()?;
+        if id >= MachineCtx::new(machine).max_cpus() {
+            return None;
+        }
+        Some(machine.vcpu(id))
+    }
+
     /// Returns the state of the instance.
     pub fn current_state(&self) -> State {
         let state = self.inner.lock().unwrap();
@@ -299,6 +387,13 @@ impl Instance {
         });
     }
 
+    /// Sets whether the instance, once reset, is held in [`State::Quiesce`]
+    /// rather than booting again, such as while it is replaced by another.
+    pub fn set_hold_on_reset(&self, hold: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.hold_on_reset = hold;
+    }
+
     /// Registers  callback, `func`, which is invoked whenever a state
     /// transition occurs.
     pub fn on_transition(&self, func: Box<TransitionFunc>) {
@@ -397,6 +492,13 @@ impl Instance {
                         self.disp.release();
                     }
                 }
+                State::Reset => {
+                    if inner.hold_on_reset
+                        && matches!(inner.state_target, None | Some(State::Run))
+                    {
+                        inner.state_target = Some(State::Quiesce);
+                    }
+                }
                 State::Destroy => {}
                 _ => {}
             }
@@ -457,6 +559,7 @@ impl Instance {
                 machine: Some(machine),
                 inv: Inventory::new(),
                 transition_funcs: Vec::new(),
+                hold_on_reset: false,
             }),
             cv: Condvar::new(),
             disp,
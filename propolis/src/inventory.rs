@@ -405,6 +405,12 @@ pub trait Entity: Send + Sync + 'static {
         ctx: &DispCtx,
     ) {
     }
+
+    /// Releases resources held by the entity as it is removed from an
+    /// instance which continues to run, such as when the guest ejects the
+    /// device it provides from a hotplug slot.
+    #[allow(unused_variables)]
+    fn unplug(&self, ctx: &DispCtx) {}
 }
 
 /// ID referencing an entity stored within the inventory.
@@ -17,6 +17,7 @@ pub mod chardev;
 pub mod common;
 pub mod dispatch;
 pub mod exits;
+pub mod firmware;
 pub mod hw;
 pub mod instance;
 pub mod intr_pins;
@@ -110,6 +110,27 @@ impl VcpuHdl {
         Ok(())
     }
 
+    /// Injects a non-maskable interrupt into the virtual CPU.
+    pub fn inject_nmi(&self) -> Result<()> {
+        self.hdl.inject_nmi(self.id)
+    }
+
+    /// Injects the exception `vector` into the virtual CPU, with an optional
+    /// `error_code`.
+    pub fn inject_exception(
+        &self,
+        vector: u8,
+        error_code: Option<u32>,
+    ) -> Result<()> {
+        self.hdl.inject_exception(self.id, vector, error_code)
+    }
+
+    /// Delivers an interrupt with the given `vector` to the local APIC of the
+    /// virtual CPU.
+    pub fn lapic_irq(&self, vector: u8) -> Result<()> {
+        self.hdl.lapic_irq(self.id, vector)
+    }
+
     /// Executes the guest by running the virtual CPU.
     ///
     /// Blocks the calling thread until the vCPU returns execution,
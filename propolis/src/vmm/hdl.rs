@@ -345,6 +345,37 @@ impl VmmHdl {
         let mut data = bhyve_api::vm_lapic_msi { msg, addr };
         self.ioctl(bhyve_api::VM_LAPIC_MSI, &mut data)
     }
+    /// Delivers an interrupt with the given `vector` to the local APIC of
+    /// virtual CPU `vcpu`.
+    pub fn lapic_irq(&self, vcpu: i32, vector: u8) -> Result<()> {
+        let mut data =
+            bhyve_api::vm_lapic_irq { cpuid: vcpu, vector: vector as i32 };
+        self.ioctl(bhyve_api::VM_LAPIC_IRQ, &mut data)
+    }
+    /// Injects a non-maskable interrupt into virtual CPU `vcpu`.
+    pub fn inject_nmi(&self, vcpu: i32) -> Result<()> {
+        let mut data = bhyve_api::vm_nmi { cpuid: vcpu };
+        self.ioctl(bhyve_api::VM_INJECT_NMI, &mut data)
+    }
+    /// Injects the exception `vector` into virtual CPU `vcpu`.
+    ///
+    /// `error_code` (if supplied) is pushed onto the guest stack along with
+    /// the exception frame, as is done for exceptions such as #GP and #PF.
+    pub fn inject_exception(
+        &self,
+        vcpu: i32,
+        vector: u8,
+        error_code: Option<u32>,
+    ) -> Result<()> {
+        let mut data = bhyve_api::vm_exception {
+            cpuid: vcpu,
+            vector: vector as i32,
+            error_code: error_code.unwrap_or(0),
+            error_code_valid: error_code.is_some() as i32,
+            restart_instruction: 1,
+        };
+        self.ioctl(bhyve_api::VM_INJECT_EXCEPTION, &mut data)
+    }
 
     pub fn pmtmr_locate(&self, port: u16) -> Result<()> {
         self.ioctl(bhyve_api::VM_PMTMR_LOCATE, port as *mut usize)
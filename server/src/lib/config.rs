@@ -5,6 +5,7 @@ use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 
+use propolis::hw::chipset::ChipsetKind;
 use serde_derive::Deserialize;
 use thiserror::Error;
 
@@ -31,11 +32,24 @@ pub enum ParseError {
 pub struct Config {
     bootrom: PathBuf,
 
+    /// Chipset on which instances are built: "i440fx" (the default) or "q35"
+    #[serde(default)]
+    chipset: Option<String>,
+
     #[serde(default, rename = "dev")]
     devices: BTreeMap<String, Device>,
 
     #[serde(default, rename = "block_dev")]
     block_devs: BTreeMap<String, BlockDevice>,
+
+    #[serde(default)]
+    smbios: Smbios,
+
+    #[serde(default)]
+    serial_log: Option<SerialLog>,
+
+    #[serde(default)]
+    serial: BTreeMap<String, SerialPort>,
 }
 
 impl Config {
@@ -49,13 +63,42 @@ impl Config {
         devices: BTreeMap<String, Device>,
         block_devs: BTreeMap<String, BlockDevice>,
     ) -> Config {
-        Config { bootrom: bootrom.into(), devices, block_devs }
+        Config {
+            bootrom: bootrom.into(),
+            chipset: None,
+            devices,
+            block_devs,
+            smbios: Smbios::default(),
+            serial_log: None,
+            serial: BTreeMap::new(),
+        }
     }
 
     pub fn get_bootrom(&self) -> &Path {
         &self.bootrom
     }
 
+    pub fn chipset(&self) -> Result<ChipsetKind, ParseError> {
+        match &self.chipset {
+            Some(name) => Ok(name.parse()?),
+            None => Ok(ChipsetKind::default()),
+        }
+    }
+
+    pub fn smbios(&self) -> &Smbios {
+        &self.smbios
+    }
+
+    pub fn serial_log(&self) -> Option<&SerialLog> {
+        self.serial_log.as_ref()
+    }
+
+    /// Returns the configuration of serial port `port` ("com1" through
+    /// "com4"), which defaults to a WebSocket console.
+    pub fn serial_port(&self, port: &str) -> SerialPort {
+        self.serial.get(port).cloned().unwrap_or_default()
+    }
+
     pub fn devs(&self) -> IterDevs {
         IterDevs { inner: self.devices.iter() }
     }
@@ -137,6 +180,102 @@ impl BlockDevice {
     }
 }
 
+/// Overrides for the identifying information presented to guests through
+/// SMBIOS.  Fields which are not specified are derived from the instance
+/// properties, or take on a default value.
+#[derive(Deserialize, Debug, Default)]
+pub struct Smbios {
+    pub bios_vendor: Option<String>,
+    pub bios_version: Option<String>,
+    pub bios_release_date: Option<String>,
+
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub serial_number: Option<String>,
+    pub sku: Option<String>,
+    pub family: Option<String>,
+
+    pub chassis_asset_tag: Option<String>,
+}
+
+/// Logging of the output of instances' serial ports to files, which persist
+/// whether or not a client is attached to the console.
+///
+/// The output of each port is written to `<instance id>.<port>.log` in
+/// `directory`, whichever backend the port is connected to.
+#[derive(Deserialize, Debug)]
+pub struct SerialLog {
+    pub directory: PathBuf,
+
+    /// Ports to log, from "com1" through "com4".
+    #[serde(default = "SerialLog::default_ports")]
+    pub ports: Vec<String>,
+
+    /// Size in bytes at which a log file is rotated.
+    #[serde(default = "SerialLog::default_max_size")]
+    pub max_size: u64,
+
+    /// Number of rotated log files to keep for each port.
+    #[serde(default = "SerialLog::default_keep")]
+    pub keep: usize,
+}
+
+impl SerialLog {
+    fn default_ports() -> Vec<String> {
+        vec!["com1".to_string()]
+    }
+
+    fn default_max_size() -> u64 {
+        1024 * 1024
+    }
+
+    fn default_keep() -> usize {
+        4
+    }
+}
+
+/// The host resource to which a serial port is connected.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SerialBackend {
+    /// Served through the instance's serial console endpoints.
+    WebSocket,
+    /// A Unix domain socket, to which one client may connect at a time.
+    Socket,
+    /// A file to which output is appended.  Input is not supported.
+    File,
+    /// Output is discarded.
+    Null,
+}
+
+impl Default for SerialBackend {
+    fn default() -> Self {
+        SerialBackend::WebSocket
+    }
+}
+
+/// Configuration of a serial port, in a `[serial.<port>]` table.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SerialPort {
+    #[serde(default)]
+    pub backend: SerialBackend,
+
+    /// Path of the socket or file backing the port, in which any "{id}" is
+    /// replaced by the ID of the instance.
+    pub path: Option<String>,
+}
+
+impl SerialPort {
+    /// Returns the path backing the port for instance `id`.
+    pub fn path_for(&self, id: &str) -> Result<PathBuf, ParseError> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            ParseError::KeyNotFound("path".to_string(), "serial".to_string())
+        })?;
+        Ok(PathBuf::from(path.replace("{id}", id)))
+    }
+}
+
 /// Iterator returned from [`Config::devs`] which allows iteration over
 /// all [`Device`] objects.
 pub struct IterDevs<'a> {
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use slog::{error, Logger};
 use std::fs::File;
 use std::io::{Error, ErrorKind};
 use std::num::NonZeroUsize;
@@ -6,11 +7,14 @@ use std::sync::Arc;
 use tokio::runtime::Handle;
 
 use propolis::block;
-use propolis::chardev::{self, BlockingSource, Source};
+use propolis::chardev::{self, BlockingSource, RotatingFile, Sink, Source};
 use propolis::common::PAGE_SIZE;
 use propolis::dispatch::Dispatcher;
-use propolis::hw::chipset::{i440fx::I440Fx, Chipset};
+use propolis::firmware::{acpi, smbios};
+use propolis::hw::chipset::{i440fx::I440Fx, q35, q35::Q35};
+use propolis::hw::chipset::{Chipset, ChipsetKind};
 use propolis::hw::ibmpc;
+use propolis::hw::nvme;
 use propolis::hw::pci;
 use propolis::hw::ps2ctrl::PS2Ctrl;
 use propolis::hw::qemu::{debug::QemuDebugPort, fwcfg, ramfb};
@@ -25,6 +29,26 @@ use crate::serial::Serial;
 // Arbitrary ROM limit for now
 const MAX_ROM_SIZE: usize = 0x20_0000;
 
+/// What must be torn down, beyond detaching it from the bus, should a PCI
+/// device be unplugged from the running instance.
+pub struct DeviceResources {
+    /// Inventory registration of the device
+    pub id: EntityID,
+    /// Dispatch thread of the block backend of the device, if any
+    pub worker: Option<String>,
+}
+
+impl DeviceResources {
+    /// Releases the resources of a device which was created for, but never
+    /// attached to, the instance.
+    pub fn release(self, disp: &Dispatcher, inv: &Inventory) {
+        if let Some(worker) = self.worker {
+            let _ = disp.stop_sync(&worker);
+        }
+        let _ = inv.deregister(self.id);
+    }
+}
+
 fn open_bootrom<P: AsRef<std::path::Path>>(path: P) -> Result<(File, usize)> {
     let fp = File::open(path.as_ref())?;
     let len = fp.metadata()?.len();
@@ -82,14 +106,17 @@ pub fn build_instance(
     Ok(inst)
 }
 
-pub struct RegisteredChipset(Arc<I440Fx>, EntityID);
+pub struct RegisteredChipset(Arc<dyn Chipset>, EntityID, ChipsetKind);
 impl RegisteredChipset {
-    pub fn device(&self) -> &Arc<I440Fx> {
+    pub fn device(&self) -> &Arc<dyn Chipset> {
         &self.0
     }
     pub fn id(&self) -> EntityID {
         self.1
     }
+    pub fn kind(&self) -> ChipsetKind {
+        self.2
+    }
 }
 
 pub struct MachineInitializer<'a> {
@@ -132,21 +159,37 @@ impl<'a> MachineInitializer<'a> {
         Ok(())
     }
 
-    pub fn initialize_chipset(&self) -> Result<RegisteredChipset, Error> {
+    pub fn initialize_chipset(
+        &self,
+        kind: ChipsetKind,
+    ) -> Result<RegisteredChipset, Error> {
         let hdl = self.machine.get_hdl();
-        let chipset = I440Fx::create(Arc::clone(&hdl));
-        chipset.attach(self.mctx);
-        let id = self
-            .inv
-            .register(&chipset, "chipset".to_string(), None)
-            .map_err(|e| -> std::io::Error { e.into() })?;
-        Ok(RegisteredChipset(chipset, id))
+        let name = "chipset".to_string();
+        let (chipset, id) = match kind {
+            ChipsetKind::I440Fx => {
+                let chipset = I440Fx::create(Arc::clone(&hdl));
+                chipset.attach(self.mctx);
+                let id = self.inv.register(&chipset, name, None);
+                (chipset as Arc<dyn Chipset>, id)
+            }
+            ChipsetKind::Q35 => {
+                let chipset = Q35::create(Arc::clone(&hdl));
+                chipset.attach(self.mctx);
+                let id = self.inv.register(&chipset, name, None);
+                (chipset as Arc<dyn Chipset>, id)
+            }
+        };
+        let id = id.map_err(|e| -> std::io::Error { e.into() })?;
+        Ok(RegisteredChipset(chipset, id, kind))
     }
 
+    /// Attaches the COM1-COM4 UARTs, returning them in that order.
+    ///
+    /// The UARTs discard their output until connected to a backend.
     pub fn initialize_uart(
         &self,
         chipset: &RegisteredChipset,
-    ) -> Result<Serial<LpcUart>, Error> {
+    ) -> Result<Vec<Arc<LpcUart>>, Error> {
         let cid = Some(chipset.id());
         let uarts = vec![
             (ibmpc::IRQ_COM1, ibmpc::PORT_COM1, "com1"),
@@ -155,7 +198,7 @@ impl<'a> MachineInitializer<'a> {
             (ibmpc::IRQ_COM4, ibmpc::PORT_COM4, "com4"),
         ];
         let pio = self.mctx.pio();
-        let mut com1 = None;
+        let mut devs = Vec::with_capacity(uarts.len());
         for (irq, port, name) in uarts.iter() {
             let dev = LpcUart::new(chipset.device().irq_pin(*irq).unwrap());
             dev.set_autodiscard(true);
@@ -163,27 +206,80 @@ impl<'a> MachineInitializer<'a> {
             self.inv
                 .register(&dev, name.to_string(), cid)
                 .map_err(|e| -> std::io::Error { e.into() })?;
-            if com1.is_none() {
-                com1 = Some(dev);
-            }
+            devs.push(dev);
         }
+        Ok(devs)
+    }
 
+    /// Connects `uart` to a buffered serial connection, which records its
+    /// output for replay to clients, and writes it to `log` if provided.
+    ///
+    /// Failures to write `log` are reported to `logger`.
+    pub fn initialize_uart_console(
+        &self,
+        uart: Arc<LpcUart>,
+        log: Option<RotatingFile>,
+        logger: Logger,
+    ) -> Arc<Serial<LpcUart>> {
         let sink_size = NonZeroUsize::new(64).unwrap();
         let source_size = NonZeroUsize::new(1024).unwrap();
-        Ok(Serial::new(com1.unwrap(), sink_size, source_size))
+        let history_size = NonZeroUsize::new(64 * 1024).unwrap();
+        let serial =
+            Arc::new(Serial::new(uart, sink_size, source_size, history_size));
+
+        let pump = Arc::clone(&serial);
+        self.disp.spawn_async(|actx| async move {
+            pump.pump(&actx, log, logger).await
+        });
+        serial
+    }
+
+    /// Writes the output of `uart` to `log`, whichever backend it is connected
+    /// to.  Should that fail, the failure is reported to `logger`.
+    pub fn initialize_uart_log(
+        &self,
+        uart: &Arc<LpcUart>,
+        log: RotatingFile,
+        logger: Logger,
+    ) -> Result<(), Error> {
+        let on_error = move |e: Error| {
+            error!(logger, "Serial log write failed, disabling: {}", e);
+        };
+        let output = chardev::BlockingFileOutput::rotating(
+            log,
+            Some(Box::new(on_error)),
+        )?;
+        output.attach(Arc::clone(uart) as Arc<dyn BlockingSource>, self.disp);
+        Ok(())
+    }
+
+    /// Connects `uart` to a Unix domain socket bound at `path`.
+    pub fn initialize_uart_socket(
+        &self,
+        uart: Arc<LpcUart>,
+        path: &std::path::Path,
+    ) -> Result<(), Error> {
+        let sock = chardev::UDSock::bind(path)?;
+        sock.spawn(
+            Arc::clone(&uart) as Arc<dyn Sink>,
+            Arc::clone(&uart) as Arc<dyn Source>,
+            self.disp,
+        );
+        uart.set_autodiscard(false);
+        Ok(())
     }
 
     pub fn initialize_ps2(
         &self,
         chipset: &RegisteredChipset,
-    ) -> Result<(), Error> {
+    ) -> Result<Arc<PS2Ctrl>, Error> {
         let pio = self.mctx.pio();
         let ps2_ctrl = PS2Ctrl::create();
         ps2_ctrl.attach(pio, chipset.device().as_ref());
         self.inv
             .register(&ps2_ctrl, "ps2_ctrl".to_string(), Some(chipset.id()))
             .map_err(|e| -> std::io::Error { e.into() })?;
-        Ok(())
+        Ok(ps2_ctrl)
     }
 
     pub fn initialize_qemu_debug_port(&self) -> Result<(), Error> {
@@ -197,24 +293,93 @@ impl<'a> MachineInitializer<'a> {
         Ok(())
     }
 
+    /// Attach PCI-PCI bridges at the given root bus locations, each providing
+    /// the paired logical bus number for devices attached behind it.
+    pub fn initialize_pci_bridges(
+        &self,
+        chipset: &RegisteredChipset,
+        bridges: &[(pci::Bdf, u8)],
+    ) -> Result<(), Error> {
+        for (bdf, bus) in bridges {
+            let bridge = pci::Bridge::new(0x1b36, 0x0001, *bus);
+            self.inv
+                .register(&bridge, format!("pci-bridge-{}", bdf), None)
+                .map_err(|e| -> std::io::Error { e.into() })?;
+            chipset.device().pci_attach(*bdf, bridge)?;
+        }
+        Ok(())
+    }
+
     pub fn initialize_block(
         &self,
         chipset: &RegisteredChipset,
         bdf: pci::Bdf,
         block_dev_name: &str,
         block_dev: Arc<dyn block::BlockDev<virtio::block::Request>>,
-    ) -> Result<(), Error> {
+    ) -> Result<DeviceResources, Error> {
+        let (vioblk, res) =
+            self.create_vioblk(bdf, block_dev_name, block_dev)?;
+        chipset.device().pci_attach(bdf, vioblk)?;
+        Ok(res)
+    }
+
+    pub fn initialize_nvme(
+        &self,
+        chipset: &RegisteredChipset,
+        bdf: pci::Bdf,
+        block_dev_name: &str,
+        block_dev: Arc<dyn block::BlockDev<nvme::Request>>,
+    ) -> Result<DeviceResources, Error> {
+        let (nvme, res) = self.create_nvme(bdf, block_dev_name, block_dev)?;
+        chipset.device().pci_attach(bdf, nvme)?;
+        Ok(res)
+    }
+
+    /// Creates a virtio block device, to be attached at `bdf`, and starts
+    /// dispatching requests to its backing `block_dev`.
+    pub fn create_vioblk(
+        &self,
+        bdf: pci::Bdf,
+        block_dev_name: &str,
+        block_dev: Arc<dyn block::BlockDev<virtio::block::Request>>,
+    ) -> Result<(Arc<pci::DeviceInst>, DeviceResources), Error> {
         let vioblk = virtio::VirtioBlock::create(0x100, Arc::clone(&block_dev));
-        self.inv
+        let id = self
+            .inv
             .register(&vioblk, format!("vioblk-{}", bdf), None)
             .map_err(|e| -> std::io::Error { e.into() })?;
-        chipset.device().pci_attach(bdf, vioblk);
 
-        block_dev.start_dispatch(
-            format!("bdev-{} thread", block_dev_name),
-            &self.disp,
-        );
-        Ok(())
+        let worker = format!("bdev-{} thread", block_dev_name);
+        block_dev.start_dispatch(worker.clone(), &self.disp);
+        Ok((vioblk, DeviceResources { id, worker: Some(worker) }))
+    }
+
+    /// Creates an NVMe controller, to be attached at `bdf`, with a single
+    /// namespace backed by `block_dev`.
+    pub fn create_nvme(
+        &self,
+        bdf: pci::Bdf,
+        block_dev_name: &str,
+        block_dev: Arc<dyn block::BlockDev<nvme::Request>>,
+    ) -> Result<(Arc<pci::DeviceInst>, DeviceResources), Error> {
+        let nvme = nvme::PciNvme::create(0x1de, 0x1000, None)?;
+        let ns = nvme::NvmeNs::create(Arc::clone(&block_dev));
+        nvme.with_inner(|nvme: Arc<nvme::PciNvme>| nvme.add_ns(ns)).map_err(
+            |e| {
+                Error::new(
+                    ErrorKind::Other,
+                    format!("Cannot add NVMe namespace: {}", e),
+                )
+            },
+        )?;
+        let id = self
+            .inv
+            .register(&nvme, format!("nvme-{}", bdf), None)
+            .map_err(|e| -> std::io::Error { e.into() })?;
+
+        let worker = format!("bdev-{} thread", block_dev_name);
+        block_dev.start_dispatch(worker.clone(), &self.disp);
+        Ok((nvme, DeviceResources { id, worker: Some(worker) }))
     }
 
     pub fn initialize_vnic(
@@ -222,18 +387,34 @@ impl<'a> MachineInitializer<'a> {
         chipset: &RegisteredChipset,
         vnic_name: &str,
         bdf: pci::Bdf,
-    ) -> Result<(), Error> {
+    ) -> Result<DeviceResources, Error> {
+        let (viona, res) = self.create_vnic(vnic_name, bdf)?;
+        chipset.device().pci_attach(bdf, viona)?;
+        Ok(res)
+    }
+
+    /// Creates a virtio network device, to be attached at `bdf`, backed by the
+    /// named VNIC.
+    pub fn create_vnic(
+        &self,
+        vnic_name: &str,
+        bdf: pci::Bdf,
+    ) -> Result<(Arc<pci::DeviceInst>, DeviceResources), Error> {
         let hdl = self.machine.get_hdl();
         let viona = virtio::viona::VirtioViona::create(vnic_name, 0x100, &hdl)?;
-        chipset.device().pci_attach(bdf, viona);
-        Ok(())
+        let id = self
+            .inv
+            .register(&viona, format!("viona-{}", bdf), None)
+            .map_err(|e| -> std::io::Error { e.into() })?;
+        Ok((viona, DeviceResources { id, worker: None }))
     }
 
     pub fn initialize_fwcfg(
         &self,
         chipset: &RegisteredChipset,
         cpus: u8,
-    ) -> Result<(), Error> {
+        smbios_cfg: &smbios::Config,
+    ) -> Result<Arc<ramfb::RamFb>, Error> {
         let mut fwcfg = fwcfg::FwCfgBuilder::new();
         fwcfg
             .add_legacy(
@@ -242,6 +423,38 @@ impl<'a> MachineInitializer<'a> {
             )
             .unwrap();
 
+        let ecam = match chipset.kind() {
+            // The i440fx offers no enhanced configuration mechanism
+            ChipsetKind::I440Fx => Vec::new(),
+            ChipsetKind::Q35 => vec![acpi::Ecam {
+                base: q35::ECAM_BASE as u64,
+                segment: 0,
+                start_bus: 0,
+                end_bus: 0xff,
+            }],
+        };
+        let acpi_cfg = acpi::Config {
+            chipset: chipset.kind(),
+            num_cpus: cpus,
+            pci_mem32: 0xc000_0000..=0xdfff_ffff,
+            pci_mem64: Some(
+                vmm::MAX_SYSMEM as u64..=vmm::MAX_PHYSMEM as u64 - 1,
+            ),
+            ecam,
+            serial_ports: vec![
+                (ibmpc::PORT_COM1, ibmpc::IRQ_COM1),
+                (ibmpc::PORT_COM2, ibmpc::IRQ_COM2),
+                (ibmpc::PORT_COM3, ibmpc::IRQ_COM3),
+                (ibmpc::PORT_COM4, ibmpc::IRQ_COM4),
+            ],
+        };
+        acpi::build(&acpi_cfg)
+            .attach(&mut fwcfg)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        smbios::build(smbios_cfg)
+            .attach(&mut fwcfg)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
         let ramfb = ramfb::RamFb::create();
         ramfb.attach(&mut fwcfg);
 
@@ -255,7 +468,7 @@ impl<'a> MachineInitializer<'a> {
         self.inv
             .register(&ramfb, "ramfb".to_string(), Some(chipset.id()))
             .map_err(|e| -> std::io::Error { e.into() })?;
-        Ok(())
+        Ok(ramfb)
     }
 
     pub fn initialize_cpus(&self) -> Result<(), Error> {
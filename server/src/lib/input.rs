@@ -0,0 +1,226 @@
+//! Translation of input requested via the HTTP interface into events for the
+//! PS/2 keyboard and mouse.
+
+use propolis::hw::ps2ctrl::{
+    decode_scancodes, PS2Ctrl, PS2MButtons, PS2ScanCodeSet,
+};
+use propolis_client::api;
+
+use crate::vnc::keysym::ascii_scancode;
+
+const KEY_LSHIFT: u16 = 0x2a;
+
+/// A single event to be injected into the PS/2 controller.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Key { code: u16, pressed: bool },
+    Mouse { dx: i32, dy: i32, buttons: PS2MButtons },
+}
+
+/// Translates requested input events into the actions which produce them,
+/// failing if any of the events cannot be represented.
+pub fn translate(events: &[api::InputEvent]) -> Result<Vec<Action>, String> {
+    let mut actions = Vec::new();
+    for event in events {
+        match event {
+            api::InputEvent::Key { name } => {
+                let codes = parse_key_combo(name)
+                    .ok_or_else(|| format!("unknown key \"{}\"", name))?;
+                for &code in codes.iter() {
+                    actions.push(Action::Key { code, pressed: true });
+                }
+                for &code in codes.iter().rev() {
+                    actions.push(Action::Key { code, pressed: false });
+                }
+            }
+            api::InputEvent::Text { text } => {
+                for c in text.chars() {
+                    let (code, shift) = Some(c)
+                        .filter(char::is_ascii)
+                        .and_then(|c| ascii_scancode(c as u8))
+                        .ok_or_else(|| {
+                            format!("cannot type character {:?}", c)
+                        })?;
+                    if shift {
+                        actions.push(Action::Key {
+                            code: KEY_LSHIFT,
+                            pressed: true,
+                        });
+                    }
+                    actions.push(Action::Key { code, pressed: true });
+                    actions.push(Action::Key { code, pressed: false });
+                    if shift {
+                        actions.push(Action::Key {
+                            code: KEY_LSHIFT,
+                            pressed: false,
+                        });
+                    }
+                }
+            }
+            api::InputEvent::Scancodes { set, codes } => {
+                let set = match set {
+                    api::ScanCodeSet::Set1 => PS2ScanCodeSet::Set1,
+                    api::ScanCodeSet::Set2 => PS2ScanCodeSet::Set2,
+                };
+                let events = decode_scancodes(set, codes).ok_or_else(|| {
+                    format!("invalid {:?} scan code sequence {:x?}", set, codes)
+                })?;
+                for (code, pressed) in events {
+                    actions.push(Action::Key { code, pressed });
+                }
+            }
+            api::InputEvent::Mouse { dx, dy, buttons } => {
+                let mut ps2_buttons = PS2MButtons::empty();
+                ps2_buttons.set(PS2MButtons::LEFT, buttons.left);
+                ps2_buttons.set(PS2MButtons::RIGHT, buttons.right);
+                ps2_buttons.set(PS2MButtons::MIDDLE, buttons.middle);
+                actions.push(Action::Mouse {
+                    dx: *dx,
+                    dy: *dy,
+                    buttons: ps2_buttons,
+                });
+            }
+        }
+    }
+    Ok(actions)
+}
+
+/// Injects actions into the PS/2 controller.
+pub fn inject(ps2: &PS2Ctrl, actions: &[Action]) {
+    for action in actions {
+        match action {
+            Action::Key { code, pressed } => ps2.key_event(*code, *pressed),
+            Action::Mouse { dx, dy, buttons } => {
+                ps2.mouse_event(*dx, *dy, *buttons)
+            }
+        }
+    }
+}
+
+/// Parses a combination of keys joined by '+' (e.g. "Ctrl+Alt+Delete").
+fn parse_key_combo(combo: &str) -> Option<Vec<u16>> {
+    // The '+' key itself may only appear last, as in "Shift++"
+    let (mods, last) = if combo == "+" {
+        ("", "+")
+    } else if let Some(mods) = combo.strip_suffix("++") {
+        (mods, "+")
+    } else {
+        match combo.rfind('+') {
+            Some(idx) => (&combo[..idx], &combo[idx + 1..]),
+            None => ("", combo),
+        }
+    };
+
+    let mut codes = Vec::new();
+    if !mods.is_empty() {
+        for name in mods.split('+') {
+            codes.push(key_scancode(name)?);
+        }
+    }
+    codes.push(key_scancode(last)?);
+    Some(codes)
+}
+
+/// Returns the key with the given (case-insensitive) name.
+///
+/// Single characters name the key which produces them, ignoring whether
+/// Shift is required to do so.
+fn key_scancode(name: &str) -> Option<u16> {
+    if name.len() == 1 {
+        return ascii_scancode(name.as_bytes()[0]).map(|(code, _)| code);
+    }
+    let code = match name.to_ascii_lowercase().as_str() {
+        "esc" | "escape" => 0x01,
+        "backspace" => 0x0e,
+        "tab" => 0x0f,
+        "enter" | "return" => 0x1c,
+        "space" => 0x39,
+        "ctrl" | "control" | "lctrl" => 0x1d,
+        "rctrl" => 0xe01d,
+        "shift" | "lshift" => KEY_LSHIFT,
+        "rshift" => 0x36,
+        "alt" | "lalt" => 0x38,
+        "ralt" | "altgr" => 0xe038,
+        "super" | "win" | "meta" => 0xe05b,
+        "menu" => 0xe05d,
+        "capslock" => 0x3a,
+        "numlock" => 0x45,
+        "scrolllock" => 0x46,
+        "insert" | "ins" => 0xe052,
+        "delete" | "del" => 0xe053,
+        "home" => 0xe047,
+        "end" => 0xe04f,
+        "pageup" | "pgup" => 0xe049,
+        "pagedown" | "pgdn" => 0xe051,
+        "up" => 0xe048,
+        "down" => 0xe050,
+        "left" => 0xe04b,
+        "right" => 0xe04d,
+        "f11" => 0x57,
+        "f12" => 0x58,
+        fkey => match fkey.strip_prefix('f').map(str::parse::<u16>) {
+            Some(Ok(n @ 1..=10)) => 0x3a + n,
+            _ => return None,
+        },
+    };
+    Some(code)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_combos() {
+        assert_eq!(parse_key_combo("Enter"), Some(vec![0x1c]));
+        assert_eq!(parse_key_combo("F2"), Some(vec![0x3c]));
+        assert_eq!(
+            parse_key_combo("Ctrl+Alt+Delete"),
+            Some(vec![0x1d, 0x38, 0xe053])
+        );
+        assert_eq!(parse_key_combo("shift++"), Some(vec![KEY_LSHIFT, 0x0d]));
+        assert_eq!(parse_key_combo("+"), Some(vec![0x0d]));
+        assert_eq!(parse_key_combo("Ctrl+Bogus"), None);
+        assert_eq!(parse_key_combo("F13"), None);
+    }
+
+    #[test]
+    fn text_shifted() {
+        let events = vec![api::InputEvent::Text { text: "a!".to_string() }];
+        let expected = vec![
+            Action::Key { code: 0x1e, pressed: true },
+            Action::Key { code: 0x1e, pressed: false },
+            Action::Key { code: KEY_LSHIFT, pressed: true },
+            Action::Key { code: 0x02, pressed: true },
+            Action::Key { code: 0x02, pressed: false },
+            Action::Key { code: KEY_LSHIFT, pressed: false },
+        ];
+        assert_eq!(translate(&events), Ok(expected));
+
+        let events = vec![api::InputEvent::Text { text: "é".to_string() }];
+        assert!(translate(&events).is_err());
+    }
+
+    #[test]
+    fn scancodes_validated() {
+        let events = vec![api::InputEvent::Scancodes {
+            set: api::ScanCodeSet::Set2,
+            codes: vec![0x1c, 0xf0, 0x1c],
+        }];
+        let expected = vec![
+            Action::Key { code: 0x1e, pressed: true },
+            Action::Key { code: 0x1e, pressed: false },
+        ];
+        assert_eq!(translate(&events), Ok(expected));
+
+        // Nothing is translated if any sequence is invalid
+        let events = vec![
+            api::InputEvent::Key { name: "Enter".to_string() },
+            api::InputEvent::Scancodes {
+                set: api::ScanCodeSet::Set1,
+                codes: vec![0x1c, 0xe0],
+            },
+        ];
+        assert!(translate(&events).is_err());
+    }
+}
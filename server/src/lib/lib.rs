@@ -2,5 +2,7 @@
 
 pub mod config;
 mod initializer;
+mod input;
 mod serial;
 pub mod server;
+pub mod vnc;
@@ -1,16 +1,81 @@
+use std::collections::VecDeque;
 use std::num::NonZeroUsize;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use propolis::chardev::{pollers, Sink, Source};
+use slog::{error, Logger};
+use tokio::sync::watch;
+
+use propolis::chardev::{pollers, RotatingFile, Sink, Source};
 use propolis::dispatch::AsyncCtx;
 
+/// A bounded record of the most recent output of a serial device.
+///
+/// Each byte is addressed by its offset in the device's output since the
+/// record was created, so readers can resume where they left off, and tell
+/// when output they have yet to see has been discarded.
+struct History {
+    buf: VecDeque<u8>,
+    cap: usize,
+    /// Offset of the oldest byte in `buf`.
+    start: u64,
+}
+
+impl History {
+    fn new(cap: NonZeroUsize) -> Self {
+        History {
+            buf: VecDeque::with_capacity(cap.get()),
+            cap: cap.get(),
+            start: 0,
+        }
+    }
+
+    /// Offset one past the newest byte recorded.
+    fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+
+    /// Records `data`, discarding the oldest output to make room.
+    fn push(&mut self, data: &[u8]) {
+        let skipped = data.len().saturating_sub(self.cap);
+        let data = &data[skipped..];
+        let excess = (self.buf.len() + data.len()).saturating_sub(self.cap);
+        self.buf.drain(..excess);
+        self.start += (excess + skipped) as u64;
+        self.buf.extend(data);
+    }
+
+    /// Copies output from `offset` into `out`, returning the offset of the
+    /// first byte copied and the number of bytes copied.
+    ///
+    /// Reading begins at the oldest byte retained if `offset` has been
+    /// discarded, and copies nothing if `offset` is beyond the newest byte.
+    fn read(&self, offset: u64, out: &mut [u8]) -> (u64, usize) {
+        let offset = offset.max(self.start).min(self.end());
+        let skip = (offset - self.start) as usize;
+        let n = out.len().min(self.buf.len() - skip);
+        for (dst, src) in out[..n].iter_mut().zip(self.buf.iter().skip(skip)) {
+            *dst = *src;
+        }
+        (offset, n)
+    }
+}
+
 /// Represents a serial connection into the VM.
+///
+/// Output from the device is recorded (see [`Serial::pump`]) so that any
+/// number of readers may observe it, including that written before they
+/// began reading.
 pub struct Serial<Device: Sink + Source> {
     uart: Arc<Device>,
 
     sink_poller: Arc<pollers::SinkBuffer>,
     source_poller: Arc<pollers::SourceBuffer>,
+
+    history: Mutex<History>,
+    /// Updated with the end offset of the history whenever output arrives.
+    output_tx: watch::Sender<u64>,
+    output_rx: watch::Receiver<u64>,
 }
 
 impl<Device: Sink + Source> Serial<Device> {
@@ -24,10 +89,12 @@ impl<Device: Sink + Source> Serial<Device> {
     /// * `uart` - The device which data will be read from / written to.
     /// * `sink_size` - A lower bound on the size of the writeback buffer.
     /// * `source_size` - A lower bound on the size of the read buffer.
+    /// * `history_size` - The number of bytes of output retained for replay.
     pub fn new(
         uart: Arc<Device>,
         sink_size: NonZeroUsize,
         source_size: NonZeroUsize,
+        history_size: NonZeroUsize,
     ) -> Serial<Device> {
         let sink_poller = pollers::SinkBuffer::new(sink_size);
         let source_poller = pollers::SourceBuffer::new(pollers::Params {
@@ -39,15 +106,90 @@ impl<Device: Sink + Source> Serial<Device> {
         source_poller.attach(uart.as_ref());
         uart.set_autodiscard(false);
 
-        Serial { uart, sink_poller, source_poller }
+        let (output_tx, output_rx) = watch::channel(0);
+        Serial {
+            uart,
+            sink_poller,
+            source_poller,
+            history: Mutex::new(History::new(history_size)),
+            output_tx,
+            output_rx,
+        }
     }
 
-    pub async fn read_source(
+    /// Records output from the device until the dispatcher stops the task
+    /// running it, also writing it to `log` if one is provided.  Should that
+    /// fail, the failure is reported to `logger` and `log` is abandoned.
+    ///
+    /// This should be the only consumer of the device's output; readers
+    /// observe it through [`Serial::read_output`].
+    pub async fn pump(
         &self,
-        buf: &mut [u8],
         actx: &AsyncCtx,
-    ) -> Option<usize> {
-        self.source_poller.read(buf, self.uart.as_ref(), actx).await
+        mut log: Option<RotatingFile>,
+        logger: Logger,
+    ) {
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = match self
+                .source_poller
+                .read(&mut buf, self.uart.as_ref(), actx)
+                .await
+            {
+                Some(n) if n > 0 => n,
+                _ => return,
+            };
+            if let Some(fp) = log.as_mut() {
+                if let Err(e) = fp.write_all(&buf[..n]).await {
+                    error!(logger, "Serial log write failed, disabling: {}", e);
+                    log = None;
+                }
+            }
+            let end = {
+                let mut history = self.history.lock().unwrap();
+                history.push(&buf[..n]);
+                history.end()
+            };
+            let _ = self.output_tx.send(end);
+        }
+    }
+
+    /// Returns the offset one past the newest byte of output.
+    pub fn output_end(&self) -> u64 {
+        self.history.lock().unwrap().end()
+    }
+
+    /// Returns a receiver to pass to [`Serial::read_output`].
+    pub fn output_watcher(&self) -> watch::Receiver<u64> {
+        self.output_rx.clone()
+    }
+
+    /// Copies output from `offset` into `buf`, waiting for some to arrive
+    /// if there is none yet.
+    ///
+    /// Returns the offset of the first byte copied, which is later than
+    /// `offset` if the output there has been discarded, and the number of
+    /// bytes copied.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe.
+    pub async fn read_output(
+        &self,
+        offset: u64,
+        buf: &mut [u8],
+        watcher: &mut watch::Receiver<u64>,
+    ) -> (u64, usize) {
+        loop {
+            let (start, n) = self.history.lock().unwrap().read(offset, buf);
+            if n > 0 || buf.is_empty() {
+                return (start, n);
+            }
+            if watcher.changed().await.is_err() {
+                // The sender lives as long as `self`, so this is unreachable.
+                return (start, 0);
+            }
+        }
     }
 
     pub async fn write_sink(
@@ -64,3 +206,52 @@ impl<Device: Sink + Source> Drop for Serial<Device> {
         self.uart.set_autodiscard(true);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn history(cap: usize) -> History {
+        History::new(NonZeroUsize::new(cap).unwrap())
+    }
+
+    #[test]
+    fn read_from_offset() {
+        let mut hist = history(8);
+        hist.push(b"hello");
+        let mut out = [0u8; 8];
+        assert_eq!(hist.read(0, &mut out), (0, 5));
+        assert_eq!(&out[..5], b"hello");
+        assert_eq!(hist.read(3, &mut out), (3, 2));
+        assert_eq!(&out[..2], b"lo");
+        assert_eq!(hist.read(5, &mut out), (5, 0));
+        assert_eq!(hist.read(100, &mut out), (5, 0));
+    }
+
+    #[test]
+    fn discard_oldest() {
+        let mut hist = history(8);
+        hist.push(b"hello");
+        hist.push(b"world");
+        assert_eq!(hist.end(), 10);
+
+        let mut out = [0u8; 8];
+        assert_eq!(hist.read(0, &mut out), (2, 8));
+        assert_eq!(&out, b"lloworld");
+
+        hist.push(b"0123456789");
+        assert_eq!(hist.end(), 20);
+        assert_eq!(hist.read(0, &mut out), (12, 8));
+        assert_eq!(&out, b"23456789");
+    }
+
+    #[test]
+    fn short_buffer() {
+        let mut hist = history(8);
+        hist.push(b"hello");
+        hist.push(b"world");
+        let mut out = [0u8; 3];
+        assert_eq!(hist.read(4, &mut out), (4, 3));
+        assert_eq!(&out, b"owo");
+    }
+}
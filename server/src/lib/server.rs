@@ -2,8 +2,9 @@
 
 use anyhow::Result;
 use dropshot::{
-    endpoint, ApiDescription, HttpError, HttpResponseCreated, HttpResponseOk,
-    HttpResponseUpdatedNoContent, Path, RequestContext, TypedBody,
+    endpoint, ApiDescription, HttpError, HttpResponseCreated,
+    HttpResponseDeleted, HttpResponseOk, HttpResponseUpdatedNoContent, Path,
+    Query, RequestContext, TypedBody,
 };
 use futures::future::Fuse;
 use futures::{FutureExt, SinkExt, StreamExt};
@@ -11,29 +12,48 @@ use hyper::upgrade::{self, Upgraded};
 use hyper::{header, Body, Response, StatusCode};
 use slog::{error, info, o, Logger};
 use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::{Error, ErrorKind};
 use std::ops::Range;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 use thiserror::Error;
-use tokio::sync::{oneshot, watch, Mutex};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
 use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
 use tokio_tungstenite::tungstenite::{
     self, handshake, protocol::Role, Message,
 };
 use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
 
 use propolis::bhyve_api;
-use propolis::dispatch::{AsyncCtx, AsyncTaskId};
+use propolis::block::{BlockDev, BlockReq, CryptBdev, NetBdev};
+use propolis::chardev::RotatingFile;
+use propolis::dispatch::{AsyncCtx, AsyncTaskId, DispCtx, Dispatcher};
+use propolis::firmware::smbios;
 use propolis::hw::chipset::Chipset;
 use propolis::hw::pci;
+use propolis::hw::ps2ctrl::PS2Ctrl;
+use propolis::hw::qemu::ramfb::{Frame, RamFb};
 use propolis::hw::uart::LpcUart;
-use propolis::instance::Instance;
+use propolis::instance::{Instance, ReqState};
+use propolis::vmm::destroy_vm;
 use propolis_client::api;
 
-use crate::config::Config;
-use crate::initializer::{build_instance, MachineInitializer};
+use crate::config::{self, Config, SerialBackend};
+use crate::initializer::{build_instance, DeviceResources, MachineInitializer};
+use crate::input;
 use crate::serial::Serial;
+use crate::vnc::VncServer;
+
+/// Time allowed for a guest to respond to a graceful stop or reboot request
+/// before the instance is forcibly stopped or reset.
+const GRACEFUL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Names of the serial ports, in the order they are initialized.
+const SERIAL_PORTS: [&str; 4] = ["com1", "com2", "com3", "com4"];
 
 // TODO(error) Do a pass of HTTP codes (error and ok)
 // TODO(idempotency) Idempotency mechanisms?
@@ -67,10 +87,45 @@ impl SerialTask {
     }
 }
 
+/// A serial port served through the console endpoints.
+struct SerialConsole {
+    serial: Arc<Serial<LpcUart>>,
+    /// The client holding input, and any others observing output
+    writer: Option<SerialTask>,
+    observers: Vec<SerialTask>,
+}
+
+impl SerialConsole {
+    fn new(serial: Arc<Serial<LpcUart>>) -> Self {
+        SerialConsole { serial, writer: None, observers: Vec::new() }
+    }
+
+    /// Is any client attached to the console
+    fn is_attached(&self) -> bool {
+        self.writer.iter().chain(self.observers.iter()).any(|s| s.is_attached())
+    }
+
+    /// Detaches all clients, waiting for their tasks to exit.
+    async fn detach(&mut self, disp: &Dispatcher) {
+        let tasks = self.writer.take().into_iter();
+        for serial_task in tasks.chain(self.observers.drain(..)) {
+            if serial_task.detach_ch.send(()).is_ok() {
+                disp.wait_exited(serial_task.taskid).await;
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 struct StateChange {
     gen: u64,
-    state: propolis::instance::State,
+    state: api::InstanceState,
+}
+
+// A disk attached to the instance, at its creation or since.
+struct DiskRecord {
+    bdf: pci::Bdf,
+    attachment: api::DiskAttachment,
 }
 
 // All context for a single propolis instance.
@@ -78,21 +133,99 @@ struct InstanceContext {
     // The instance, which may or may not be instantiated.
     instance: Arc<Instance>,
     properties: api::InstanceProperties,
-    serial: Arc<Serial<LpcUart>>,
+    // Properties to be applied when the instance next reboots.
+    pending: Option<api::InstanceProperties>,
+    // Whether the instance is held once reset, to be re-created.
+    hold_on_reset: bool,
+    // The devices to attach should the instance be re-created.
+    nics: Vec<api::NetworkInterfaceRequest>,
+    disk_requests: Vec<api::DiskRequest>,
+    hot_disks: Vec<api::InstanceDiskAttachRequest>,
+    // Shared with the eject callbacks of the disks, which mark them detached.
+    disks: Arc<std::sync::Mutex<Vec<DiskRecord>>>,
+    chipset: Arc<dyn Chipset>,
+    // The serial ports using the WebSocket backend, by name.
+    consoles: BTreeMap<String, SerialConsole>,
+    ramfb: Arc<RamFb>,
+    ps2: Arc<PS2Ctrl>,
+    state_tx: Arc<watch::Sender<StateChange>>,
     state_watcher: watch::Receiver<StateChange>,
-    serial_task: Option<SerialTask>,
+    // Signalled whenever the instance is reset.
+    reset_tx: mpsc::UnboundedSender<()>,
+    // Set when the instance is replaced, to silence its state reports.
+    retired: Arc<AtomicBool>,
+}
+
+impl InstanceContext {
+    /// Detaches all serial console clients, waiting for their tasks to exit.
+    async fn detach_serial(&mut self) {
+        for console in self.consoles.values_mut() {
+            console.detach(&self.instance.disp).await;
+        }
+    }
+
+    /// Halts the instance and waits for it to be torn down, before destroying
+    /// the VMM in the kernel.
+    async fn destroy(&mut self) -> Result<(), HttpError> {
+        self.detach_serial().await;
+
+        // Halting fails harmlessly if the instance already has been.
+        let _ = self.instance.set_target_state(ReqState::Halt);
+        let instance = Arc::clone(&self.instance);
+        tokio::task::spawn_blocking(move || {
+            instance.wait_for_state(propolis::instance::State::Destroy)
+        })
+        .await
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+        destroy_vm(self.properties.id.to_string()).map_err(|e| {
+            HttpError::for_internal_error(format!(
+                "Failed to destroy VM: {}",
+                e
+            ))
+        })
+    }
+}
+
+// The instances managed by the server.
+#[derive(Default)]
+struct Instances {
+    active: BTreeMap<Uuid, Arc<Mutex<InstanceContext>>>,
+    // IDs of the instances still being created, which are not yet active.
+    creating: BTreeSet<Uuid>,
 }
 
 /// Contextual information accessible from HTTP callbacks.
 pub struct Context {
-    context: Mutex<Option<InstanceContext>>,
-    config: Config,
+    instances: Mutex<Instances>,
+    config: Arc<Config>,
+    vnc: Arc<VncServer>,
 }
 
 impl Context {
     /// Creates a new server context object.
     pub fn new(config: Config) -> Self {
-        Context { context: Mutex::new(None), config }
+        Context {
+            instances: Mutex::new(Instances::default()),
+            config: Arc::new(config),
+            vnc: VncServer::new(),
+        }
+    }
+
+    /// Returns the VNC server, which provides the console of one instance at
+    /// a time.
+    pub fn vnc_server(&self) -> Arc<VncServer> {
+        Arc::clone(&self.vnc)
+    }
+
+    /// Returns the context of the instance with ID `id`.
+    async fn instance(
+        &self,
+        id: Uuid,
+    ) -> Result<Arc<Mutex<InstanceContext>>, HttpError> {
+        let instances = self.instances.lock().await;
+        instances.active.get(&id).cloned().ok_or_else(|| {
+            HttpError::for_not_found(None, format!("No such instance: {}", id))
+        })
     }
 }
 
@@ -102,10 +235,12 @@ fn api_to_propolis_state(
     use api::InstanceStateRequested as ApiState;
     use propolis::instance::ReqState as PropolisState;
 
+    // Graceful requests map to the state forced upon a guest which does not
+    // respond to them.
     match state {
         ApiState::Run => PropolisState::Run,
-        ApiState::Stop => PropolisState::Halt,
-        ApiState::Reboot => PropolisState::Reset,
+        ApiState::Stop | ApiState::GracefulStop => PropolisState::Halt,
+        ApiState::Reboot | ApiState::GracefulReboot => PropolisState::Reset,
     }
 }
 
@@ -129,10 +264,17 @@ fn propolis_to_api_state(
 #[derive(Clone, Copy, Debug)]
 enum SlotType {
     NIC,
-    #[allow(dead_code)]
     Disk,
 }
 
+// NICs and disks which do not fit on the root bus are placed behind a PCI-PCI
+// bridge for each type.  These are the root bus slots of those bridges, and the
+// bus numbers used to address the devices behind them.
+const NIC_BRIDGE_SLOT: u8 = 0x1c;
+const NIC_BRIDGE_BUS: u8 = 0x10;
+const DISK_BRIDGE_SLOT: u8 = 0x1d;
+const DISK_BRIDGE_BUS: u8 = 0x11;
+
 // This is a somewhat hard-coded translation of a stable "PCI slot" to a BDF.
 //
 // For all the devices requested by Nexus (network interfaces, disks, etc),
@@ -145,6 +287,13 @@ fn slot_to_bdf(slot: api::Slot, ty: SlotType) -> Result<pci::Bdf> {
         SlotType::NIC if slot.0 <= 7 => Ok(pci::Bdf::new(0, slot.0 + 0x8, 0)),
         // Slots for Disks: 0x10 -> 0x17
         SlotType::Disk if slot.0 <= 7 => Ok(pci::Bdf::new(0, slot.0 + 0x10, 0)),
+        // Further NICs and disks occupy the slots behind their bridge
+        SlotType::NIC if slot.0 <= 39 => {
+            Ok(pci::Bdf::new(NIC_BRIDGE_BUS, slot.0 - 8, 0))
+        }
+        SlotType::Disk if slot.0 <= 39 => {
+            Ok(pci::Bdf::new(DISK_BRIDGE_BUS, slot.0 - 8, 0))
+        }
         _ => Err(anyhow::anyhow!(
             "PCI Slot {} has no translation to BDF for type {:?}",
             slot.0,
@@ -153,63 +302,340 @@ fn slot_to_bdf(slot: api::Slot, ty: SlotType) -> Result<pci::Bdf> {
     }
 }
 
-/*
- * Instances: CRUD API
- */
-
-#[endpoint {
-    method = PUT,
-    path = "/instances/{instance_id}",
-}]
-async fn instance_ensure(
-    rqctx: Arc<RequestContext<Context>>,
-    path_params: Path<api::InstancePathParams>,
-    request: TypedBody<api::InstanceEnsureRequest>,
-) -> Result<HttpResponseCreated<api::InstanceEnsureResponse>, HttpError> {
-    let server_context = rqctx.context();
-
-    let request = request.into_inner();
-    let (properties, nics) = (request.properties, request.nics);
-    if path_params.into_inner().instance_id != properties.id {
-        return Err(HttpError::for_internal_error(
-            "UUID mismatch (path did not match struct)".to_string(),
+/// Translates a slot, given to one of the hotplug endpoints, to a BDF.
+///
+/// Only the slots of the root bus support hotplug, not those behind the
+/// bridges.
+fn hotplug_slot_to_bdf(
+    slot: api::Slot,
+    ty: SlotType,
+) -> Result<pci::Bdf, HttpError> {
+    let bdf = slot_to_bdf(slot, ty)
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+    if bdf.bus() != 0 {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("slot {} does not support hotplug", slot.0),
         ));
     }
+    Ok(bdf)
+}
 
-    // Handle requsts to an instance that has already been initialized.
-    let mut context = server_context.context.lock().await;
-    if let Some(ctx) = &*context {
-        if ctx.properties.id != properties.id {
-            return Err(HttpError::for_internal_error(format!(
-                "Server already initialized with ID {}",
-                ctx.properties.id
-            )));
+/// Arranges for the resources of the device at `bdf` to be released once
+/// the guest ejects it, so that another may take its place.
+///
+/// `on_release` is invoked once they have been.
+fn release_on_eject(
+    instance: &Arc<Instance>,
+    chipset: &Arc<dyn Chipset>,
+    bdf: pci::Bdf,
+    res: DeviceResources,
+    on_release: impl FnOnce() + Send + 'static,
+) -> std::io::Result<()> {
+    let instance = Arc::downgrade(instance);
+    chipset.pci_on_eject(
+        bdf,
+        Box::new(move |ctx: &DispCtx| {
+            // Joining the backend thread and updating the inventory block, so
+            // cannot be done on the vCPU or instance driver thread performing
+            // the eject.
+            ctx.spawn_async(move |_actx| async move {
+                let _ = tokio::task::spawn_blocking(move || {
+                    let instance = match instance.upgrade() {
+                        Some(instance) => instance,
+                        None => return,
+                    };
+                    if let Some(worker) = res.worker {
+                        let _ = instance.disp.stop_sync(&worker);
+                    }
+                    let _ = instance.unplug(res.id);
+                    on_release();
+                })
+                .await;
+            });
+        }),
+    )
+}
+
+/// Creates the disk described by `request`, backed by one of the block
+/// devices in the config, to be attached at `bdf`.
+fn create_config_disk(
+    init: &MachineInitializer,
+    config: &Config,
+    request: &api::InstanceDiskAttachRequest,
+    bdf: pci::Bdf,
+) -> Result<(Arc<pci::DeviceInst>, DeviceResources), Error> {
+    let parse_err = |e: config::ParseError| {
+        Error::new(ErrorKind::InvalidData, format!("ParseError: {:?}", e))
+    };
+    match request.interface {
+        api::DiskType::VirtioBlock => {
+            let block_dev = config
+                .create_block_device::<propolis::hw::virtio::block::Request>(
+                    &request.name,
+                )
+                .map_err(parse_err)?;
+            init.create_vioblk(bdf, &request.name, block_dev)
+        }
+        api::DiskType::NVMe => {
+            let block_dev = config
+                .create_block_device::<propolis::hw::nvme::Request>(
+                    &request.name,
+                )
+                .map_err(parse_err)?;
+            init.create_nvme(bdf, &request.name, block_dev)
         }
+    }
+}
 
-        // If properties match, we return Ok. Otherwise, we could attempt to
-        // update the instance.
-        //
-        // TODO: The initial implementation does not modify any properties,
-        // but we plausibly could do so - need to work out which properties
-        // can be changed without rebooting.
-        //
-        // TODO: We presumably would want to alter network interfaces here too.
-        // Might require the instance to be powered off.
-        if ctx.properties != properties {
-            return Err(HttpError::for_internal_error(
-                "Cannot update running server".to_string(),
+/// Marks the disk at `bdf` as detached, once the guest has ejected it.
+///
+/// The guest may eject a disk unprompted, as well as once asked to.
+fn disk_ejected(disks: &std::sync::Mutex<Vec<DiskRecord>>, bdf: pci::Bdf) {
+    let mut disks = disks.lock().unwrap();
+    let ejected = disks.iter_mut().find(|disk| {
+        disk.bdf == bdf
+            && matches!(
+                disk.attachment.state,
+                api::DiskAttachmentState::Attached(_)
+                    | api::DiskAttachmentState::Detaching(_)
+            )
+    });
+    if let Some(disk) = ejected {
+        disk.attachment.generation_id += 1;
+        disk.attachment.state = api::DiskAttachmentState::Detached;
+    }
+}
+
+/// Reports a failure to attach a device, as bad input if the request named
+/// a backend which does not exist or cannot be used.
+fn attach_err(what: &str, e: Error) -> HttpError {
+    let msg = format!("Failed to attach {}: {}", what, e);
+    match e.kind() {
+        ErrorKind::NotFound
+        | ErrorKind::InvalidInput
+        | ErrorKind::InvalidData => HttpError::for_bad_request(None, msg),
+        _ => HttpError::for_internal_error(msg),
+    }
+}
+
+/// Checks that the devices requested from the HTTP interface each occupy a
+/// distinct slot, which no device from the config already claims.
+///
+/// Two devices at the same BDF would otherwise only be caught when the
+/// second is attached to the bus, well into the creation of the instance.
+fn check_slots(
+    config: &Config,
+    nics: &[api::NetworkInterfaceRequest],
+    disks: &[api::DiskRequest],
+) -> Result<(), HttpError> {
+    let mut claimed: Vec<(pci::Bdf, String)> = config
+        .devs()
+        .filter_map(|(name, dev)| {
+            Some((dev.get("pci-path")?, format!("config device {}", name)))
+        })
+        .collect();
+    let requested = nics
+        .iter()
+        .map(|nic| (nic.slot, SlotType::NIC, format!("NIC {}", nic.name)))
+        .chain(disks.iter().map(|disk| {
+            (disk.slot, SlotType::Disk, format!("disk {}", disk.disk.id))
+        }));
+    for (slot, ty, what) in requested {
+        let bdf = slot_to_bdf(slot, ty)
+            .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+        if let Some((_, other)) = claimed.iter().find(|(b, _)| *b == bdf) {
+            return Err(HttpError::for_bad_request(
+                None,
+                format!(
+                    "{} cannot use slot {}, which is taken by {}",
+                    what, slot.0, other
+                ),
             ));
         }
+        claimed.push((bdf, what));
+    }
+    Ok(())
+}
+
+/// Creates the block device backing a disk requested by the client.
+///
+/// A disk with storage agents is backed by those agents.  Otherwise, it is
+/// backed by the block device of the server config named after the disk's
+/// UUID, the geometry of which must match that of the disk.
+///
+/// If the disk has a key, its contents are encrypted with it before reaching
+/// the backing device.
+fn create_disk_backend<R: BlockReq>(
+    config: &Config,
+    disk: &api::Disk,
+) -> std::io::Result<Arc<dyn BlockDev<R>>> {
+    let agents = &disk.storage_agents;
+    let block_dev: Arc<dyn BlockDev<R>> = if agents.agents.is_empty() {
+        config.create_block_device::<R>(&disk.id.to_string()).map_err(|e| {
+            Error::new(ErrorKind::InvalidData, format!("ParseError: {:?}", e))
+        })?
+    } else {
+        let addrs = agents.agents.iter().map(|a| (*a).into()).collect();
+        NetBdev::<R>::create(
+            addrs,
+            agents.write_redundancy_threshold as usize,
+            disk.block_size,
+            disk.block_count,
+        )?
+    };
+
+    let info = block_dev.inquire();
+    if info.block_size != disk.block_size || info.total_size != disk.block_count
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Disk {} has {} blocks of {} bytes, but its block device has \
+                {} blocks of {} bytes",
+                disk.id,
+                disk.block_count,
+                disk.block_size,
+                info.total_size,
+                info.block_size
+            ),
+        ));
+    }
+    if agents.key.is_empty() {
+        return Ok(block_dev);
+    }
+
+    let store = block_dev.as_store().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Block device of disk {} cannot be encrypted", disk.id),
+        )
+    })?;
+    Ok(CryptBdev::<R>::create(store, info, &agents.key)?)
+}
+
+/// Describes an instance to the client.
+fn instance_info(context: &InstanceContext) -> api::Instance {
+    api::Instance {
+        properties: context.properties.clone(),
+        state: context.state_watcher.borrow().state,
+        disks: context
+            .disks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|disk| disk.attachment.clone())
+            .collect(),
+        // TODO: Fix this; we need a way to enumerate attached NICs.
+        // Possibly using the inventory of the instance?
+        //
+        // We *could* record whatever information about the NIC we want
+        // when they're requested (adding fields to the server), but that
+        // would make it difficult for Propolis to update any dynamic info
+        // (i.e., has the device faulted, etc).
+        nics: vec![],
+    }
+}
+
+/// Derives the SMBIOS identification of an instance from its properties,
+/// subject to any overrides in the server config.
+fn smbios_config(
+    properties: &api::InstanceProperties,
+    overrides: &config::Smbios,
+    lowmem: usize,
+    highmem: usize,
+) -> smbios::Config {
+    let pick = |field: &Option<String>, default: &str| {
+        field.clone().unwrap_or_else(|| default.to_string())
+    };
+    let id = properties.id.to_string();
+    let manufacturer = pick(&overrides.manufacturer, "Oxide");
+    let serial_number = pick(&overrides.serial_number, &id);
+
+    let mut mem_regions = vec![(0, lowmem as u64)];
+    if highmem > 0 {
+        mem_regions.push((0x1_0000_0000, highmem as u64));
+    }
 
-        return Ok(HttpResponseCreated(api::InstanceEnsureResponse {}));
+    smbios::Config {
+        bios: smbios::BiosInfo {
+            vendor: pick(&overrides.bios_vendor, "Oxide"),
+            version: pick(&overrides.bios_version, env!("CARGO_PKG_VERSION")),
+            release_date: pick(&overrides.bios_release_date, ""),
+        },
+        system: smbios::SystemInfo {
+            manufacturer: manufacturer.clone(),
+            product: pick(&overrides.product, "Propolis"),
+            version: pick(&overrides.version, ""),
+            serial_number: serial_number.clone(),
+            uuid: *properties.id.as_bytes(),
+            sku: pick(&overrides.sku, ""),
+            family: pick(&overrides.family, ""),
+        },
+        chassis: smbios::ChassisInfo {
+            manufacturer,
+            version: String::new(),
+            serial_number,
+            asset_tag: pick(&overrides.chassis_asset_tag, &properties.name),
+            sku: String::new(),
+        },
+        num_cpus: properties.vcpus,
+        mem_regions,
     }
+}
+
+/// Opens the file to which the output of serial port `port` is logged, if the
+/// config calls for it.
+fn open_serial_log(
+    config: &Config,
+    properties: &api::InstanceProperties,
+    port: &str,
+) -> Result<Option<RotatingFile>, Error> {
+    let log = match config.serial_log() {
+        Some(log) if log.ports.iter().any(|p| p == port) => log,
+        _ => return Ok(None),
+    };
+    let path = log.directory.join(format!("{}.{}.log", properties.id, port));
+    RotatingFile::create(&path, log.max_size, log.keep).map(Some).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Cannot open serial log {}: {}", path.display(), e),
+        )
+    })
+}
 
+/// Creates an instance with the given properties and devices, reporting its
+/// state transitions through `tx`, and its resets through `reset_tx`.
+///
+/// Problems with the instance's devices once it is running, such as failures
+/// to write serial logs, are reported to `log`.
+fn create_instance(
+    config: &Config,
+    vnc: &VncServer,
+    log: &Logger,
+    properties: api::InstanceProperties,
+    nics: Vec<api::NetworkInterfaceRequest>,
+    disks: Vec<api::DiskRequest>,
+    hot_disks: Vec<api::InstanceDiskAttachRequest>,
+    tx: Arc<watch::Sender<StateChange>>,
+    rx: watch::Receiver<StateChange>,
+    reset_tx: mpsc::UnboundedSender<()>,
+) -> Result<InstanceContext, HttpError> {
     const MB: usize = 1024 * 1024;
     const GB: usize = 1024 * 1024 * 1024;
     let memsize = properties.memory as usize * MB;
     let lowmem = memsize.min(3 * GB);
     let highmem = memsize.saturating_sub(3 * GB);
 
+    check_slots(config, &nics, &disks)?;
+
+    let chipset_kind = config.chipset().map_err(|err| {
+        HttpError::for_internal_error(format!(
+            "Invalid chipset: {}",
+            err.to_string()
+        ))
+    })?;
+
     // Create the instance.
     //
     // The VM is named after the UUID, ensuring that it is unique.
@@ -230,17 +656,74 @@ async fn instance_ensure(
     //
     // This initialization may be refactored to be client-controlled,
     // but it is currently hard-coded for simplicity.
-    let mut com1: Option<Serial<LpcUart>> = None;
+    let mut chipset_dev: Option<Arc<dyn Chipset>> = None;
+    let mut consoles = BTreeMap::new();
+    let mut ps2: Option<Arc<PS2Ctrl>> = None;
+    let mut ramfb: Option<Arc<RamFb>> = None;
+    let mut disk_records = Vec::new();
+    let mut ejectable = Vec::new();
 
     instance
         .initialize(|machine, mctx, disp, inv| {
             let init = MachineInitializer::new(machine, mctx, disp, inv);
-            init.initialize_rom(server_context.config.get_bootrom())?;
+            init.initialize_rom(config.get_bootrom())?;
             machine.initialize_rtc(lowmem, highmem).unwrap();
-            let chipset = init.initialize_chipset()?;
-            com1 = Some(init.initialize_uart(&chipset)?);
-            init.initialize_ps2(&chipset)?;
+            let chipset = init.initialize_chipset(chipset_kind)?;
+            chipset_dev = Some(Arc::clone(chipset.device()));
+            let uarts = init.initialize_uart(&chipset)?;
+            for (uart, port) in uarts.into_iter().zip(SERIAL_PORTS.iter()) {
+                let port_config = config.serial_port(port);
+                let port_log = log.new(o!("serial" => port.to_string()));
+                if let Some(file) = open_serial_log(config, &properties, port)? {
+                    init.initialize_uart_log(&uart, file, port_log.clone())?;
+                }
+                let path = || {
+                    port_config.path_for(&properties.id.to_string()).map_err(
+                        |e| {
+                            Error::new(
+                                ErrorKind::InvalidData,
+                                format!("ParseError: {:?}", e),
+                            )
+                        },
+                    )
+                };
+                match port_config.backend {
+                    SerialBackend::WebSocket => {
+                        // Output is recorded from the outset, so it can be
+                        // replayed to clients attaching later.
+                        let serial =
+                            init.initialize_uart_console(uart, None, port_log);
+                        consoles.insert(
+                            port.to_string(),
+                            SerialConsole::new(serial),
+                        );
+                    }
+                    SerialBackend::Socket => {
+                        init.initialize_uart_socket(uart, &path()?)?;
+                    }
+                    SerialBackend::File => {
+                        let fp = std::fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path()?)?;
+                        init.initialize_uart_console(
+                            uart,
+                            Some(RotatingFile::unbounded(fp)),
+                            port_log,
+                        );
+                    }
+                    SerialBackend::Null => {}
+                }
+            }
+            ps2 = Some(init.initialize_ps2(&chipset)?);
             init.initialize_qemu_debug_port()?;
+            init.initialize_pci_bridges(
+                &chipset,
+                &[
+                    (pci::Bdf::new(0, NIC_BRIDGE_SLOT, 0), NIC_BRIDGE_BUS),
+                    (pci::Bdf::new(0, DISK_BRIDGE_SLOT, 0), DISK_BRIDGE_BUS),
+                ],
+            )?;
 
             // Attach devices which have been requested from the HTTP interface.
             for nic in &nics {
@@ -251,7 +734,66 @@ async fn instance_ensure(
                             format!("Cannot parse vnic PCI: {}", e),
                         )
                     })?;
-                init.initialize_vnic(&chipset, &nic.name, bdf)?;
+                let res = init.initialize_vnic(&chipset, &nic.name, bdf)?;
+                ejectable.push((bdf, res));
+            }
+            for disk in &disks {
+                let bdf =
+                    slot_to_bdf(disk.slot, SlotType::Disk).map_err(|e| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Cannot parse disk PCI: {}", e),
+                        )
+                    })?;
+                let name = disk.disk.id.to_string();
+                let res = match disk.disk.interface {
+                    api::DiskType::VirtioBlock => {
+                        let block_dev = create_disk_backend::<
+                            propolis::hw::virtio::block::Request,
+                        >(config, &disk.disk)?;
+                        init.initialize_block(&chipset, bdf, &name, block_dev)?
+                    }
+                    api::DiskType::NVMe => {
+                        let block_dev = create_disk_backend::<
+                            propolis::hw::nvme::Request,
+                        >(config, &disk.disk)?;
+                        init.initialize_nvme(&chipset, bdf, &name, block_dev)?
+                    }
+                };
+                ejectable.push((bdf, res));
+                disk_records.push(DiskRecord {
+                    bdf,
+                    attachment: api::DiskAttachment {
+                        generation_id: 0,
+                        disk_id: disk.disk.id,
+                        state: api::DiskAttachmentState::Attached(
+                            properties.id,
+                        ),
+                    },
+                });
+            }
+            // Disks hot-plugged into a predecessor of the instance.
+            for disk in &hot_disks {
+                let bdf =
+                    slot_to_bdf(disk.slot, SlotType::Disk).map_err(|e| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Cannot parse disk PCI: {}", e),
+                        )
+                    })?;
+                let (dev, res) = create_config_disk(&init, config, disk, bdf)?;
+                chipset.device().pci_attach(bdf, dev)?;
+                ejectable.push((bdf, res));
+                disk_records.push(DiskRecord {
+                    bdf,
+                    attachment: api::DiskAttachment {
+                        generation_id: 0,
+                        disk_id: disk.id,
+                        state: api::DiskAttachmentState::Attached(
+                            properties.id,
+                        ),
+                    },
+                });
             }
 
             // Attach devices which are hard-coded in the config.
@@ -259,7 +801,7 @@ async fn instance_ensure(
             // NOTE: This interface is effectively a stop-gap for development
             // purposes. Longer term, peripherals will be attached via separate
             // HTTP interfaces.
-            for (devname, dev) in server_context.config.devs() {
+            for (devname, dev) in config.devs() {
                 let driver = &dev.driver as &str;
                 match driver {
                     "pci-virtio-block" => {
@@ -274,8 +816,7 @@ async fn instance_ensure(
                                 Error::new(ErrorKind::InvalidData, format!("as_str() failed for {}'s block_dev!", devname))
                             })?;
 
-                        let block_dev = server_context
-                            .config
+                        let block_dev = config
                             .create_block_device::<propolis::hw::virtio::block::Request>(
                             block_dev_name,
                         ).map_err(|e| {
@@ -324,7 +865,17 @@ async fn instance_ensure(
 
             // Finalize device.
             chipset.device().pci_finalize(mctx);
-            init.initialize_fwcfg(&chipset, properties.vcpus)?;
+            let smbios = smbios_config(
+                &properties,
+                config.smbios(),
+                lowmem,
+                highmem,
+            );
+            ramfb = Some(init.initialize_fwcfg(
+                &chipset,
+                properties.vcpus,
+                &smbios,
+            )?);
             init.initialize_cpus()?;
             Ok(())
         })
@@ -335,12 +886,31 @@ async fn instance_ensure(
             ))
         })?;
 
-    let (tx, rx) = watch::channel(StateChange {
-        gen: 0,
-        state: propolis::instance::State::Initialize,
-    });
     instance.print();
 
+    // Devices requested from the HTTP interface may later be unplugged, if
+    // they sit on the root bus.
+    let chipset = chipset_dev.unwrap();
+    let disk_records = Arc::new(std::sync::Mutex::new(disk_records));
+    for (bdf, res) in ejectable {
+        if bdf.bus() == 0 {
+            let disks = Arc::clone(&disk_records);
+            release_on_eject(&instance, &chipset, bdf, res, move || {
+                disk_ejected(&disks, bdf)
+            })
+            .map_err(|e| {
+                HttpError::for_internal_error(format!(
+                    "Failed to initialize machine: {}",
+                    e
+                ))
+            })?;
+        }
+    }
+
+    let retired = Arc::new(AtomicBool::new(false));
+    let retired_cb = Arc::clone(&retired);
+    let tx_cb = Arc::clone(&tx);
+    let reset_cb = reset_tx.clone();
     instance.on_transition(Box::new(move |next_state, ctx| {
         match next_state {
             propolis::instance::State::Boot => {
@@ -359,23 +929,316 @@ async fn instance_ensure(
                     }
                 }
             }
+            propolis::instance::State::Reset => {
+                // Watchers of the state may only see a later one, so resets
+                // are signalled separately.
+                let _ = reset_cb.send(());
+            }
             _ => {}
         }
         println!("state cb: {:?}", next_state);
-        let last = (*tx.borrow()).clone();
-        let _ = tx.send(StateChange { gen: last.gen + 1, state: next_state });
+        if retired_cb.load(Ordering::Acquire) {
+            // The instance is being replaced, and its successor reports the
+            // state from here on.
+            return;
+        }
+        let last = (*tx_cb.borrow()).clone();
+        let _ = tx_cb.send(StateChange {
+            gen: last.gen + 1,
+            state: propolis_to_api_state(next_state),
+        });
     }));
 
-    // Save the newly created instance in the server's context.
-    *context = Some(InstanceContext {
+    // The instance is still usable without a graphical console.
+    if let Err(e) = vnc.attach(
+        properties.id.to_string(),
+        Arc::clone(&instance),
+        Arc::clone(ramfb.as_ref().unwrap()),
+        Arc::clone(ps2.as_ref().unwrap()),
+    ) {
+        info!(log, "No VNC console for instance {}: {}", properties.id, e);
+    }
+
+    Ok(InstanceContext {
         instance,
         properties,
-        serial: Arc::new(com1.unwrap()),
+        pending: None,
+        hold_on_reset: false,
+        nics,
+        disk_requests: disks,
+        hot_disks,
+        disks: disk_records,
+        chipset,
+        consoles,
+        ramfb: ramfb.unwrap(),
+        ps2: ps2.unwrap(),
+        state_tx: tx,
         state_watcher: rx,
-        serial_task: None,
+        reset_tx,
+        retired,
+    })
+}
+
+/// Updates the properties of a running instance.
+///
+/// The name and description are applied immediately. The vCPU count and
+/// memory size of a VM are fixed when it is created, so changes to them are
+/// deferred until the instance next reboots, at which point it is re-created
+/// (see [`apply_on_reset`]).
+///
+/// TODO: We presumably would want to alter network interfaces here too.
+fn update_instance(
+    ctx: &mut InstanceContext,
+    properties: api::InstanceProperties,
+) -> Result<api::InstanceEnsureResponse, HttpError> {
+    use api::InstanceProperty;
+
+    if ctx.properties.image_id != properties.image_id
+        || ctx.properties.bootrom_id != properties.bootrom_id
+    {
+        return Err(HttpError::for_internal_error(
+            "Cannot change the image or bootrom of a running server"
+                .to_string(),
+        ));
+    }
+
+    let mut response = api::InstanceEnsureResponse::default();
+    if ctx.properties.name != properties.name {
+        response.applied.push(InstanceProperty::Name);
+    }
+    if ctx.properties.description != properties.description {
+        response.applied.push(InstanceProperty::Description);
+    }
+    if ctx.properties.vcpus != properties.vcpus {
+        response.deferred.push(InstanceProperty::Vcpus);
+    }
+    if ctx.properties.memory != properties.memory {
+        response.deferred.push(InstanceProperty::Memory);
+    }
+
+    ctx.properties.name = properties.name.clone();
+    ctx.properties.description = properties.description.clone();
+    // A request restoring the current vCPUs and memory cancels any
+    // outstanding change to them.
+    ctx.pending =
+        if response.deferred.is_empty() { None } else { Some(properties) };
+    // Rather than booting again, the instance waits to be re-created. It may
+    // already be held, so remains so even if the change is withdrawn.
+    if ctx.pending.is_some() && !ctx.hold_on_reset {
+        ctx.instance.set_hold_on_reset(true);
+        ctx.hold_on_reset = true;
+    }
+    Ok(response)
+}
+
+/// Re-creates the instance behind `context`, with its pending properties, on
+/// each reset `reset_rx` receives while it is held for that.
+///
+/// The old instance is held in the Quiesce state from its reset until it is
+/// torn down. The devices attached to it, including those hot-plugged since
+/// it was created, are attached to its successor. Should it not be possible
+/// to re-create the instance, it is reported as failed.
+///
+/// Exits once the instance has been deleted.
+async fn apply_on_reset(
+    config: Arc<Config>,
+    vnc: Arc<VncServer>,
+    context: Weak<Mutex<InstanceContext>>,
+    mut reset_rx: mpsc::UnboundedReceiver<()>,
+    log: Logger,
+) {
+    while reset_rx.recv().await.is_some() {
+        let context = match context.upgrade() {
+            Some(context) => context,
+            None => return,
+        };
+        // Held throughout, so that no request acts upon the old instance
+        // once it is torn down.
+        let mut context = context.lock().await;
+        if !context.hold_on_reset {
+            continue;
+        }
+        let properties = match context.pending.take() {
+            Some(properties) => properties,
+            None => context.properties.clone(),
+        };
+
+        info!(
+            log,
+            "Re-creating instance with {} vCPUs and {} MiB of memory",
+            properties.vcpus,
+            properties.memory
+        );
+        context.retired.store(true, Ordering::Release);
+        if let Err(e) = context.destroy().await {
+            error!(log, "Failed to destroy instance: {}", e.internal_message);
+            report_failed(&context.state_tx);
+            return;
+        }
+
+        let attached: Vec<Uuid> = context
+            .disks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|disk| {
+                matches!(
+                    disk.attachment.state,
+                    api::DiskAttachmentState::Attached(_)
+                )
+            })
+            .map(|disk| disk.attachment.disk_id)
+            .collect();
+        let disks = context
+            .disk_requests
+            .iter()
+            .filter(|req| attached.contains(&req.disk.id))
+            .cloned()
+            .collect();
+        let hot_disks = context
+            .hot_disks
+            .iter()
+            .filter(|req| attached.contains(&req.id))
+            .cloned()
+            .collect();
+
+        // Building the instance blocks, so is kept off the async runtime.
+        let (config, vnc, log) =
+            (Arc::clone(&config), Arc::clone(&vnc), log.clone());
+        let nics = context.nics.clone();
+        let state_tx = Arc::clone(&context.state_tx);
+        let state_watcher = context.state_watcher.clone();
+        let reset_tx = context.reset_tx.clone();
+        let created = tokio::task::spawn_blocking(move || {
+            create_instance(
+                &config,
+                &vnc,
+                &log,
+                properties,
+                nics,
+                disks,
+                hot_disks,
+                state_tx,
+                state_watcher,
+                reset_tx,
+            )
+        })
+        .await
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))
+        .and_then(|created| created);
+        match created {
+            Ok(created) => {
+                *context = created;
+                let _ = context.instance.set_target_state(ReqState::Run);
+            }
+            Err(e) => {
+                error!(
+                    log,
+                    "Failed to re-create instance: {}", e.internal_message
+                );
+                report_failed(&context.state_tx);
+                return;
+            }
+        }
+    }
+}
+
+/// Reports through `state_tx` that the instance has failed.
+fn report_failed(state_tx: &watch::Sender<StateChange>) {
+    let last = (*state_tx.borrow()).clone();
+    let _ = state_tx.send(StateChange {
+        gen: last.gen + 1,
+        state: api::InstanceState::Failed,
     });
+}
+
+/*
+ * Instances: CRUD API
+ */
+
+#[endpoint {
+    method = PUT,
+    path = "/instances/{instance_id}",
+}]
+async fn instance_ensure(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstancePathParams>,
+    request: TypedBody<api::InstanceEnsureRequest>,
+) -> Result<HttpResponseCreated<api::InstanceEnsureResponse>, HttpError> {
+    let server_context = rqctx.context();
 
-    Ok(HttpResponseCreated(api::InstanceEnsureResponse {}))
+    let request = request.into_inner();
+    let (properties, nics, disks) =
+        (request.properties, request.nics, request.disks);
+    if path_params.into_inner().instance_id != properties.id {
+        return Err(HttpError::for_internal_error(
+            "UUID mismatch (path did not match struct)".to_string(),
+        ));
+    }
+
+    // Handle requsts to an instance that has already been initialized.
+    //
+    // Otherwise, the ID is reserved while the instance is created, so that
+    // concurrent requests cannot create it twice, without holding up those
+    // for other instances.
+    let id = properties.id;
+    let mut instances = server_context.instances.lock().await;
+    if let Some(ctx) = instances.active.get(&id).cloned() {
+        drop(instances);
+        let mut ctx = ctx.lock().await;
+        let response = update_instance(&mut ctx, properties)?;
+        return Ok(HttpResponseCreated(response));
+    }
+    if instances.creating.contains(&id) {
+        return Err(HttpError::for_unavail(
+            None,
+            format!("Instance {} is still being created", id),
+        ));
+    }
+    // The backends of the devices in the config cannot be shared.
+    let in_use = !instances.active.is_empty() || !instances.creating.is_empty();
+    if in_use && server_context.config.devs().next().is_some() {
+        return Err(HttpError::for_bad_request(
+            None,
+            "Devices from the config can only be attached to a single \
+            instance"
+                .to_string(),
+        ));
+    }
+    instances.creating.insert(id);
+    drop(instances);
+
+    let (tx, rx) = watch::channel(StateChange {
+        gen: 0,
+        state: api::InstanceState::Creating,
+    });
+    let (reset_tx, reset_rx) = mpsc::unbounded_channel();
+    let log = rqctx.log.new(o!("instance" => id.to_string()));
+    let context = create_instance(
+        &server_context.config,
+        &server_context.vnc,
+        &log,
+        properties,
+        nics,
+        disks,
+        Vec::new(),
+        Arc::new(tx),
+        rx,
+        reset_tx,
+    );
+    let mut instances = server_context.instances.lock().await;
+    instances.creating.remove(&id);
+    let context = Arc::new(Mutex::new(context?));
+    tokio::spawn(apply_on_reset(
+        Arc::clone(&server_context.config),
+        Arc::clone(&server_context.vnc),
+        Arc::downgrade(&context),
+        reset_rx,
+        log,
+    ));
+    instances.active.insert(id, context);
+
+    Ok(HttpResponseCreated(api::InstanceEnsureResponse::default()))
 }
 
 #[endpoint {
@@ -386,37 +1249,73 @@ async fn instance_get(
     rqctx: Arc<RequestContext<Context>>,
     path_params: Path<api::InstancePathParams>,
 ) -> Result<HttpResponseOk<api::InstanceGetResponse>, HttpError> {
-    let context = rqctx.context().context.lock().await;
+    let context =
+        rqctx.context().instance(path_params.into_inner().instance_id).await?;
+    let context = context.lock().await;
+    let instance_info = instance_info(&context);
 
-    let context = context.as_ref().ok_or_else(|| {
-        HttpError::for_internal_error(
-            "Server not initialized (no instance)".to_string(),
-        )
-    })?;
+    Ok(HttpResponseOk(api::InstanceGetResponse { instance: instance_info }))
+}
 
-    if path_params.into_inner().instance_id != context.properties.id {
-        return Err(HttpError::for_internal_error(
-            "UUID mismatch (path did not match struct)".to_string(),
-        ));
+#[endpoint {
+    method = GET,
+    path = "/instances",
+}]
+async fn instance_list(
+    rqctx: Arc<RequestContext<Context>>,
+) -> Result<HttpResponseOk<api::InstanceListResponse>, HttpError> {
+    // The instances are collected first, as the lock on each is taken before
+    // the map's when it is deleted.
+    let instances: Vec<_> = rqctx
+        .context()
+        .instances
+        .lock()
+        .await
+        .active
+        .values()
+        .cloned()
+        .collect();
+    let mut list = Vec::with_capacity(instances.len());
+    for context in instances {
+        list.push(instance_info(&*context.lock().await));
     }
-    let instance_info = api::Instance {
-        properties: context.properties.clone(),
-        state: propolis_to_api_state(context.instance.current_state()),
-        disks: vec![],
-        // TODO: Fix this; we need a way to enumerate attached NICs.
-        // Possibly using the inventory of the instance?
-        //
-        // We *could* record whatever information about the NIC we want
-        // when they're requested (adding fields to the server), but that
-        // would make it difficult for Propolis to update any dynamic info
-        // (i.e., has the device faulted, etc).
-        nics: vec![],
-    };
 
-    Ok(HttpResponseOk(api::InstanceGetResponse { instance: instance_info }))
+    Ok(HttpResponseOk(api::InstanceListResponse { instances: list }))
 }
 
-// TODO: Instance delete. What happens to the server? Does it shut down?
+#[endpoint {
+    method = DELETE,
+    path = "/instances/{instance_id}",
+}]
+async fn instance_delete(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstancePathParams>,
+) -> Result<HttpResponseDeleted, HttpError> {
+    let server_context = rqctx.context();
+    let id = path_params.into_inner().instance_id;
+    let context = server_context.instance(id).await?;
+    let mut guard = context.lock().await;
+
+    // The instance is only forgotten once it has been destroyed, so that a
+    // failure to do so may be retried.  A concurrent request may have beaten
+    // this one to it, though.
+    let instances = server_context.instances.lock().await;
+    match instances.active.get(&id) {
+        Some(active) if Arc::ptr_eq(active, &context) => {}
+        _ => {
+            return Err(HttpError::for_not_found(
+                None,
+                format!("No such instance: {}", id),
+            ))
+        }
+    }
+    drop(instances);
+    guard.destroy().await?;
+    server_context.vnc.detach(&id.to_string());
+    server_context.instances.lock().await.active.remove(&id);
+
+    Ok(HttpResponseDeleted())
+}
 
 #[endpoint {
     method = GET,
@@ -428,18 +1327,11 @@ async fn instance_state_monitor(
     request: TypedBody<api::InstanceStateMonitorRequest>,
 ) -> Result<HttpResponseOk<api::InstanceStateMonitorResponse>, HttpError> {
     let (mut state_watcher, gen) = {
-        let context = rqctx.context().context.lock().await;
-        let context = context.as_ref().ok_or_else(|| {
-            HttpError::for_internal_error(
-                "Server not initialized (no instance)".to_string(),
-            )
-        })?;
-        let path_params = path_params.into_inner();
-        if path_params.instance_id != context.properties.id {
-            return Err(HttpError::for_internal_error(
-                "UUID mismatch (path did not match struct)".to_string(),
-            ));
-        }
+        let context = rqctx
+            .context()
+            .instance(path_params.into_inner().instance_id)
+            .await?;
+        let context = context.lock().await;
 
         let gen = request.into_inner().gen;
         let state_watcher = context.state_watcher.clone();
@@ -452,11 +1344,15 @@ async fn instance_state_monitor(
         if gen <= last.gen {
             let response = api::InstanceStateMonitorResponse {
                 gen: last.gen,
-                state: propolis_to_api_state(last.state),
+                state: last.state,
             };
             return Ok(HttpResponseOk(response));
         }
-        state_watcher.changed().await.unwrap();
+        if state_watcher.changed().await.is_err() {
+            return Err(HttpError::for_internal_error(
+                "Instance was deleted".to_string(),
+            ));
+        }
     }
 }
 
@@ -469,35 +1365,71 @@ async fn instance_state_put(
     path_params: Path<api::InstancePathParams>,
     request: TypedBody<api::InstanceStateRequested>,
 ) -> Result<HttpResponseUpdatedNoContent, HttpError> {
-    let context = rqctx.context().context.lock().await;
-
-    let context = context.as_ref().ok_or_else(|| {
-        HttpError::for_internal_error(
-            "Server not initialized (no instance)".to_string(),
-        )
-    })?;
-
-    if path_params.into_inner().instance_id != context.properties.id {
-        return Err(HttpError::for_internal_error(
-            "UUID mismatch (path did not match struct)".to_string(),
-        ));
-    }
+    let context =
+        rqctx.context().instance(path_params.into_inner().instance_id).await?;
+    let context = context.lock().await;
+
+    let requested = request.into_inner();
+    let state = api_to_propolis_state(requested);
+    let reboot = match requested {
+        api::InstanceStateRequested::GracefulStop => false,
+        api::InstanceStateRequested::GracefulReboot => true,
+        _ => {
+            context.instance.set_target_state(state).map_err(|err| {
+                HttpError::for_internal_error(format!(
+                    "Failed to set state: {:?}",
+                    err
+                ))
+            })?;
+            return Ok(HttpResponseUpdatedNoContent {});
+        }
+    };
 
-    let state = api_to_propolis_state(request.into_inner());
-    context.instance.set_target_state(state).map_err(|err| {
-        HttpError::for_internal_error(format!("Failed to set state: {:?}", err))
-    })?;
+    // Ask the guest to shut down.  If the instance has not changed state by
+    // the timeout, the guest is assumed to have ignored the request: withdraw
+    // it, lest a later shutdown by the guest be mistaken for a response, and
+    // force the requested state instead.
+    let mut state_watcher = context.state_watcher.clone();
+    let gen = state_watcher.borrow().gen;
+    context.chipset.press_power_button(reboot);
+
+    let instance = Arc::clone(&context.instance);
+    let chipset = Arc::clone(&context.chipset);
+    let log = rqctx.log.new(o!());
+    tokio::spawn(async move {
+        let transitioned = tokio::time::timeout(GRACEFUL_TIMEOUT, async {
+            while state_watcher.borrow().gen == gen {
+                if state_watcher.changed().await.is_err() {
+                    break;
+                }
+            }
+        })
+        .await
+        .is_ok();
+        if !transitioned {
+            info!(log, "Guest ignored power button, forcing {:?}", state);
+            chipset.cancel_power_button();
+            let _ = instance.set_target_state(state);
+        }
+    });
 
     Ok(HttpResponseUpdatedNoContent {})
 }
 
+/// Relays a serial console over a WebSocket, beginning with its output from
+/// `offset`.
+///
+/// Input from the WebSocket is discarded unless the client is the `writer`.
 async fn instance_serial_task(
     mut detach: oneshot::Receiver<()>,
     serial: Arc<Serial<LpcUart>>,
     ws_stream: WebSocketStream<Upgraded>,
+    mut offset: u64,
+    writer: bool,
     log: Logger,
     actx: &AsyncCtx,
 ) -> Result<(), SerialTaskError> {
+    let mut watcher = serial.output_watcher();
     let mut output = [0u8; 1024];
     let mut cur_output: Option<Range<usize>> = None;
     let mut cur_input: Option<(Vec<u8>, usize)> = None;
@@ -506,7 +1438,7 @@ async fn instance_serial_task(
     loop {
         let (uart_read, ws_send) = match &cur_output {
             None => (
-                serial.read_source(&mut output, actx).fuse(),
+                serial.read_output(offset, &mut output, &mut watcher).fuse(),
                 Fuse::terminated(),
             ),
             Some(r) => (
@@ -559,18 +1491,19 @@ async fn instance_serial_task(
                 cur_output = None;
             }
 
-            // Read bytes from the UART to be transmitted out the WS
-            nread = uart_read => {
-                match nread {
-                    Some(0) | None => break,
-                    Some(n) => { cur_output = Some(0..n) }
+            // Read bytes output by the UART to be transmitted out the WS
+            (start, n) = uart_read => {
+                if n == 0 {
+                    break;
                 }
+                cur_output = Some(0..n);
+                offset = start + n as u64;
             }
 
             // Receive bytes from the WS to be injected into the UART
             msg = ws_recv => {
                 match msg {
-                    Some(Ok(Message::Binary(input))) => {
+                    Some(Ok(Message::Binary(input))) if writer => {
                         cur_input = Some((input, 0));
                     }
                     Some(Ok(Message::Close(..))) | None => break,
@@ -582,6 +1515,25 @@ async fn instance_serial_task(
     Ok(())
 }
 
+/// Returns the console of serial port `port`.
+fn serial_console<'a>(
+    context: &'a mut InstanceContext,
+    port: &str,
+) -> Result<&'a mut SerialConsole, HttpError> {
+    if !SERIAL_PORTS.contains(&port) {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("No such serial port: {}", port),
+        ));
+    }
+    context.consoles.get_mut(port).ok_or_else(|| {
+        HttpError::for_bad_request(
+            None,
+            format!("Serial port {} is not a WebSocket console", port),
+        )
+    })
+}
+
 #[endpoint {
     method = GET,
     path = "/instances/{instance_id}/serial",
@@ -589,26 +1541,58 @@ async fn instance_serial_task(
 async fn instance_serial(
     rqctx: Arc<RequestContext<Context>>,
     path_params: Path<api::InstancePathParams>,
+    query_params: Query<api::InstanceSerialParams>,
 ) -> Result<Response<Body>, HttpError> {
-    let mut context = rqctx.context().context.lock().await;
+    let instance_id = path_params.into_inner().instance_id;
+    let offset = query_params.into_inner().offset;
+    serial_attach(&rqctx, instance_id, SERIAL_PORTS[0], offset).await
+}
 
-    let context = context.as_mut().ok_or_else(|| {
-        HttpError::for_internal_error(
-            "Server not initialized (no instance)".to_string(),
-        )
-    })?;
-    if path_params.into_inner().instance_id != context.properties.id {
-        return Err(HttpError::for_internal_error(
-            "UUID mismatch (path did not match struct)".to_string(),
-        ));
-    }
-    if context.serial_task.as_ref().map_or(false, |s| s.is_attached()) {
-        return Err(HttpError::for_unavail(
+#[endpoint {
+    method = GET,
+    path = "/instances/{instance_id}/serial-port/{port}",
+}]
+async fn instance_serial_port(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstanceSerialPathParams>,
+    query_params: Query<api::InstanceSerialParams>,
+) -> Result<Response<Body>, HttpError> {
+    let path_params = path_params.into_inner();
+    let offset = query_params.into_inner().offset;
+    serial_attach(&rqctx, path_params.instance_id, &path_params.port, offset)
+        .await
+}
+
+/// Upgrades the request to a WebSocket relaying serial port `port`.
+async fn serial_attach(
+    rqctx: &RequestContext<Context>,
+    instance_id: Uuid,
+    port: &str,
+    offset: u64,
+) -> Result<Response<Body>, HttpError> {
+    let context = rqctx.context().instance(instance_id).await?;
+    let mut context = context.lock().await;
+    let disp = Arc::clone(&context.instance.disp);
+    let console = serial_console(&mut context, port)?;
+
+    // Nothing is read from beyond the end of the output, however much more
+    // arrives, so such an offset is refused rather than never answered.
+    let end = console.serial.output_end();
+    if offset > end {
+        return Err(HttpError::for_bad_request(
             None,
-            "serial console already attached".to_string(),
+            format!(
+                "offset {} is beyond the end of the output, {}",
+                offset, end
+            ),
         ));
     }
 
+    // The first client to attach holds the console's input. Any others
+    // attached alongside it only observe its output.
+    let writer = !console.writer.as_ref().map_or(false, |s| s.is_attached());
+    console.observers.retain(|s| s.is_attached());
+
     let request = &mut *rqctx.request.lock().await;
 
     if !request
@@ -667,10 +1651,10 @@ async fn instance_serial(
     let (detach_ch, detach_recv) = oneshot::channel();
 
     let upgrade_fut = upgrade::on(&mut *request);
-    let serial = context.serial.clone();
-    let ws_log = rqctx.log.new(o!());
+    let serial = Arc::clone(&console.serial);
+    let ws_log = rqctx.log.new(o!("port" => port.to_string()));
     let err_log = ws_log.clone();
-    let taskid = context.instance.disp.spawn_async(|actx| async move {
+    let taskid = disp.spawn_async(|actx| async move {
         let upgraded = match upgrade_fut.await {
             Ok(u) => u,
             Err(e) => {
@@ -688,13 +1672,25 @@ async fn instance_serial(
             Some(config),
         )
         .await;
-        let _ =
-            instance_serial_task(detach_recv, serial, ws_stream, ws_log, &actx)
-                .await;
+        let _ = instance_serial_task(
+            detach_recv,
+            serial,
+            ws_stream,
+            offset,
+            writer,
+            ws_log,
+            &actx,
+        )
+        .await;
     });
 
     // Save active serial task handle
-    context.serial_task = Some(SerialTask { taskid, detach_ch });
+    let task = SerialTask { taskid, detach_ch };
+    if writer {
+        console.writer = Some(task);
+    } else {
+        console.observers.push(task);
+    }
 
     Ok(Response::builder()
         .status(StatusCode::SWITCHING_PROTOCOLS)
@@ -712,40 +1708,316 @@ async fn instance_serial_detach(
     rqctx: Arc<RequestContext<Context>>,
     path_params: Path<api::InstancePathParams>,
 ) -> Result<HttpResponseUpdatedNoContent, HttpError> {
-    let mut context = rqctx.context().context.lock().await;
+    let instance_id = path_params.into_inner().instance_id;
+    serial_detach(&rqctx, instance_id, SERIAL_PORTS[0]).await
+}
+
+#[endpoint {
+    method = PUT,
+    path = "/instances/{instance_id}/serial-port/{port}/detach",
+}]
+async fn instance_serial_port_detach(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstanceSerialPathParams>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let path_params = path_params.into_inner();
+    serial_detach(&rqctx, path_params.instance_id, &path_params.port).await
+}
+
+/// Detaches the clients of serial port `port`.
+async fn serial_detach(
+    rqctx: &RequestContext<Context>,
+    instance_id: Uuid,
+    port: &str,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let context = rqctx.context().instance(instance_id).await?;
+    let mut context = context.lock().await;
+    let disp = Arc::clone(&context.instance.disp);
+    let console = serial_console(&mut context, port)?;
 
-    let context = context.as_mut().ok_or_else(|| {
-        HttpError::for_internal_error(
-            "Server not initialized (no instance)".to_string(),
+    if !console.is_attached() {
+        return Err(HttpError::for_bad_request(
+            None,
+            "serial console already detached".to_string(),
+        ));
+    }
+    console.detach(&disp).await;
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Encodes a framebuffer snapshot as an RGB PNG image.
+fn frame_to_png(frame: &Frame) -> Result<Vec<u8>, png::EncodingError> {
+    let mut data = Vec::with_capacity(frame.pixels.len() * 3);
+    for px in frame.pixels.iter() {
+        let [b, g, r, _] = px.to_le_bytes();
+        data.extend_from_slice(&[r, g, b]);
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, frame.width, frame.height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+#[endpoint {
+    method = GET,
+    path = "/instances/{instance_id}/screenshot",
+}]
+async fn instance_screenshot(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstancePathParams>,
+) -> Result<Response<Body>, HttpError> {
+    let context =
+        rqctx.context().instance(path_params.into_inner().instance_id).await?;
+    let context = context.lock().await;
+
+    // Guest memory is only accessible from a dispatcher context
+    let (tx, rx) = oneshot::channel();
+    let ramfb = context.ramfb.clone();
+    context.instance.disp.spawn_async(|actx| async move {
+        let frame = match actx.dispctx().await {
+            Some(ctx) => ramfb.read_frame(&ctx),
+            None => None,
+        };
+        let _ = tx.send(frame);
+    });
+    let frame = rx.await.ok().flatten().ok_or_else(|| {
+        HttpError::for_unavail(
+            None,
+            "framebuffer not configured by guest".to_string(),
         )
     })?;
-    if path_params.into_inner().instance_id != context.properties.id {
-        return Err(HttpError::for_internal_error(
-            "UUID mismatch (path did not match struct)".to_string(),
-        ));
+
+    let png = frame_to_png(&frame).map_err(|e| {
+        HttpError::for_internal_error(format!("Cannot encode PNG: {}", e))
+    })?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/png")
+        .body(png.into())?)
+}
+
+#[endpoint {
+    method = POST,
+    path = "/instances/{instance_id}/input",
+}]
+async fn instance_input(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstancePathParams>,
+    request: TypedBody<api::InstanceInputRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let context =
+        rqctx.context().instance(path_params.into_inner().instance_id).await?;
+    let context = context.lock().await;
+
+    let actions = input::translate(&request.into_inner().events)
+        .map_err(|e| HttpError::for_bad_request(None, e))?;
+    input::inject(&context.ps2, &actions);
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+#[endpoint {
+    method = POST,
+    path = "/instances/{instance_id}/disks",
+}]
+async fn instance_disk_attach(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstancePathParams>,
+    request: TypedBody<api::InstanceDiskAttachRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let context =
+        rqctx.context().instance(path_params.into_inner().instance_id).await?;
+    let mut context = context.lock().await;
+
+    let request = request.into_inner();
+    let bdf = hotplug_slot_to_bdf(request.slot, SlotType::Disk)?;
+    context
+        .chipset
+        .pci_hotplug_check(bdf)
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+
+    let config = &rqctx.context().config;
+    let res = context
+        .instance
+        .hotplug(|machine, mctx, disp, inv| {
+            let init = MachineInitializer::new(machine, mctx, disp, inv);
+            let (dev, res) = create_config_disk(&init, config, &request, bdf)?;
+            if let Err(e) = context.chipset.pci_hotplug(bdf, dev) {
+                res.release(disp, inv);
+                return Err(e);
+            }
+            Ok(res)
+        })
+        .map_err(|e| attach_err("disk", e))?;
+    let disks = Arc::clone(&context.disks);
+    release_on_eject(
+        &context.instance,
+        &context.chipset,
+        bdf,
+        res,
+        move || disk_ejected(&disks, bdf),
+    )
+    .map_err(|e| attach_err("disk", e))?;
+    let instance_id = context.properties.id;
+    context.disks.lock().unwrap().push(DiskRecord {
+        bdf,
+        attachment: api::DiskAttachment {
+            generation_id: 0,
+            disk_id: request.id,
+            state: api::DiskAttachmentState::Attached(instance_id),
+        },
+    });
+    context.hot_disks.push(request);
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+#[endpoint {
+    method = DELETE,
+    path = "/instances/{instance_id}/disks/{slot}",
+}]
+async fn instance_disk_detach(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstanceSlotPathParams>,
+) -> Result<HttpResponseDeleted, HttpError> {
+    let path_params = path_params.into_inner();
+    let context = rqctx.context().instance(path_params.instance_id).await?;
+    let context = context.lock().await;
+
+    let bdf = hotplug_slot_to_bdf(api::Slot(path_params.slot), SlotType::Disk)?;
+    context
+        .chipset
+        .pci_unplug(bdf)
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+
+    // Earlier disks in the slot have already been detached. This one is
+    // only once the guest ejects it.
+    let mut disks = context.disks.lock().unwrap();
+    let attached = disks.iter_mut().find(|disk| {
+        disk.bdf == bdf
+            && matches!(
+                disk.attachment.state,
+                api::DiskAttachmentState::Attached(_)
+            )
+    });
+    if let Some(disk) = attached {
+        if let api::DiskAttachmentState::Attached(id) = disk.attachment.state {
+            disk.attachment.generation_id += 1;
+            disk.attachment.state = api::DiskAttachmentState::Detaching(id);
+        }
     }
 
-    let serial_task =
-        context.serial_task.take().filter(|s| s.is_attached()).ok_or_else(
-            || {
+    Ok(HttpResponseDeleted())
+}
+
+#[endpoint {
+    method = POST,
+    path = "/instances/{instance_id}/nics",
+}]
+async fn instance_nic_attach(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstancePathParams>,
+    request: TypedBody<api::NetworkInterfaceRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let context =
+        rqctx.context().instance(path_params.into_inner().instance_id).await?;
+    let mut context = context.lock().await;
+
+    let nic = request.into_inner();
+    let bdf = hotplug_slot_to_bdf(nic.slot, SlotType::NIC)?;
+    context
+        .chipset
+        .pci_hotplug_check(bdf)
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+
+    let res = context
+        .instance
+        .hotplug(|machine, mctx, disp, inv| {
+            let init = MachineInitializer::new(machine, mctx, disp, inv);
+            let (dev, res) = init.create_vnic(&nic.name, bdf)?;
+            if let Err(e) = context.chipset.pci_hotplug(bdf, dev) {
+                res.release(disp, inv);
+                return Err(e);
+            }
+            Ok(res)
+        })
+        .map_err(|e| attach_err("NIC", e))?;
+    release_on_eject(&context.instance, &context.chipset, bdf, res, || {})
+        .map_err(|e| attach_err("NIC", e))?;
+    context.nics.push(nic);
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+#[endpoint {
+    method = DELETE,
+    path = "/instances/{instance_id}/nics/{slot}",
+}]
+async fn instance_nic_detach(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstanceSlotPathParams>,
+) -> Result<HttpResponseDeleted, HttpError> {
+    let path_params = path_params.into_inner();
+    let context = rqctx.context().instance(path_params.instance_id).await?;
+    let mut context = context.lock().await;
+
+    let bdf = hotplug_slot_to_bdf(api::Slot(path_params.slot), SlotType::NIC)?;
+    context
+        .chipset
+        .pci_unplug(bdf)
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+    context.nics.retain(|nic| {
+        slot_to_bdf(nic.slot, SlotType::NIC).map_or(true, |b| b != bdf)
+    });
+
+    Ok(HttpResponseDeleted())
+}
+
+#[endpoint {
+    method = POST,
+    path = "/instances/{instance_id}/nmi",
+}]
+async fn instance_nmi(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstancePathParams>,
+    request: TypedBody<api::InstanceNmiRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let context =
+        rqctx.context().instance(path_params.into_inner().instance_id).await?;
+    let context = context.lock().await;
+
+    let vcpus = request
+        .into_inner()
+        .vcpus
+        .unwrap_or_else(|| (0..context.properties.vcpus).collect());
+    // Resolve all of the vCPUs up front, so a bad ID in the request does not
+    // leave the NMI delivered to only some of them.
+    let hdls = vcpus
+        .iter()
+        .map(|id| {
+            context.instance.vcpu(*id as usize).ok_or_else(|| {
                 HttpError::for_bad_request(
                     None,
-                    "serial console already detached".to_string(),
+                    format!("No such vCPU: {}", id),
                 )
-            },
-        )?;
-
-    serial_task.detach_ch.send(()).map_err(|_| {
-        HttpError::for_internal_error(
-            "couldn't send detach message to serial task".to_string(),
-        )
-    })?;
-    context.instance.disp.wait_exited(serial_task.taskid).await;
-    // let _ = serial_task.taskid.await.map_err(|_| {
-    //     HttpError::for_internal_error(
-    //         "failed to complete existing serial task".to_string(),
-    //     )
-    // })?;
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for hdl in hdls {
+        hdl.inject_nmi().map_err(|e| {
+            HttpError::for_internal_error(format!(
+                "Failed to inject NMI into vCPU {}: {}",
+                hdl.cpuid(),
+                e
+            ))
+        })?;
+    }
 
     Ok(HttpResponseUpdatedNoContent {})
 }
@@ -755,9 +2027,20 @@ pub fn api() -> ApiDescription<Context> {
     let mut api = ApiDescription::new();
     api.register(instance_ensure).unwrap();
     api.register(instance_get).unwrap();
+    api.register(instance_list).unwrap();
+    api.register(instance_delete).unwrap();
     api.register(instance_state_monitor).unwrap();
     api.register(instance_state_put).unwrap();
     api.register(instance_serial).unwrap();
+    api.register(instance_serial_port).unwrap();
     api.register(instance_serial_detach).unwrap();
+    api.register(instance_serial_port_detach).unwrap();
+    api.register(instance_screenshot).unwrap();
+    api.register(instance_input).unwrap();
+    api.register(instance_disk_attach).unwrap();
+    api.register(instance_disk_detach).unwrap();
+    api.register(instance_nic_attach).unwrap();
+    api.register(instance_nic_detach).unwrap();
+    api.register(instance_nmi).unwrap();
     api
 }
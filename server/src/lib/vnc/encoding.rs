@@ -0,0 +1,544 @@
+//! Framebuffer change tracking and the encodings used to send updates.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+
+use flate2::{Compress, Compression, FlushCompress};
+use propolis::hw::qemu::ramfb::Frame;
+
+use super::rfb::{FbUpdate, PixelFormat, Rect, ENC_RAW, ENC_ZRLE};
+
+/// Granularity at which changes to the framebuffer are detected.
+const DIRTY_TILE_SZ: u16 = 16;
+
+/// Size of the tiles a ZRLE rectangle is divided into.
+///
+/// See RFC 6143 Section 7.7.6 ZRLE
+const ZRLE_TILE_SZ: u16 = 64;
+
+const ZRLE_SUBENC_RAW: u8 = 0;
+const ZRLE_SUBENC_SOLID: u8 = 1;
+const ZRLE_SUBENC_PLAIN_RLE: u8 = 128;
+/// Largest palette usable with the packed palette sub-encoding.
+const ZRLE_MAX_PACKED_PALETTE: usize = 16;
+
+/// Fewest rows a scrolled region must span to be sent with CopyRect.
+const MIN_SCROLL_ROWS: usize = 16;
+
+/// A copy of the framebuffer contents as last sent to the client.
+pub struct Shadow {
+    frame: Frame,
+}
+impl Shadow {
+    pub fn new(width: u16, height: u16) -> Self {
+        Self { frame: Frame::blank(width as u32, height as u32) }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.frame.width as u16
+    }
+
+    pub fn height(&self) -> u16 {
+        self.frame.height as u16
+    }
+
+    /// Finds the regions within `bounds` where `frame` differs from the
+    /// contents last sent to the client.
+    ///
+    /// Changes are detected per tile, with adjacent dirty tiles coalesced
+    /// into larger rectangles.
+    pub fn diff(&self, frame: &Frame, bounds: Rect) -> Vec<Rect> {
+        let bounds = bounds.clip(self.width(), self.height());
+        let mut done: Vec<Rect> = Vec::new();
+        // Rectangles which may still be extended down by the next band
+        let mut open: Vec<Rect> = Vec::new();
+
+        let mut y = bounds.y;
+        while y < bounds.y + bounds.h {
+            let h = DIRTY_TILE_SZ.min(bounds.y + bounds.h - y);
+            let mut spans = Vec::new();
+            let mut x = bounds.x;
+            while x < bounds.x + bounds.w {
+                let w = DIRTY_TILE_SZ.min(bounds.x + bounds.w - x);
+                if self.differs(frame, Rect::new(x, y, w, h)) {
+                    match spans.last_mut() {
+                        Some(Rect { x: sx, w: sw, .. }) if *sx + *sw == x => {
+                            *sw += w
+                        }
+                        _ => spans.push(Rect::new(x, y, w, h)),
+                    }
+                }
+                x += w;
+            }
+
+            let mut next_open = Vec::with_capacity(spans.len());
+            for span in spans {
+                let pos =
+                    open.iter().position(|r| r.x == span.x && r.w == span.w);
+                match pos {
+                    Some(i) => {
+                        let mut r = open.swap_remove(i);
+                        r.h += span.h;
+                        next_open.push(r);
+                    }
+                    None => next_open.push(span),
+                }
+            }
+            done.append(&mut open);
+            open = next_open;
+            y += h;
+        }
+        done.append(&mut open);
+        done
+    }
+
+    fn differs(&self, frame: &Frame, rect: Rect) -> bool {
+        (rect.y..rect.y + rect.h).any(|y| {
+            let start =
+                y as usize * self.frame.width as usize + rect.x as usize;
+            let end = start + rect.w as usize;
+            self.frame.pixels[start..end] != frame.pixels[start..end]
+        })
+    }
+
+    /// Records the contents of `rect` in `frame` as sent to the client.
+    pub fn update(&mut self, frame: &Frame, rect: Rect) {
+        for y in rect.y..rect.y + rect.h {
+            let start =
+                y as usize * self.frame.width as usize + rect.x as usize;
+            let end = start + rect.w as usize;
+            self.frame.pixels[start..end]
+                .copy_from_slice(&frame.pixels[start..end]);
+        }
+    }
+
+    /// Looks for a band of rows which has scrolled vertically between the
+    /// contents last sent and `frame`, as is typical for a text console.
+    ///
+    /// Returns the destination of the band, and the row it was copied from.
+    pub fn detect_scroll(&self, frame: &Frame) -> Option<(Rect, u16)> {
+        let width = self.frame.width as usize;
+        let height = self.frame.height as usize;
+        let row = |f: &Frame, y: usize| &f.pixels[y * width..(y + 1) * width];
+        let hash = |px: &[u32]| {
+            let mut hasher = DefaultHasher::new();
+            px.hash(&mut hasher);
+            hasher.finish()
+        };
+        // Rows of a single colour (such as a blank background) match at
+        // any offset and so say nothing about how far content moved.
+        let uniform = |px: &[u32]| px.iter().all(|p| *p == px[0]);
+
+        let mut old_rows: HashMap<u64, Vec<usize>> = HashMap::new();
+        for y in 0..height {
+            let px = row(&self.frame, y);
+            if !uniform(px) {
+                old_rows.entry(hash(px)).or_default().push(y);
+            }
+        }
+
+        // Tally how far each changed row appears to have moved
+        let mut votes: HashMap<isize, usize> = HashMap::new();
+        for y in 0..height {
+            let px = row(frame, y);
+            if px == row(&self.frame, y) || uniform(px) {
+                continue;
+            }
+            if let Some(srcs) = old_rows.get(&hash(px)) {
+                for src in srcs.iter().take(4) {
+                    *votes.entry(*src as isize - y as isize).or_default() += 1;
+                }
+            }
+        }
+        let (shift, count) = votes.into_iter().max_by_key(|(_, c)| *c)?;
+        if count < MIN_SCROLL_ROWS {
+            return None;
+        }
+
+        // Find the longest run of rows matching at that offset
+        let (mut best, mut cur) = ((0, 0), None);
+        for y in 0..height {
+            let src = y as isize + shift;
+            let matches = src >= 0
+                && (src as usize) < height
+                && row(frame, y) == row(&self.frame, src as usize);
+            match (matches, cur) {
+                (true, None) => cur = Some(y),
+                (false, Some(start)) => {
+                    if y - start > best.1 - best.0 {
+                        best = (start, y);
+                    }
+                    cur = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = cur {
+            if height - start > best.1 - best.0 {
+                best = (start, height);
+            }
+        }
+        let (start, end) = best;
+        if end - start < MIN_SCROLL_ROWS {
+            return None;
+        }
+        let dst =
+            Rect::new(0, start as u16, width as u16, (end - start) as u16);
+        Some((dst, (start as isize + shift) as u16))
+    }
+
+    /// Applies a copy within the contents last sent, mirroring what the
+    /// client does upon receiving a CopyRect.
+    pub fn copy(&mut self, dst: Rect, src_y: u16) {
+        let width = self.frame.width as usize;
+        let src = src_y as usize * width;
+        let dst_start = dst.y as usize * width;
+        let len = dst.h as usize * width;
+        self.frame.pixels.copy_within(src..src + len, dst_start);
+    }
+}
+
+/// Encodes framebuffer contents in the client's requested format.
+pub struct Encoder {
+    pub pf: PixelFormat,
+    pub zrle: Option<ZrleStream>,
+}
+impl Encoder {
+    pub fn new() -> Self {
+        Self { pf: PixelFormat::XRGB8888, zrle: None }
+    }
+
+    /// Adds a rectangle with the contents of `rect` in `frame` to `update`.
+    pub fn encode(
+        &mut self,
+        frame: &Frame,
+        rect: Rect,
+        update: &mut FbUpdate,
+    ) -> io::Result<()> {
+        let pf = self.pf;
+        match &mut self.zrle {
+            Some(zrle) => {
+                let data = zrle.encode(frame, rect, &pf)?;
+                let out = update.add_rect(rect, ENC_ZRLE);
+                out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                out.extend_from_slice(&data);
+            }
+            None => {
+                let out = update.add_rect(rect, ENC_RAW);
+                encode_raw(frame, rect, &pf, out);
+            }
+        }
+        Ok(())
+    }
+}
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn rect_pixels<'a>(
+    frame: &'a Frame,
+    rect: Rect,
+) -> impl Iterator<Item = u32> + 'a {
+    let width = frame.width as usize;
+    (rect.y..rect.y + rect.h).flat_map(move |y| {
+        let start = y as usize * width + rect.x as usize;
+        frame.pixels[start..start + rect.w as usize].iter().copied()
+    })
+}
+
+/// Encodes a rectangle as raw pixel data.
+///
+/// See RFC 6143 Section 7.7.1 Raw Encoding
+pub fn encode_raw(
+    frame: &Frame,
+    rect: Rect,
+    pf: &PixelFormat,
+    out: &mut Vec<u8>,
+) {
+    out.reserve(rect.w as usize * rect.h as usize * pf.bytes_per_pixel());
+    for px in rect_pixels(frame, rect) {
+        pf.write_pixel(pf.convert(px), out);
+    }
+}
+
+/// State of the zlib stream shared by all ZRLE rectangles sent to a client.
+///
+/// See RFC 6143 Section 7.7.6 ZRLE
+pub struct ZrleStream {
+    z: Compress,
+}
+impl ZrleStream {
+    pub fn new() -> Self {
+        Self { z: Compress::new(Compression::default(), true) }
+    }
+
+    /// Returns the compressed ZRLE data for a rectangle.
+    fn encode(
+        &mut self,
+        frame: &Frame,
+        rect: Rect,
+        pf: &PixelFormat,
+    ) -> io::Result<Vec<u8>> {
+        let mut raw = Vec::new();
+        let mut ty = rect.y;
+        while ty < rect.y + rect.h {
+            let th = ZRLE_TILE_SZ.min(rect.y + rect.h - ty);
+            let mut tx = rect.x;
+            while tx < rect.x + rect.w {
+                let tw = ZRLE_TILE_SZ.min(rect.x + rect.w - tx);
+                encode_zrle_tile(
+                    frame,
+                    Rect::new(tx, ty, tw, th),
+                    pf,
+                    &mut raw,
+                );
+                tx += tw;
+            }
+            ty += th;
+        }
+        self.compress(&raw)
+    }
+
+    fn compress(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(input.len() / 2 + 64);
+        let start = self.z.total_in();
+        loop {
+            let consumed = (self.z.total_in() - start) as usize;
+            if out.capacity() - out.len() < 64 {
+                out.reserve(input.len() - consumed + 64);
+            }
+            self.z
+                .compress_vec(&input[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let consumed = (self.z.total_in() - start) as usize;
+            // The flush is complete once all input is consumed without
+            // filling the output buffer.
+            if consumed == input.len() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+        }
+    }
+}
+impl Default for ZrleStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encodes a single ZRLE tile, picking whichever sub-encoding suits its
+/// contents.
+fn encode_zrle_tile(
+    frame: &Frame,
+    tile: Rect,
+    pf: &PixelFormat,
+    out: &mut Vec<u8>,
+) {
+    let pixels: Vec<u32> =
+        rect_pixels(frame, tile).map(|px| pf.convert(px)).collect();
+
+    let mut palette: Vec<u32> = Vec::with_capacity(ZRLE_MAX_PACKED_PALETTE);
+    for px in &pixels {
+        if !palette.contains(px) {
+            if palette.len() == ZRLE_MAX_PACKED_PALETTE {
+                palette.clear();
+                break;
+            }
+            palette.push(*px);
+        }
+    }
+
+    match palette.len() {
+        1 => {
+            out.push(ZRLE_SUBENC_SOLID);
+            pf.write_cpixel(palette[0], out);
+        }
+        2..=ZRLE_MAX_PACKED_PALETTE => {
+            // Packed palette, with each row padded out to a whole byte
+            out.push(palette.len() as u8);
+            for px in &palette {
+                pf.write_cpixel(*px, out);
+            }
+            let bits = match palette.len() {
+                2 => 1,
+                3..=4 => 2,
+                _ => 4,
+            };
+            for row in pixels.chunks(tile.w as usize) {
+                let (mut byte, mut used) = (0u8, 0);
+                for px in row {
+                    let idx = palette.iter().position(|p| p == px).unwrap();
+                    byte |= (idx as u8) << (8 - bits - used);
+                    used += bits;
+                    if used == 8 {
+                        out.push(byte);
+                        byte = 0;
+                        used = 0;
+                    }
+                }
+                if used != 0 {
+                    out.push(byte);
+                }
+            }
+        }
+        _ => {
+            let runs = pixels
+                .iter()
+                .zip(pixels.iter().skip(1))
+                .filter(|(a, b)| a != b)
+                .count()
+                + 1;
+            if runs * 2 < pixels.len() {
+                // Plain RLE, with each run length less one sent as a series
+                // of bytes summing to it, all but the last being 255.
+                out.push(ZRLE_SUBENC_PLAIN_RLE);
+                let mut iter = pixels.iter().peekable();
+                while let Some(px) = iter.next() {
+                    let mut len = 1;
+                    while iter.peek() == Some(&px) {
+                        iter.next();
+                        len += 1;
+                    }
+                    pf.write_cpixel(*px, out);
+                    let mut rem = len - 1;
+                    while rem >= 255 {
+                        out.push(255);
+                        rem -= 255;
+                    }
+                    out.push(rem as u8);
+                }
+            } else {
+                out.push(ZRLE_SUBENC_RAW);
+                for px in &pixels {
+                    pf.write_cpixel(*px, out);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(width: u32, height: u32, f: impl Fn(u32, u32) -> u32) -> Frame {
+        let pixels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| f(x, y))
+            .collect();
+        Frame { width, height, pixels }
+    }
+
+    #[test]
+    fn diff_coalesces_tiles() {
+        let shadow = Shadow::new(64, 64);
+        // A 20x40 block straddling the first tile boundaries
+        let fb = frame(64, 64, |x, y| {
+            if (8..28).contains(&x) && (4..44).contains(&y) {
+                0xff_ffff
+            } else {
+                0
+            }
+        });
+        let dirty = shadow.diff(&fb, Rect::new(0, 0, 64, 64));
+        assert_eq!(dirty, vec![Rect::new(0, 0, 32, 48)]);
+
+        // Nothing outside of the requested bounds is reported
+        let dirty = shadow.diff(&fb, Rect::new(32, 0, 32, 64));
+        assert!(dirty.is_empty());
+    }
+
+    #[test]
+    fn diff_after_update() {
+        let mut shadow = Shadow::new(40, 40);
+        let fb = frame(40, 40, |x, y| x ^ y);
+        let all = Rect::new(0, 0, 40, 40);
+        shadow.update(&fb, all);
+        assert!(shadow.diff(&fb, all).is_empty());
+    }
+
+    #[test]
+    fn scroll_detected() {
+        // Every row is distinct, and none are a single colour
+        let text =
+            |x: u32, y: u32| if x % 4 == 0 { (y + 1) * 0x10101 } else { 0 };
+        let mut shadow = Shadow::new(32, 64);
+        shadow.update(&frame(32, 64, text), Rect::new(0, 0, 32, 64));
+
+        // Contents move up by 8 rows with a new blank line at the bottom
+        let fb = frame(32, 64, |x, y| if y < 56 { text(x, y + 8) } else { 0 });
+        let (dst, src_y) = shadow.detect_scroll(&fb).unwrap();
+        assert_eq!(dst, Rect::new(0, 0, 32, 56));
+        assert_eq!(src_y, 8);
+
+        shadow.copy(dst, src_y);
+        let dirty = shadow.diff(&fb, Rect::new(0, 0, 32, 64));
+        assert_eq!(dirty, vec![Rect::new(0, 48, 32, 16)]);
+    }
+
+    #[test]
+    fn cpixel_formats() {
+        let mut out = Vec::new();
+        let pf = PixelFormat::XRGB8888;
+        pf.write_cpixel(pf.convert(0x123456), &mut out);
+        assert_eq!(out, [0x56, 0x34, 0x12]);
+
+        // Colours in the upper bytes, big-endian
+        let pf = PixelFormat {
+            big_endian: true,
+            red_shift: 24,
+            green_shift: 16,
+            blue_shift: 8,
+            ..PixelFormat::XRGB8888
+        };
+        out.clear();
+        pf.write_cpixel(pf.convert(0x123456), &mut out);
+        assert_eq!(out, [0x12, 0x34, 0x56]);
+
+        // 16bpp RGB565 keeps the full pixel
+        let pf = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+            ..PixelFormat::XRGB8888
+        };
+        out.clear();
+        pf.write_cpixel(pf.convert(0xffffff), &mut out);
+        assert_eq!(out, [0xff, 0xff]);
+    }
+
+    #[test]
+    fn zrle_tile_subencodings() {
+        let pf = PixelFormat::XRGB8888;
+        let mut out = Vec::new();
+
+        let solid = frame(4, 4, |_, _| 0x0000ff);
+        encode_zrle_tile(&solid, Rect::new(0, 0, 4, 4), &pf, &mut out);
+        assert_eq!(out, [ZRLE_SUBENC_SOLID, 0xff, 0, 0]);
+
+        // Two colours, 1 bit per index, rows padded out to a byte
+        out.clear();
+        let stripes = frame(3, 2, |x, _| if x == 1 { 0xffffff } else { 0 });
+        encode_zrle_tile(&stripes, Rect::new(0, 0, 3, 2), &pf, &mut out);
+        assert_eq!(
+            out,
+            [2, 0, 0, 0, 0xff, 0xff, 0xff, 0b0100_0000, 0b0100_0000]
+        );
+
+        // Too many colours for a palette, but long runs
+        out.clear();
+        let runs = frame(64, 18, |_, y| y);
+        encode_zrle_tile(&runs, Rect::new(0, 0, 64, 18), &pf, &mut out);
+        assert_eq!(out[0], ZRLE_SUBENC_PLAIN_RLE);
+        assert_eq!(out.len(), 1 + 18 * (3 + 1));
+        assert_eq!(&out[1..9], &[0, 0, 0, 63, 1, 0, 0, 63]);
+    }
+}
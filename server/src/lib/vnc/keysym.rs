@@ -0,0 +1,116 @@
+//! Translation of X11 keysyms, as sent by RFB clients, into the scan code
+//! set 1 make codes accepted by [`PS2Ctrl::key_event`].
+//!
+//! [`PS2Ctrl::key_event`]: propolis::hw::ps2ctrl::PS2Ctrl::key_event
+
+/// Returns the key which produces an ASCII character on a US keyboard
+/// layout, along with whether Shift must be held for it.
+pub fn ascii_scancode(c: u8) -> Option<(u16, bool)> {
+    let code = match c {
+        b'a'..=b'z' => return Some((LETTERS[(c - b'a') as usize], false)),
+        b'A'..=b'Z' => return Some((LETTERS[(c - b'A') as usize], true)),
+        b'1'..=b'9' => return Some(((c - b'1') as u16 + 0x02, false)),
+        b'0' => (0x0b, false),
+        b'!' => (0x02, true),
+        b'@' => (0x03, true),
+        b'#' => (0x04, true),
+        b'$' => (0x05, true),
+        b'%' => (0x06, true),
+        b'^' => (0x07, true),
+        b'&' => (0x08, true),
+        b'*' => (0x09, true),
+        b'(' => (0x0a, true),
+        b')' => (0x0b, true),
+        b'-' => (0x0c, false),
+        b'_' => (0x0c, true),
+        b'=' => (0x0d, false),
+        b'+' => (0x0d, true),
+        b'[' => (0x1a, false),
+        b'{' => (0x1a, true),
+        b']' => (0x1b, false),
+        b'}' => (0x1b, true),
+        b';' => (0x27, false),
+        b':' => (0x27, true),
+        b'\'' => (0x28, false),
+        b'"' => (0x28, true),
+        b'`' => (0x29, false),
+        b'~' => (0x29, true),
+        b'\\' => (0x2b, false),
+        b'|' => (0x2b, true),
+        b',' => (0x33, false),
+        b'<' => (0x33, true),
+        b'.' => (0x34, false),
+        b'>' => (0x34, true),
+        b'/' => (0x35, false),
+        b'?' => (0x35, true),
+        b' ' => (0x39, false),
+        b'\t' => (0x0f, false),
+        b'\n' => (0x1c, false),
+        0x08 => (0x0e, false),
+        0x1b => (0x01, false),
+        _ => return None,
+    };
+    Some(code)
+}
+
+// a through z
+const LETTERS: [u16; 26] = [
+    0x1e, 0x30, 0x2e, 0x20, 0x12, 0x21, 0x22, 0x23, 0x17, 0x24, 0x25, 0x26,
+    0x32, 0x31, 0x18, 0x19, 0x10, 0x13, 0x1f, 0x14, 0x16, 0x2f, 0x11, 0x2d,
+    0x15, 0x2c,
+];
+
+// keypad 0 through 9
+const KEYPAD_DIGITS: [u16; 10] =
+    [0x52, 0x4f, 0x50, 0x51, 0x4b, 0x4c, 0x4d, 0x47, 0x48, 0x49];
+
+/// Returns the key corresponding to an X11 keysym.
+///
+/// Clients send the keysym for the symbol a key produces with the current
+/// modifiers (e.g. `!` rather than `1` with Shift held), along with events
+/// for the modifier keys themselves, so only the key is of interest here.
+pub fn keysym_scancode(keysym: u32) -> Option<u16> {
+    let code = match keysym {
+        // Latin-1 keysyms match their ASCII values
+        0x20..=0x7e => return ascii_scancode(keysym as u8).map(|(c, _)| c),
+        0xff08 => 0x0e,   // BackSpace
+        0xff09 => 0x0f,   // Tab
+        0xff0d => 0x1c,   // Return
+        0xff14 => 0x46,   // Scroll_Lock
+        0xff1b => 0x01,   // Escape
+        0xff50 => 0xe047, // Home
+        0xff51 => 0xe04b, // Left
+        0xff52 => 0xe048, // Up
+        0xff53 => 0xe04d, // Right
+        0xff54 => 0xe050, // Down
+        0xff55 => 0xe049, // Prior (Page Up)
+        0xff56 => 0xe051, // Next (Page Down)
+        0xff57 => 0xe04f, // End
+        0xff63 => 0xe052, // Insert
+        0xff67 => 0xe05d, // Menu
+        0xff7f => 0x45,   // Num_Lock
+        0xff8d => 0xe01c, // KP_Enter
+        0xffaa => 0x37,   // KP_Multiply
+        0xffab => 0x4e,   // KP_Add
+        0xffad => 0x4a,   // KP_Subtract
+        0xffae => 0x53,   // KP_Decimal
+        0xffaf => 0xe035, // KP_Divide
+        0xffb0..=0xffb9 => KEYPAD_DIGITS[(keysym - 0xffb0) as usize],
+        0xffbe..=0xffc7 => (keysym - 0xffbe) as u16 + 0x3b, // F1 - F10
+        0xffc8 => 0x57,                                     // F11
+        0xffc9 => 0x58,                                     // F12
+        0xffe1 => 0x2a,                                     // Shift_L
+        0xffe2 => 0x36,                                     // Shift_R
+        0xffe3 => 0x1d,                                     // Control_L
+        0xffe4 => 0xe01d,                                   // Control_R
+        0xffe5 => 0x3a,                                     // Caps_Lock
+        0xffe7 | 0xffe9 => 0x38,                            // Meta_L, Alt_L
+        0xffe8 | 0xffea => 0xe038,                          // Meta_R, Alt_R
+        0xfe03 => 0xe038, // ISO_Level3_Shift (AltGr)
+        0xffeb => 0xe05b, // Super_L
+        0xffec => 0xe05c, // Super_R
+        0xffff => 0xe053, // Delete
+        _ => return None,
+    };
+    Some(code)
+}
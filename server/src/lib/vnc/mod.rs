@@ -0,0 +1,431 @@
+//! A VNC (RFB) server providing a graphical console for an instance.
+//!
+//! The contents of the guest's `ramfb` framebuffer are sent to clients, with
+//! keyboard and pointer input from clients forwarded into the PS/2
+//! controller.
+//!
+//! RFB offers clients no means to pick among several desktops, so the server
+//! serves a single instance: the first attached, until it is detached.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use slog::{info, o, Logger};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+
+use propolis::dispatch::AsyncCtx;
+use propolis::hw::ps2ctrl::{PS2Ctrl, PS2MButtons};
+use propolis::hw::qemu::ramfb::{Frame, RamFb};
+use propolis::instance::Instance;
+
+mod encoding;
+pub mod keysym;
+mod rfb;
+
+use encoding::{Encoder, Shadow, ZrleStream};
+use rfb::{ClientMsg, FbUpdate, ProtoVersion, Rect};
+
+/// Framebuffer size presented before the guest has configured its own.
+const DEFAULT_WIDTH: u16 = 640;
+const DEFAULT_HEIGHT: u16 = 480;
+
+/// How often the framebuffer is checked for changes while a client is
+/// waiting on an update.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The devices of an instance which the VNC server connects clients to.
+#[derive(Clone)]
+struct Target {
+    name: String,
+    instance: Arc<Instance>,
+    ramfb: Arc<RamFb>,
+    ps2: Arc<PS2Ctrl>,
+}
+
+/// Accepts VNC connections for the attached instance, if any.
+#[derive(Default)]
+pub struct VncServer {
+    target: Mutex<Option<Target>>,
+}
+
+impl VncServer {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Directs subsequent connections to the given instance's devices.
+    ///
+    /// An instance re-created under the same `name` takes the place of its
+    /// predecessor.  Returns an error if another instance is attached.
+    pub fn attach(
+        &self,
+        name: String,
+        instance: Arc<Instance>,
+        ramfb: Arc<RamFb>,
+        ps2: Arc<PS2Ctrl>,
+    ) -> io::Result<()> {
+        let mut target = self.target.lock().unwrap();
+        if let Some(other) = target.as_ref().filter(|t| t.name != name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("VNC server already serves instance {}", other.name),
+            ));
+        }
+        *target = Some(Target { name, instance, ramfb, ps2 });
+        Ok(())
+    }
+
+    /// Stops directing new connections to the instance named `name`, if it is
+    /// the one currently attached.
+    ///
+    /// Established connections are torn down along with the instance.
+    pub fn detach(&self, name: &str) {
+        let mut target = self.target.lock().unwrap();
+        if target.as_ref().map_or(false, |t| t.name == name) {
+            *target = None;
+        }
+    }
+
+    /// Listens for clients on `addr`, running until an error is encountered
+    /// accepting connections.
+    ///
+    /// Connections made while no instance is attached are dropped.
+    pub async fn listen(
+        self: Arc<Self>,
+        addr: SocketAddr,
+        log: Logger,
+    ) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!(log, "VNC server listening on {}", addr);
+        loop {
+            let (sock, peer) = listener.accept().await?;
+            let target = self.target.lock().unwrap().clone();
+            let target = match target {
+                Some(target) => target,
+                None => {
+                    info!(log, "Rejecting VNC client {}: no instance", peer);
+                    continue;
+                }
+            };
+
+            let conn_log = log.new(o!("vnc_client" => peer.to_string()));
+            let conn = Connection::new(&target, conn_log.clone());
+            target.instance.disp.spawn_async(|actx| async move {
+                info!(conn_log, "VNC client connected");
+                match conn.run(sock, &actx).await {
+                    Ok(()) => info!(conn_log, "VNC client disconnected"),
+                    Err(e) => info!(conn_log, "VNC client failed: {}", e),
+                }
+            });
+        }
+    }
+}
+
+/// A pending FramebufferUpdateRequest from the client.
+#[derive(Copy, Clone, Debug)]
+struct UpdateRequest {
+    incremental: bool,
+    rect: Rect,
+}
+impl UpdateRequest {
+    /// Combines two requests, covering the area of both.
+    ///
+    /// Client rects are not yet clipped to the framebuffer, so their far
+    /// edges are computed in u32 and saturated to the u16 range.
+    fn merge(self, other: UpdateRequest) -> UpdateRequest {
+        let end = |pos: u16, len: u16| clamp_dim(pos as u32 + len as u32);
+        let x = self.rect.x.min(other.rect.x);
+        let y = self.rect.y.min(other.rect.y);
+        let x2 =
+            end(self.rect.x, self.rect.w).max(end(other.rect.x, other.rect.w));
+        let y2 =
+            end(self.rect.y, self.rect.h).max(end(other.rect.y, other.rect.h));
+        UpdateRequest {
+            incremental: self.incremental && other.incremental,
+            rect: Rect::new(x, y, x2 - x, y2 - y),
+        }
+    }
+}
+
+/// State of a single client connection.
+struct Connection {
+    name: String,
+    ramfb: Arc<RamFb>,
+    ps2: Arc<PS2Ctrl>,
+    log: Logger,
+
+    encoder: Encoder,
+    shadow: Shadow,
+    copy_rect: bool,
+    desktop_size: bool,
+    pending: Option<UpdateRequest>,
+    pointer: Option<(u16, u16)>,
+}
+
+impl Connection {
+    fn new(target: &Target, log: Logger) -> Self {
+        Self {
+            name: target.name.clone(),
+            ramfb: Arc::clone(&target.ramfb),
+            ps2: Arc::clone(&target.ps2),
+            log,
+            encoder: Encoder::new(),
+            shadow: Shadow::new(DEFAULT_WIDTH, DEFAULT_HEIGHT),
+            copy_rect: false,
+            desktop_size: false,
+            pending: None,
+            pointer: None,
+        }
+    }
+
+    async fn run(mut self, sock: TcpStream, actx: &AsyncCtx) -> io::Result<()> {
+        let (rd, mut wr) = sock.into_split();
+        let mut rd = BufReader::new(rd);
+
+        // See RFC 6143 Section 7.1 Handshaking Messages
+        wr.write_all(rfb::PROTO_VERSION).await?;
+        let mut version = [0u8; 12];
+        rd.read_exact(&mut version).await?;
+        let version = ProtoVersion::parse(&version).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "bad protocol version")
+        })?;
+        if version == ProtoVersion::V3_3 {
+            // The server decides upon the security type
+            wr.write_u32(rfb::SEC_TYPE_NONE as u32).await?;
+        } else {
+            wr.write_all(&[1, rfb::SEC_TYPE_NONE]).await?;
+            if rd.read_u8().await? != rfb::SEC_TYPE_NONE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unsupported security type",
+                ));
+            }
+            if version == ProtoVersion::V3_8 {
+                wr.write_u32(rfb::SEC_RESULT_OK).await?;
+            }
+        }
+
+        // Whether the client wants exclusive access is ignored
+        let _shared = rd.read_u8().await?;
+        let frame = match self.read_frame(actx).await {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        if let Some(frame) = frame {
+            self.shadow =
+                Shadow::new(clamp_dim(frame.width), clamp_dim(frame.height));
+        }
+        let init = rfb::server_init(
+            self.shadow.width(),
+            self.shadow.height(),
+            &self.name,
+        );
+        wr.write_all(&init).await?;
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let reader = read_msgs(rd, tx);
+        tokio::pin!(reader);
+        let mut next_poll = Instant::now();
+
+        loop {
+            tokio::select! {
+                res = &mut reader => return res,
+                msg = rx.recv() => match msg {
+                    Some(msg) => self.handle_msg(msg)?,
+                    None => return Ok(()),
+                },
+                _ = sleep_until(next_poll), if self.pending.is_some() => {}
+            }
+
+            let req = match self.pending {
+                Some(req) => req,
+                None => continue,
+            };
+            // Respond to non-incremental requests right away, as the client
+            // has no framebuffer contents to fall back on.
+            if req.incremental && Instant::now() < next_poll {
+                continue;
+            }
+            next_poll = Instant::now() + POLL_INTERVAL;
+
+            let frame = match self.read_frame(actx).await {
+                Some(frame) => frame,
+                None => return Ok(()),
+            };
+            if let Some(update) = self.build_update(frame, req)? {
+                self.pending = None;
+                wr.write_all(&update).await?;
+            }
+        }
+    }
+
+    /// Reads the guest framebuffer, returning [`None`] if the instance is
+    /// being torn down.
+    async fn read_frame(&self, actx: &AsyncCtx) -> Option<Option<Frame>> {
+        let ctx = actx.dispctx().await?;
+        Some(self.ramfb.read_frame(&ctx))
+    }
+
+    fn handle_msg(&mut self, msg: ClientMsg) -> io::Result<()> {
+        match msg {
+            ClientMsg::SetPixelFormat(pf) => {
+                if !pf.is_supported() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unsupported pixel format {:?}", pf),
+                    ));
+                }
+                self.encoder.pf = pf;
+            }
+            ClientMsg::SetEncodings(encodings) => {
+                // The zlib stream persists for the life of the connection
+                let zrle = encodings.contains(&rfb::ENC_ZRLE);
+                match (zrle, self.encoder.zrle.is_some()) {
+                    (true, false) => {
+                        self.encoder.zrle = Some(ZrleStream::new())
+                    }
+                    (false, true) => self.encoder.zrle = None,
+                    _ => {}
+                }
+                self.copy_rect = encodings.contains(&rfb::ENC_COPY_RECT);
+                self.desktop_size = encodings.contains(&rfb::ENC_DESKTOP_SIZE);
+            }
+            ClientMsg::FbUpdateRequest { incremental, rect } => {
+                let req = UpdateRequest { incremental, rect };
+                self.pending = Some(match self.pending {
+                    Some(pending) => pending.merge(req),
+                    None => req,
+                });
+            }
+            ClientMsg::KeyEvent { down, keysym } => {
+                match keysym::keysym_scancode(keysym) {
+                    Some(code) => self.ps2.key_event(code, down),
+                    None => {
+                        info!(self.log, "Ignoring unknown keysym {:#x}", keysym)
+                    }
+                }
+            }
+            ClientMsg::PointerEvent { buttons, x, y } => {
+                // PS/2 mice only report relative movement, with positive
+                // values moving up rather than down.
+                let (dx, dy) = match self.pointer {
+                    Some((px, py)) => {
+                        (x as i32 - px as i32, py as i32 - y as i32)
+                    }
+                    None => (0, 0),
+                };
+                self.pointer = Some((x, y));
+
+                let mut ps2_buttons = PS2MButtons::empty();
+                ps2_buttons.set(PS2MButtons::LEFT, buttons & 0b001 != 0);
+                ps2_buttons.set(PS2MButtons::MIDDLE, buttons & 0b010 != 0);
+                ps2_buttons.set(PS2MButtons::RIGHT, buttons & 0b100 != 0);
+                self.ps2.mouse_event(dx, dy, ps2_buttons);
+            }
+            ClientMsg::CutText => {}
+        }
+        Ok(())
+    }
+
+    /// Builds the FramebufferUpdate in response to `req`, or [`None`] if
+    /// an incremental request has nothing to be updated yet.
+    fn build_update(
+        &mut self,
+        frame: Option<Frame>,
+        mut req: UpdateRequest,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let mut update = FbUpdate::new();
+
+        // Until the guest sets up the framebuffer, present a blank screen
+        let frame = frame.unwrap_or_else(|| {
+            Frame::blank(
+                self.shadow.width() as u32,
+                self.shadow.height() as u32,
+            )
+        });
+        let (width, height) = (clamp_dim(frame.width), clamp_dim(frame.height));
+        if (width, height) != (self.shadow.width(), self.shadow.height())
+            && self.desktop_size
+        {
+            // The client discards its contents upon being resized
+            self.shadow = Shadow::new(width, height);
+            update.add_rect(
+                Rect::new(0, 0, width, height),
+                rfb::ENC_DESKTOP_SIZE,
+            );
+            req = UpdateRequest {
+                incremental: false,
+                rect: Rect::new(0, 0, width, height),
+            };
+        }
+        // Clients unable to be resized see the framebuffer cropped or padded
+        let frame = fit(frame, self.shadow.width(), self.shadow.height());
+        let full = Rect::new(0, 0, self.shadow.width(), self.shadow.height());
+
+        let dirty = if req.incremental {
+            if self.copy_rect && req.rect == full {
+                if let Some((dst, src_y)) = self.shadow.detect_scroll(&frame) {
+                    update.add_copy_rect(dst, 0, src_y);
+                    self.shadow.copy(dst, src_y);
+                }
+            }
+            self.shadow.diff(&frame, req.rect)
+        } else {
+            let rect = req.rect.clip(full.w, full.h);
+            if rect.is_empty() {
+                Vec::new()
+            } else {
+                vec![rect]
+            }
+        };
+        for rect in dirty {
+            self.encoder.encode(&frame, rect, &mut update)?;
+            self.shadow.update(&frame, rect);
+        }
+
+        if update.is_empty() && req.incremental {
+            Ok(None)
+        } else {
+            Ok(Some(update.finish()))
+        }
+    }
+}
+
+/// Reads messages from the client, passing them along to `tx`.
+async fn read_msgs<R: AsyncRead + Unpin>(
+    mut rd: R,
+    tx: mpsc::Sender<ClientMsg>,
+) -> io::Result<()> {
+    loop {
+        let msg = ClientMsg::read(&mut rd).await?;
+        if tx.send(msg).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn clamp_dim(dim: u32) -> u16 {
+    dim.min(u16::MAX as u32) as u16
+}
+
+/// Crops or pads `frame` to the given dimensions.
+fn fit(frame: Frame, width: u16, height: u16) -> Frame {
+    let (width, height) = (width as u32, height as u32);
+    if frame.width == width && frame.height == height {
+        return frame;
+    }
+    let mut out = Frame::blank(width, height);
+    let copy_w = frame.width.min(width) as usize;
+    for y in 0..frame.height.min(height) as usize {
+        let src = y * frame.width as usize;
+        let dst = y * width as usize;
+        out.pixels[dst..dst + copy_w]
+            .copy_from_slice(&frame.pixels[src..src + copy_w]);
+    }
+    out
+}
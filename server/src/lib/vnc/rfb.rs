@@ -0,0 +1,368 @@
+//! Wire format of the Remote Framebuffer (RFB) protocol.
+//!
+//! See RFC 6143 "The Remote Framebuffer Protocol"
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Protocol version advertised by the server.
+pub const PROTO_VERSION: &[u8; 12] = b"RFB 003.008\n";
+
+/// Security type requiring no authentication.
+pub const SEC_TYPE_NONE: u8 = 1;
+/// SecurityResult indicating the handshake succeeded.
+pub const SEC_RESULT_OK: u32 = 0;
+
+pub const ENC_RAW: i32 = 0;
+pub const ENC_COPY_RECT: i32 = 1;
+pub const ENC_ZRLE: i32 = 16;
+/// Pseudo-encoding indicating the client can handle framebuffer resizes.
+pub const ENC_DESKTOP_SIZE: i32 = -223;
+
+const CLIENT_SET_PIXEL_FORMAT: u8 = 0;
+const CLIENT_SET_ENCODINGS: u8 = 2;
+const CLIENT_FB_UPDATE_REQ: u8 = 3;
+const CLIENT_KEY_EVENT: u8 = 4;
+const CLIENT_POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+const SERVER_FB_UPDATE: u8 = 0;
+
+/// Upper bound on the cut text we are willing to read (and discard).
+const MAX_CUT_TEXT: u32 = 1024 * 1024;
+
+/// Protocol versions a client may select.
+///
+/// Only differences in the security handshake are of concern.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProtoVersion {
+    V3_3,
+    V3_7,
+    V3_8,
+}
+impl ProtoVersion {
+    pub fn parse(buf: &[u8; 12]) -> Option<Self> {
+        match buf {
+            b"RFB 003.003\n" => Some(ProtoVersion::V3_3),
+            b"RFB 003.007\n" => Some(ProtoVersion::V3_7),
+            b"RFB 003.008\n" => Some(ProtoVersion::V3_8),
+            // Clients are expected to choose a version no newer than the
+            // server's, so treat anything else as the oldest we know of.
+            _ if buf.starts_with(b"RFB ") => Some(ProtoVersion::V3_3),
+            _ => None,
+        }
+    }
+}
+
+/// A rectangular region of the framebuffer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub w: u16,
+    pub h: u16,
+}
+impl Rect {
+    pub fn new(x: u16, y: u16, w: u16, h: u16) -> Self {
+        Self { x, y, w, h }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.w == 0 || self.h == 0
+    }
+    /// Clips the rectangle to a framebuffer of the given size.
+    pub fn clip(&self, width: u16, height: u16) -> Rect {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        let w = self.w.min(width - x);
+        let h = self.h.min(height - y);
+        Rect { x, y, w, h }
+    }
+    fn write_header(&self, encoding: i32, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.x.to_be_bytes());
+        out.extend_from_slice(&self.y.to_be_bytes());
+        out.extend_from_slice(&self.w.to_be_bytes());
+        out.extend_from_slice(&self.h.to_be_bytes());
+        out.extend_from_slice(&encoding.to_be_bytes());
+    }
+}
+
+/// Layout of the pixel values sent to a client.
+///
+/// See RFC 6143 Section 7.4 Pixel Format Data Structure
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bits_per_pixel: u8,
+    pub depth: u8,
+    pub big_endian: bool,
+    pub true_colour: bool,
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+impl PixelFormat {
+    /// The format the framebuffer is kept in, which clients start out with.
+    pub const XRGB8888: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_colour: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn parse(buf: &[u8; 16]) -> Self {
+        Self {
+            bits_per_pixel: buf[0],
+            depth: buf[1],
+            big_endian: buf[2] != 0,
+            true_colour: buf[3] != 0,
+            red_max: u16::from_be_bytes([buf[4], buf[5]]),
+            green_max: u16::from_be_bytes([buf[6], buf[7]]),
+            blue_max: u16::from_be_bytes([buf[8], buf[9]]),
+            red_shift: buf[10],
+            green_shift: buf[11],
+            blue_shift: buf[12],
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.bits_per_pixel);
+        out.push(self.depth);
+        out.push(self.big_endian as u8);
+        out.push(self.true_colour as u8);
+        out.extend_from_slice(&self.red_max.to_be_bytes());
+        out.extend_from_slice(&self.green_max.to_be_bytes());
+        out.extend_from_slice(&self.blue_max.to_be_bytes());
+        out.push(self.red_shift);
+        out.push(self.green_shift);
+        out.push(self.blue_shift);
+        // padding
+        out.extend_from_slice(&[0; 3]);
+    }
+
+    /// Whether we are able to produce pixels in this format.
+    ///
+    /// Colour maps are not supported, nor are shifts placing the colour
+    /// values outside of the pixel.
+    pub fn is_supported(&self) -> bool {
+        let fits = |max: u16, shift: u8| {
+            shift < self.bits_per_pixel
+                && (max as u64) << shift < 1u64 << self.bits_per_pixel
+        };
+        self.true_colour
+            && matches!(self.bits_per_pixel, 8 | 16 | 32)
+            && fits(self.red_max, self.red_shift)
+            && fits(self.green_max, self.green_shift)
+            && fits(self.blue_max, self.blue_shift)
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel as usize / 8
+    }
+
+    /// Converts a `0x00RRGGBB` pixel into its value in this format.
+    pub fn convert(&self, px: u32) -> u32 {
+        let scale = |c: u32, max: u16| c * max as u32 / 255;
+        let r = scale((px >> 16) & 0xff, self.red_max);
+        let g = scale((px >> 8) & 0xff, self.green_max);
+        let b = scale(px & 0xff, self.blue_max);
+        r << self.red_shift | g << self.green_shift | b << self.blue_shift
+    }
+
+    /// Appends a converted pixel value.
+    pub fn write_pixel(&self, val: u32, out: &mut Vec<u8>) {
+        match (self.bits_per_pixel, self.big_endian) {
+            (8, _) => out.push(val as u8),
+            (16, false) => out.extend_from_slice(&(val as u16).to_le_bytes()),
+            (16, true) => out.extend_from_slice(&(val as u16).to_be_bytes()),
+            (_, false) => out.extend_from_slice(&val.to_le_bytes()),
+            (_, true) => out.extend_from_slice(&val.to_be_bytes()),
+        }
+    }
+
+    /// Appends a converted pixel value in its compressed (CPIXEL) form.
+    ///
+    /// 32-bit pixels whose colour values all fit within 3 of the bytes are
+    /// sent as just those 3 bytes.
+    ///
+    /// See RFC 6143 Section 7.7.6 ZRLE
+    pub fn write_cpixel(&self, val: u32, out: &mut Vec<u8>) {
+        match self.cpixel_bytes() {
+            Some(CPixel::Low) if self.big_endian => {
+                out.extend_from_slice(&val.to_be_bytes()[1..])
+            }
+            Some(CPixel::Low) => out.extend_from_slice(&val.to_le_bytes()[..3]),
+            Some(CPixel::High) if self.big_endian => {
+                out.extend_from_slice(&val.to_be_bytes()[..3])
+            }
+            Some(CPixel::High) => {
+                out.extend_from_slice(&val.to_le_bytes()[1..])
+            }
+            None => self.write_pixel(val, out),
+        }
+    }
+
+    fn cpixel_bytes(&self) -> Option<CPixel> {
+        if self.bits_per_pixel != 32 || self.depth > 24 {
+            return None;
+        }
+        let mask = (self.red_max as u32) << self.red_shift
+            | (self.green_max as u32) << self.green_shift
+            | (self.blue_max as u32) << self.blue_shift;
+        if mask & 0xff00_0000 == 0 {
+            Some(CPixel::Low)
+        } else if mask & 0xff == 0 {
+            Some(CPixel::High)
+        } else {
+            None
+        }
+    }
+}
+
+/// Which 3 bytes of a 32-bit pixel value make up its CPIXEL
+enum CPixel {
+    Low,
+    High,
+}
+
+/// Messages sent from the client to the server.
+///
+/// See RFC 6143 Section 7.5 Client-to-Server Messages
+#[derive(Debug)]
+pub enum ClientMsg {
+    SetPixelFormat(PixelFormat),
+    SetEncodings(Vec<i32>),
+    FbUpdateRequest { incremental: bool, rect: Rect },
+    KeyEvent { down: bool, keysym: u32 },
+    PointerEvent { buttons: u8, x: u16, y: u16 },
+    CutText,
+}
+impl ClientMsg {
+    /// Reads the next message sent by the client.
+    pub async fn read<R: AsyncRead + Unpin>(rd: &mut R) -> io::Result<Self> {
+        match rd.read_u8().await? {
+            CLIENT_SET_PIXEL_FORMAT => {
+                let mut buf = [0u8; 3 + 16];
+                rd.read_exact(&mut buf).await?;
+                let mut pf = [0u8; 16];
+                pf.copy_from_slice(&buf[3..]);
+                Ok(ClientMsg::SetPixelFormat(PixelFormat::parse(&pf)))
+            }
+            CLIENT_SET_ENCODINGS => {
+                let _pad = rd.read_u8().await?;
+                let count = rd.read_u16().await?;
+                let mut encodings = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    encodings.push(rd.read_i32().await?);
+                }
+                Ok(ClientMsg::SetEncodings(encodings))
+            }
+            CLIENT_FB_UPDATE_REQ => {
+                let incremental = rd.read_u8().await? != 0;
+                let x = rd.read_u16().await?;
+                let y = rd.read_u16().await?;
+                let w = rd.read_u16().await?;
+                let h = rd.read_u16().await?;
+                Ok(ClientMsg::FbUpdateRequest {
+                    incremental,
+                    rect: Rect::new(x, y, w, h),
+                })
+            }
+            CLIENT_KEY_EVENT => {
+                let down = rd.read_u8().await? != 0;
+                let _pad = rd.read_u16().await?;
+                let keysym = rd.read_u32().await?;
+                Ok(ClientMsg::KeyEvent { down, keysym })
+            }
+            CLIENT_POINTER_EVENT => {
+                let buttons = rd.read_u8().await?;
+                let x = rd.read_u16().await?;
+                let y = rd.read_u16().await?;
+                Ok(ClientMsg::PointerEvent { buttons, x, y })
+            }
+            CLIENT_CUT_TEXT => {
+                let mut pad = [0u8; 3];
+                rd.read_exact(&mut pad).await?;
+                let len = rd.read_u32().await?;
+                if len > MAX_CUT_TEXT {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "cut text too long",
+                    ));
+                }
+                // The guest has no clipboard to receive it
+                let mut text = vec![0u8; len as usize];
+                rd.read_exact(&mut text).await?;
+                Ok(ClientMsg::CutText)
+            }
+            kind => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown client message type {}", kind),
+            )),
+        }
+    }
+}
+
+/// Builds the ServerInit message sent once the handshake completes.
+///
+/// See RFC 6143 Section 7.3.2 ServerInit
+pub fn server_init(width: u16, height: u16, name: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(24 + name.len());
+    out.extend_from_slice(&width.to_be_bytes());
+    out.extend_from_slice(&height.to_be_bytes());
+    PixelFormat::XRGB8888.write(&mut out);
+    out.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    out.extend_from_slice(name.as_bytes());
+    out
+}
+
+/// Incrementally builds a FramebufferUpdate message.
+///
+/// See RFC 6143 Section 7.6.1 FramebufferUpdate
+pub struct FbUpdate {
+    buf: Vec<u8>,
+    count: u16,
+}
+impl FbUpdate {
+    pub fn new() -> Self {
+        // message type, padding, and the rectangle count filled in later
+        Self { buf: vec![SERVER_FB_UPDATE, 0, 0, 0], count: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Adds a rectangle, returning the buffer its encoded data should be
+    /// appended to.
+    pub fn add_rect(&mut self, rect: Rect, encoding: i32) -> &mut Vec<u8> {
+        rect.write_header(encoding, &mut self.buf);
+        self.count += 1;
+        &mut self.buf
+    }
+
+    /// Adds a CopyRect rectangle sourcing its contents from `(src_x, src_y)`.
+    pub fn add_copy_rect(&mut self, rect: Rect, src_x: u16, src_y: u16) {
+        let out = self.add_rect(rect, ENC_COPY_RECT);
+        out.extend_from_slice(&src_x.to_be_bytes());
+        out.extend_from_slice(&src_y.to_be_bytes());
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.buf[2..4].copy_from_slice(&self.count.to_be_bytes());
+        self.buf
+    }
+}
+impl Default for FbUpdate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -6,6 +6,7 @@ use dropshot::{
     ConfigDropshot, ConfigLogging, ConfigLoggingLevel, HttpServerStarter,
 };
 use propolis::usdt::register_probes;
+use slog::{error, o};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -27,6 +28,10 @@ enum Args {
 
         #[structopt(name = "PROPOLIS_IP:PORT", parse(try_from_str))]
         propolis_addr: SocketAddr,
+
+        /// Address on which to serve the instance console over VNC.
+        #[structopt(long, parse(try_from_str))]
+        vnc_addr: Option<SocketAddr>,
     },
 }
 
@@ -51,7 +56,7 @@ async fn main() -> anyhow::Result<()> {
     match args {
         Args::OpenApi => run_openapi()
             .map_err(|e| anyhow!("Cannot generate OpenAPI spec: {}", e)),
-        Args::Run { cfg, propolis_addr } => {
+        Args::Run { cfg, propolis_addr, vnc_addr } => {
             let config = config::parse(&cfg)?;
 
             // Dropshot configuration.
@@ -67,6 +72,15 @@ async fn main() -> anyhow::Result<()> {
             )?;
 
             let context = server::Context::new(config);
+            if let Some(addr) = vnc_addr {
+                let vnc = context.vnc_server();
+                let vnc_log = log.new(o!("component" => "vnc"));
+                tokio::spawn(async move {
+                    if let Err(e) = vnc.listen(addr, vnc_log.clone()).await {
+                        error!(vnc_log, "VNC server failed: {}", e);
+                    }
+                });
+            }
             let server = HttpServerStarter::new(
                 &config_dropshot,
                 server::api(),
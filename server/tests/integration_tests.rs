@@ -114,6 +114,7 @@ mod illumos_integration_tests {
                 vcpus: 2,
             },
             nics: vec![],
+            disks: vec![],
         }
     }
 